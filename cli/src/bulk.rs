@@ -0,0 +1,147 @@
+//! Loads `key=value` lines from a file or the standard input through the
+//! client-streaming `BulkInsert` RPC, without holding them all in memory.
+
+use futures::stream;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+use structopt::StructOpt;
+use tokio::sync::mpsc;
+use tonic::transport::Channel;
+use tonic::{Code, Response, Status};
+
+use dumpstors_lib::models::Record;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{BulkInsertChunk, BulkInsertMode, BulkInsertSummary, Limits};
+
+#[derive(Debug, StructOpt)]
+pub struct BulkInsertOpt {
+    #[structopt(long, short)]
+    pub keyspace: String,
+
+    /// File of `key=value` lines, the standard input when omitted
+    pub file: Option<PathBuf>,
+
+    /// Publish the records at once when all of them are received, rather than
+    /// committing them as they arrive
+    #[structopt(long)]
+    pub stage: bool,
+
+    /// Records read before being sent, split further to fit the server limits
+    #[structopt(long, default_value = "1000")]
+    pub chunk_records: usize,
+}
+
+#[allow(clippy::result_large_err)]
+fn parse(number: usize, line: &str) -> Result<Record, Status> {
+    match line.split_once('=') {
+        Some((key, value)) => Ok(Record {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }),
+        None => Err(Status::invalid_argument(format!(
+            "Line {} is not a key=value record",
+            number
+        ))),
+    }
+}
+
+/// Reads the input and sends it in chunks, the first one naming the mode.
+/// Stops early once the call ended, which then reports why.
+#[allow(clippy::result_large_err)]
+fn read_chunks(
+    input: Box<dyn BufRead + Send>,
+    opts: &BulkInsertOpt,
+    limits: &Limits,
+    tx: &mpsc::Sender<Result<BulkInsertChunk, ()>>,
+) -> Result<(), Status> {
+    let mut mode = match opts.stage {
+        true => BulkInsertMode::StageThenPublish,
+        false => BulkInsertMode::CommitAsYouGo,
+    } as i32;
+    let mut sent = false;
+    let mut send = |records: Vec<Record>| {
+        for records in limits.batches(&opts.keyspace, records) {
+            let chunk = BulkInsertChunk {
+                keyspace: opts.keyspace.clone(),
+                records,
+                mode: std::mem::take(&mut mode),
+            };
+            sent = true;
+            if tx.blocking_send(Ok(chunk)).is_err() {
+                return false;
+            }
+        }
+        true
+    };
+
+    let mut records = vec![];
+    for (i, line) in input.lines().enumerate() {
+        let line = line.map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if line.is_empty() {
+            continue;
+        }
+        records.push(parse(i + 1, &line)?);
+        if records.len() >= opts.chunk_records.max(1) && !send(std::mem::take(&mut records)) {
+            return Ok(());
+        }
+    }
+    send(records);
+    if !sent {
+        // The keyspace and mode are still needed to load nothing.
+        let _ = tx.blocking_send(Ok(BulkInsertChunk {
+            keyspace: opts.keyspace.clone(),
+            records: vec![],
+            mode,
+        }));
+    }
+    Ok(())
+}
+
+#[allow(clippy::result_large_err)]
+pub async fn bulk_insert(
+    client: &mut StoreClient<Channel>,
+    opts: BulkInsertOpt,
+) -> Result<Response<BulkInsertSummary>, Status> {
+    let limits = match client.get_limits(()).await {
+        Ok(limits) => limits.into_inner(),
+        Err(e) if e.code() == Code::Unimplemented => Limits::default(),
+        Err(e) => return Err(e),
+    };
+    let input: Box<dyn BufRead + Send> = match &opts.file {
+        Some(path) => match File::open(path) {
+            Ok(file) => Box::new(BufReader::new(file)),
+            Err(e) => {
+                return Err(Status::invalid_argument(format!(
+                    "Failed to open '{}': {}",
+                    path.display(),
+                    e
+                )))
+            }
+        },
+        None => Box::new(BufReader::new(std::io::stdin())),
+    };
+
+    let (tx, rx) = mpsc::channel(4);
+    let reader = tokio::task::spawn_blocking(move || {
+        let read = read_chunks(input, &opts, &limits, &tx);
+        if read.is_err() {
+            let _ = tx.blocking_send(Err(()));
+        }
+        read
+    });
+    let chunks = stream::unfold(rx, |mut rx| async move {
+        match rx.recv().await {
+            Some(Ok(chunk)) => Some((chunk, rx)),
+            // Ending the stream would commit what was sent, so the call is
+            // left pending until cancelled with the error of the input.
+            Some(Err(())) => futures::future::pending().await,
+            None => None,
+        }
+    });
+
+    tokio::select! {
+        summary = client.bulk_insert(chunks) => summary,
+        Ok(Err(e)) = reader => Err(e),
+    }
+}
@@ -0,0 +1,73 @@
+use structopt::StructOpt;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::{Interceptor, Request, Status};
+
+use super::tls::TlsOpt;
+use dumpstors_lib::admin::admin_client::AdminClient;
+use dumpstors_lib::raft::cluster_client::ClusterClient;
+use dumpstors_lib::store::store_client::StoreClient;
+
+#[derive(Debug, Default, StructOpt)]
+pub struct ConnectOpt {
+    #[structopt(flatten)]
+    pub tls: TlsOpt,
+
+    /// Bearer token authenticating requests
+    #[structopt(long)]
+    pub token: Option<String>,
+
+    /// Identifies the client to server rate limits, instead of its address
+    #[structopt(long)]
+    pub client_id: Option<String>,
+}
+
+impl ConnectOpt {
+    #[allow(clippy::result_large_err)]
+    fn interceptor(&self) -> Result<Interceptor, Status> {
+        let value = match &self.token {
+            Some(token) => Some(
+                MetadataValue::from_str(&format!("Bearer {}", token))
+                    .map_err(|_| Status::invalid_argument("Invalid token"))?,
+            ),
+            None => None,
+        };
+        let client_id = match &self.client_id {
+            Some(id) => Some(
+                MetadataValue::from_str(id)
+                    .map_err(|_| Status::invalid_argument("Invalid client ID"))?,
+            ),
+            None => None,
+        };
+
+        Ok(Interceptor::new(move |mut request: Request<()>| {
+            if let Some(value) = &value {
+                request
+                    .metadata_mut()
+                    .insert("authorization", value.clone());
+            }
+            if let Some(id) = &client_id {
+                request.metadata_mut().insert("client-id", id.clone());
+            }
+            Ok(request)
+        }))
+    }
+
+    pub async fn store_client(&self, addr: &str) -> Result<StoreClient<Channel>, Status> {
+        let channel = self.tls.connect(addr).await?;
+        Ok(StoreClient::with_interceptor(channel, self.interceptor()?))
+    }
+
+    pub async fn cluster_client(&self, addr: &str) -> Result<ClusterClient<Channel>, Status> {
+        let channel = self.tls.connect(addr).await?;
+        Ok(ClusterClient::with_interceptor(
+            channel,
+            self.interceptor()?,
+        ))
+    }
+
+    pub async fn admin_client(&self, addr: &str) -> Result<AdminClient<Channel>, Status> {
+        let channel = self.tls.connect(addr).await?;
+        Ok(AdminClient::with_interceptor(channel, self.interceptor()?))
+    }
+}
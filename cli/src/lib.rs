@@ -1,20 +1,73 @@
+use dumpstors_lib::ring::HashRing;
 use dumpstors_lib::store::store_client::StoreClient;
+use tonic::transport::Channel;
 
+pub mod bulk;
+pub mod connect;
 pub mod query;
+pub mod repair;
 pub mod store;
+pub mod tls;
 
+use connect::ConnectOpt;
 use query::*;
+use store::admin::*;
+use store::cluster::*;
 use store::keyspace::*;
 
+/// Connects to the node owning `key`, as advertised by the bootstrap node's
+/// cluster topology. Falls back to the bootstrap node when it is not sharded.
+async fn connect_owner(
+    connect: &ConnectOpt,
+    client: &mut StoreClient<Channel>,
+    keyspace: &str,
+    key: &str,
+) -> Result<Option<StoreClient<Channel>>, tonic::Status> {
+    let topology = client.get_cluster_topology(()).await?.into_inner();
+    let ring = HashRing::new(&topology);
+
+    match ring.node_for(keyspace, key.as_bytes()) {
+        Some(node) => match connect.store_client(&node.addr).await {
+            Ok(client) => Ok(Some(client)),
+            // The bootstrap node forwards requests to the owner anyway.
+            Err(_) => Ok(None),
+        },
+        None => Ok(None),
+    }
+}
+
 pub async fn execute(q: Query) -> Result<QueryResult, tonic::Status> {
-    let mut client = StoreClient::connect(q.bootstrap.clone()).await.unwrap();
+    let mut client = q.connect.store_client(&q.bootstrap).await?;
 
     let resp: QueryResult = match q.opts {
-        QueryOpt::Get(args) => client.get_key(args).await?.into(),
+        QueryOpt::Get(args) => {
+            let mut client = connect_owner(&q.connect, &mut client, &args.keyspace, &args.key)
+                .await?
+                .unwrap_or(client);
+            client.get_key(args).await?.into()
+        }
+
+        QueryOpt::Insert(args) => {
+            let mut client = connect_owner(&q.connect, &mut client, &args.keyspace, &args.key)
+                .await?
+                .unwrap_or(client);
+            client.insert_key(args).await?.into()
+        }
 
-        QueryOpt::Insert(args) => client.insert_key(args).await?.into(),
+        QueryOpt::Delete(args) => {
+            let mut client = connect_owner(&q.connect, &mut client, &args.keyspace, &args.key)
+                .await?
+                .unwrap_or(client);
+            client.delete_key(args).await?.into()
+        }
 
-        QueryOpt::Delete(args) => client.delete_key(args).await?.into(),
+        QueryOpt::BulkInsert(opts) => bulk::bulk_insert(&mut client, opts).await?.into(),
+
+        QueryOpt::Repair(opts) => repair::repair(&q.connect, &mut client, opts).await?.into(),
+
+        QueryOpt::Topology => client.get_cluster_topology(()).await?.into(),
+
+        QueryOpt::Limits => client.get_limits(()).await?.into(),
 
         QueryOpt::Keyspaces(ks) => match ks {
             KeyspaceCommand::Get(args) => client.get_keyspace(args).await?.into(),
@@ -26,7 +79,37 @@ pub async fn execute(q: Query) -> Result<QueryResult, tonic::Status> {
             KeyspaceCommand::Delete(args) => client.delete_keyspace(args).await?.into(),
 
             KeyspaceCommand::Truncate(args) => client.truncate_keyspace(args).await?.into(),
+
+            KeyspaceCommand::Quota(args) => client.set_keyspace_quota(args).await?.into(),
+
+            KeyspaceCommand::Usage(args) => client.get_keyspace_usage(args).await?.into(),
         },
+
+        QueryOpt::Cluster(cmd) => {
+            let mut client = q.connect.cluster_client(&q.bootstrap).await?;
+
+            match cmd {
+                ClusterCommand::Status => client.get_cluster_status(()).await?.into(),
+
+                ClusterCommand::Add(args) => client.add_member(args).await?.into(),
+
+                ClusterCommand::Remove(args) => client.remove_member(args).await?.into(),
+            }
+        }
+
+        QueryOpt::Admin(cmd) => {
+            let mut client = q.connect.admin_client(&q.bootstrap).await?;
+
+            match cmd {
+                AdminCommand::Quarantined => client.list_quarantined_keyspaces(()).await?.into(),
+
+                AdminCommand::ReadOnly(args) => client.set_read_only(args).await?.into(),
+
+                AdminCommand::RateLimits => client.get_rate_limits(()).await?.into(),
+
+                AdminCommand::RateLimit(args) => client.set_rate_limit(args).await?.into(),
+            }
+        }
     };
 
     Ok(resp)
@@ -3,8 +3,13 @@ use std::str;
 use structopt::StructOpt;
 use tonic::Response;
 
+use super::bulk;
+use super::connect::ConnectOpt;
+use super::repair;
 use super::store::*;
+use dumpstors_lib::admin as admin_lib;
 use dumpstors_lib::models::*;
+use dumpstors_lib::raft;
 use dumpstors_lib::store as store_lib;
 
 #[derive(Debug, StructOpt)]
@@ -12,14 +17,27 @@ pub enum QueryOpt {
     Insert(InsertKeyOpt),
     Get(GetKeyOpt),
     Delete(DeleteKeyOpt),
+    /// Load `key=value` lines in a single streaming call
+    BulkInsert(bulk::BulkInsertOpt),
     Keyspaces(keyspace::KeyspaceCommand),
+    Cluster(cluster::ClusterCommand),
+    Admin(admin::AdminCommand),
+    Topology,
+    /// Show the limits of the requests accepted by the server
+    Limits,
+    Repair(repair::RepairOpt),
 }
 
 #[derive(Debug, StructOpt)]
 pub struct Query {
+    /// Server address, such as `https://host:4242` or
+    /// `unix:///run/dumpstors/dumpstors.sock`
     #[structopt(short, long, default_value = "http://localhost:4242")]
     pub bootstrap: String,
 
+    #[structopt(flatten)]
+    pub connect: ConnectOpt,
+
     #[structopt(flatten)]
     pub opts: QueryOpt,
 }
@@ -36,9 +54,27 @@ pub enum QueryResult {
     Record(Response<Record>),
     Keyspace(Response<Keyspace>),
     KeyspaceList(Response<store_lib::ListKeyspacesResponse>),
+    KeyspaceUsage(Response<store_lib::KeyspaceUsage>),
+    Limits(Response<store_lib::Limits>),
+    BulkInsert(Response<store_lib::BulkInsertSummary>),
+    ClusterStatus(Response<raft::ClusterStatus>),
+    Membership(Response<raft::Membership>),
+    Topology(Response<ClusterTopology>),
+    Repair(repair::RepairReport),
+    Quarantined(Response<admin_lib::QuarantinedKeyspaces>),
+    RateLimits(Response<admin_lib::RateLimits>),
     Empty(Response<()>),
 }
 
+fn format_membership(membership: &raft::Membership) -> String {
+    membership
+        .members
+        .iter()
+        .map(|m| format!("{}={}", m.id, m.addr))
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
 impl fmt::Display for QueryResult {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -64,6 +100,100 @@ impl fmt::Display for QueryResult {
                     .join("\n")
             ),
             Self::Keyspace(resp) => write!(f, "{}", resp.get_ref().name),
+            Self::KeyspaceUsage(resp) => {
+                let usage = resp.get_ref();
+                let quota = usage.quota.clone().unwrap_or_default();
+
+                write!(
+                    f,
+                    "keys={} bytes={} max_keys={} max_bytes={} max_key_size={} max_value_size={}",
+                    usage.keys,
+                    usage.bytes,
+                    quota.max_keys,
+                    quota.max_bytes,
+                    quota.max_key_size,
+                    quota.max_value_size
+                )
+            }
+            Self::Limits(resp) => {
+                let limits = resp.get_ref();
+
+                write!(
+                    f,
+                    "max_message_size={} max_batch_records={} max_key_size={} max_value_size={} max_staged_bytes={}",
+                    limits.max_message_size,
+                    limits.max_batch_records,
+                    limits.max_key_size,
+                    limits.max_value_size,
+                    limits.max_staged_bytes
+                )
+            }
+            Self::BulkInsert(resp) => {
+                let summary = resp.get_ref();
+
+                write!(
+                    f,
+                    "records={} bytes={} duration_ms={}",
+                    summary.records, summary.bytes, summary.duration_ms
+                )
+            }
+            Self::ClusterStatus(resp) => {
+                let status = resp.get_ref();
+
+                writeln!(
+                    f,
+                    "id={} role={} term={} leader={} commit={} applied={}",
+                    status.id,
+                    status.role,
+                    status.term,
+                    status.leader_id,
+                    status.commit_index,
+                    status.last_applied
+                )?;
+                match &status.membership {
+                    Some(membership) => write!(f, "{}", format_membership(membership)),
+                    None => Ok(()),
+                }
+            }
+            Self::Membership(resp) => write!(f, "{}", format_membership(resp.get_ref())),
+            Self::Topology(resp) => {
+                let topology = resp.get_ref();
+
+                write!(f, "version={} vnodes={}", topology.version, topology.vnodes)?;
+                for node in topology.nodes.iter() {
+                    write!(f, "\n{}={}", node.id, node.addr)?;
+                }
+                Ok(())
+            }
+            Self::Repair(report) => write!(f, "{}", report),
+            Self::Quarantined(resp) => write!(
+                f,
+                "{}",
+                resp.get_ref()
+                    .keyspaces
+                    .iter()
+                    .map(|ks| format!("{} {} {}", ks.name, ks.path, ks.reason))
+                    .collect::<Vec<String>>()
+                    .join("\n")
+            ),
+            Self::RateLimits(resp) => {
+                let limits = resp.get_ref();
+                let clients = limits.clients.iter().map(|l| ("client", l));
+                let keyspaces = limits.keyspaces.iter().map(|l| ("keyspace", l));
+
+                write!(
+                    f,
+                    "{}",
+                    clients
+                        .chain(keyspaces)
+                        .map(|(kind, l)| format!(
+                            "{} {} requests_per_sec={} bytes_per_sec={}",
+                            kind, l.name, l.requests_per_sec, l.bytes_per_sec
+                        ))
+                        .collect::<Vec<String>>()
+                        .join("\n")
+                )
+            }
             Self::Empty(_) => write!(f, ""),
         }
     }
@@ -87,6 +217,60 @@ impl From<Response<store_lib::ListKeyspacesResponse>> for QueryResult {
     }
 }
 
+impl From<Response<store_lib::KeyspaceUsage>> for QueryResult {
+    fn from(resp: Response<store_lib::KeyspaceUsage>) -> Self {
+        QueryResult::KeyspaceUsage(resp)
+    }
+}
+
+impl From<Response<store_lib::Limits>> for QueryResult {
+    fn from(resp: Response<store_lib::Limits>) -> Self {
+        QueryResult::Limits(resp)
+    }
+}
+
+impl From<Response<store_lib::BulkInsertSummary>> for QueryResult {
+    fn from(resp: Response<store_lib::BulkInsertSummary>) -> Self {
+        QueryResult::BulkInsert(resp)
+    }
+}
+
+impl From<Response<raft::ClusterStatus>> for QueryResult {
+    fn from(resp: Response<raft::ClusterStatus>) -> Self {
+        QueryResult::ClusterStatus(resp)
+    }
+}
+
+impl From<Response<raft::Membership>> for QueryResult {
+    fn from(resp: Response<raft::Membership>) -> Self {
+        QueryResult::Membership(resp)
+    }
+}
+
+impl From<Response<ClusterTopology>> for QueryResult {
+    fn from(resp: Response<ClusterTopology>) -> Self {
+        QueryResult::Topology(resp)
+    }
+}
+
+impl From<repair::RepairReport> for QueryResult {
+    fn from(report: repair::RepairReport) -> Self {
+        QueryResult::Repair(report)
+    }
+}
+
+impl From<Response<admin_lib::QuarantinedKeyspaces>> for QueryResult {
+    fn from(resp: Response<admin_lib::QuarantinedKeyspaces>) -> Self {
+        QueryResult::Quarantined(resp)
+    }
+}
+
+impl From<Response<admin_lib::RateLimits>> for QueryResult {
+    fn from(resp: Response<admin_lib::RateLimits>) -> Self {
+        QueryResult::RateLimits(resp)
+    }
+}
+
 impl From<Response<()>> for QueryResult {
     fn from(resp: Response<()>) -> QueryResult {
         QueryResult::Empty(resp)
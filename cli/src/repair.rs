@@ -0,0 +1,253 @@
+//! Reconciles the keyspaces of two servers by comparing their Merkle trees
+//! and only transferring the key ranges that differ.
+//!
+//! Keys missing on one server are copied from the other one. When both hold
+//! a key with different values, the source server wins. Deletes are not
+//! recorded, so a key deleted on one server only is brought back.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use structopt::StructOpt;
+use tonic::transport::Channel;
+use tonic::{Code, Status};
+
+use super::connect::ConnectOpt;
+use dumpstors_lib::merkle::{self, MerkleTree};
+use dumpstors_lib::models::*;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{GetKeyRangesQuery, GetKeyspaceDigestQuery, InsertKeysQuery, Limits};
+
+#[derive(Debug, StructOpt)]
+pub struct RepairOpt {
+    /// Server to reconcile with the bootstrap server
+    pub target: String,
+
+    /// Only repair this keyspace
+    #[structopt(long, short)]
+    pub keyspace: Option<String>,
+
+    /// Only report the differences
+    #[structopt(long)]
+    pub dry_run: bool,
+
+    #[structopt(long, default_value = "10")]
+    pub depth: u32,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct KeyspaceRepair {
+    pub keyspace: String,
+    pub ranges: usize,
+    pub missing_on_source: usize,
+    pub missing_on_target: usize,
+    pub conflicting: usize,
+}
+
+#[derive(Debug, Default)]
+pub struct RepairReport {
+    pub dry_run: bool,
+    pub keyspaces: Vec<KeyspaceRepair>,
+}
+
+impl RepairReport {
+    pub fn in_sync(&self) -> bool {
+        self.keyspaces.iter().all(|ks| ks.ranges == 0)
+    }
+}
+
+impl fmt::Display for RepairReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .keyspaces
+            .iter()
+            .map(|ks| match ks.ranges {
+                0 => format!("{}: in sync", ks.keyspace),
+                _ => format!(
+                    "{}: {} differing ranges, {} missing on source, {} missing on target, {} conflicting",
+                    ks.keyspace,
+                    ks.ranges,
+                    ks.missing_on_source,
+                    ks.missing_on_target,
+                    ks.conflicting
+                ),
+            })
+            .collect::<Vec<String>>();
+
+        write!(f, "{}", lines.join("\n"))?;
+        if self.dry_run && !self.in_sync() {
+            write!(f, "\n(dry run, nothing was repaired)")?;
+        }
+        Ok(())
+    }
+}
+
+async fn keyspace_names(client: &mut StoreClient<Channel>) -> Result<BTreeSet<String>, Status> {
+    let resp = client.list_keyspaces(()).await?.into_inner();
+    Ok(resp.keyspaces.into_iter().map(|ks| ks.name).collect())
+}
+
+/// Returns the Merkle tree of a keyspace, empty when the keyspace is missing.
+async fn merkle_tree(
+    client: &mut StoreClient<Channel>,
+    keyspace: &str,
+    depth: u32,
+) -> Result<MerkleTree, Status> {
+    let resp = client
+        .get_keyspace_digest(GetKeyspaceDigestQuery {
+            keyspace: keyspace.to_string(),
+            depth,
+        })
+        .await;
+
+    match resp {
+        Ok(digest) => digest
+            .into_inner()
+            .into_tree()
+            .ok_or_else(|| Status::internal("Malformed keyspace digest")),
+        Err(e) if e.code() == Code::NotFound => Ok(MerkleTree::new(depth)),
+        Err(e) => Err(e),
+    }
+}
+
+async fn records(
+    client: &mut StoreClient<Channel>,
+    keyspace: &str,
+    depth: u32,
+    buckets: &[u32],
+) -> Result<BTreeMap<Vec<u8>, Vec<u8>>, Status> {
+    let resp = client
+        .get_key_ranges(GetKeyRangesQuery {
+            keyspace: keyspace.to_string(),
+            depth,
+            buckets: buckets.to_vec(),
+        })
+        .await;
+
+    let mut stream = match resp {
+        Ok(resp) => resp.into_inner(),
+        Err(e) if e.code() == Code::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let mut records = BTreeMap::new();
+    while let Some(record) = stream.message().await? {
+        records.insert(record.key, record.value);
+    }
+    Ok(records)
+}
+
+async fn insert(
+    client: &mut StoreClient<Channel>,
+    keyspace: &str,
+    records: Vec<Record>,
+) -> Result<(), Status> {
+    if records.is_empty() {
+        return Ok(());
+    }
+
+    let created = client
+        .create_keyspace(Keyspace {
+            name: keyspace.to_string(),
+        })
+        .await;
+    match created {
+        Err(e) if e.code() != Code::AlreadyExists => return Err(e),
+        _ => {}
+    }
+
+    let limits = match client.get_limits(()).await {
+        Ok(limits) => limits.into_inner(),
+        // Servers predating `GetLimits` accept batches of any size.
+        Err(e) if e.code() == Code::Unimplemented => Limits::default(),
+        Err(e) => return Err(e),
+    };
+    for records in limits.batches(keyspace, records) {
+        client
+            .insert_keys(InsertKeysQuery {
+                keyspace: keyspace.to_string(),
+                records,
+            })
+            .await?;
+    }
+    Ok(())
+}
+
+async fn repair_keyspace(
+    source: &mut StoreClient<Channel>,
+    target: &mut StoreClient<Channel>,
+    keyspace: &str,
+    opts: &RepairOpt,
+) -> Result<KeyspaceRepair, Status> {
+    let source_tree = merkle_tree(source, keyspace, opts.depth).await?;
+    let target_tree = merkle_tree(target, keyspace, opts.depth).await?;
+    let buckets = source_tree.diff(&target_tree);
+
+    let mut report = KeyspaceRepair {
+        keyspace: keyspace.to_string(),
+        ranges: buckets.len(),
+        ..Default::default()
+    };
+    if buckets.is_empty() {
+        return Ok(report);
+    }
+
+    let source_records = records(source, keyspace, opts.depth, &buckets).await?;
+    let mut target_records = records(target, keyspace, opts.depth, &buckets).await?;
+
+    let mut to_target = vec![];
+    for (key, value) in source_records {
+        match target_records.remove(&key) {
+            Some(v) if v == value => continue,
+            Some(_) => report.conflicting += 1,
+            None => report.missing_on_target += 1,
+        }
+        to_target.push(Record { key, value });
+    }
+
+    // Whatever is left only exists on the target.
+    report.missing_on_source = target_records.len();
+    let to_source = target_records
+        .into_iter()
+        .map(|(key, value)| Record { key, value })
+        .collect();
+
+    if !opts.dry_run {
+        insert(target, keyspace, to_target).await?;
+        insert(source, keyspace, to_source).await?;
+    }
+    Ok(report)
+}
+
+pub async fn repair(
+    connect: &ConnectOpt,
+    source: &mut StoreClient<Channel>,
+    opts: RepairOpt,
+) -> Result<RepairReport, Status> {
+    if opts.depth > merkle::MAX_DEPTH {
+        return Err(Status::invalid_argument(format!(
+            "Depth must be at most {}",
+            merkle::MAX_DEPTH
+        )));
+    }
+
+    let mut target = connect.store_client(&opts.target).await?;
+
+    let keyspaces = match &opts.keyspace {
+        Some(keyspace) => vec![keyspace.clone()],
+        None => {
+            let mut names = keyspace_names(source).await?;
+            names.extend(keyspace_names(&mut target).await?);
+            names.into_iter().collect()
+        }
+    };
+
+    let mut report = RepairReport {
+        dry_run: opts.dry_run,
+        keyspaces: vec![],
+    };
+    for keyspace in keyspaces {
+        let ks = repair_keyspace(source, &mut target, &keyspace, &opts).await?;
+        report.keyspaces.push(ks);
+    }
+    Ok(report)
+}
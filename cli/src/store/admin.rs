@@ -0,0 +1,73 @@
+use structopt::StructOpt;
+use tonic::{IntoRequest, Request};
+
+use dumpstors_lib::admin::*;
+
+#[derive(Debug, StructOpt)]
+pub enum AdminCommand {
+    /// List the keyspaces quarantined because they failed to load
+    Quarantined,
+    /// Reject or accept writes on the server, or on a keyspace
+    ReadOnly(ReadOnlyOpt),
+    /// List the rate limits of the store service
+    RateLimits,
+    /// Set the rate limit of a client or a keyspace, removed when unlimited
+    RateLimit(RateLimitOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct ReadOnlyOpt {
+    #[structopt(possible_values = &["on", "off"])]
+    pub mode: String,
+
+    /// Switch this keyspace only, rather than the whole server
+    #[structopt(long, short)]
+    pub keyspace: Option<String>,
+}
+
+impl IntoRequest<SetReadOnlyQuery> for ReadOnlyOpt {
+    fn into_request(self) -> Request<SetReadOnlyQuery> {
+        SetReadOnlyQuery {
+            keyspace: self.keyspace.unwrap_or_default(),
+            read_only: self.mode == "on",
+        }
+        .into_request()
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RateLimitOpt {
+    /// Client ID or IP address, `*` for every client without a limit
+    #[structopt(long, required_unless = "keyspace", conflicts_with = "keyspace")]
+    pub client: Option<String>,
+
+    /// Keyspace, `*` for every keyspace without a limit
+    #[structopt(long, short)]
+    pub keyspace: Option<String>,
+
+    /// Unlimited when 0
+    #[structopt(long, default_value = "0")]
+    pub requests_per_sec: u64,
+
+    /// Unlimited when 0
+    #[structopt(long, default_value = "0")]
+    pub bytes_per_sec: u64,
+}
+
+impl IntoRequest<SetRateLimitQuery> for RateLimitOpt {
+    fn into_request(self) -> Request<SetRateLimitQuery> {
+        let (target, name) = match (self.client, self.keyspace) {
+            (Some(client), _) => (RateLimitTarget::Client, client),
+            (None, keyspace) => (RateLimitTarget::Keyspace, keyspace.unwrap_or_default()),
+        };
+        SetRateLimitQuery {
+            target: target as i32,
+            limit: Some(RateLimit {
+                name,
+                requests_per_sec: self.requests_per_sec,
+                bytes_per_sec: self.bytes_per_sec,
+            }),
+        }
+        .into_request()
+    }
+}
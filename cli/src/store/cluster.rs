@@ -0,0 +1,38 @@
+use structopt::StructOpt;
+
+use dumpstors_lib::raft::*;
+use tonic::{IntoRequest, Request};
+
+#[derive(Debug, StructOpt)]
+pub enum ClusterCommand {
+    Status,
+    Add(AddMemberOpt),
+    Remove(RemoveMemberOpt),
+}
+
+#[derive(Debug, StructOpt)]
+pub struct AddMemberOpt {
+    pub id: u64,
+    pub addr: String,
+}
+
+impl IntoRequest<Member> for AddMemberOpt {
+    fn into_request(self) -> Request<Member> {
+        Member {
+            id: self.id,
+            addr: self.addr,
+        }
+        .into_request()
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct RemoveMemberOpt {
+    pub id: u64,
+}
+
+impl IntoRequest<RemoveMemberQuery> for RemoveMemberOpt {
+    fn into_request(self) -> Request<RemoveMemberQuery> {
+        RemoveMemberQuery { id: self.id }.into_request()
+    }
+}
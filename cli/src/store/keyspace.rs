@@ -11,6 +11,10 @@ pub enum KeyspaceCommand {
     Delete(DeleteKeyspaceOpt),
     List,
     Truncate(TruncateKeyspaceOpt),
+    /// Set the limits of a keyspace, 0 meaning unlimited
+    Quota(SetQuotaOpt),
+    /// Show the key count and size of a keyspace with its limits
+    Usage(GetUsageOpt),
 }
 
 #[derive(Debug, StructOpt)]
@@ -65,3 +69,50 @@ impl IntoRequest<TruncateKeyspaceQuery> for TruncateKeyspaceOpt {
         .into_request()
     }
 }
+
+#[derive(Debug, StructOpt)]
+pub struct SetQuotaOpt {
+    pub keyspace: String,
+
+    #[structopt(long, default_value = "0")]
+    pub max_keys: u64,
+
+    /// Limit of the summed key and value lengths
+    #[structopt(long, default_value = "0")]
+    pub max_bytes: u64,
+
+    #[structopt(long, default_value = "0")]
+    pub max_key_size: u64,
+
+    #[structopt(long, default_value = "0")]
+    pub max_value_size: u64,
+}
+
+impl IntoRequest<SetKeyspaceQuotaQuery> for SetQuotaOpt {
+    fn into_request(self) -> Request<SetKeyspaceQuotaQuery> {
+        SetKeyspaceQuotaQuery {
+            keyspace: self.keyspace,
+            quota: Some(KeyspaceQuota {
+                max_keys: self.max_keys,
+                max_bytes: self.max_bytes,
+                max_key_size: self.max_key_size,
+                max_value_size: self.max_value_size,
+            }),
+        }
+        .into_request()
+    }
+}
+
+#[derive(Debug, StructOpt)]
+pub struct GetUsageOpt {
+    pub keyspace: String,
+}
+
+impl IntoRequest<GetKeyspaceUsageQuery> for GetUsageOpt {
+    fn into_request(self) -> Request<GetKeyspaceUsageQuery> {
+        GetKeyspaceUsageQuery {
+            keyspace: self.keyspace,
+        }
+        .into_request()
+    }
+}
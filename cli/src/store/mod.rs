@@ -1,3 +1,5 @@
+pub mod admin;
+pub mod cluster;
 pub mod keyspace;
 
 use structopt::StructOpt;
@@ -23,6 +25,7 @@ impl IntoRequest<InsertKeyQuery> for InsertKeyOpt {
                 key: self.key.as_bytes().to_vec(),
                 value: self.value.as_bytes().to_vec(),
             }),
+            ..Default::default()
         }
         .into_request()
     }
@@ -0,0 +1,65 @@
+use std::fs;
+use structopt::StructOpt;
+use tonic::transport::{Certificate, Channel, ClientTlsConfig, Identity};
+use tonic::Status;
+
+use dumpstors_lib::transport;
+
+#[derive(Debug, Default, StructOpt)]
+pub struct TlsOpt {
+    /// CA certificate verifying the server, enables TLS
+    #[structopt(long)]
+    pub tls_ca: Option<String>,
+
+    /// Client certificate, for servers requiring mutual TLS
+    #[structopt(long, requires = "tls-key")]
+    pub tls_cert: Option<String>,
+
+    #[structopt(long, requires = "tls-cert")]
+    pub tls_key: Option<String>,
+
+    /// Name the server certificate is verified against, defaults to the
+    /// host of the server address
+    #[structopt(long)]
+    pub tls_domain: Option<String>,
+}
+
+#[allow(clippy::result_large_err)]
+fn read(path: &str) -> Result<Vec<u8>, Status> {
+    fs::read(path).map_err(|e| Status::invalid_argument(format!("Can't read '{}': {}", path, e)))
+}
+
+impl TlsOpt {
+    fn enabled(&self, addr: &str) -> bool {
+        self.tls_ca.is_some() || self.tls_cert.is_some() || addr.starts_with("https://")
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn client_config(&self) -> Result<ClientTlsConfig, Status> {
+        let mut tls = ClientTlsConfig::new();
+        if let Some(ca) = &self.tls_ca {
+            tls = tls.ca_certificate(Certificate::from_pem(read(ca)?));
+        }
+        if let (Some(cert), Some(key)) = (&self.tls_cert, &self.tls_key) {
+            tls = tls.identity(Identity::from_pem(read(cert)?, read(key)?));
+        }
+        if let Some(domain) = &self.tls_domain {
+            tls = tls.domain_name(domain.clone());
+        }
+        Ok(tls)
+    }
+
+    pub async fn connect(&self, addr: &str) -> Result<Channel, Status> {
+        let mut endpoint =
+            transport::endpoint(addr).map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if self.enabled(addr) {
+            endpoint = endpoint
+                .tls_config(self.client_config()?)
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+
+        transport::connect(addr, endpoint)
+            .await
+            .map_err(|e| Status::unavailable(format!("Can't connect to '{}': {}", addr, e)))
+    }
+}
@@ -0,0 +1,275 @@
+mod common;
+use dumpstors::auth::{sign, token_interceptor, Claims};
+use dumpstors::settings::{Auth, Grant, Permission, Token};
+use dumpstors_cli::{execute, query::*};
+use dumpstors_lib::models::Record;
+use dumpstors_lib::raft::raft_client::RaftClient;
+use dumpstors_lib::raft::AppendEntriesRequest;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{GetKeyQuery, InsertKeysQuery};
+use structopt::StructOpt;
+use tonic::transport::Channel;
+use tonic::Code;
+
+fn grant(keyspaces: &str, permission: Permission) -> Vec<Grant> {
+    vec![Grant {
+        keyspaces: keyspaces.to_string(),
+        permission,
+    }]
+}
+
+fn token(subject: &str, token: &str, role: &str) -> Token {
+    Token {
+        subject: subject.to_string(),
+        token: token.to_string(),
+        roles: vec![role.to_string()],
+    }
+}
+
+async fn run(port: u16, token: Option<&str>, args: &[&str]) -> Result<String, Code> {
+    let addr = format!("http://localhost:{}", port);
+    let mut argv = vec!["dumpstors_cli", "-b", &addr];
+    if let Some(token) = token {
+        argv.extend_from_slice(&["--token", token]);
+    }
+    argv.extend_from_slice(args);
+
+    execute(Query::from_iter(&argv))
+        .await
+        .map(|r| format!("{}", r))
+        .map_err(|e| e.code())
+}
+
+#[tokio::test]
+async fn test_auth() {
+    let port = 55501;
+    let mut auth = Auth {
+        secret: Some(String::from("secret")),
+        ..Default::default()
+    };
+    auth.roles
+        .insert(String::from("admin"), grant("*", Permission::Admin));
+    auth.roles
+        .insert(String::from("reader"), grant("logs-*", Permission::Read));
+    auth.roles
+        .insert(String::from("writer"), grant("logs-*", Permission::Write));
+    auth.tokens.push(token("ops", "admin-token", "admin"));
+    auth.tokens.push(token("ci", "reader-token", "reader"));
+    common::start_ephemeral_auth_server(port, auth)
+        .await
+        .unwrap();
+
+    let admin = Some("admin-token");
+    let reader = Some("reader-token");
+    let list = ["keyspaces", "list"];
+
+    assert_eq!(run(port, None, &list).await, Err(Code::Unauthenticated));
+    assert_eq!(
+        run(port, Some("nope"), &list).await,
+        Err(Code::Unauthenticated)
+    );
+
+    for ks in ["logs-a", "users"].iter() {
+        run(port, admin, &["keyspaces", "create", ks])
+            .await
+            .unwrap();
+    }
+    assert_eq!(run(port, admin, &list).await.unwrap(), "logs-a\nusers");
+    assert_eq!(run(port, reader, &list).await.unwrap(), "logs-a");
+
+    let insert = ["insert", "-k", "logs-a", "key", "value"];
+    let get = ["get", "-k", "logs-a", "key"];
+    assert_eq!(run(port, reader, &get).await, Err(Code::NotFound));
+    assert_eq!(
+        run(port, reader, &insert).await,
+        Err(Code::PermissionDenied)
+    );
+    assert_eq!(
+        run(port, reader, &["get", "-k", "users", "key"]).await,
+        Err(Code::PermissionDenied)
+    );
+
+    let writer = sign(
+        b"secret",
+        &Claims {
+            sub: String::from("app"),
+            roles: vec![String::from("writer")],
+            exp: None,
+        },
+    );
+    let writer = Some(writer.as_str());
+    assert_eq!(run(port, writer, &insert).await.unwrap(), "");
+    assert_eq!(run(port, reader, &get).await.unwrap(), "key=value");
+    assert_eq!(
+        run(port, writer, &["keyspaces", "delete", "logs-a"]).await,
+        Err(Code::PermissionDenied)
+    );
+
+    let expired = sign(
+        b"secret",
+        &Claims {
+            sub: String::from("app"),
+            roles: vec![String::from("writer")],
+            exp: Some(1),
+        },
+    );
+    assert_eq!(
+        run(port, Some(&expired), &insert).await,
+        Err(Code::Unauthenticated)
+    );
+}
+
+#[tokio::test]
+async fn test_cluster_members_authenticate() {
+    let ports = [55503, 55505];
+    let mut auth = Auth {
+        peer_token: Some(String::from("peer-token")),
+        ..Default::default()
+    };
+    auth.roles
+        .insert(String::from("admin"), grant("*", Permission::Admin));
+    auth.roles
+        .insert(String::from("reader"), grant("*", Permission::Read));
+    auth.tokens.push(token("ops", "admin-token", "admin"));
+    auth.tokens.push(token("ci", "reader-token", "reader"));
+
+    let members: Vec<dumpstors::settings::Member> = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| dumpstors::settings::Member {
+            id: i as u64 + 1,
+            addr: format!("http://127.0.0.1:{}", port),
+        })
+        .collect();
+    for (i, port) in ports.iter().enumerate() {
+        let data = format!("./.data/{}", uuid::Uuid::new_v4());
+        let mut conf = common::settings_at(*port, format!("{}/store", data));
+        conf.cluster = Some(dumpstors::settings::Cluster::new(
+            i as u64 + 1,
+            members.clone(),
+            format!("{}/raft", data),
+        ));
+        conf.auth = Some(auth.clone());
+        common::start_server(conf).await.unwrap();
+    }
+
+    let admin = Some("peer-token");
+    let mut leader = None;
+    for _ in 0..100 {
+        for port in ports.iter() {
+            let status = run(*port, admin, &["cluster", "status"]).await.unwrap();
+            if status.contains("role=leader") {
+                leader = Some(*port);
+            }
+        }
+        if leader.is_some() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    let leader = leader.expect("No leader was elected");
+
+    // Writes are only committed once the other member, reached with the
+    // peer token, accepted them.
+    run(leader, admin, &["keyspaces", "create", "ks"])
+        .await
+        .unwrap();
+
+    let channel = Channel::from_shared(format!("http://127.0.0.1:{}", ports[0]))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    for (token, code) in [
+        (None, Code::Unauthenticated),
+        (Some("reader-token"), Code::PermissionDenied),
+        (Some("admin-token"), Code::PermissionDenied),
+    ]
+    .iter()
+    {
+        let interceptor = token_interceptor(token.map(String::from));
+        let mut client = RaftClient::with_interceptor(channel.clone(), interceptor);
+        match client.append_entries(AppendEntriesRequest::default()).await {
+            Err(e) => assert_eq!(e.code(), *code),
+            Ok(_) => panic!("Raft requests must carry the peer token"),
+        };
+    }
+}
+
+#[tokio::test]
+async fn test_shard_nodes_only_trust_peers() {
+    let ports = [55751, 55753];
+    let mut auth = Auth {
+        peer_token: Some(String::from("peer-token")),
+        ..Default::default()
+    };
+    auth.roles
+        .insert(String::from("admin"), grant("*", Permission::Admin));
+    auth.tokens.push(token("ops", "admin-token", "admin"));
+
+    let nodes: Vec<dumpstors::settings::Member> = ports
+        .iter()
+        .enumerate()
+        .map(|(i, port)| dumpstors::settings::Member {
+            id: i as u64 + 1,
+            addr: format!("http://127.0.0.1:{}", port),
+        })
+        .collect();
+    for (i, port) in ports.iter().enumerate() {
+        let mut conf = common::settings_at(*port, format!("./.data/{}", uuid::Uuid::new_v4()));
+        conf.sharding = Some(dumpstors::settings::Sharding::new(
+            i as u64 + 1,
+            nodes.clone(),
+        ));
+        conf.auth = Some(auth.clone());
+        common::start_server(conf).await.unwrap();
+    }
+
+    let admin = Some("admin-token");
+    run(ports[0], admin, &["keyspaces", "create", "ks"])
+        .await
+        .unwrap();
+    let records: Vec<Record> = (0..50)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: b"value".to_vec(),
+        })
+        .collect();
+    let channel = Channel::from_shared(format!("http://127.0.0.1:{}", ports[0]))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let interceptor = token_interceptor(admin.map(String::from));
+    StoreClient::with_interceptor(channel, interceptor)
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records: records.clone(),
+        })
+        .await
+        .unwrap();
+
+    // Only requests carrying the peer token are served locally when marked
+    // as forwarded, others are still routed to the key owner.
+    let channel = Channel::from_shared(format!("http://127.0.0.1:{}", ports[1]))
+        .unwrap()
+        .connect()
+        .await
+        .unwrap();
+    let mut local = vec![];
+    for token in ["admin-token", "peer-token"].iter() {
+        let interceptor = token_interceptor(Some(token.to_string()));
+        let mut client = StoreClient::with_interceptor(channel.clone(), interceptor);
+        let mut found = 0;
+        for r in records.iter() {
+            let request = dumpstors::shard::forwarded(GetKeyQuery {
+                keyspace: String::from("ks"),
+                key: r.key.clone(),
+            });
+            found += client.get_key(request).await.is_ok() as usize;
+        }
+        local.push(found);
+    }
+    assert_eq!(local[0], records.len());
+    assert!(local[1] > 0 && local[1] < records.len(), "{:?}", local);
+}
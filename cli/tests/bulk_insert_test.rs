@@ -0,0 +1,151 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use dumpstors_lib::models::Record;
+use dumpstors_lib::rpc;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{BulkInsertChunk, BulkInsertMode, KeyspaceQuota, SetKeyspaceQuotaQuery};
+use structopt::StructOpt;
+use tonic::Code;
+use uuid::Uuid;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+fn chunk(keyspace: &str, range: std::ops::Range<usize>, value_size: usize) -> BulkInsertChunk {
+    BulkInsertChunk {
+        keyspace: keyspace.to_string(),
+        records: range
+            .map(|i| Record {
+                key: format!("key{}", i).into_bytes(),
+                value: vec![b'v'; value_size],
+            })
+            .collect(),
+        mode: BulkInsertMode::CommitAsYouGo as i32,
+    }
+}
+
+fn input(lines: &[String]) -> String {
+    let path = std::env::temp_dir().join(format!("dumpstors-{}", Uuid::new_v4()));
+    std::fs::write(&path, lines.join("\n")).unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_bulk_insert() {
+    let port = 55741;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.limits.max_batch_records = 10;
+    conf.limits.max_staged_bytes = 100;
+    common::start_server(conf).await.unwrap();
+    let addr = &format!("http://127.0.0.1:{}", port);
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+
+    let lines: Vec<String> = (0..25).map(|i| format!("key{}=value{}", i, i)).collect();
+    let file = input(&lines);
+    let summary = run(addr, &["bulk-insert", "-k", "ks", &file])
+        .await
+        .unwrap();
+    assert!(format!("{}", summary).starts_with("records=25 bytes=280 "));
+    let record = run(addr, &["get", "-k", "ks", "key24"]).await.unwrap();
+    assert_eq!(format!("{}", record), "key24=value24");
+
+    // A malformed line cancels a staged load, leaving nothing behind.
+    let mut lines: Vec<String> = (0..5).map(|i| format!("staged{}=value", i)).collect();
+    lines.push(String::from("malformed"));
+    let file = input(&lines);
+    let args = [
+        "bulk-insert",
+        "-k",
+        "ks",
+        "--stage",
+        "--chunk-records",
+        "1",
+        &file,
+    ];
+    match run(addr, &args).await {
+        Err(e) => assert_eq!(e.code(), Code::InvalidArgument),
+        Ok(_) => panic!("The malformed line should be rejected"),
+    };
+    match run(addr, &["get", "-k", "ks", "staged0"]).await {
+        Err(e) => assert_eq!(e.code(), Code::NotFound),
+        Ok(_) => panic!("Nothing should be published"),
+    };
+
+    // Staged loads exceeding the quota are not published at all.
+    let mut client = StoreClient::connect(addr.to_string()).await.unwrap();
+    client
+        .set_keyspace_quota(SetKeyspaceQuotaQuery {
+            keyspace: String::from("ks"),
+            quota: Some(KeyspaceQuota {
+                max_keys: 30,
+                ..Default::default()
+            }),
+        })
+        .await
+        .unwrap();
+    let mut first = chunk("ks", 100..108, 4);
+    first.mode = BulkInsertMode::StageThenPublish as i32;
+    let chunks = vec![first, chunk("", 108..110, 4)];
+    match client.bulk_insert(futures::stream::iter(chunks)).await {
+        Err(e) => assert_eq!(rpc::error_info(&e).unwrap().reason, "QUOTA_EXCEEDED"),
+        Ok(_) => panic!("The quota should be exceeded"),
+    };
+    match run(addr, &["get", "-k", "ks", "key100"]).await {
+        Err(e) => assert_eq!(e.code(), Code::NotFound),
+        Ok(_) => panic!("Nothing should be published"),
+    };
+
+    // Staged loads larger than the limit are rejected before being published.
+    let mut first = chunk("ks", 300..310, 4);
+    first.mode = BulkInsertMode::StageThenPublish as i32;
+    let chunks = vec![first, chunk("", 310..320, 4)];
+    match client.bulk_insert(futures::stream::iter(chunks)).await {
+        Err(e) => assert_eq!(rpc::error_info(&e).unwrap().reason, "LIMIT_EXCEEDED"),
+        Ok(_) => panic!("The staged load should be too large"),
+    };
+    match run(addr, &["get", "-k", "ks", "key300"]).await {
+        Err(e) => assert_eq!(e.code(), Code::NotFound),
+        Ok(_) => panic!("Nothing should be published"),
+    };
+
+    // Chunks over the batch limit and chunks of other keyspaces are rejected.
+    let chunks = vec![chunk("ks", 200..211, 4)];
+    match client.bulk_insert(futures::stream::iter(chunks)).await {
+        Err(e) => assert_eq!(e.code(), Code::InvalidArgument),
+        Ok(_) => panic!("The chunk should be too large"),
+    };
+    let chunks = vec![chunk("ks", 200..201, 4), chunk("other", 201..202, 4)];
+    match client.bulk_insert(futures::stream::iter(chunks)).await {
+        Err(e) => assert_eq!(e.code(), Code::InvalidArgument),
+        Ok(_) => panic!("The chunk should target the first keyspace"),
+    };
+}
+
+#[tokio::test]
+async fn test_bulk_insert_is_throttled() {
+    let port = 55743;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.rate_limits.keyspaces = vec![dumpstors::settings::RateLimit {
+        name: String::from("ks"),
+        requests_per_sec: 0,
+        bytes_per_sec: 20_000,
+    }];
+    common::start_server(conf).await.unwrap();
+    let addr = &format!("http://127.0.0.1:{}", port);
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+
+    // Streamed bytes over the limit slow the load down rather than failing it.
+    let mut client = StoreClient::connect(addr.to_string()).await.unwrap();
+    let chunks: Vec<BulkInsertChunk> = (0..6)
+        .map(|i| chunk("ks", i * 10..(i + 1) * 10, 1000))
+        .collect();
+    let summary = client
+        .bulk_insert(futures::stream::iter(chunks))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(summary.records, 60);
+    assert!(summary.duration_ms >= 1000, "{:?}", summary);
+}
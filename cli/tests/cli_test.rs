@@ -1,3 +1,5 @@
+#![allow(clippy::assertions_on_constants)]
+
 mod common;
 use dumpstors_cli::{execute, query::*};
 use structopt::StructOpt;
@@ -0,0 +1,132 @@
+mod common;
+use std::time::Duration;
+use tokio::time::sleep;
+use tonic::transport::Channel;
+use tonic::Code;
+
+use dumpstors_lib::models::*;
+use dumpstors_lib::raft::cluster_client::ClusterClient;
+use dumpstors_lib::raft::*;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::*;
+
+fn addr(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+async fn wait_for_leader(ports: &[u16]) -> u16 {
+    for _ in 0..100 {
+        for port in ports {
+            let mut client = ClusterClient::connect(addr(*port)).await.unwrap();
+            let status = client.get_cluster_status(()).await.unwrap().into_inner();
+            if status.role == "leader" {
+                return *port;
+            }
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("No leader was elected");
+}
+
+async fn wait_for_key(client: &mut StoreClient<Channel>, key: &[u8]) -> Vec<u8> {
+    for _ in 0..100 {
+        let resp = client
+            .get_key(GetKeyQuery {
+                keyspace: String::from("ks"),
+                key: key.to_vec(),
+            })
+            .await;
+        if let Ok(resp) = resp {
+            return resp.into_inner().value;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Key was never replicated");
+}
+
+#[tokio::test]
+async fn test_cluster_replication() {
+    let ports = [55101, 55102, 55103];
+    let members: Vec<(u64, u16)> = vec![(1, ports[0]), (2, ports[1]), (3, ports[2])];
+    for (id, port) in members.clone() {
+        common::start_ephemeral_cluster_node(port, id, members.clone())
+            .await
+            .unwrap();
+    }
+
+    let leader = wait_for_leader(&ports).await;
+    let mut client = StoreClient::connect(addr(leader)).await.unwrap();
+    client
+        .create_keyspace(Keyspace {
+            name: String::from("ks"),
+        })
+        .await
+        .unwrap();
+
+    let records: Vec<Record> = (0..20)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+        })
+        .collect();
+    for r in records.clone() {
+        client
+            .insert_key(InsertKeyQuery {
+                keyspace: String::from("ks"),
+                record: Some(r),
+                ..Default::default()
+            })
+            .await
+            .unwrap();
+    }
+
+    for port in ports.iter() {
+        let mut client = StoreClient::connect(addr(*port)).await.unwrap();
+        for r in records.iter() {
+            assert_eq!(wait_for_key(&mut client, &r.key).await, r.value);
+        }
+    }
+
+    let follower = *ports.iter().find(|p| **p != leader).unwrap();
+    let mut follower_client = StoreClient::connect(addr(follower)).await.unwrap();
+    let resp = follower_client
+        .insert_key(InsertKeyQuery {
+            keyspace: String::from("ks"),
+            record: Some(records[0].clone()),
+            ..Default::default()
+        })
+        .await;
+    match resp {
+        Err(e) => assert_eq!(e.code(), Code::Unavailable),
+        _ => panic!("Followers must reject writes"),
+    };
+
+    // The log was compacted, so the new member catches up through a snapshot.
+    let new_port = 55104;
+    common::start_ephemeral_cluster_node(new_port, 4, vec![])
+        .await
+        .unwrap();
+
+    let mut cluster_client = ClusterClient::connect(addr(leader)).await.unwrap();
+    let membership = cluster_client
+        .add_member(Member {
+            id: 4,
+            addr: addr(new_port),
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(membership.members.len(), 4);
+
+    let mut new_client = StoreClient::connect(addr(new_port)).await.unwrap();
+    for r in records.iter() {
+        assert_eq!(wait_for_key(&mut new_client, &r.key).await, r.value);
+    }
+
+    let membership = cluster_client
+        .remove_member(RemoveMemberQuery { id: 4 })
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!(membership.members.len(), 3);
+}
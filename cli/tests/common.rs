@@ -1,18 +1,182 @@
-use dumpstors;
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio::time::sleep;
 use uuid::Uuid;
 
-pub async fn start_ephemeral_server(port: u16) -> Result<(), Box<dyn std::error::Error>> {
-    let conf = dumpstors::settings::Settings {
-        listen_addr: "127.0.0.1".to_string(),
-        port: port,
-        store: dumpstors::settings::Store {
-            path: format!("./.data/{}", Uuid::new_v4()),
-        },
+use dumpstors::shutdown::Shutdown;
+use dumpstors_lib::health::health_check_response::ServingStatus;
+use dumpstors_lib::health::health_client::HealthClient;
+use dumpstors_lib::health::HealthCheckRequest;
+
+async fn is_serving(port: u16) -> bool {
+    let mut client = match HealthClient::connect(format!("http://127.0.0.1:{}", port)).await {
+        Ok(client) => client,
+        Err(_) => return false,
     };
+    match client
+        .check(HealthCheckRequest {
+            service: String::new(),
+        })
+        .await
+    {
+        Ok(resp) => resp.into_inner().status == ServingStatus::Serving as i32,
+        Err(_) => false,
+    }
+}
+
+/// Waits until the server reports that it is serving. Servers using TLS are
+/// only waited for until they accept connections.
+async fn wait_for_server(port: u16, tls: bool) -> Result<(), Box<dyn std::error::Error>> {
+    for _ in 0..50 {
+        let ready = match tls {
+            true => TcpStream::connect(("127.0.0.1", port)).await.is_ok(),
+            false => is_serving(port).await,
+        };
+        if ready {
+            return Ok(());
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    Err(format!("Server on port {} did not start", port).into())
+}
 
+#[allow(dead_code)]
+pub async fn start_server(
+    conf: dumpstors::settings::Settings,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let port = conf.port;
+    let tls = conf.tls.is_some();
+    let shutdown = Shutdown::new();
+    let handle = shutdown.clone();
     tokio::spawn(async move {
-        dumpstors::start_server(conf).await.unwrap();
+        dumpstors::start_server_with_shutdown(conf, handle)
+            .await
+            .unwrap();
     });
 
-    Ok(())
+    wait_for_server(port, tls).await?;
+    Ok(shutdown)
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_server(port: u16) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    start_server_at(port, format!("./.data/{}", Uuid::new_v4())).await
+}
+
+#[allow(dead_code)]
+pub async fn start_server_at(
+    port: u16,
+    path: String,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    start_server(settings_at(port, path)).await
+}
+
+/// Settings of a standalone server storing its data at `path`.
+#[allow(dead_code)]
+pub fn settings_at(port: u16, path: String) -> dumpstors::settings::Settings {
+    dumpstors::settings::Settings {
+        listen_addr: "127.0.0.1".to_string(),
+        port,
+        listeners: vec![],
+        store: dumpstors::settings::Store::new(path),
+        tls: None,
+        auth: None,
+        cluster: None,
+        sharding: None,
+        metrics: None,
+        rest: None,
+        grpc_web: None,
+        resp: None,
+        memcached: None,
+        limits: Default::default(),
+        rate_limits: Default::default(),
+        logging: Default::default(),
+        shutdown_timeout_secs: 5,
+    }
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_cluster_node(
+    port: u16,
+    node_id: u64,
+    members: Vec<(u64, u16)>,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let data = format!("./.data/{}", Uuid::new_v4());
+    let mut cluster = dumpstors::settings::Cluster::new(
+        node_id,
+        members
+            .into_iter()
+            .map(|(id, port)| dumpstors::settings::Member {
+                id,
+                addr: format!("http://127.0.0.1:{}", port),
+            })
+            .collect(),
+        format!("{}/raft", data),
+    );
+    cluster.snapshot_threshold = 10;
+    // Small enough for snapshots to be sent in several chunks.
+    cluster.snapshot_chunk_size = 64;
+
+    let mut conf = settings_at(port, format!("{}/store", data));
+    conf.cluster = Some(cluster);
+
+    start_server(conf).await
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_shard_node(
+    port: u16,
+    node_id: u64,
+    nodes: Vec<(u64, u16)>,
+    version: u64,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let mut sharding = dumpstors::settings::Sharding::new(
+        node_id,
+        nodes
+            .into_iter()
+            .map(|(id, port)| dumpstors::settings::Member {
+                id,
+                addr: format!("http://127.0.0.1:{}", port),
+            })
+            .collect(),
+    );
+    sharding.version = version;
+
+    let mut conf = settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.sharding = Some(sharding);
+
+    start_server(conf).await
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_tls_server(
+    port: u16,
+    tls: dumpstors::settings::Tls,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let mut conf = settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.tls = Some(tls);
+
+    start_server(conf).await
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_auth_server(
+    port: u16,
+    auth: dumpstors::settings::Auth,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let mut conf = settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.auth = Some(auth);
+
+    start_server(conf).await
+}
+
+#[allow(dead_code)]
+pub async fn start_ephemeral_metrics_server(
+    port: u16,
+    metrics_port: u16,
+) -> Result<Shutdown, Box<dyn std::error::Error>> {
+    let mut conf = settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.metrics = Some(dumpstors::settings::Metrics { port: metrics_port });
+
+    start_server(conf).await
 }
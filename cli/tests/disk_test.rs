@@ -0,0 +1,30 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tonic::Code;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_high_watermark() {
+    let port = 55641;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.store.watermarks.low = 0.0;
+    conf.store.watermarks.high = 0.0;
+    common::start_server(conf).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "keyspaces", "create", "ks"]);
+    match execute(q).await {
+        Err(e) if e.code() == Code::ResourceExhausted => (),
+        _ => panic!("Writes should be rejected above the high watermark"),
+    };
+
+    // Reads and deletes are still served.
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "keyspaces", "list"]);
+    execute(q).await.unwrap();
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "keyspaces", "delete", "ks"]);
+    match execute(q).await {
+        Err(e) if e.code() == Code::NotFound => (),
+        _ => panic!("Deletes should not be rejected"),
+    };
+}
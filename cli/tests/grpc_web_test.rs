@@ -0,0 +1,227 @@
+mod common;
+use dumpstors_lib::models;
+use dumpstors_lib::store::{GetKeyQuery, InsertKeyQuery};
+use prost::Message;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+const ORIGIN: &str = "Origin: https://admin.example.com";
+
+struct HttpResponse {
+    status: u16,
+    headers: String,
+    body: Vec<u8>,
+}
+
+/// Sends a request over a fresh connection, which the server closes.
+async fn http(port: u16, method: &str, path: &str, headers: &[&str], body: &[u8]) -> HttpResponse {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    for header in headers {
+        request.push_str(&format!("{}\r\n", header));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    let mut body = response[split + 4..].to_vec();
+    if head.to_lowercase().contains("transfer-encoding: chunked") {
+        body = dechunk(&body);
+    }
+    HttpResponse {
+        status: head[9..12].parse().unwrap(),
+        headers: head.to_lowercase(),
+        body,
+    }
+}
+
+fn dechunk(mut body: &[u8]) -> Vec<u8> {
+    let mut data = vec![];
+    loop {
+        let end = body.windows(2).position(|w| w == b"\r\n").unwrap();
+        let size = std::str::from_utf8(&body[..end]).unwrap();
+        let size = usize::from_str_radix(size, 16).unwrap();
+        if size == 0 {
+            return data;
+        }
+        data.extend_from_slice(&body[end + 2..end + 2 + size]);
+        body = &body[end + 4 + size..];
+    }
+}
+
+fn frame(message: &impl Message) -> Vec<u8> {
+    let mut encoded = vec![];
+    message.encode(&mut encoded).unwrap();
+    let mut frame = vec![0];
+    frame.extend_from_slice(&(encoded.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&encoded);
+    frame
+}
+
+/// Messages and trailers of a gRPC-Web response body.
+fn frames(mut body: &[u8]) -> (Vec<Vec<u8>>, String) {
+    let mut messages = vec![];
+    while !body.is_empty() {
+        let len = u32::from_be_bytes([body[1], body[2], body[3], body[4]]) as usize;
+        let data = body[5..5 + len].to_vec();
+        if body[0] & 0x80 != 0 {
+            return (messages, String::from_utf8(data).unwrap());
+        }
+        messages.push(data);
+        body = &body[5 + len..];
+    }
+    (messages, String::new())
+}
+
+#[tokio::test]
+async fn test_grpc_web() {
+    let port = 55721;
+    let web_port = 55722;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.grpc_web = Some(dumpstors::settings::GrpcWeb {
+        port: web_port,
+        allowed_origins: vec![String::from("https://admin.example.com")],
+    });
+    conf.limits.max_key_size = 8;
+    common::start_server(conf).await.unwrap();
+
+    let resp = http(
+        web_port,
+        "OPTIONS",
+        "/dumpstors.store.Store/GetKey",
+        &[ORIGIN, "Access-Control-Request-Method: POST"],
+        b"",
+    )
+    .await;
+    assert_eq!(resp.status, 204);
+    assert!(resp
+        .headers
+        .contains("access-control-allow-origin: https://admin.example.com"));
+    let resp = http(
+        web_port,
+        "OPTIONS",
+        "/dumpstors.store.Store/GetKey",
+        &["Origin: https://evil.example.com"],
+        b"",
+    )
+    .await;
+    assert_eq!(resp.status, 403);
+
+    let binary = "Content-Type: application/grpc-web+proto";
+    let keyspace = models::Keyspace {
+        name: String::from("ks"),
+    };
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.store.Store/CreateKeyspace",
+        &[ORIGIN, binary],
+        &frame(&keyspace),
+    )
+    .await;
+    assert_eq!(resp.status, 200);
+    assert!(resp
+        .headers
+        .contains("content-type: application/grpc-web+proto"));
+    // Headers set by the tower layers of the gRPC server are kept.
+    assert!(resp.headers.contains("x-request-id: "));
+    assert!(resp.headers.contains("access-control-expose-headers: "));
+    let (messages, trailers) = frames(&resp.body);
+    assert_eq!(messages.len(), 1);
+    assert!(trailers.contains("grpc-status: 0\r\n"), "{}", trailers);
+
+    let insert = InsertKeyQuery {
+        keyspace: String::from("ks"),
+        record: Some(models::Record {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+        }),
+        ..Default::default()
+    };
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.store.Store/InsertKey",
+        &[binary],
+        &frame(&insert),
+    )
+    .await;
+    assert!(frames(&resp.body).1.contains("grpc-status: 0\r\n"));
+
+    // Text calls are base64 encoded both ways.
+    let text = "Content-Type: application/grpc-web-text";
+    let get = GetKeyQuery {
+        keyspace: String::from("ks"),
+        key: b"key".to_vec(),
+    };
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.store.Store/GetKey",
+        &[ORIGIN, text],
+        base64::encode(frame(&get)).as_bytes(),
+    )
+    .await;
+    assert!(resp
+        .headers
+        .contains("content-type: application/grpc-web-text+proto"));
+    // Each message is encoded on its own, so padding may occur mid-body.
+    let body: Vec<u8> = resp
+        .body
+        .chunks(4)
+        .flat_map(|group| base64::decode(group).unwrap())
+        .collect();
+    let (messages, trailers) = frames(&body);
+    let record = models::Record::decode(&messages[0][..]).unwrap();
+    assert_eq!(record.value, b"value");
+    assert!(trailers.contains("grpc-status: 0\r\n"));
+
+    // Errors of the services are answered in trailers-only responses.
+    let get = GetKeyQuery {
+        keyspace: String::from("ks"),
+        key: b"too-long-key".to_vec(),
+    };
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.store.Store/GetKey",
+        &[binary],
+        &frame(&get),
+    )
+    .await;
+    assert_eq!(resp.status, 200);
+    assert!(
+        resp.headers.contains("grpc-status: 3\r\n"),
+        "{}",
+        resp.headers
+    );
+
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.unknown.Service/Call",
+        &[binary],
+        &frame(&get),
+    )
+    .await;
+    assert!(resp.headers.contains("grpc-status: 12\r\n"));
+    let resp = http(
+        web_port,
+        "POST",
+        "/dumpstors.store.Store/GetKey",
+        &["Content-Type: application/json"],
+        b"{}",
+    )
+    .await;
+    assert_eq!(resp.status, 415);
+}
@@ -0,0 +1,86 @@
+mod common;
+use futures::StreamExt;
+use std::time::Duration;
+use tokio::time::timeout;
+use tonic::Code;
+
+use dumpstors_lib::health::health_check_response::ServingStatus;
+use dumpstors_lib::health::health_client::HealthClient;
+use dumpstors_lib::health::HealthCheckRequest;
+use dumpstors_lib::reflection::server_reflection_client::ServerReflectionClient;
+use dumpstors_lib::reflection::server_reflection_request::MessageRequest;
+use dumpstors_lib::reflection::server_reflection_response::MessageResponse;
+use dumpstors_lib::reflection::ServerReflectionRequest;
+
+fn check(service: &str) -> HealthCheckRequest {
+    HealthCheckRequest {
+        service: service.to_string(),
+    }
+}
+
+fn reflect(request: MessageRequest) -> ServerReflectionRequest {
+    ServerReflectionRequest {
+        host: String::new(),
+        message_request: Some(request),
+    }
+}
+
+#[tokio::test]
+async fn test_health_and_reflection() {
+    let port = 55701;
+    let shutdown = common::start_ephemeral_server(port).await.unwrap();
+    let addr = format!("http://127.0.0.1:{}", port);
+
+    let mut health = HealthClient::connect(addr.clone()).await.unwrap();
+    for service in &["", "dumpstors.store.Store", "grpc.health.v1.Health"] {
+        let resp = health.check(check(service)).await.unwrap().into_inner();
+        assert_eq!(resp.status, ServingStatus::Serving as i32);
+    }
+    match health.check(check("dumpstors.raft.Raft")).await {
+        Err(e) if e.code() == Code::NotFound => (),
+        _ => panic!("Services that are not served must be unknown"),
+    };
+
+    let mut reflection = ServerReflectionClient::connect(addr).await.unwrap();
+    let requests = futures::stream::iter(vec![
+        reflect(MessageRequest::ListServices(String::new())),
+        reflect(MessageRequest::FileContainingSymbol(String::from(
+            "dumpstors.store.Store",
+        ))),
+    ]);
+    let responses: Vec<MessageResponse> = reflection
+        .server_reflection_info(requests)
+        .await
+        .unwrap()
+        .into_inner()
+        .map(|r| r.unwrap().message_response.unwrap())
+        .collect()
+        .await;
+    match &responses[0] {
+        MessageResponse::ListServicesResponse(r) => {
+            assert!(r.service.iter().any(|s| s.name == "dumpstors.store.Store"))
+        }
+        _ => panic!("Expected the list of services"),
+    };
+    match &responses[1] {
+        MessageResponse::FileDescriptorResponse(r) => {
+            assert!(!r.file_descriptor_proto.is_empty())
+        }
+        _ => panic!("Expected the descriptor of store.proto"),
+    };
+
+    let mut watch = health.watch(check("")).await.unwrap().into_inner();
+    let first = watch.message().await.unwrap().unwrap();
+    assert_eq!(first.status, ServingStatus::Serving as i32);
+
+    shutdown.trigger();
+    let last = timeout(Duration::from_secs(5), watch.message())
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(last.status, ServingStatus::NotServing as i32);
+    timeout(Duration::from_secs(10), shutdown.stop())
+        .await
+        .expect("Watching the health must not hold up the shutdown");
+}
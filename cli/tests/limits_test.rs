@@ -0,0 +1,80 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use dumpstors_lib::models::Record;
+use dumpstors_lib::rpc;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::InsertKeysQuery;
+use structopt::StructOpt;
+use tonic::{Code, Status};
+use uuid::Uuid;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+fn limit(status: &Status) -> String {
+    assert_eq!(status.code(), Code::InvalidArgument);
+    rpc::error_info(status).unwrap().metadata["limit"].clone()
+}
+
+fn records(count: usize, value_size: usize) -> Vec<Record> {
+    (0..count)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: vec![0; value_size],
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn test_request_limits() {
+    let port = 55671;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.limits.max_message_size = 256;
+    conf.limits.max_batch_records = 2;
+    conf.limits.max_key_size = 8;
+    conf.limits.max_value_size = 64;
+    conf.limits.max_staged_bytes = 1024;
+    common::start_server(conf).await.unwrap();
+    let addr = &format!("http://127.0.0.1:{}", port);
+
+    let limits = run(addr, &["limits"]).await.unwrap();
+    assert_eq!(
+        format!("{}", limits),
+        "max_message_size=256 max_batch_records=2 max_key_size=8 max_value_size=64 max_staged_bytes=1024"
+    );
+
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+    match run(addr, &["insert", "-k", "ks", "too-long-key", "value"]).await {
+        Err(e) => assert_eq!(limit(&e), "max_key_size"),
+        Ok(_) => panic!("The key should be too large"),
+    };
+    match run(addr, &["get", "-k", "ks", "too-long-key"]).await {
+        Err(e) => assert_eq!(limit(&e), "max_key_size"),
+        Ok(_) => panic!("The key should be too large"),
+    };
+
+    let mut client = StoreClient::connect(addr.to_string()).await.unwrap();
+    let insert = |records| InsertKeysQuery {
+        keyspace: String::from("ks"),
+        records,
+    };
+    client.insert_keys(insert(records(2, 8))).await.unwrap();
+    match client.insert_keys(insert(records(3, 8))).await {
+        Err(e) => assert_eq!(limit(&e), "max_batch_records"),
+        Ok(_) => panic!("The batch should be too large"),
+    };
+    match client.insert_keys(insert(records(1, 65))).await {
+        Err(e) => assert_eq!(limit(&e), "max_value_size"),
+        Ok(_) => panic!("The value should be too large"),
+    };
+    client.insert_keys(insert(records(2, 64))).await.unwrap();
+    // Rejected before the message is read.
+    match client.insert_keys(insert(records(2, 200))).await {
+        Err(e) => assert_eq!(limit(&e), "max_message_size"),
+        Ok(_) => panic!("The message should be too large"),
+    };
+    // The connection is still usable.
+    client.insert_keys(insert(records(1, 1))).await.unwrap();
+}
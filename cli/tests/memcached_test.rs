@@ -0,0 +1,121 @@
+mod common;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+/// Sends commands and reads the reply until it ends with `until`.
+async fn send(stream: &mut TcpStream, commands: &str, until: &str) -> String {
+    stream.write_all(commands.as_bytes()).await.unwrap();
+    let mut reply = vec![];
+    while !reply.ends_with(until.as_bytes()) {
+        let mut buf = [0; 4096];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert!(n > 0, "connection closed after {:?}", reply);
+        reply.extend_from_slice(&buf[..n]);
+    }
+    String::from_utf8(reply).unwrap()
+}
+
+#[tokio::test]
+async fn test_memcached_protocol() {
+    let port = 55711;
+    let memcached_port = 55712;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.memcached = Some(dumpstors::settings::Memcached {
+        port: memcached_port,
+        keyspace: String::from("cache"),
+    });
+    common::start_server(conf).await.unwrap();
+
+    let mut con = TcpStream::connect(("127.0.0.1", memcached_port))
+        .await
+        .unwrap();
+    let end = "\r\n";
+
+    assert_eq!(
+        send(&mut con, "set a 5 0 3\r\nabc\r\n", end).await,
+        "STORED\r\n"
+    );
+    assert_eq!(
+        send(&mut con, "get a missing\r\n", "END\r\n").await,
+        "VALUE a 5 3\r\nabc\r\nEND\r\n"
+    );
+    assert_eq!(
+        send(&mut con, "add a 0 0 1\r\nx\r\n", end).await,
+        "NOT_STORED\r\n"
+    );
+    assert_eq!(
+        send(&mut con, "replace b 0 0 1\r\nx\r\n", end).await,
+        "NOT_STORED\r\n"
+    );
+    assert_eq!(
+        send(&mut con, "add b 0 0 1\r\nx\r\n", end).await,
+        "STORED\r\n"
+    );
+
+    let gets = send(&mut con, "gets a\r\n", "END\r\n").await;
+    let unique: u64 = gets.split_whitespace().nth(4).unwrap().parse().unwrap();
+    let cas =
+        |value: &str, unique: u64| format!("cas a 0 0 {} {}\r\n{}\r\n", value.len(), unique, value);
+    assert_eq!(
+        send(&mut con, &cas("new", unique + 1), end).await,
+        "EXISTS\r\n"
+    );
+    assert_eq!(send(&mut con, &cas("new", unique), end).await, "STORED\r\n");
+    assert_eq!(
+        send(&mut con, &cas("newer", unique), end).await,
+        "EXISTS\r\n"
+    );
+
+    assert_eq!(
+        send(&mut con, "set n 0 0 2\r\n10\r\nincr n 5\r\n", "15\r\n").await,
+        "STORED\r\n15\r\n"
+    );
+    assert_eq!(send(&mut con, "decr n 20\r\n", end).await, "0\r\n");
+    assert_eq!(
+        send(&mut con, "incr a 1\r\n", end).await,
+        "CLIENT_ERROR cannot increment or decrement non-numeric value\r\n"
+    );
+    assert_eq!(
+        send(&mut con, "incr missing 1\r\n", end).await,
+        "NOT_FOUND\r\n"
+    );
+
+    // Items expire, and touch changes their expiry.
+    assert_eq!(
+        send(
+            &mut con,
+            "set e 0 1 1\r\ne\r\nset t 0 1 1\r\nt\r\ntouch t 100\r\n",
+            "TOUCHED\r\n"
+        )
+        .await,
+        "STORED\r\nSTORED\r\nTOUCHED\r\n"
+    );
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    assert_eq!(
+        send(&mut con, "get e t\r\n", "END\r\n").await,
+        "VALUE t 0 1\r\nt\r\nEND\r\n"
+    );
+
+    assert_eq!(
+        send(&mut con, "delete b noreply\r\ndelete b\r\n", end).await,
+        "NOT_FOUND\r\n"
+    );
+    assert_eq!(send(&mut con, "flush_all\r\n", end).await, "ERROR\r\n");
+    // The rest of a bad data chunk is read as a command.
+    assert_eq!(
+        send(&mut con, "set x 0 0 1\r\nabc\r\n", "ERROR\r\n").await,
+        "CLIENT_ERROR bad data chunk\r\nERROR\r\n"
+    );
+
+    let stats = send(&mut con, "stats\r\n", "END\r\n").await;
+    assert!(stats.contains("STAT curr_items 3\r\n"), "{}", stats);
+    assert!(stats.contains("STAT get_hits 3\r\n"), "{}", stats);
+    assert!(stats.contains("STAT get_misses 2\r\n"), "{}", stats);
+
+    con.write_all(b"quit\r\n").await.unwrap();
+    let mut rest = vec![];
+    con.read_to_end(&mut rest).await.unwrap();
+    assert!(rest.is_empty());
+}
@@ -0,0 +1,57 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn run(port: u16, args: &[&str]) {
+    let addr = format!("http://localhost:{}", port);
+    let mut argv = vec!["dumpstors_cli", "-b", &addr];
+    argv.extend_from_slice(args);
+    let _ = execute(Query::from_iter(&argv)).await;
+}
+
+async fn scrape(port: u16, path: &str) -> String {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let request = format!("GET {} HTTP/1.0\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let port = 55801;
+    let metrics_port = 55802;
+    let shutdown = common::start_ephemeral_metrics_server(port, metrics_port)
+        .await
+        .unwrap();
+
+    run(port, &["keyspaces", "create", "ks"]).await;
+    run(port, &["insert", "--keyspace", "ks", "key", "value"]).await;
+    run(port, &["get", "--keyspace", "ks", "key"]).await;
+    run(port, &["get", "--keyspace", "ks", "missing"]).await;
+
+    let metrics = scrape(metrics_port, "/metrics").await;
+    assert!(metrics.starts_with("HTTP/1.0 200 OK"));
+    for line in &[
+        "dumpstors_grpc_requests_total{method=\"/dumpstors.store.Store/GetKey\"} 2",
+        "dumpstors_grpc_errors_total{code=\"NotFound\",method=\"/dumpstors.store.Store/GetKey\"} 1",
+        "dumpstors_grpc_request_duration_seconds_count{method=\"/dumpstors.store.Store/InsertKey\"} 1",
+        "dumpstors_grpc_requests_in_flight 0",
+        "dumpstors_keyspace_keys{keyspace=\"ks\"} 1",
+        "dumpstors_keyspace_bytes{keyspace=\"ks\"} 8",
+    ] {
+        assert!(metrics.contains(line), "Missing '{}' in:\n{}", line, metrics);
+    }
+    assert!(!metrics.contains("code=\"Ok\""));
+
+    assert!(scrape(metrics_port, "/").await.starts_with("HTTP/1.0 404"));
+
+    shutdown.stop().await;
+    assert!(TcpStream::connect(("127.0.0.1", metrics_port))
+        .await
+        .is_err());
+}
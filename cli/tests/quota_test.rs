@@ -0,0 +1,34 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tonic::Code;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_keyspace_quota() {
+    let port = 55651;
+    common::start_ephemeral_server(port).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+    run(addr, &["keyspaces", "quota", "ks", "--max-keys", "1"])
+        .await
+        .unwrap();
+    run(addr, &["insert", "-k", "ks", "foo", "bar"])
+        .await
+        .unwrap();
+    match run(addr, &["insert", "-k", "ks", "other", "bar"]).await {
+        Err(e) if e.code() == Code::ResourceExhausted => (),
+        _ => panic!("The key quota should be exceeded"),
+    };
+
+    let result = run(addr, &["keyspaces", "usage", "ks"]).await.unwrap();
+    assert_eq!(
+        format!("{}", result),
+        "keys=1 bytes=6 max_keys=1 max_bytes=0 max_key_size=0 max_value_size=0"
+    );
+}
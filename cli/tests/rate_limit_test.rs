@@ -0,0 +1,122 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tonic::Code;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    let port = 55661;
+    common::start_ephemeral_server(port).await.unwrap();
+    let addr = &format!("http://127.0.0.1:{}", port);
+
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+    let limits = run(
+        addr,
+        &[
+            "admin",
+            "rate-limit",
+            "--client",
+            "batch",
+            "--requests-per-sec",
+            "1",
+        ],
+    )
+    .await
+    .unwrap();
+    assert_eq!(
+        format!("{}", limits),
+        "client batch requests_per_sec=1 bytes_per_sec=0"
+    );
+
+    let batch = ["--client-id", "batch", "keyspaces", "get", "ks"];
+    run(addr, &batch).await.unwrap();
+    match run(addr, &batch).await {
+        Err(e) if e.code() == Code::ResourceExhausted => {
+            assert_eq!(e.metadata().get("retry-after").unwrap(), "1");
+        }
+        _ => panic!("The client should be rate limited"),
+    };
+    // Other clients are not limited.
+    run(addr, &["--client-id", "other", "keyspaces", "get", "ks"])
+        .await
+        .unwrap();
+
+    // Clients without an ID are limited by their address.
+    run(
+        addr,
+        &[
+            "admin",
+            "rate-limit",
+            "--client",
+            "127.0.0.1",
+            "--requests-per-sec",
+            "1",
+        ],
+    )
+    .await
+    .unwrap();
+    run(addr, &["keyspaces", "get", "ks"]).await.unwrap();
+    match run(addr, &["keyspaces", "get", "ks"]).await {
+        Err(e) if e.code() == Code::ResourceExhausted => (),
+        _ => panic!("The address should be rate limited"),
+    };
+    run(addr, &["--client-id", "other", "keyspaces", "get", "ks"])
+        .await
+        .unwrap();
+
+    run(
+        addr,
+        &[
+            "--client-id",
+            "other",
+            "admin",
+            "rate-limit",
+            "-k",
+            "ks",
+            "--bytes-per-sec",
+            "4",
+        ],
+    )
+    .await
+    .unwrap();
+    let insert = ["--client-id", "other", "insert", "-k", "ks", "key", "value"];
+    run(addr, &insert).await.unwrap();
+    match run(addr, &insert).await {
+        Err(e) if e.code() == Code::ResourceExhausted => (),
+        _ => panic!("The keyspace should be rate limited"),
+    };
+
+    // Limits are removed once unlimited.
+    run(
+        addr,
+        &["--client-id", "other", "admin", "rate-limit", "-k", "ks"],
+    )
+    .await
+    .unwrap();
+    run(
+        addr,
+        &[
+            "--client-id",
+            "other",
+            "admin",
+            "rate-limit",
+            "--client",
+            "batch",
+        ],
+    )
+    .await
+    .unwrap();
+    run(addr, &batch).await.unwrap();
+    let limits = run(addr, &["--client-id", "other", "admin", "rate-limits"])
+        .await
+        .unwrap();
+    assert_eq!(
+        format!("{}", limits),
+        "client 127.0.0.1 requests_per_sec=1 bytes_per_sec=0"
+    );
+}
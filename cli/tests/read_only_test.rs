@@ -0,0 +1,56 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tonic::Code;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_read_only() {
+    let port = 55631;
+    common::start_ephemeral_server(port).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    run(addr, &["keyspaces", "create", "ks1"]).await.unwrap();
+    run(addr, &["keyspaces", "create", "ks2"]).await.unwrap();
+    run(addr, &["insert", "-k", "ks1", "key", "value"])
+        .await
+        .unwrap();
+
+    run(addr, &["admin", "read-only", "on"]).await.unwrap();
+    for args in [
+        &["insert", "-k", "ks1", "key", "other"][..],
+        &["delete", "-k", "ks1", "key"],
+        &["keyspaces", "create", "ks3"],
+        &["keyspaces", "truncate", "ks1"],
+    ] {
+        match run(addr, args).await {
+            Err(e) if e.code() == Code::FailedPrecondition => (),
+            _ => panic!("{:?} should be rejected", args),
+        };
+    }
+    let result = run(addr, &["get", "-k", "ks1", "key"]).await.unwrap();
+    assert_eq!(format!("{}", result), "key=value");
+    run(addr, &["admin", "read-only", "off"]).await.unwrap();
+
+    run(addr, &["admin", "read-only", "on", "-k", "ks1"])
+        .await
+        .unwrap();
+    match run(addr, &["insert", "-k", "ks1", "key", "other"]).await {
+        Err(e) if e.code() == Code::FailedPrecondition => (),
+        _ => panic!("ks1 should be read-only"),
+    };
+    run(addr, &["insert", "-k", "ks2", "key", "value"])
+        .await
+        .unwrap();
+
+    run(addr, &["admin", "read-only", "off", "-k", "ks1"])
+        .await
+        .unwrap();
+    run(addr, &["insert", "-k", "ks1", "key", "other"])
+        .await
+        .unwrap();
+}
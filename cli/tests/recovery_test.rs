@@ -0,0 +1,41 @@
+mod common;
+use dumpstors::shutdown::Shutdown;
+use dumpstors_cli::{execute, query::*};
+use std::fs;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+/// Creates a store holding a keyspace that can not be opened.
+fn broken_store() -> String {
+    let path = format!("./.data/{}", Uuid::new_v4());
+    fs::create_dir_all(&path).unwrap();
+    fs::write(format!("{}/broken", path), b"not a keyspace").unwrap();
+    path
+}
+
+#[tokio::test]
+async fn test_quarantined_keyspaces() {
+    let path = broken_store();
+    let port = 55621;
+    common::start_server_at(port, path.clone()).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "admin", "quarantined"]);
+    let result = format!("{}", execute(q).await.unwrap());
+    assert!(result.starts_with(&format!("broken {}/.quarantine/broken.", path)));
+
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "keyspaces", "list"]);
+    assert_eq!(format!("{}", execute(q).await.unwrap()), "");
+}
+
+#[tokio::test]
+async fn test_strict_recovery() {
+    let path = broken_store();
+    let mut conf = common::settings_at(55622, path.clone());
+    conf.store.strict_recovery = true;
+
+    let started = dumpstors::start_server_with_shutdown(conf, Shutdown::new()).await;
+    assert!(started.is_err());
+    // The keyspace is left in place for the operator to inspect.
+    assert!(fs::metadata(format!("{}/broken", path)).is_ok());
+}
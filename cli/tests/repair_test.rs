@@ -0,0 +1,110 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+
+use dumpstors_lib::models::*;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::*;
+
+fn addr(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn records(range: std::ops::Range<u32>, value: &str) -> Vec<Record> {
+    range
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("{}{}", value, i).into_bytes(),
+        })
+        .collect()
+}
+
+async fn repair(source: u16, target: u16, dry_run: bool) -> String {
+    let mut args = vec![
+        String::from("dumpstors_cli"),
+        String::from("-b"),
+        addr(source),
+        String::from("repair"),
+        addr(target),
+    ];
+    if dry_run {
+        args.push(String::from("--dry-run"));
+    }
+    let result: QueryResult = execute(Query::from_iter(&args)).await.unwrap();
+    format!("{}", result)
+}
+
+#[tokio::test]
+async fn test_repair() {
+    let (source_port, target_port) = (55301, 55302);
+    common::start_ephemeral_server(source_port).await.unwrap();
+    common::start_ephemeral_server(target_port).await.unwrap();
+
+    let mut source = StoreClient::connect(addr(source_port)).await.unwrap();
+    let mut target = StoreClient::connect(addr(target_port)).await.unwrap();
+    for client in [&mut source, &mut target] {
+        client
+            .create_keyspace(Keyspace {
+                name: String::from("ks"),
+            })
+            .await
+            .unwrap();
+    }
+
+    source
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records: records(0..100, "value"),
+        })
+        .await
+        .unwrap();
+    let mut target_records = records(2..99, "value");
+    target_records.push(Record {
+        key: b"key50".to_vec(),
+        value: b"stale".to_vec(),
+    });
+    target_records.push(Record {
+        key: b"extra".to_vec(),
+        value: b"value".to_vec(),
+    });
+    target
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records: target_records,
+        })
+        .await
+        .unwrap();
+
+    let report = repair(source_port, target_port, true).await;
+    assert!(
+        report.contains("1 missing on source, 3 missing on target, 1 conflicting"),
+        "{}",
+        report
+    );
+    assert!(report.ends_with("(dry run, nothing was repaired)"));
+
+    let report = repair(source_port, target_port, false).await;
+    assert!(report.starts_with("ks: "), "{}", report);
+
+    let report = repair(source_port, target_port, true).await;
+    assert_eq!(report, "ks: in sync");
+
+    let mut expected = records(0..100, "value");
+    expected.push(Record {
+        key: b"extra".to_vec(),
+        value: b"value".to_vec(),
+    });
+    for client in [&mut source, &mut target] {
+        for r in expected.iter() {
+            let record = client
+                .get_key(GetKeyQuery {
+                    keyspace: String::from("ks"),
+                    key: r.key.clone(),
+                })
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(&record, r);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use redis::{AsyncCommands, RedisError};
+use structopt::StructOpt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_redis_protocol() {
+    let port = 55691;
+    let resp_port = 55692;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.resp = Some(dumpstors::settings::Resp {
+        port: resp_port,
+        keyspace: String::from("ks"),
+    });
+    common::start_server(conf).await.unwrap();
+    let addr = &format!("http://127.0.0.1:{}", port);
+    run(addr, &["keyspaces", "create", "ks"]).await.unwrap();
+    run(addr, &["keyspaces", "create", "other"]).await.unwrap();
+
+    let client = redis::Client::open(format!("redis://127.0.0.1:{}/", resp_port)).unwrap();
+    let mut con = client.get_async_connection().await.unwrap();
+
+    let pong: String = redis::cmd("PING").query_async(&mut con).await.unwrap();
+    assert_eq!(pong, "PONG");
+
+    let _: () = con.set("a", "1").await.unwrap();
+    assert_eq!(
+        con.get::<_, Option<String>>("a").await.unwrap(),
+        Some("1".into())
+    );
+    assert_eq!(con.get::<_, Option<String>>("missing").await.unwrap(), None);
+
+    let set = |key: &str, value: &str, option: &str| {
+        let mut cmd = redis::cmd("SET");
+        cmd.arg(key).arg(value).arg(option);
+        cmd
+    };
+    let nx: Option<String> = set("a", "2", "NX").query_async(&mut con).await.unwrap();
+    assert_eq!(nx, None);
+    let nx: Option<String> = set("b", "2", "NX").query_async(&mut con).await.unwrap();
+    assert_eq!(nx, Some("OK".into()));
+    let xx: Option<String> = set("missing", "2", "XX")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(xx, None);
+
+    // Only one of concurrent writers gets to set a missing key.
+    let mut writers = vec![];
+    for i in 0..10 {
+        let mut con = client.get_async_connection().await.unwrap();
+        let cmd = set("lock", &i.to_string(), "NX");
+        writers.push(tokio::spawn(async move {
+            cmd.query_async::<_, Option<String>>(&mut con)
+                .await
+                .unwrap()
+        }));
+    }
+    let mut acquired = 0;
+    for writer in writers {
+        acquired += writer.await.unwrap().is_some() as usize;
+    }
+    assert_eq!(acquired, 1);
+    assert_eq!(con.del::<_, i64>("lock").await.unwrap(), 1);
+
+    let _: () = con.set_multiple(&[("c", "3"), ("d", "4")]).await.unwrap();
+    let values: Vec<Option<String>> = con.get(&["a", "b", "missing", "d"]).await.unwrap();
+    assert_eq!(
+        values,
+        vec![Some("1".into()), Some("2".into()), None, Some("4".into())]
+    );
+    assert_eq!(con.exists::<_, i64>(&["a", "missing"]).await.unwrap(), 1);
+    assert_eq!(con.del::<_, i64>(&["a", "missing"]).await.unwrap(), 1);
+
+    // Expiries could not be kept along with the value, so they are refused.
+    let e: RedisError = redis::cmd("SET")
+        .arg("e")
+        .arg("5")
+        .arg("EX")
+        .arg(1)
+        .query_async::<_, ()>(&mut con)
+        .await
+        .unwrap_err();
+    assert!(e.to_string().contains("not supported"), "{}", e);
+    assert!(!con.exists::<_, bool>("e").await.unwrap());
+
+    let mut keys = vec![];
+    let mut cursor = 0;
+    loop {
+        let (next, mut batch): (u64, Vec<String>) = redis::cmd("SCAN")
+            .arg(cursor)
+            .arg("MATCH")
+            .arg("[bc]")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(&mut con)
+            .await
+            .unwrap();
+        keys.append(&mut batch);
+        if next == 0 {
+            break;
+        }
+        cursor = next;
+    }
+    keys.sort();
+    assert_eq!(keys, vec!["b", "c"]);
+
+    let info: String = redis::cmd("INFO").query_async(&mut con).await.unwrap();
+    assert!(info.contains("ks:keys=3\r\n"), "{}", info);
+
+    // Errors carry the reason of the gRPC error as their code.
+    let _: () = redis::cmd("SELECT")
+        .arg("other")
+        .query_async(&mut con)
+        .await
+        .unwrap();
+    assert_eq!(con.get::<_, Option<String>>("b").await.unwrap(), None);
+    let e: RedisError = redis::cmd("SELECT")
+        .arg("unknown")
+        .query_async::<_, ()>(&mut con)
+        .await
+        .unwrap_err();
+    assert_eq!(e.code(), Some("KEYSPACE_NOT_FOUND"));
+    let e: RedisError = redis::cmd("LPUSH")
+        .arg("list")
+        .arg("v")
+        .query_async::<_, ()>(&mut con)
+        .await
+        .unwrap_err();
+    assert_eq!(e.code(), Some("ERR"));
+    let e: RedisError = redis::cmd("SET")
+        .arg("f")
+        .arg("6")
+        .arg("KEEPTTL")
+        .query_async::<_, ()>(&mut con)
+        .await
+        .unwrap_err();
+    assert_eq!(e.code(), Some("ERR"));
+
+    // RESP3 after HELLO, with inline commands.
+    let mut stream = TcpStream::connect(("127.0.0.1", resp_port)).await.unwrap();
+    stream
+        .write_all(b"HELLO 3\r\nGET missing\r\nQUIT\r\n")
+        .await
+        .unwrap();
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let response = String::from_utf8(response).unwrap();
+    assert!(response.starts_with("%7\r\n$6\r\nserver\r\n$9\r\ndumpstors\r\n"));
+    assert!(response.ends_with("_\r\n+OK\r\n"));
+}
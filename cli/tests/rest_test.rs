@@ -0,0 +1,132 @@
+mod common;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use uuid::Uuid;
+
+struct HttpResponse {
+    status: u16,
+    headers: String,
+    body: Vec<u8>,
+}
+
+/// Sends a request over a fresh connection, which the server closes.
+async fn http(port: u16, method: &str, path: &str, headers: &[&str], body: &[u8]) -> HttpResponse {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await.unwrap();
+    let mut request = format!(
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n",
+        method,
+        path,
+        body.len()
+    );
+    for header in headers {
+        request.push_str(&format!("{}\r\n", header));
+    }
+    request.push_str("\r\n");
+    stream.write_all(request.as_bytes()).await.unwrap();
+    stream.write_all(body).await.unwrap();
+
+    let mut response = vec![];
+    stream.read_to_end(&mut response).await.unwrap();
+    let split = response.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+    let head = String::from_utf8(response[..split].to_vec()).unwrap();
+    HttpResponse {
+        status: head[9..12].parse().unwrap(),
+        headers: head.to_lowercase(),
+        body: response[split + 4..].to_vec(),
+    }
+}
+
+#[tokio::test]
+async fn test_rest_gateway() {
+    let port = 55681;
+    let rest_port = 55682;
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.rest = Some(dumpstors::settings::Rest { port: rest_port });
+    conf.limits.max_key_size = 8;
+    common::start_server(conf).await.unwrap();
+
+    let resp = http(rest_port, "PUT", "/v1/keyspaces/ks", &[], b"").await;
+    assert_eq!(resp.status, 200);
+    let resp = http(rest_port, "PUT", "/v1/keyspaces/ks", &[], b"").await;
+    assert_eq!(resp.status, 409);
+    let resp = http(rest_port, "GET", "/v1/keyspaces", &[], b"").await;
+    assert_eq!(resp.body, br#"{"keyspaces":[{"name":"ks"}]}"#);
+
+    let resp = http(
+        rest_port,
+        "PUT",
+        "/v1/keyspaces/ks/keys/a%2Fb",
+        &[],
+        b"\x00raw",
+    )
+    .await;
+    assert_eq!(resp.status, 200);
+    let resp = http(rest_port, "GET", "/v1/keyspaces/ks/keys/a%2Fb", &[], b"").await;
+    assert_eq!(resp.body, b"\x00raw");
+    assert!(resp
+        .headers
+        .contains("content-type: application/octet-stream"));
+
+    let json = "Content-Type: application/json";
+    let resp = http(
+        rest_port,
+        "PUT",
+        "/v1/keyspaces/ks/keys/json",
+        &[json],
+        br#"{"value":"dmFsdWU="}"#,
+    )
+    .await;
+    assert_eq!(resp.status, 200);
+    let resp = http(
+        rest_port,
+        "GET",
+        "/v1/keyspaces/ks/keys/json",
+        &["Accept: application/json"],
+        b"",
+    )
+    .await;
+    assert_eq!(resp.body, br#"{"key":"anNvbg==","value":"dmFsdWU="}"#);
+    let resp = http(
+        rest_port,
+        "PUT",
+        "/v1/keyspaces/ks/keys/json",
+        &[json],
+        b"{}",
+    )
+    .await;
+    assert_eq!(resp.status, 400);
+
+    // Errors are the ones of the gRPC service.
+    let resp = http(rest_port, "GET", "/v1/keyspaces/ks/keys/missing", &[], b"").await;
+    assert_eq!(resp.status, 404);
+    let error: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+    assert_eq!(error["code"], 5);
+    assert_eq!(error["reason"], "KEY_NOT_FOUND");
+    let resp = http(
+        rest_port,
+        "PUT",
+        "/v1/keyspaces/ks/keys/too-long-key",
+        &[],
+        b"v",
+    )
+    .await;
+    assert_eq!(resp.status, 400);
+    let error: serde_json::Value = serde_json::from_slice(&resp.body).unwrap();
+    assert_eq!(error["metadata"]["limit"], "max_key_size");
+
+    let resp = http(rest_port, "PATCH", "/v1/keyspaces/ks", &[], b"").await;
+    assert_eq!(resp.status, 405);
+    let resp = http(rest_port, "GET", "/v1/unknown", &[], b"").await;
+    assert_eq!(resp.status, 404);
+
+    let resp = http(rest_port, "DELETE", "/v1/keyspaces/ks/keys/json", &[], b"").await;
+    assert_eq!(resp.status, 200);
+    let resp = http(rest_port, "POST", "/v1/keyspaces/ks/truncate", &[], b"").await;
+    assert_eq!(resp.status, 200);
+    let resp = http(rest_port, "GET", "/v1/keyspaces/ks/keys/a%2Fb", &[], b"").await;
+    assert_eq!(resp.status, 404);
+    let resp = http(rest_port, "DELETE", "/v1/keyspaces/ks", &[], b"").await;
+    assert_eq!(resp.status, 200);
+    let resp = http(rest_port, "GET", "/v1/keyspaces/ks", &[], b"").await;
+    assert_eq!(resp.status, 404);
+}
@@ -0,0 +1,277 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use structopt::StructOpt;
+use tonic::metadata::MetadataValue;
+use tonic::transport::Channel;
+use tonic::Request;
+
+use dumpstors_lib::models::*;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::*;
+
+fn addr(port: u16) -> String {
+    format!("http://127.0.0.1:{}", port)
+}
+
+fn local_get(key: &[u8]) -> Request<GetKeyQuery> {
+    let mut request = Request::new(GetKeyQuery {
+        keyspace: String::from("ks"),
+        key: key.to_vec(),
+    });
+    request.metadata_mut().insert(
+        dumpstors::shard::FORWARDED_METADATA_KEY,
+        MetadataValue::from_static("1"),
+    );
+    request
+}
+
+async fn get(client: &mut StoreClient<Channel>, key: &[u8]) -> Vec<u8> {
+    client
+        .get_key(GetKeyQuery {
+            keyspace: String::from("ks"),
+            key: key.to_vec(),
+        })
+        .await
+        .unwrap()
+        .into_inner()
+        .value
+}
+
+#[tokio::test]
+async fn test_sharding() {
+    let ports = [55201, 55202, 55203];
+    let nodes: Vec<(u64, u16)> = vec![(1, ports[0]), (2, ports[1])];
+    for (id, port) in nodes.clone() {
+        common::start_ephemeral_shard_node(port, id, nodes.clone(), 1)
+            .await
+            .unwrap();
+    }
+
+    let mut client = StoreClient::connect(addr(ports[0])).await.unwrap();
+    client
+        .create_keyspace(Keyspace {
+            name: String::from("ks"),
+        })
+        .await
+        .unwrap();
+
+    let records: Vec<Record> = (0..100)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+        })
+        .collect();
+    client
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records: records.clone(),
+        })
+        .await
+        .unwrap();
+
+    for port in ports[..2].iter() {
+        let mut client = StoreClient::connect(addr(*port)).await.unwrap();
+        for r in records.iter() {
+            assert_eq!(get(&mut client, &r.key).await, r.value);
+        }
+    }
+
+    // Keys are split between both nodes.
+    let mut second = StoreClient::connect(addr(ports[1])).await.unwrap();
+    let mut local = 0;
+    for r in records.iter() {
+        local += second.get_key(local_get(&r.key)).await.is_ok() as usize;
+    }
+    assert!(local > 0 && local < records.len(), "{} local keys", local);
+
+    let q = Query::from_iter(&[
+        "dumpstors_cli",
+        "-b",
+        &addr(ports[0]),
+        "get",
+        "--keyspace",
+        "ks",
+        "key42",
+    ]);
+    let result: QueryResult = execute(q).await.unwrap();
+    assert_eq!(format!("{}", result), "key42=value42");
+
+    // Adding a node moves some keys to it while they stay readable.
+    let mut topology = client.get_cluster_topology(()).await.unwrap().into_inner();
+    common::start_ephemeral_shard_node(ports[2], 3, vec![], 0)
+        .await
+        .unwrap();
+    topology.version = 2;
+    topology.nodes.push(ShardNode {
+        id: 3,
+        addr: addr(ports[2]),
+    });
+    client.update_cluster_topology(topology).await.unwrap();
+
+    let q = Query::from_iter(&["dumpstors_cli", "-b", &addr(ports[2]), "topology"]);
+    let result: QueryResult = execute(q).await.unwrap();
+    assert!(format!("{}", result).starts_with("version=2 vnodes=64\n1="));
+
+    for port in ports.iter() {
+        let mut client = StoreClient::connect(addr(*port)).await.unwrap();
+        for r in records.iter() {
+            assert_eq!(get(&mut client, &r.key).await, r.value);
+        }
+    }
+
+    let mut third = StoreClient::connect(addr(ports[2])).await.unwrap();
+    let mut moved = 0;
+    for _ in 0..50 {
+        moved = 0;
+        for r in records.iter() {
+            moved += third.get_key(local_get(&r.key)).await.is_ok() as usize;
+        }
+        if moved > 0 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert!(moved > 0, "No key was moved to the new node");
+}
+
+#[tokio::test]
+async fn test_successive_topologies() {
+    let ports = [55761, 55763];
+    common::start_ephemeral_shard_node(ports[0], 1, vec![(1, ports[0])], 1)
+        .await
+        .unwrap();
+    common::start_ephemeral_shard_node(ports[1], 2, vec![], 0)
+        .await
+        .unwrap();
+
+    let mut first = StoreClient::connect(addr(ports[0])).await.unwrap();
+    first
+        .create_keyspace(Keyspace {
+            name: String::from("ks"),
+        })
+        .await
+        .unwrap();
+    let records: Vec<Record> = (0..1000)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+        })
+        .collect();
+    first
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records: records.clone(),
+        })
+        .await
+        .unwrap();
+
+    // The second topology replaces the first one while its keys move.
+    let mut topology = first.get_cluster_topology(()).await.unwrap().into_inner();
+    topology.version = 2;
+    topology.nodes.push(ShardNode {
+        id: 2,
+        addr: addr(ports[1]),
+    });
+    first
+        .update_cluster_topology(topology.clone())
+        .await
+        .unwrap();
+    topology.version = 3;
+    topology.nodes.remove(0);
+    first.update_cluster_topology(topology).await.unwrap();
+
+    let mut second = StoreClient::connect(addr(ports[1])).await.unwrap();
+    for r in records.iter() {
+        assert_eq!(get(&mut second, &r.key).await, r.value);
+    }
+
+    for _ in 0..100 {
+        let mut migrated = true;
+        for client in [&mut first, &mut second] {
+            let status = client.get_migration_status(()).await.unwrap();
+            migrated &= status.into_inner().version == 3;
+        }
+        if migrated {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    for r in records.iter() {
+        assert!(second.get_key(local_get(&r.key)).await.is_ok());
+    }
+}
+
+#[tokio::test]
+async fn test_failed_migrations_are_retried() {
+    let ports = [55771, 55773];
+    common::start_ephemeral_shard_node(ports[0], 1, vec![(1, ports[0])], 1)
+        .await
+        .unwrap();
+    common::start_ephemeral_shard_node(ports[1], 2, vec![], 0)
+        .await
+        .unwrap();
+    let read_only = |state: &str| {
+        let args = [
+            "dumpstors_cli",
+            "-b",
+            &addr(ports[1]),
+            "admin",
+            "read-only",
+            state,
+        ];
+        execute(Query::from_iter(&args))
+    };
+    read_only("on").await.unwrap();
+
+    let mut first = StoreClient::connect(addr(ports[0])).await.unwrap();
+    first
+        .create_keyspace(Keyspace {
+            name: String::from("ks"),
+        })
+        .await
+        .unwrap();
+    let records: Vec<Record> = (0..100)
+        .map(|i| Record {
+            key: format!("key{}", i).into_bytes(),
+            value: format!("value{}", i).into_bytes(),
+        })
+        .collect();
+    first
+        .insert_keys(InsertKeysQuery {
+            keyspace: String::from("ks"),
+            records,
+        })
+        .await
+        .unwrap();
+
+    let mut topology = first.get_cluster_topology(()).await.unwrap().into_inner();
+    topology.version = 2;
+    topology.nodes.push(ShardNode {
+        id: 2,
+        addr: addr(ports[1]),
+    });
+    first.update_cluster_topology(topology).await.unwrap();
+
+    // The new node rejects the keys until it is writable again.
+    let mut status = MigrationStatus::default();
+    for _ in 0..50 {
+        status = first.get_migration_status(()).await.unwrap().into_inner();
+        if !status.error.is_empty() {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status.version, 1);
+    assert!(status.error.contains("read-only"), "{:?}", status);
+
+    read_only("off").await.unwrap();
+    for _ in 0..100 {
+        status = first.get_migration_status(()).await.unwrap().into_inner();
+        if status.version == 2 {
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+    assert_eq!(status.version, 2);
+    assert!(status.error.is_empty(), "{:?}", status);
+}
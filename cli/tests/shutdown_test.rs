@@ -0,0 +1,51 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+use uuid::Uuid;
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let path = format!("./.data/{}", Uuid::new_v4());
+    let port = 55601;
+    let shutdown = common::start_server_at(port, path.clone()).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    let q = Query::from_iter(&["dumpstors_cli", "-b", addr, "keyspaces", "create", "ks1"]);
+    execute(q).await.unwrap();
+    let q = Query::from_iter(&[
+        "dumpstors_cli",
+        "-b",
+        addr,
+        "insert",
+        "--keyspace",
+        "ks1",
+        "key",
+        "value",
+    ]);
+    execute(q).await.unwrap();
+
+    timeout(Duration::from_secs(10), shutdown.stop())
+        .await
+        .expect("Server did not stop in time");
+    assert!(TcpStream::connect(("127.0.0.1", port)).await.is_err());
+
+    // The store was flushed and released, so it can be opened again.
+    let port = 55602;
+    common::start_server_at(port, path).await.unwrap();
+    let addr = &format!("http://localhost:{}", port);
+
+    let q = Query::from_iter(&[
+        "dumpstors_cli",
+        "-b",
+        addr,
+        "get",
+        "--keyspace",
+        "ks1",
+        "key",
+    ]);
+    let result: QueryResult = execute(q).await.unwrap();
+    assert_eq!(format!("{}", result), "key=value");
+}
@@ -0,0 +1,28 @@
+mod common;
+use tonic::metadata::MetadataValue;
+use tonic::Request;
+
+use dumpstors::telemetry::REQUEST_ID_HEADER;
+use dumpstors_lib::store::store_client::StoreClient;
+
+#[tokio::test]
+async fn test_request_ids() {
+    let port = 55811;
+    let shutdown = common::start_ephemeral_server(port).await.unwrap();
+    let mut client = StoreClient::connect(format!("http://127.0.0.1:{}", port))
+        .await
+        .unwrap();
+
+    let mut request = Request::new(());
+    request
+        .metadata_mut()
+        .insert(REQUEST_ID_HEADER, MetadataValue::from_static("req-42"));
+    let resp = client.ping(request).await.unwrap();
+    assert_eq!(resp.metadata().get(REQUEST_ID_HEADER).unwrap(), "req-42");
+
+    let resp = client.list_keyspaces(()).await.unwrap();
+    let id = resp.metadata().get(REQUEST_ID_HEADER).unwrap();
+    assert_eq!(id.len(), 36);
+
+    shutdown.stop().await;
+}
@@ -0,0 +1,102 @@
+mod common;
+use dumpstors_cli::{execute, query::*};
+use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+use std::fs;
+use std::time::Duration;
+use structopt::StructOpt;
+use tokio::time::sleep;
+use uuid::Uuid;
+
+fn generate_ca() -> Certificate {
+    let mut params = CertificateParams::new(vec![]);
+    params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+    Certificate::from_params(params).unwrap()
+}
+
+/// Writes a certificate signed by `ca` and its key, returning their paths.
+fn write_signed(dir: &str, name: &str, ca: &Certificate) -> (String, String) {
+    let cert =
+        Certificate::from_params(CertificateParams::new(vec![String::from("localhost")])).unwrap();
+    let (cert_path, key_path) = (
+        format!("{}/{}.pem", dir, name),
+        format!("{}/{}.key", dir, name),
+    );
+    fs::write(&cert_path, cert.serialize_pem_with_signer(ca).unwrap()).unwrap();
+    fs::write(&key_path, cert.serialize_private_key_pem()).unwrap();
+    (cert_path, key_path)
+}
+
+fn write_ca(dir: &str, name: &str, ca: &Certificate) -> String {
+    let path = format!("{}/{}.pem", dir, name);
+    fs::write(&path, ca.serialize_pem().unwrap()).unwrap();
+    path
+}
+
+async fn list_keyspaces(port: u16, tls: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let addr = format!("https://localhost:{}", port);
+    let mut args = vec!["dumpstors_cli", "-b", &addr];
+    args.extend_from_slice(tls);
+    args.extend_from_slice(&["keyspaces", "list"]);
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_mutual_tls() {
+    let port = 55401;
+    let dir = format!("./.data/{}", Uuid::new_v4());
+    fs::create_dir_all(&dir).unwrap();
+
+    let ca = generate_ca();
+    let ca_path = write_ca(&dir, "ca", &ca);
+    let (cert_path, key_path) = write_signed(&dir, "server", &ca);
+    let (client_cert, client_key) = write_signed(&dir, "client", &ca);
+
+    let mut tls = dumpstors::settings::Tls::new(cert_path.clone(), key_path.clone());
+    tls.client_ca_path = Some(ca_path.clone());
+    tls.reload_interval_secs = 1;
+    common::start_ephemeral_tls_server(port, tls).await.unwrap();
+
+    let client_tls = [
+        "--tls-ca",
+        &ca_path,
+        "--tls-cert",
+        &client_cert,
+        "--tls-key",
+        &client_key,
+    ];
+    let result = list_keyspaces(port, &client_tls).await.unwrap();
+    assert_eq!(format!("{}", result), "");
+
+    if list_keyspaces(port, &["--tls-ca", &ca_path]).await.is_ok() {
+        panic!("Clients without a certificate must be rejected");
+    }
+
+    // Rotate the server certificate to one signed by another CA.
+    let new_ca = generate_ca();
+    let new_ca_path = write_ca(&dir, "new_ca", &new_ca);
+    let (new_cert, new_key) = write_signed(&dir, "new_server", &new_ca);
+    fs::rename(new_key, &key_path).unwrap();
+    fs::rename(new_cert, &cert_path).unwrap();
+
+    let rotated_tls = [
+        "--tls-ca",
+        &new_ca_path,
+        "--tls-cert",
+        &client_cert,
+        "--tls-key",
+        &client_key,
+    ];
+    let mut reloaded = false;
+    for _ in 0..50 {
+        if list_keyspaces(port, &rotated_tls).await.is_ok() {
+            reloaded = true;
+            break;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    assert!(reloaded, "The new certificate was never served");
+
+    if list_keyspaces(port, &client_tls).await.is_ok() {
+        panic!("The previous certificate must not be served anymore");
+    }
+}
@@ -0,0 +1,51 @@
+#![cfg(unix)]
+mod common;
+use dumpstors_cli::{execute, query::*};
+use std::os::unix::fs::PermissionsExt;
+use structopt::StructOpt;
+use uuid::Uuid;
+
+async fn run(addr: &str, args: &[&str]) -> Result<QueryResult, tonic::Status> {
+    let args = [&["dumpstors_cli", "-b", addr], args].concat();
+    execute(Query::from_iter(&args)).await
+}
+
+#[tokio::test]
+async fn test_unix_socket_listener() {
+    let port = 55731;
+    let socket = std::env::temp_dir().join(format!("dumpstors-{}.sock", Uuid::new_v4()));
+    let mut conf = common::settings_at(port, format!("./.data/{}", Uuid::new_v4()));
+    conf.listeners = vec![
+        dumpstors::settings::Listener {
+            addr: Some(String::from("127.0.0.1:55733")),
+            path: None,
+            mode: String::from("660"),
+        },
+        dumpstors::settings::Listener {
+            addr: None,
+            path: Some(socket.to_str().unwrap().to_string()),
+            mode: String::from("600"),
+        },
+    ];
+    let shutdown = common::start_server(conf).await.unwrap();
+
+    let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let unix = &format!("unix://{}", socket.display());
+    run(unix, &["keyspaces", "create", "ks"]).await.unwrap();
+    run(unix, &["insert", "-k", "ks", "key", "value"])
+        .await
+        .unwrap();
+    match run("http://127.0.0.1:55733", &["get", "-k", "ks", "key"]).await {
+        Ok(QueryResult::Record(record)) => assert_eq!(record.get_ref().value, b"value"),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+
+    shutdown.stop().await;
+    assert!(!socket.exists());
+    match run(unix, &["keyspaces", "list"]).await {
+        Err(status) => assert_eq!(status.code(), tonic::Code::Unavailable),
+        other => panic!("Unexpected result: {:?}", other),
+    }
+}
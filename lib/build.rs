@@ -1,11 +1,27 @@
+use std::env;
 use std::error::Error;
+use std::path::PathBuf;
 use std::process::exit;
 
+const PROTOS: &[&str] = &[
+    "proto/models.proto",
+    "proto/store.proto",
+    "proto/raft.proto",
+    "proto/admin.proto",
+    "proto/grpc/health/v1/health.proto",
+    "proto/grpc/reflection/v1alpha/reflection.proto",
+    "proto/google/rpc/status.proto",
+    "proto/google/rpc/error_details.proto",
+];
+
 #[cfg(not(feature = "structopt"))]
 fn compile_prototypes() -> Result<(), Box<dyn Error>> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR")?);
     tonic_build::configure()
         // .format(false) // disable code formatting since docs.rs will otherwise break
-        .compile(&["proto/models.proto", "proto/store.proto"], &["."])?;
+        // Served by the reflection service.
+        .file_descriptor_set_path(out_dir.join("descriptor_set.bin"))
+        .compile(PROTOS, &["."])?;
     Ok(())
 }
 
@@ -15,6 +31,7 @@ fn main() {
         exit(1);
     }
 
-    println!("cargo:rerun-if-changed=proto/store.proto");
-    println!("cargo:rerun-if-changed=proto/models.proto");
+    for proto in PROTOS {
+        println!("cargo:rerun-if-changed={}", proto);
+    }
 }
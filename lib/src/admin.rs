@@ -0,0 +1,21 @@
+tonic::include_proto!("dumpstors.admin");
+
+use super::store::recovery::{KeyspaceLoad, LoadOutcome, RecoveryReport};
+
+impl From<&RecoveryReport> for QuarantinedKeyspaces {
+    fn from(report: &RecoveryReport) -> Self {
+        let keyspaces = report
+            .keyspaces
+            .iter()
+            .filter_map(|KeyspaceLoad { name, outcome }| match outcome {
+                LoadOutcome::Quarantined { path, reason } => Some(QuarantinedKeyspace {
+                    name: name.clone(),
+                    path: path.clone(),
+                    reason: reason.clone(),
+                }),
+                _ => None,
+            })
+            .collect();
+        QuarantinedKeyspaces { keyspaces }
+    }
+}
@@ -1,4 +1,10 @@
+pub mod admin;
+pub mod merkle;
+pub mod raft;
+pub mod ring;
+pub mod rpc;
 pub mod store;
+pub mod transport;
 
 #[cfg(feature = "structopt")]
 extern crate structopt;
@@ -14,3 +20,17 @@ pub mod models {
         }
     }
 }
+
+/// Standard gRPC health checking protocol.
+pub mod health {
+    tonic::include_proto!("grpc.health.v1");
+}
+
+/// Standard gRPC server reflection protocol.
+pub mod reflection {
+    tonic::include_proto!("grpc.reflection.v1alpha");
+
+    /// Encoded `FileDescriptorSet` of every proto file compiled by this crate.
+    pub const FILE_DESCRIPTOR_SET: &[u8] =
+        include_bytes!(concat!(env!("OUT_DIR"), "/descriptor_set.bin"));
+}
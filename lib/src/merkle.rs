@@ -0,0 +1,168 @@
+//! Merkle trees over the records of a keyspace, used to find the key ranges
+//! two replicas disagree on without sending the records themselves.
+//!
+//! Keys are split into `2^depth` buckets by the high bits of their hash. Each
+//! leaf digests the records of one bucket, and each inner node its children.
+//! Nodes are stored breadth-first, the root first.
+
+use std::collections::HashSet;
+
+use super::ring::hash;
+use super::store::KeyspaceDigest;
+
+pub const DEFAULT_DEPTH: u32 = 10;
+pub const MAX_DEPTH: u32 = 16;
+
+/// Returns the bucket of `key` in a tree of the given depth.
+pub fn bucket(key: &[u8], depth: u32) -> u32 {
+    match depth {
+        0 => 0,
+        _ => (hash(&[key]) >> (64 - depth)) as u32,
+    }
+}
+
+fn node_count(depth: u32) -> usize {
+    (2 << depth) - 1
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MerkleTree {
+    depth: u32,
+    nodes: Vec<u64>,
+}
+
+impl MerkleTree {
+    pub fn new(depth: u32) -> Self {
+        Self {
+            depth,
+            nodes: vec![0; node_count(depth)],
+        }
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    fn leaf(&self, bucket: u32) -> usize {
+        (1 << self.depth) - 1 + bucket as usize
+    }
+
+    /// Adds a record to its leaf and updates the nodes above it.
+    pub fn insert(&mut self, key: &[u8], value: &[u8]) {
+        let len = (key.len() as u64).to_be_bytes();
+        let mut i = self.leaf(bucket(key, self.depth));
+        // Keys are unique, so summing record hashes is enough for a leaf and
+        // does not depend on the order records are inserted in.
+        self.nodes[i] = self.nodes[i].wrapping_add(hash(&[&len, key, value]));
+
+        while i > 0 {
+            i = (i - 1) / 2;
+            let left = self.nodes[2 * i + 1].to_be_bytes();
+            let right = self.nodes[2 * i + 2].to_be_bytes();
+            self.nodes[i] = hash(&[&left, &right]);
+        }
+    }
+
+    /// Returns the buckets whose records differ between both trees, only
+    /// descending into the subtrees whose digests differ. Trees of different
+    /// depths can not be compared, so every bucket is returned for them.
+    pub fn diff(&self, other: &MerkleTree) -> Vec<u32> {
+        if self.depth != other.depth {
+            return (0..1 << self.depth).collect();
+        }
+
+        let first_leaf = self.leaf(0);
+        let mut buckets = vec![];
+        let mut stack = vec![0];
+        while let Some(i) = stack.pop() {
+            if self.nodes[i] == other.nodes[i] {
+                continue;
+            }
+            if i >= first_leaf {
+                buckets.push((i - first_leaf) as u32);
+            } else {
+                stack.push(2 * i + 2);
+                stack.push(2 * i + 1);
+            }
+        }
+        buckets
+    }
+
+    /// Returns a filter matching the keys of the given buckets.
+    pub fn bucket_filter(depth: u32, buckets: &[u32]) -> impl Fn(&[u8]) -> bool {
+        let buckets: HashSet<u32> = buckets.iter().cloned().collect();
+        move |key| buckets.contains(&bucket(key, depth))
+    }
+}
+
+impl From<MerkleTree> for KeyspaceDigest {
+    fn from(tree: MerkleTree) -> Self {
+        KeyspaceDigest {
+            depth: tree.depth,
+            nodes: tree.nodes,
+        }
+    }
+}
+
+impl KeyspaceDigest {
+    /// Returns the tree held by the digest, unless it is malformed.
+    pub fn into_tree(self) -> Option<MerkleTree> {
+        if self.depth > MAX_DEPTH || self.nodes.len() != node_count(self.depth) {
+            return None;
+        }
+        Some(MerkleTree {
+            depth: self.depth,
+            nodes: self.nodes,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree(records: &[(&str, &str)]) -> MerkleTree {
+        let mut tree = MerkleTree::new(4);
+        for (key, value) in records {
+            tree.insert(key.as_bytes(), value.as_bytes());
+        }
+        tree
+    }
+
+    #[test]
+    fn insertion_order_does_not_matter() {
+        let a = tree(&[("foo", "bar"), ("boo", "far"), ("doo", "dar")]);
+        let b = tree(&[("doo", "dar"), ("foo", "bar"), ("boo", "far")]);
+        assert_eq!(a, b);
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[test]
+    fn diff_finds_differing_buckets() {
+        let a = tree(&[("foo", "bar"), ("boo", "far"), ("doo", "dar")]);
+        let b = tree(&[("foo", "bar"), ("boo", "other")]);
+
+        let mut expected = vec![bucket(b"boo", 4), bucket(b"doo", 4)];
+        expected.sort_unstable();
+        expected.dedup();
+        let mut buckets = a.diff(&b);
+        buckets.sort_unstable();
+        assert_eq!(buckets, expected);
+
+        let filter = MerkleTree::bucket_filter(4, &buckets);
+        assert!(filter(b"boo") && filter(b"doo"));
+    }
+
+    #[test]
+    fn digest_roundtrip() {
+        let a = tree(&[("foo", "bar")]);
+        let digest = KeyspaceDigest::from(a.clone());
+        assert_eq!(digest.into_tree(), Some(a));
+
+        let malformed = KeyspaceDigest {
+            depth: 4,
+            nodes: vec![0; 3],
+        };
+        assert_eq!(malformed.into_tree(), None);
+    }
+}
@@ -0,0 +1,85 @@
+use super::store::{InsertCondition, Result, Store};
+
+tonic::include_proto!("dumpstors.raft");
+
+impl Command {
+    /// Applies a replicated command to the local store.
+    pub fn apply(self, store: &Store) -> Result<()> {
+        match self.op {
+            Some(command::Op::CreateKeyspace(ks)) => store.create_keyspace(ks),
+            Some(command::Op::DeleteKeyspace(q)) => store.delete_keyspace(q.keyspace),
+            Some(command::Op::TruncateKeyspace(q)) => store.truncate_keyspace(q.keyspace),
+            Some(command::Op::InsertKey(q)) => {
+                let condition = q.condition();
+                let ks = store.get_keyspace(q.keyspace)?;
+                match (q.record, condition) {
+                    (Some(record), InsertCondition::Always) => ks.insert(record),
+                    (Some(record), InsertCondition::IfMissing) => ks.insert_if(record, false),
+                    (Some(record), InsertCondition::IfExists) => ks.insert_if(record, true),
+                    (None, _) => Ok(()),
+                }
+            }
+            Some(command::Op::DeleteKey(q)) => store.get_keyspace(q.keyspace)?.delete(q.key),
+            Some(command::Op::InsertKeys(q)) => {
+                store.get_keyspace(q.keyspace)?.batch_insert(q.records)
+            }
+            Some(command::Op::DeleteKeys(q)) => {
+                store.get_keyspace(q.keyspace)?.batch_delete(q.keys)
+            }
+            Some(command::Op::SetKeyspaceQuota(q)) => store
+                .get_keyspace(q.keyspace)?
+                .set_quota(q.quota.unwrap_or_default()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<command::Op> for Command {
+    fn from(op: command::Op) -> Self {
+        Command { op: Some(op) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use crate::store::{Error, InsertKeyQuery};
+    use uuid::Uuid;
+
+    #[test]
+    fn apply_commands() {
+        let store = Store::new(format!(".data/{}", Uuid::new_v4())).unwrap();
+
+        Command::from(command::Op::CreateKeyspace(models::Keyspace {
+            name: String::from("ks"),
+        }))
+        .apply(&store)
+        .unwrap();
+
+        Command::from(command::Op::InsertKey(InsertKeyQuery {
+            keyspace: String::from("ks"),
+            record: Some(models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            }),
+            ..Default::default()
+        }))
+        .apply(&store)
+        .unwrap();
+
+        let ks = store.get_keyspace(String::from("ks")).unwrap();
+        assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"bar".to_vec());
+
+        match Command::from(command::Op::InsertKey(InsertKeyQuery {
+            keyspace: String::from("NotFound"),
+            record: None,
+            ..Default::default()
+        }))
+        .apply(&store)
+        {
+            Err(Error::KeyspaceNotFound(_)) => (),
+            _ => panic!("Applying a command on an unknown keyspace must fail"),
+        };
+    }
+}
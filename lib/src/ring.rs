@@ -0,0 +1,128 @@
+//! Consistent hashing of keys onto the nodes of a sharded cluster.
+
+use super::models::{ClusterTopology, ShardNode};
+
+/// FNV-1a followed by a murmur3 finalizer, stable across platforms and
+/// compiler versions so that clients and servers agree on key placement.
+pub(crate) fn hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in parts {
+        for byte in part.iter() {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    hash ^ (hash >> 33)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct HashRing {
+    points: Vec<(u64, usize)>,
+    nodes: Vec<ShardNode>,
+}
+
+impl HashRing {
+    pub fn new(topology: &ClusterTopology) -> Self {
+        let vnodes = topology.vnodes.max(1);
+        let mut points: Vec<(u64, usize)> = topology
+            .nodes
+            .iter()
+            .enumerate()
+            .flat_map(|(i, node)| {
+                (0..vnodes).map(move |v| {
+                    let point = hash(&[&node.id.to_be_bytes(), &v.to_be_bytes()]);
+                    (point, i)
+                })
+            })
+            .collect();
+        points.sort_unstable();
+
+        Self {
+            points,
+            nodes: topology.nodes.clone(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    pub fn nodes(&self) -> &[ShardNode] {
+        &self.nodes
+    }
+
+    /// Returns the node owning `key` in `keyspace`.
+    pub fn node_for(&self, keyspace: &str, key: &[u8]) -> Option<&ShardNode> {
+        if self.points.is_empty() {
+            return None;
+        }
+
+        let h = hash(&[keyspace.as_bytes(), &[0], key]);
+        let i = match self.points.binary_search_by(|(point, _)| point.cmp(&h)) {
+            Ok(i) => i,
+            Err(i) => i % self.points.len(),
+        };
+        self.nodes.get(self.points[i].1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn topology(ids: &[u64]) -> ClusterTopology {
+        ClusterTopology {
+            version: 1,
+            vnodes: 64,
+            nodes: ids
+                .iter()
+                .map(|id| ShardNode {
+                    id: *id,
+                    addr: format!("http://node{}:4242", id),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn empty_ring() {
+        let ring = HashRing::new(&topology(&[]));
+        assert!(ring.is_empty());
+        assert_eq!(ring.node_for("ks", b"foo"), None);
+    }
+
+    #[test]
+    fn keys_are_spread_across_nodes() {
+        let ring = HashRing::new(&topology(&[1, 2, 3]));
+        let mut counts = [0; 3];
+        for i in 0..3000 {
+            let node = ring.node_for("ks", format!("key{}", i).as_bytes()).unwrap();
+            counts[node.id as usize - 1] += 1;
+        }
+        assert!(counts.iter().all(|c| *c > 500), "{:?}", counts);
+    }
+
+    #[test]
+    fn adding_a_node_moves_few_keys() {
+        let before = HashRing::new(&topology(&[1, 2, 3]));
+        let after = HashRing::new(&topology(&[1, 2, 3, 4]));
+
+        let moved = (0..3000)
+            .map(|i| format!("key{}", i))
+            .filter(|key| {
+                let old = before.node_for("ks", key.as_bytes()).unwrap();
+                let new = after.node_for("ks", key.as_bytes()).unwrap();
+                if old.id != new.id {
+                    assert_eq!(new.id, 4, "Keys must only move to the new node");
+                }
+                old.id != new.id
+            })
+            .count();
+        assert!(moved > 300 && moved < 1200, "{} keys moved", moved);
+    }
+}
@@ -0,0 +1,186 @@
+//! `google.rpc` error model. Failed calls carry an encoded `google.rpc.Status`
+//! in their details, listing messages that describe the cause of the error.
+
+use prost::Message;
+use prost_types::Any;
+use std::collections::HashMap;
+use std::time::Duration;
+use tonic::Code;
+
+tonic::include_proto!("google.rpc");
+
+/// Domain of the `ErrorInfo` reasons set by dumpstors.
+pub const DOMAIN: &str = "dumpstors";
+
+const TYPE_URL_PREFIX: &str = "type.googleapis.com/google.rpc.";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Detail {
+    ErrorInfo(ErrorInfo),
+    ResourceInfo(ResourceInfo),
+    RetryInfo(RetryInfo),
+    BadRequest(BadRequest),
+    QuotaFailure(QuotaFailure),
+    PreconditionFailure(PreconditionFailure),
+}
+
+fn pack<M: Message>(name: &str, msg: &M) -> Any {
+    let mut value = Vec::with_capacity(msg.encoded_len());
+    // Encoding into a Vec can only fail on insufficient capacity, which Vec grows.
+    let _ = msg.encode(&mut value);
+    Any {
+        type_url: format!("{}{}", TYPE_URL_PREFIX, name),
+        value,
+    }
+}
+
+impl Detail {
+    /// Reason of the error, with metadata such as the keyspace concerned.
+    pub fn error_info(reason: &str, metadata: HashMap<String, String>) -> Self {
+        Detail::ErrorInfo(ErrorInfo {
+            reason: reason.to_string(),
+            domain: DOMAIN.to_string(),
+            metadata,
+        })
+    }
+
+    pub fn resource_info(resource_type: &str, resource_name: &str) -> Self {
+        Detail::ResourceInfo(ResourceInfo {
+            resource_type: resource_type.to_string(),
+            resource_name: resource_name.to_string(),
+            owner: String::new(),
+            description: String::new(),
+        })
+    }
+
+    /// Marks the request as worth retrying after the delay.
+    pub fn retry_info(delay: Duration) -> Self {
+        Detail::RetryInfo(RetryInfo {
+            retry_delay: Some(prost_types::Duration {
+                seconds: delay.as_secs() as i64,
+                nanos: delay.subsec_nanos() as i32,
+            }),
+        })
+    }
+
+    pub fn bad_request(field: &str, description: &str) -> Self {
+        Detail::BadRequest(BadRequest {
+            field_violations: vec![bad_request::FieldViolation {
+                field: field.to_string(),
+                description: description.to_string(),
+            }],
+        })
+    }
+
+    pub fn quota_failure(subject: &str, description: &str) -> Self {
+        Detail::QuotaFailure(QuotaFailure {
+            violations: vec![quota_failure::Violation {
+                subject: subject.to_string(),
+                description: description.to_string(),
+            }],
+        })
+    }
+
+    pub fn precondition_failure(violation_type: &str, subject: &str, description: &str) -> Self {
+        Detail::PreconditionFailure(PreconditionFailure {
+            violations: vec![precondition_failure::Violation {
+                r#type: violation_type.to_string(),
+                subject: subject.to_string(),
+                description: description.to_string(),
+            }],
+        })
+    }
+
+    fn to_any(&self) -> Any {
+        match self {
+            Detail::ErrorInfo(d) => pack("ErrorInfo", d),
+            Detail::ResourceInfo(d) => pack("ResourceInfo", d),
+            Detail::RetryInfo(d) => pack("RetryInfo", d),
+            Detail::BadRequest(d) => pack("BadRequest", d),
+            Detail::QuotaFailure(d) => pack("QuotaFailure", d),
+            Detail::PreconditionFailure(d) => pack("PreconditionFailure", d),
+        }
+    }
+
+    fn from_any(any: &Any) -> Option<Self> {
+        let value = any.value.as_slice();
+        let detail = match any.type_url.strip_prefix(TYPE_URL_PREFIX)? {
+            "ErrorInfo" => Detail::ErrorInfo(ErrorInfo::decode(value).ok()?),
+            "ResourceInfo" => Detail::ResourceInfo(ResourceInfo::decode(value).ok()?),
+            "RetryInfo" => Detail::RetryInfo(RetryInfo::decode(value).ok()?),
+            "BadRequest" => Detail::BadRequest(BadRequest::decode(value).ok()?),
+            "QuotaFailure" => Detail::QuotaFailure(QuotaFailure::decode(value).ok()?),
+            "PreconditionFailure" => {
+                Detail::PreconditionFailure(PreconditionFailure::decode(value).ok()?)
+            }
+            _ => return None,
+        };
+        Some(detail)
+    }
+}
+
+/// Builds a gRPC status carrying the given details.
+pub fn status(code: Code, message: impl Into<String>, details: Vec<Detail>) -> tonic::Status {
+    let message = message.into();
+    let status = Status {
+        code: code as i32,
+        message: message.clone(),
+        details: details.iter().map(Detail::to_any).collect(),
+    };
+    let mut buf = Vec::with_capacity(status.encoded_len());
+    let _ = status.encode(&mut buf);
+    tonic::Status::with_details(code, message, buf.into())
+}
+
+/// Decodes the details of a gRPC status, skipping those of unknown types.
+pub fn details(status: &tonic::Status) -> Vec<Detail> {
+    match Status::decode(status.details()) {
+        Ok(status) => status.details.iter().filter_map(Detail::from_any).collect(),
+        Err(_) => vec![],
+    }
+}
+
+/// Returns the `ErrorInfo` of a gRPC status, if any.
+pub fn error_info(status: &tonic::Status) -> Option<ErrorInfo> {
+    details(status).into_iter().find_map(|d| match d {
+        Detail::ErrorInfo(info) => Some(info),
+        _ => None,
+    })
+}
+
+/// Whether the server marked the failed request as worth retrying.
+pub fn is_retryable(status: &tonic::Status) -> bool {
+    details(status)
+        .iter()
+        .any(|d| matches!(d, Detail::RetryInfo(_)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn details_roundtrip() {
+        let mut metadata = HashMap::new();
+        metadata.insert(String::from("keyspace"), String::from("ks"));
+        let status = status(
+            Code::Unavailable,
+            "Try again",
+            vec![
+                Detail::error_info("STORAGE_UNAVAILABLE", metadata),
+                Detail::retry_info(Duration::from_millis(1500)),
+            ],
+        );
+
+        assert_eq!(status.code(), Code::Unavailable);
+        assert!(is_retryable(&status));
+        let info = error_info(&status).unwrap();
+        assert_eq!(info.reason, "STORAGE_UNAVAILABLE");
+        assert_eq!(info.domain, DOMAIN);
+        assert_eq!(info.metadata["keyspace"], "ks");
+
+        let plain = tonic::Status::not_found("Missing");
+        assert!(details(&plain).is_empty());
+        assert!(!is_retryable(&plain));
+    }
+}
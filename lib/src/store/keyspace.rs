@@ -1,66 +1,681 @@
-use super::models;
-use super::{Error, Result};
+use prost::Message;
+use sled::transaction::{ConflictableTransactionError, TransactionError};
+use std::collections::HashMap;
 use std::iter::Iterator;
-use std::sync::Arc;
+use std::ops::{AddAssign, Bound, Neg, Sub};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+
+use super::models;
+use super::{Error, KeyspaceQuota, Result};
+use crate::merkle::MerkleTree;
+
+/// Page cache size of each keyspace, sled's default made explicit.
+pub const CACHE_CAPACITY: u64 = 1024 * 1024 * 1024;
+
+/// Tree holding the settings of a keyspace, apart from its records.
+const META_TREE: &str = "meta";
+const READ_ONLY_KEY: &[u8] = b"read_only";
+const QUOTA_KEY: &[u8] = b"quota";
+/// Prefix of the trees holding staged bulk inserts.
+const STAGING_PREFIX: &[u8] = b"staging-";
+
+/// Size of a keyspace, computed by scanning it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyspaceStats {
+    pub keys: u64,
+    /// Sum of the key and value lengths.
+    pub bytes: u64,
+    /// Size of the sled files, including space not reclaimed yet.
+    pub disk_bytes: u64,
+}
+
+/// Key count and size of a keyspace, tracked as it is written.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Usage {
+    pub keys: u64,
+    /// Sum of the key and value lengths.
+    pub bytes: u64,
+}
+
+/// Change of the usage caused by writes.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Delta {
+    keys: i64,
+    bytes: i64,
+}
+
+impl Delta {
+    /// Change caused by replacing a value of length `old` of a key with one of
+    /// length `new`, `None` meaning absent.
+    fn of(key: usize, old: Option<usize>, new: Option<usize>) -> Self {
+        match (old, new) {
+            (None, Some(new)) => Self {
+                keys: 1,
+                bytes: (key + new) as i64,
+            },
+            (Some(old), Some(new)) => Self {
+                keys: 0,
+                bytes: new as i64 - old as i64,
+            },
+            (Some(old), None) => Self {
+                keys: -1,
+                bytes: -((key + old) as i64),
+            },
+            (None, None) => Self::default(),
+        }
+    }
+}
+
+impl AddAssign for Delta {
+    fn add_assign(&mut self, other: Self) {
+        self.keys += other.keys;
+        self.bytes += other.bytes;
+    }
+}
+
+impl Sub for Delta {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        Self {
+            keys: self.keys - other.keys,
+            bytes: self.bytes - other.bytes,
+        }
+    }
+}
+
+impl Neg for Delta {
+    type Output = Self;
+
+    fn neg(self) -> Self {
+        Self::default() - self
+    }
+}
+
+/// Usage tracked while a key or byte quota needs it. Writes reserve their
+/// change before applying it and correct it afterwards, so that no lock is
+/// held across I/O. Writes racing with the scan starting the tracking may be
+/// left out.
+#[derive(Debug, Default)]
+struct Tracker {
+    tracked: AtomicBool,
+    keys: AtomicI64,
+    bytes: AtomicI64,
+    /// Serializes starting and stopping the tracking.
+    switch: Mutex<()>,
+}
+
+impl Tracker {
+    fn usage(&self) -> Option<Usage> {
+        if !self.tracked.load(Ordering::Acquire) {
+            return None;
+        }
+        Some(Usage {
+            keys: self.keys.load(Ordering::Relaxed).max(0) as u64,
+            bytes: self.bytes.load(Ordering::Relaxed).max(0) as u64,
+        })
+    }
+
+    /// Adds `delta` to the usage, returning the new one.
+    fn add(&self, delta: Delta) -> Usage {
+        let keys = self.keys.fetch_add(delta.keys, Ordering::Relaxed) + delta.keys;
+        let bytes = self.bytes.fetch_add(delta.bytes, Ordering::Relaxed) + delta.bytes;
+        Usage {
+            keys: keys.max(0) as u64,
+            bytes: bytes.max(0) as u64,
+        }
+    }
+
+    fn start(&self, usage: Usage) {
+        self.keys.store(usage.keys as i64, Ordering::Relaxed);
+        self.bytes.store(usage.bytes as i64, Ordering::Relaxed);
+        self.tracked.store(true, Ordering::Release);
+    }
+}
 
 #[derive(Clone, Debug)]
 pub struct Keyspace {
     pub name: String,
     db: Arc<sled::Db>,
+    quota: Arc<RwLock<KeyspaceQuota>>,
+    usage: Arc<Tracker>,
+    /// Persisted flag, cached to be checked by every write.
+    read_only: Arc<AtomicBool>,
+}
+
+fn limits_usage(quota: &KeyspaceQuota) -> bool {
+    quota.max_keys > 0 || quota.max_bytes > 0
 }
 
 impl Keyspace {
     pub fn new(path: String, name: String) -> Result<Self> {
-        Ok(Self {
-            name: name.clone(),
-            db: Arc::new(sled::open(format!("{}/{}", path, name))?),
-        })
+        let db = sled::Config::new()
+            .path(format!("{}/{}", path, name))
+            .cache_capacity(CACHE_CAPACITY)
+            .open()?;
+        // Loads staged by a server that stopped before publishing them.
+        for tree in db.tree_names() {
+            if tree.starts_with(STAGING_PREFIX) {
+                db.drop_tree(tree)?;
+            }
+        }
+        let meta = db.open_tree(META_TREE)?;
+        let read_only = meta.contains_key(READ_ONLY_KEY)?;
+        let quota = match meta.get(QUOTA_KEY)? {
+            Some(bytes) => KeyspaceQuota::decode(bytes.as_ref())
+                .map_err(|_| Error::SledErr(sled::Error::Corruption { at: None, bt: () }))?,
+            None => KeyspaceQuota::default(),
+        };
+
+        let keyspace = Self {
+            name,
+            db: Arc::new(db),
+            usage: Arc::new(Tracker::default()),
+            quota: Arc::new(RwLock::new(quota)),
+            read_only: Arc::new(AtomicBool::new(read_only)),
+        };
+        if limits_usage(&keyspace.quota()) {
+            keyspace.track_usage()?;
+        }
+        Ok(keyspace)
+    }
+
+    /// Whether writes to the keyspace are rejected.
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Persists the read-only flag of the keyspace.
+    pub fn set_read_only(&self, read_only: bool) -> Result<()> {
+        let meta = self.db.open_tree(META_TREE)?;
+        match read_only {
+            true => meta.insert(READ_ONLY_KEY, &[])?,
+            false => meta.remove(READ_ONLY_KEY)?,
+        };
+        meta.flush()?;
+        self.read_only.store(read_only, Ordering::SeqCst);
+        Ok(())
+    }
+
+    pub fn quota(&self) -> KeyspaceQuota {
+        self.quota
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Persists the quota of the keyspace. Usage already above it is kept,
+    /// further writes increasing it are rejected.
+    pub fn set_quota(&self, quota: KeyspaceQuota) -> Result<()> {
+        let tracked = limits_usage(&quota);
+        if tracked {
+            self.track_usage()?;
+        }
+
+        let mut buf = Vec::with_capacity(quota.encoded_len());
+        // Encoding into a Vec can only fail on insufficient capacity, which Vec grows.
+        let _ = quota.encode(&mut buf);
+        let meta = self.db.open_tree(META_TREE)?;
+        meta.insert(QUOTA_KEY, buf)?;
+        meta.flush()?;
+
+        *self.quota.write().unwrap_or_else(PoisonError::into_inner) = quota;
+        if !tracked {
+            let _switch = self
+                .usage
+                .switch
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            self.usage.tracked.store(false, Ordering::Release);
+        }
+        Ok(())
+    }
+
+    /// Returns the usage of the keyspace, scanning it unless it is tracked.
+    pub fn usage(&self) -> Result<Usage> {
+        match self.usage.usage() {
+            Some(usage) => Ok(usage),
+            None => self.scan(),
+        }
+    }
+
+    fn track_usage(&self) -> Result<()> {
+        // Only held by quota changes, never by writes.
+        let _switch = self
+            .usage
+            .switch
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if self.usage.usage().is_none() {
+            self.usage.start(self.scan()?);
+        }
+        Ok(())
+    }
+
+    fn scan(&self) -> Result<Usage> {
+        let mut usage = Usage::default();
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            usage.keys += 1;
+            usage.bytes += (key.len() + value.len()) as u64;
+        }
+        Ok(usage)
+    }
+
+    fn check_quota(&self, limit: u64, value: u64, quota: &'static str) -> Result<()> {
+        if limit > 0 && value > limit {
+            return Err(Error::QuotaExceeded {
+                keyspace: self.name.clone(),
+                quota,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    fn check_sizes(&self, records: &[models::Record]) -> Result<()> {
+        let quota = self.quota();
+        for r in records {
+            self.check_quota(quota.max_key_size, r.key.len() as u64, "max_key_size")?;
+            self.check_quota(quota.max_value_size, r.value.len() as u64, "max_value_size")?;
+        }
+        Ok(())
+    }
+
+    /// Estimates the change of the usage once `writes` are applied in order,
+    /// each setting a key to a value of the given length or removing it.
+    fn estimate(&self, writes: &[(&[u8], Option<usize>)]) -> Result<Delta> {
+        let mut delta = Delta::default();
+        let mut pending: HashMap<&[u8], Option<usize>> = HashMap::new();
+        for (key, value) in writes {
+            let previous = match pending.get(key) {
+                Some(previous) => *previous,
+                None => self.db.get(key)?.map(|v| v.len()),
+            };
+            delta += Delta::of(key.len(), previous, *value);
+            pending.insert(key, *value);
+        }
+        Ok(delta)
+    }
+
+    /// Adds `delta` to the usage, rejecting increases above the quota when
+    /// `enforce` is set.
+    fn reserve(&self, delta: Delta, enforce: bool) -> Result<()> {
+        let next = self.usage.add(delta);
+        if !enforce {
+            return Ok(());
+        }
+        let checked = self.check_usage(delta, next);
+        if checked.is_err() {
+            self.usage.add(-delta);
+        }
+        checked
+    }
+
+    fn check_usage(&self, delta: Delta, next: Usage) -> Result<()> {
+        let quota = self.quota();
+        if delta.keys > 0 {
+            self.check_quota(quota.max_keys, next.keys, "max_keys")?;
+        }
+        if delta.bytes > 0 {
+            self.check_quota(quota.max_bytes, next.bytes, "max_bytes")?;
+        }
+        Ok(())
+    }
+
+    /// Runs `apply`, which returns the actual change of the usage when told
+    /// that the usage is tracked. The change estimated from `writes` is
+    /// reserved beforehand, so that concurrent writes can't exceed the quota.
+    fn write<T, F>(&self, writes: &[(&[u8], Option<usize>)], enforce: bool, apply: F) -> Result<T>
+    where
+        F: FnOnce(bool) -> Result<(T, Delta)>,
+    {
+        if self.usage.usage().is_none() {
+            return Ok(apply(false)?.0);
+        }
+        let reserved = self.estimate(writes)?;
+        self.reserve(reserved, enforce)?;
+        match apply(true) {
+            Ok((value, actual)) => {
+                self.usage.add(actual - reserved);
+                Ok(value)
+            }
+            Err(e) => {
+                self.usage.add(-reserved);
+                Err(e)
+            }
+        }
+    }
+
+    /// Applies writes at once, each setting a key or removing it. When
+    /// `tracked`, they run in a transaction returning the values they
+    /// replace, so that the returned change of the usage is exact.
+    fn apply_writes(&self, writes: &[(&[u8], Option<&[u8]>)], tracked: bool) -> Result<Delta> {
+        if !tracked {
+            let mut batch = sled::Batch::default();
+            for (key, value) in writes {
+                match value {
+                    Some(value) => batch.insert(*key, *value),
+                    None => batch.remove(*key),
+                }
+            }
+            self.db.apply_batch(batch)?;
+            return Ok(Delta::default());
+        }
+
+        self.db
+            .transaction(|tx| {
+                let mut delta = Delta::default();
+                for (key, value) in writes {
+                    let old = match value {
+                        Some(value) => tx.insert(*key, *value)?,
+                        None => tx.remove(*key)?,
+                    };
+                    delta += Delta::of(key.len(), old.map(|v| v.len()), value.map(<[u8]>::len));
+                }
+                Ok::<_, ConflictableTransactionError<sled::Error>>(delta)
+            })
+            .map_err(|e| match e {
+                TransactionError::Abort(e) | TransactionError::Storage(e) => Error::SledErr(e),
+            })
     }
 
     pub fn get(&self, key: Vec<u8>) -> Result<Vec<u8>> {
-        match self.db.get(key)? {
+        match self.db.get(&key)? {
             Some(v) => Ok(v.to_vec()),
-            None => Err(Error::KeyNotFound),
+            None => Err(Error::KeyNotFound {
+                keyspace: self.name.clone(),
+                key,
+            }),
+        }
+    }
+
+    pub fn insert(&self, record: models::Record) -> Result<()> {
+        self.check_sizes(std::slice::from_ref(&record))?;
+        let (key, value) = (record.key.as_slice(), record.value.as_slice());
+        self.write(&[(key, Some(value.len()))], true, |_| {
+            let old = self.db.insert(key, value)?;
+            let delta = Delta::of(key.len(), old.map(|v| v.len()), Some(value.len()));
+            Ok(((), delta))
+        })
+    }
+
+    /// Inserts a record only if its key already exists when `exists`, or is
+    /// missing otherwise, checking the key and writing it at once.
+    pub fn insert_if(&self, record: models::Record, exists: bool) -> Result<()> {
+        self.check_sizes(std::slice::from_ref(&record))?;
+        let (key, value) = (record.key.as_slice(), record.value.as_slice());
+        let inserted = self.write(&[(key, Some(value.len()))], true, |_| loop {
+            let old = self.db.get(key)?;
+            if old.is_some() != exists {
+                return Ok((false, Delta::default()));
+            }
+            let delta = Delta::of(key.len(), old.as_ref().map(|v| v.len()), Some(value.len()));
+            if self.db.compare_and_swap(key, old, Some(value))?.is_ok() {
+                return Ok((true, delta));
+            }
+        })?;
+        match inserted {
+            true => Ok(()),
+            false => Err(Error::ConditionNotMet {
+                keyspace: self.name.clone(),
+                key: record.key,
+            }),
+        }
+    }
+
+    pub fn delete(&self, key: Vec<u8>) -> Result<()> {
+        let removed = self.write(&[(key.as_slice(), None)], false, |_| {
+            let old = self.db.remove(&key)?.map(|v| v.len());
+            Ok((old.is_some(), Delta::of(key.len(), old, None)))
+        })?;
+        match removed {
+            true => Ok(()),
+            false => Err(Error::KeyNotFound {
+                keyspace: self.name.clone(),
+                key,
+            }),
+        }
+    }
+
+    pub fn batch_insert(&self, records: Vec<models::Record>) -> Result<()> {
+        self.check_sizes(&records)?;
+        let writes: Vec<_> = records
+            .iter()
+            .map(|r| (r.key.as_slice(), Some(r.value.len())))
+            .collect();
+        self.write(&writes, true, |tracked| {
+            let writes: Vec<_> = records
+                .iter()
+                .map(|r| (r.key.as_slice(), Some(r.value.as_slice())))
+                .collect();
+            Ok(((), self.apply_writes(&writes, tracked)?))
+        })
+    }
+
+    pub fn batch_delete(&self, keys: Vec<Vec<u8>>) -> Result<()> {
+        let writes: Vec<_> = keys.iter().map(|k| (k.as_slice(), None)).collect();
+        self.write(&writes, false, |tracked| {
+            let writes: Vec<_> = keys.iter().map(|k| (k.as_slice(), None)).collect();
+            Ok(((), self.apply_writes(&writes, tracked)?))
+        })
+    }
+
+    pub fn truncate(&self) -> Result<()> {
+        self.db.clear()?;
+        if self.usage.usage().is_some() {
+            self.usage.start(Usage::default());
+        }
+        Ok(())
+    }
+
+    /// Inserts the records whose key is not already present. Keys moved
+    /// between nodes are not subject to the quota.
+    pub fn insert_missing(&self, records: Vec<models::Record>) -> Result<()> {
+        self.write(&[], false, |_| {
+            let mut delta = Delta::default();
+            for r in records {
+                let inserted = Delta::of(r.key.len(), None, Some(r.value.len()));
+                // A failed swap means the key already exists, in which case it is kept.
+                let swapped =
+                    self.db
+                        .compare_and_swap(r.key, None as Option<&[u8]>, Some(r.value))?;
+                if swapped.is_ok() {
+                    delta += inserted;
+                }
+            }
+            Ok(((), delta))
+        })
+    }
+
+    /// Deletes a key only if it still holds `value`.
+    pub fn delete_if_unchanged(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(&[], false, |_| {
+            let deleted = Delta::of(key.len(), Some(value.len()), None);
+            let swapped = self
+                .db
+                .compare_and_swap(key, Some(value), None as Option<&[u8]>)?;
+            Ok((
+                (),
+                if swapped.is_ok() {
+                    deleted
+                } else {
+                    Delta::default()
+                },
+            ))
+        })
+    }
+
+    /// Sets a key to `new`, or removes it when `None`, only if it still holds
+    /// `old`, `None` meaning absent. Returns whether it was swapped.
+    pub fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        old: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if let Some(value) = &new {
+            self.check_sizes(&[models::Record {
+                key: key.clone(),
+                value: value.clone(),
+            }])?;
         }
+        let write = (key.as_slice(), new.as_ref().map(Vec::len));
+        self.write(&[write], new.is_some(), |_| {
+            let delta = Delta::of(key.len(), old.as_ref().map(Vec::len), write.1);
+            let swapped = self
+                .db
+                .compare_and_swap(&key, old.as_deref(), new.as_deref())?
+                .is_ok();
+            Ok((swapped, if swapped { delta } else { Delta::default() }))
+        })
+    }
+
+    /// Starts a load whose records are kept apart from the keyspace until
+    /// published.
+    pub fn stage(&self) -> Result<Staging> {
+        let name = format!("staging-{}", uuid::Uuid::new_v4());
+        Ok(Staging {
+            keyspace: self.clone(),
+            tree: self.db.open_tree(&name)?,
+            name,
+            published: false,
+        })
     }
 
-    pub fn insert(&mut self, record: models::Record) -> Result<()> {
-        self.db.insert(record.key, record.value)?;
+    /// Writes buffered changes to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
         Ok(())
     }
 
-    pub fn delete(&mut self, key: Vec<u8>) -> Result<()> {
-        match self.db.remove(key)? {
-            Some(_) => Ok(()),
-            None => Err(Error::KeyNotFound),
+    pub fn stats(&self) -> Result<KeyspaceStats> {
+        let usage = self.scan()?;
+        Ok(KeyspaceStats {
+            keys: usage.keys,
+            bytes: usage.bytes,
+            disk_bytes: self.db.size_on_disk()?,
+        })
+    }
+
+    pub fn merkle_tree(&self, depth: u32) -> Result<MerkleTree> {
+        let mut tree = MerkleTree::new(depth);
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            tree.insert(&key, &value);
         }
+        Ok(tree)
+    }
+
+    /// Returns the records falling into the given buckets of a Merkle tree.
+    pub fn records_in_buckets(&self, depth: u32, buckets: &[u32]) -> Result<Vec<models::Record>> {
+        let filter = MerkleTree::bucket_filter(depth, buckets);
+        let mut records = vec![];
+        for kv in self.db.iter() {
+            let (key, value) = kv?;
+            if filter(&key) {
+                records.push(models::Record {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                });
+            }
+        }
+        Ok(records)
+    }
+
+    /// Returns up to `limit` records following the key `after`, or from the
+    /// first key.
+    pub fn records_after(&self, after: Option<&[u8]>, limit: usize) -> Result<Vec<models::Record>> {
+        let range = match after {
+            Some(key) => self
+                .db
+                .range::<&[u8], _>((Bound::Excluded(key), Bound::Unbounded)),
+            None => self.db.iter(),
+        };
+        range
+            .take(limit)
+            .map(|kv| {
+                let (key, value) = kv?;
+                Ok(models::Record {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn records(&self) -> Result<Vec<models::Record>> {
+        self.db
+            .iter()
+            .map(|kv| {
+                let (key, value) = kv?;
+                Ok(models::Record {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                })
+            })
+            .collect()
     }
+}
+
+/// Records of a load staged in a tree of their own, dropped unless published.
+pub struct Staging {
+    keyspace: Keyspace,
+    tree: sled::Tree,
+    name: String,
+    published: bool,
+}
 
-    pub fn batch_insert(&mut self, records: Vec<models::Record>) -> Result<()> {
+impl Staging {
+    pub fn insert(&self, records: Vec<models::Record>) -> Result<()> {
+        self.keyspace.check_sizes(&records)?;
         let mut batch = sled::Batch::default();
         records
             .into_iter()
             .for_each(|r| batch.insert(r.key, r.value));
-
-        self.db.apply_batch(batch)?;
+        self.tree.apply_batch(batch)?;
         Ok(())
     }
 
-    pub fn batch_delete(&mut self, keys: Vec<Vec<u8>>) -> Result<()> {
-        let mut batch = sled::Batch::default();
-        keys.into_iter().for_each(|k| batch.remove(k));
+    /// Writes the staged records to the keyspace in a single batch, so that
+    /// they appear at once. The batch is built in memory, so the server
+    /// bounds staged loads with `Limits::max_staged_bytes`.
+    pub fn publish(mut self) -> Result<()> {
+        let records = self
+            .tree
+            .iter()
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        let keyspace = &self.keyspace;
+        let writes: Vec<_> = records
+            .iter()
+            .map(|(key, value)| (key.as_ref(), Some(value.len())))
+            .collect();
+        keyspace.write(&writes, true, |tracked| {
+            let writes: Vec<_> = records
+                .iter()
+                .map(|(key, value)| (key.as_ref(), Some(value.as_ref())))
+                .collect();
+            Ok(((), keyspace.apply_writes(&writes, tracked)?))
+        })?;
 
-        self.db.apply_batch(batch)?;
+        self.published = true;
+        keyspace.db.drop_tree(&self.name)?;
         Ok(())
     }
+}
 
-    pub fn truncate(&mut self) -> Result<()> {
-        self.db.clear()?;
-        Ok(())
+impl Drop for Staging {
+    fn drop(&mut self) {
+        if !self.published {
+            // Otherwise dropped when the keyspace is next opened.
+            let _ = self.keyspace.db.drop_tree(&self.name);
+        }
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
     use uuid::Uuid;
@@ -70,9 +685,25 @@ mod tests {
         Keyspace::new(path, String::from("ks")).unwrap()
     }
 
+    /// Opens a keyspace again once sled released it, its background threads
+    /// holding the lock for a moment after the last handle is dropped.
+    fn reopen(path: String) -> Keyspace {
+        for _ in 0..100 {
+            match Keyspace::new(path.clone(), String::from("ks")) {
+                Err(Error::SledErr(sled::Error::Io(e)))
+                    if e.to_string().starts_with("could not acquire lock") =>
+                {
+                    std::thread::sleep(std::time::Duration::from_millis(10))
+                }
+                result => return result.unwrap(),
+            }
+        }
+        panic!("Keyspace '{}' is still locked", path);
+    }
+
     #[test]
     fn get_key() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         ks.insert(models::Record {
             key: b"foo".to_vec(),
             value: b"bar".to_vec(),
@@ -81,18 +712,187 @@ mod tests {
         assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"bar".to_vec());
     }
 
+    #[test]
+    fn insert_if() {
+        let ks = create_random_keyspace();
+        ks.insert_if(record(b"foo", b"bar"), true).unwrap_err();
+        ks.insert_if(record(b"foo", b"bar"), false).unwrap();
+        match ks.insert_if(record(b"foo", b"baz"), false) {
+            Err(Error::ConditionNotMet { key, .. }) => assert_eq!(key, b"foo".to_vec()),
+            other => panic!("Existing keys must not be replaced, got {:?}", other),
+        };
+        ks.insert_if(record(b"foo", b"baz"), true).unwrap();
+        assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"baz".to_vec());
+    }
+
+    #[test]
+    fn stats() {
+        let ks = create_random_keyspace();
+        ks.batch_insert(vec![
+            models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            },
+            models::Record {
+                key: b"a".to_vec(),
+                value: b"bc".to_vec(),
+            },
+        ])
+        .unwrap();
+
+        let stats = ks.stats().unwrap();
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.bytes, 9);
+        assert!(stats.disk_bytes > 0);
+    }
+
+    fn record(key: &[u8], value: &[u8]) -> models::Record {
+        models::Record {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        }
+    }
+
+    fn assert_exceeded(result: Result<()>, expected: &str) {
+        match result {
+            Err(Error::QuotaExceeded { quota, .. }) if quota == expected => (),
+            other => panic!("Expected {} to be exceeded, got {:?}", expected, other),
+        };
+    }
+
+    #[test]
+    fn quotas() {
+        let path = format!(".data/{}", Uuid::new_v4());
+        let ks = Keyspace::new(path.clone(), String::from("ks")).unwrap();
+        ks.insert(record(b"a", b"1")).unwrap();
+        ks.set_quota(KeyspaceQuota {
+            max_keys: 2,
+            max_bytes: 6,
+            max_key_size: 3,
+            max_value_size: 4,
+        })
+        .unwrap();
+        assert_eq!(ks.usage().unwrap(), Usage { keys: 1, bytes: 2 });
+
+        assert_exceeded(ks.insert(record(b"long", b"1")), "max_key_size");
+        assert_exceeded(ks.insert(record(b"b", b"12345")), "max_value_size");
+        // Keys written twice in a batch are counted once.
+        ks.batch_insert(vec![record(b"b", b"1"), record(b"b", b"12")])
+            .unwrap();
+        assert_eq!(ks.usage().unwrap(), Usage { keys: 2, bytes: 5 });
+        assert_exceeded(ks.insert(record(b"c", b"1")), "max_keys");
+        assert_exceeded(ks.insert(record(b"b", b"1234")), "max_bytes");
+        // Overwriting a key within the limits is allowed.
+        ks.insert(record(b"b", b"123")).unwrap();
+
+        ks.delete(b"a".to_vec()).unwrap();
+        ks.insert(record(b"c", b"1")).unwrap();
+        assert_eq!(ks.usage().unwrap(), Usage { keys: 2, bytes: 6 });
+        drop(ks);
+
+        let ks = reopen(path);
+        assert_eq!(ks.quota().max_keys, 2);
+        assert_eq!(ks.usage().unwrap(), Usage { keys: 2, bytes: 6 });
+        assert_exceeded(ks.insert(record(b"d", b"1")), "max_keys");
+        ks.truncate().unwrap();
+        assert_eq!(ks.usage().unwrap(), Usage::default());
+    }
+
+    #[test]
+    fn concurrent_writes_respect_the_quota() {
+        let ks = create_random_keyspace();
+        ks.set_quota(KeyspaceQuota {
+            max_keys: 50,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let ks = ks.clone();
+                std::thread::spawn(move || {
+                    for j in 0..20 {
+                        let _ = ks.insert(record(format!("{}-{}", i, j).as_bytes(), b"1"));
+                        let _ = ks.insert(record(format!("{}-0", i).as_bytes(), b"12"));
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        let usage = ks.usage().unwrap();
+        assert!(usage.keys <= 50, "{:?}", usage);
+        assert_eq!(usage, ks.scan().unwrap());
+    }
+
+    #[test]
+    fn records_are_read_in_pages() {
+        let path = format!(".data/{}", Uuid::new_v4());
+        let ks = Keyspace::new(path, String::from("ks")).unwrap();
+        ks.batch_insert(vec![
+            record(b"a", b"1"),
+            record(b"b", b"2"),
+            record(b"c", b"3"),
+        ])
+        .unwrap();
+
+        let first = ks.records_after(None, 2).unwrap();
+        assert_eq!(first, vec![record(b"a", b"1"), record(b"b", b"2")]);
+        let last = ks.records_after(Some(b"b"), 2).unwrap();
+        assert_eq!(last, vec![record(b"c", b"3")]);
+        assert!(ks.records_after(Some(b"c"), 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn staged_records_are_published_at_once() {
+        let path = format!(".data/{}", Uuid::new_v4());
+        let ks = Keyspace::new(path.clone(), String::from("ks")).unwrap();
+        ks.set_quota(KeyspaceQuota {
+            max_keys: 3,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let staging = ks.stage().unwrap();
+        staging
+            .insert(vec![record(b"a", b"1"), record(b"b", b"2")])
+            .unwrap();
+        staging.insert(vec![record(b"c", b"3")]).unwrap();
+        assert!(ks.records().unwrap().is_empty());
+        staging.publish().unwrap();
+        assert_eq!(ks.records().unwrap().len(), 3);
+        assert_eq!(ks.usage().unwrap(), Usage { keys: 3, bytes: 6 });
+
+        // Nothing is written when the quota would be exceeded.
+        let staging = ks.stage().unwrap();
+        staging.insert(vec![record(b"d", b"4")]).unwrap();
+        assert_exceeded(staging.publish(), "max_keys");
+        assert_eq!(ks.records().unwrap().len(), 3);
+
+        // Unpublished loads are discarded.
+        let staging = ks.stage().unwrap();
+        staging.insert(vec![record(b"a", b"5")]).unwrap();
+        drop(staging);
+        drop(ks);
+        let ks = reopen(path);
+        assert_eq!(ks.db.tree_names().len(), 2);
+        assert_eq!(ks.get(b"a".to_vec()).unwrap(), b"1".to_vec());
+    }
+
     #[test]
     fn get_inexistant_key() {
         let ks = create_random_keyspace();
         match ks.get(b"foo".to_vec()) {
-            Err(Error::KeyNotFound) => assert!(true),
+            Err(Error::KeyNotFound { .. }) => (),
             _ => assert!(false, "Key should not exist"),
         };
     }
 
     #[test]
     fn insert_existing_key() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         ks.insert(models::Record {
             key: b"foo".to_vec(),
             value: b"bar".to_vec(),
@@ -110,7 +910,7 @@ mod tests {
 
     #[test]
     fn delete_key() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         ks.insert(models::Record {
             key: b"foo".to_vec(),
             value: b"bar".to_vec(),
@@ -119,7 +919,7 @@ mod tests {
         ks.delete(b"foo".to_vec()).unwrap();
 
         match ks.get(b"foo".to_vec()) {
-            Err(Error::KeyNotFound) => assert!(true),
+            Err(Error::KeyNotFound { .. }) => (),
             _ => assert!(false, "Key should not exist after being deleted"),
         };
     }
@@ -127,7 +927,7 @@ mod tests {
     #[test]
 
     fn batch_insert_key() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         let records = vec![
             models::Record {
                 key: b"foo".to_vec(),
@@ -155,7 +955,7 @@ mod tests {
 
     #[test]
     fn batch_delete_key() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         let records = vec![
             models::Record {
                 key: b"foo".to_vec(),
@@ -180,15 +980,141 @@ mod tests {
 
         records.into_iter().for_each(|r| {
             match ks.get(r.key) {
-                Err(Error::KeyNotFound) => assert!(true),
+                Err(Error::KeyNotFound { .. }) => (),
                 _ => assert!(false, "Key should not exist after being deleted"),
             };
         });
     }
 
+    #[test]
+    fn records_test() {
+        let ks = create_random_keyspace();
+        let records = vec![
+            models::Record {
+                key: b"boo".to_vec(),
+                value: b"far".to_vec(),
+            },
+            models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            },
+        ];
+        ks.batch_insert(records.clone()).unwrap();
+
+        assert_eq!(ks.records().unwrap(), records);
+    }
+
+    #[test]
+    fn merkle_tree_test() {
+        let a = create_random_keyspace();
+        let b = create_random_keyspace();
+        let records: Vec<models::Record> = (0..100)
+            .map(|i| models::Record {
+                key: format!("key{}", i).into_bytes(),
+                value: format!("value{}", i).into_bytes(),
+            })
+            .collect();
+        a.batch_insert(records.clone()).unwrap();
+        b.batch_insert(records).unwrap();
+        assert!(a
+            .merkle_tree(6)
+            .unwrap()
+            .diff(&b.merkle_tree(6).unwrap())
+            .is_empty());
+
+        b.delete(b"key42".to_vec()).unwrap();
+        let buckets = a.merkle_tree(6).unwrap().diff(&b.merkle_tree(6).unwrap());
+        assert_eq!(buckets.len(), 1);
+
+        let differing = a.records_in_buckets(6, &buckets).unwrap();
+        assert!(differing.iter().any(|r| r.key == b"key42"));
+        assert!(differing.len() < 10);
+    }
+
+    #[test]
+    fn insert_missing_test() {
+        let ks = create_random_keyspace();
+        ks.insert(models::Record {
+            key: b"foo".to_vec(),
+            value: b"bar".to_vec(),
+        })
+        .unwrap();
+
+        ks.insert_missing(vec![
+            models::Record {
+                key: b"foo".to_vec(),
+                value: b"old".to_vec(),
+            },
+            models::Record {
+                key: b"boo".to_vec(),
+                value: b"far".to_vec(),
+            },
+        ])
+        .unwrap();
+
+        assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"bar".to_vec());
+        assert_eq!(ks.get(b"boo".to_vec()).unwrap(), b"far".to_vec());
+    }
+
+    #[test]
+    fn delete_if_unchanged_test() {
+        let ks = create_random_keyspace();
+        ks.insert(models::Record {
+            key: b"foo".to_vec(),
+            value: b"bar".to_vec(),
+        })
+        .unwrap();
+
+        ks.delete_if_unchanged(b"foo".to_vec(), b"old".to_vec())
+            .unwrap();
+        assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"bar".to_vec());
+
+        ks.delete_if_unchanged(b"foo".to_vec(), b"bar".to_vec())
+            .unwrap();
+        match ks.get(b"foo".to_vec()) {
+            Err(Error::KeyNotFound { .. }) => (),
+            _ => panic!("Key should not exist after being deleted"),
+        };
+    }
+
+    #[test]
+    fn compare_and_swap_test() {
+        let ks = create_random_keyspace();
+        ks.set_quota(KeyspaceQuota {
+            max_keys: 1,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert!(ks
+            .compare_and_swap(b"foo".to_vec(), None, Some(b"bar".to_vec()))
+            .unwrap());
+        assert!(!ks
+            .compare_and_swap(b"foo".to_vec(), None, Some(b"baz".to_vec()))
+            .unwrap());
+        assert!(ks
+            .compare_and_swap(
+                b"foo".to_vec(),
+                Some(b"bar".to_vec()),
+                Some(b"baz".to_vec())
+            )
+            .unwrap());
+        assert_eq!(ks.get(b"foo".to_vec()).unwrap(), b"baz".to_vec());
+        assert_exceeded(
+            ks.compare_and_swap(b"boo".to_vec(), None, Some(b"far".to_vec()))
+                .map(|_| ()),
+            "max_keys",
+        );
+
+        assert!(ks
+            .compare_and_swap(b"foo".to_vec(), Some(b"baz".to_vec()), None)
+            .unwrap());
+        assert_eq!(ks.usage().unwrap(), Usage::default());
+    }
+
     #[test]
     fn truncate_test() {
-        let mut ks = create_random_keyspace();
+        let ks = create_random_keyspace();
         let records = vec![
             models::Record {
                 key: b"foo".to_vec(),
@@ -212,7 +1138,7 @@ mod tests {
 
         records.into_iter().for_each(|r| {
             match ks.get(r.key) {
-                Err(Error::KeyNotFound) => assert!(true),
+                Err(Error::KeyNotFound { .. }) => (),
                 _ => assert!(false, "Key should not exist after being deleted"),
             };
         });
@@ -0,0 +1,175 @@
+//! Limits of the requests accepted by a server, which it advertises through
+//! `GetLimits` so that clients can split their batches.
+
+use prost::Message;
+
+use super::{Error, InsertKeysQuery, Limits, Result};
+use crate::models::Record;
+
+fn check(limit: &'static str, field: &'static str, size: usize, max: u64) -> Result<()> {
+    if max == 0 || size as u64 <= max {
+        return Ok(());
+    }
+    Err(Error::LimitExceeded {
+        limit,
+        field,
+        size: size as u64,
+        max,
+    })
+}
+
+/// Size a record adds to the message of a batch.
+fn record_len(record: &Record) -> usize {
+    let len = record.encoded_len();
+    1 + prost::length_delimiter_len(len) + len
+}
+
+impl Limits {
+    /// Checks the size of an encoded request message.
+    pub fn check_message_size(&self, size: usize) -> Result<()> {
+        check("max_message_size", "message", size, self.max_message_size)
+    }
+
+    /// Checks the number of records or keys of a batch held by `field`.
+    pub fn check_batch(&self, field: &'static str, len: usize) -> Result<()> {
+        check("max_batch_records", field, len, self.max_batch_records)
+    }
+
+    pub fn check_key(&self, key: &[u8]) -> Result<()> {
+        check("max_key_size", "key", key.len(), self.max_key_size)
+    }
+
+    pub fn check_record(&self, record: &Record) -> Result<()> {
+        self.check_key(&record.key)?;
+        check(
+            "max_value_size",
+            "value",
+            record.value.len(),
+            self.max_value_size,
+        )
+    }
+
+    /// Checks the bytes of keys and values staged by a bulk insert.
+    pub fn check_staged(&self, bytes: u64) -> Result<()> {
+        check(
+            "max_staged_bytes",
+            "records",
+            bytes as usize,
+            self.max_staged_bytes,
+        )
+    }
+
+    /// Splits records into batches fitting the limits, in order. A record
+    /// too large to share a message is left alone in its batch.
+    pub fn batches(&self, keyspace: &str, records: Vec<Record>) -> Vec<Vec<Record>> {
+        let empty = InsertKeysQuery {
+            keyspace: keyspace.to_string(),
+            records: vec![],
+        }
+        .encoded_len();
+
+        let mut batches = vec![];
+        let mut batch = vec![];
+        let mut size = empty;
+        for record in records {
+            let len = record_len(&record);
+            let full = self.max_batch_records > 0 && batch.len() as u64 >= self.max_batch_records;
+            let too_large =
+                self.max_message_size > 0 && (size + len) as u64 > self.max_message_size;
+            if !batch.is_empty() && (full || too_large) {
+                batches.push(std::mem::take(&mut batch));
+                size = empty;
+            }
+            size += len;
+            batch.push(record);
+        }
+        if !batch.is_empty() {
+            batches.push(batch);
+        }
+        batches
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(key: &str, value: &str) -> Record {
+        Record {
+            key: key.as_bytes().to_vec(),
+            value: value.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn records_are_checked() {
+        let limits = Limits {
+            max_message_size: 0,
+            max_batch_records: 2,
+            max_key_size: 3,
+            max_value_size: 4,
+            max_staged_bytes: 10,
+        };
+        limits.check_record(&record("foo", "barz")).unwrap();
+        limits.check_batch("records", 2).unwrap();
+        limits.check_message_size(usize::MAX).unwrap();
+        limits.check_staged(10).unwrap();
+
+        match limits.check_record(&record("foo", "value")) {
+            Err(Error::LimitExceeded {
+                limit: "max_value_size",
+                size: 5,
+                max: 4,
+                ..
+            }) => (),
+            _ => panic!("The value should be too large"),
+        };
+        match limits.check_batch("keys", 3) {
+            Err(e) => assert_eq!(e.code(), tonic::Code::InvalidArgument),
+            _ => panic!("The batch should be too large"),
+        };
+        match limits.check_staged(11) {
+            Err(Error::LimitExceeded {
+                limit: "max_staged_bytes",
+                ..
+            }) => (),
+            _ => panic!("The staged load should be too large"),
+        };
+    }
+
+    #[test]
+    fn batches_fit_the_limits() {
+        let records: Vec<Record> = (0..5).map(|i| record(&i.to_string(), "value")).collect();
+
+        let limits = Limits {
+            max_batch_records: 2,
+            ..Default::default()
+        };
+        let batches = limits.batches("ks", records.clone());
+        assert_eq!(
+            batches.iter().map(Vec::len).collect::<Vec<usize>>(),
+            vec![2, 2, 1]
+        );
+
+        let limits = Limits {
+            max_message_size: 30,
+            ..Default::default()
+        };
+        let batches = limits.batches("ks", records.clone());
+        for batch in batches.iter() {
+            let query = InsertKeysQuery {
+                keyspace: String::from("ks"),
+                records: batch.clone(),
+            };
+            assert!(query.encoded_len() <= 30);
+        }
+        assert_eq!(batches.concat(), records);
+
+        // A record over the limit still gets through, alone.
+        let limits = Limits {
+            max_message_size: 1,
+            ..Default::default()
+        };
+        assert_eq!(limits.batches("ks", records).len(), 5);
+    }
+}
@@ -1,35 +1,219 @@
 tonic::include_proto!("dumpstors.store");
 pub mod keyspace;
+pub mod limits;
+pub mod recovery;
 
 use sled::Error as SledError;
 use std::collections::HashMap;
-use std::fs;
-use std::io::Error as IoError;
+use std::io::{Error as IoError, ErrorKind};
 use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::Duration;
+use thiserror::Error;
 use tonic::{Code, Status};
 
 use super::models;
+use super::raft::KeyspaceSnapshot;
+use super::rpc::{self, Detail};
 use keyspace::Keyspace;
+use recovery::RecoveryReport;
 
-#[derive(Debug)]
+#[derive(Debug, Error)]
 pub enum Error {
-    SledErr(SledError),
-    IoErr(IoError),
-
-    KeyspaceNotFound,
-    KeyspaceAlreadyExists,
-    KeyNotFound,
+    #[error("Storage engine error: {0}")]
+    SledErr(#[from] SledError),
+    #[error("I/O error: {0}")]
+    IoErr(#[from] IoError),
+
+    #[error("Keyspace '{0}' not found")]
+    KeyspaceNotFound(String),
+    #[error("Keyspace '{0}' already exists")]
+    KeyspaceAlreadyExists(String),
+    #[error("Invalid keyspace name '{name}': {reason}")]
+    InvalidKeyspaceName { name: String, reason: &'static str },
+    #[error("Key not found in keyspace '{keyspace}'")]
+    KeyNotFound { keyspace: String, key: Vec<u8> },
+    #[error("Condition on key not met in keyspace '{keyspace}'")]
+    ConditionNotMet { keyspace: String, key: Vec<u8> },
+    #[error("Quota {quota} of {limit} exceeded in keyspace '{keyspace}'")]
+    QuotaExceeded {
+        keyspace: String,
+        quota: &'static str,
+        limit: u64,
+    },
+    #[error("Request exceeds {limit} of {max}: {size}")]
+    LimitExceeded {
+        limit: &'static str,
+        /// Field of the request holding the value over the limit.
+        field: &'static str,
+        size: u64,
+        max: u64,
+    },
+    #[error("Server is read-only")]
+    ServerReadOnly,
+    #[error("Keyspace '{0}' is read-only")]
+    KeyspaceReadOnly(String),
 }
 
-impl From<SledError> for Error {
-    fn from(err: SledError) -> Self {
-        Error::SledErr(err)
+fn io_code(err: &IoError) -> Code {
+    match err.kind() {
+        ErrorKind::StorageFull | ErrorKind::OutOfMemory => Code::ResourceExhausted,
+        ErrorKind::TimedOut | ErrorKind::Interrupted | ErrorKind::WouldBlock => Code::Unavailable,
+        _ => Code::Internal,
     }
 }
 
-impl From<IoError> for Error {
-    fn from(err: IoError) -> Self {
-        Error::IoErr(err)
+/// Keys are binary, so they are reported hex-encoded.
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Error {
+    /// Whether the error leaves the store in an unknown state, as opposed to
+    /// I/O failures of a single request, which are reported to its client.
+    pub fn is_fatal(&self) -> bool {
+        matches!(
+            self,
+            Error::SledErr(SledError::Corruption { .. })
+                | Error::SledErr(SledError::ReportableBug(_))
+        )
+    }
+
+    /// Whether the storage engine ran out of space or memory, which clears up
+    /// once some is reclaimed.
+    pub fn is_storage_full(&self) -> bool {
+        self.is_storage_failure() && self.code() == Code::ResourceExhausted
+    }
+
+    /// Whether the error comes from the storage engine rather than from the
+    /// request, in which case it deserves to be logged.
+    pub fn is_storage_failure(&self) -> bool {
+        matches!(self, Error::SledErr(_) | Error::IoErr(_))
+    }
+
+    /// Whether the same request may succeed later.
+    pub fn is_retryable(&self) -> bool {
+        self.code() == Code::Unavailable
+    }
+
+    pub fn code(&self) -> Code {
+        match self {
+            Error::SledErr(SledError::Io(e)) | Error::IoErr(e) => io_code(e),
+            Error::SledErr(SledError::CollectionNotFound(_)) => Code::NotFound,
+            Error::SledErr(SledError::Unsupported(_)) => Code::FailedPrecondition,
+            Error::SledErr(SledError::Corruption { .. }) => Code::DataLoss,
+            Error::SledErr(SledError::ReportableBug(_)) => Code::Internal,
+            Error::KeyspaceNotFound(_) | Error::KeyNotFound { .. } => Code::NotFound,
+            Error::KeyspaceAlreadyExists(_) => Code::AlreadyExists,
+            Error::InvalidKeyspaceName { .. } | Error::LimitExceeded { .. } => {
+                Code::InvalidArgument
+            }
+            Error::QuotaExceeded { .. } => Code::ResourceExhausted,
+            Error::ServerReadOnly | Error::KeyspaceReadOnly(_) | Error::ConditionNotMet { .. } => {
+                Code::FailedPrecondition
+            }
+        }
+    }
+
+    /// Constant identifying the cause of the error in its `ErrorInfo`.
+    pub fn reason(&self) -> &'static str {
+        match self {
+            Error::KeyspaceNotFound(_) => "KEYSPACE_NOT_FOUND",
+            Error::KeyspaceAlreadyExists(_) => "KEYSPACE_ALREADY_EXISTS",
+            Error::InvalidKeyspaceName { .. } => "INVALID_KEYSPACE_NAME",
+            Error::KeyNotFound { .. } => "KEY_NOT_FOUND",
+            Error::ConditionNotMet { .. } => "CONDITION_NOT_MET",
+            Error::QuotaExceeded { .. } => "QUOTA_EXCEEDED",
+            Error::LimitExceeded { .. } => "LIMIT_EXCEEDED",
+            Error::ServerReadOnly | Error::KeyspaceReadOnly(_) => "READ_ONLY",
+            _ => match self.code() {
+                Code::ResourceExhausted => "STORAGE_FULL",
+                Code::Unavailable => "STORAGE_UNAVAILABLE",
+                Code::DataLoss => "STORAGE_CORRUPTED",
+                _ => "STORAGE_FAILURE",
+            },
+        }
+    }
+
+    fn details(&self) -> Vec<Detail> {
+        let mut metadata = HashMap::new();
+        let mut details = vec![];
+        match self {
+            Error::KeyspaceNotFound(name) | Error::KeyspaceAlreadyExists(name) => {
+                metadata.insert(String::from("keyspace"), name.clone());
+                details.push(Detail::resource_info("keyspace", name));
+            }
+            Error::InvalidKeyspaceName { name, reason } => {
+                metadata.insert(String::from("keyspace"), name.clone());
+                details.push(Detail::bad_request("keyspace", reason));
+            }
+            Error::KeyNotFound { keyspace, key } => {
+                metadata.insert(String::from("keyspace"), keyspace.clone());
+                metadata.insert(String::from("key"), hex(key));
+                details.push(Detail::resource_info(
+                    "key",
+                    &format!("{}/{}", keyspace, hex(key)),
+                ));
+            }
+            Error::ConditionNotMet { keyspace, key } => {
+                metadata.insert(String::from("keyspace"), keyspace.clone());
+                metadata.insert(String::from("key"), hex(key));
+                details.push(Detail::precondition_failure(
+                    "CONDITION_NOT_MET",
+                    &format!("key:{}/{}", keyspace, hex(key)),
+                    "The key does not meet the condition of the write",
+                ));
+            }
+            Error::QuotaExceeded {
+                keyspace,
+                quota,
+                limit,
+            } => {
+                metadata.insert(String::from("keyspace"), keyspace.clone());
+                metadata.insert(String::from("quota"), quota.to_string());
+                metadata.insert(String::from("limit"), limit.to_string());
+                details.push(Detail::quota_failure(
+                    &format!("keyspace:{}", keyspace),
+                    &format!("{} is limited to {}", quota, limit),
+                ));
+            }
+            Error::LimitExceeded {
+                limit,
+                field,
+                size,
+                max,
+            } => {
+                metadata.insert(String::from("limit"), limit.to_string());
+                metadata.insert(String::from("max"), max.to_string());
+                metadata.insert(String::from("size"), size.to_string());
+                details.push(Detail::bad_request(
+                    field,
+                    &format!("{} is limited to {}", limit, max),
+                ));
+            }
+            Error::ServerReadOnly => {
+                details.push(Detail::precondition_failure(
+                    "READ_ONLY",
+                    "server",
+                    "Writes are disabled on this server",
+                ));
+            }
+            Error::KeyspaceReadOnly(name) => {
+                metadata.insert(String::from("keyspace"), name.clone());
+                details.push(Detail::precondition_failure(
+                    "READ_ONLY",
+                    &format!("keyspace:{}", name),
+                    "Writes are disabled on this keyspace",
+                ));
+            }
+            _ => (),
+        }
+        details.insert(0, Detail::error_info(self.reason(), metadata));
+        if self.is_retryable() {
+            details.push(Detail::retry_info(Duration::from_secs(1)));
+        }
+        details
     }
 }
 
@@ -37,113 +221,241 @@ pub type Result<T> = StdResult<T, Error>;
 
 impl From<Error> for Status {
     fn from(err: Error) -> Self {
-        match err {
-            Error::KeyspaceNotFound => Self::new(Code::NotFound, "Keyspace not found"),
-            Error::KeyspaceAlreadyExists => {
-                Self::new(Code::AlreadyExists, "Keyspace already exists")
-            }
-            Error::KeyNotFound => Self::new(Code::NotFound, "Key not found"),
-            _ => Self::new(Code::Internal, "Internal Error"),
-        }
+        rpc::status(err.code(), err.to_string(), err.details())
     }
 }
 
-#[derive(Debug, Clone)]
+/// Keyspaces are stored in a directory named after them.
+fn validate_keyspace_name(name: &str) -> Result<()> {
+    let reason = if name.is_empty() {
+        "must not be empty"
+    } else if name.len() > 255 {
+        "must be at most 255 bytes long"
+    } else if name.starts_with('.') {
+        "must not start with a dot"
+    } else if name.contains(['/', '\\', '\0']) {
+        "must not contain path separators or NUL characters"
+    } else {
+        return Ok(());
+    };
+    Err(Error::InvalidKeyspaceName {
+        name: name.to_string(),
+        reason,
+    })
+}
+
+/// Keyspace registry. Keyspaces are cheap handles over a thread-safe sled
+/// database, so the registry lock is only held to look them up or to add and
+/// remove them; reads and writes on a keyspace run concurrently. Keyspace
+/// files are opened and deleted outside of it.
+#[derive(Debug)]
 pub struct Store {
-    keyspaces: HashMap<String, Keyspace>,
+    keyspaces: RwLock<HashMap<String, Keyspace>>,
+    // Serializes the creation and deletion of each keyspace name.
+    changes: Mutex<HashMap<String, Arc<Mutex<()>>>>,
     path: String,
+    recovery: RecoveryReport,
+    read_only: AtomicBool,
 }
 
 impl Store {
-    fn load_keyspaces(path: String) -> Vec<Result<Keyspace>> {
-        fs::create_dir_all(path.clone()).unwrap();
-        let dir = fs::read_dir(path).unwrap();
+    /// Opens the store, moving the keyspaces that fail to load to its
+    /// quarantine directory.
+    pub fn new(path: String) -> Result<Self> {
+        Self::open(path, true)
+    }
 
-        dir.map(|file| {
-            let file = file?;
-            let path = file.path().into_os_string().into_string().unwrap();
-            let name = file.file_name().into_string().unwrap();
+    /// Opens the store. Keyspaces that fail to load are quarantined if
+    /// `quarantine` is set and left in place otherwise; either way they are
+    /// listed in the recovery report.
+    pub fn open(path: String, quarantine: bool) -> Result<Self> {
+        let (keyspaces, recovery) = recovery::load(&path, quarantine)?;
+        let keyspaces = keyspaces
+            .into_iter()
+            .map(|ks| (ks.name.clone(), ks))
+            .collect();
 
-            Keyspace::new(path, name)
+        Ok(Self {
+            keyspaces: RwLock::new(keyspaces),
+            changes: Mutex::new(HashMap::new()),
+            path,
+            recovery,
+            read_only: AtomicBool::new(false),
         })
-        .collect()
     }
 
-    pub fn new(path: String) -> Self {
-        let keyspaces = Self::load_keyspaces(path.clone())
-            .into_iter()
-            .map(|ks| match ks {
-                Ok(ks) => Some((ks.name.clone(), ks)),
-                Err(e) => {
-                    println!("{:?}", e);
-                    None
-                }
-            })
-            .flatten()
-            .collect();
+    /// Outcome of loading the keyspaces when the store was opened.
+    pub fn recovery(&self) -> &RecoveryReport {
+        &self.recovery
+    }
 
-        Self { keyspaces, path }
+    /// Rejects writes to every keyspace. The flag is not persisted.
+    pub fn set_read_only(&self, read_only: bool) {
+        self.read_only.store(read_only, Ordering::SeqCst);
     }
 
-    pub fn create_keyspace(&mut self, ks: models::Keyspace) -> Result<()> {
-        if self.get_keyspace(ks.name.clone()).is_ok() {
-            Err(Error::KeyspaceAlreadyExists)
-        } else {
-            let keyspace = Keyspace::new(self.path.clone(), ks.name.clone())?;
-            self.keyspaces.insert(ks.name, keyspace);
-            Ok(())
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::SeqCst)
+    }
+
+    /// Fails if the server or the keyspace, when it exists, is read-only.
+    pub fn check_writable(&self, keyspace: &str) -> Result<()> {
+        if self.is_read_only() {
+            return Err(Error::ServerReadOnly);
+        }
+        match self.get_keyspace(keyspace.to_string()) {
+            Ok(ks) if ks.is_read_only() => Err(Error::KeyspaceReadOnly(ks.name)),
+            _ => Ok(()),
         }
     }
 
-    pub fn get_keyspace(&mut self, ks: String) -> Result<&mut Keyspace> {
-        match self.keyspaces.get_mut(&ks) {
-            Some(k) => Ok(k),
-            None => Err(Error::KeyspaceNotFound),
+    // Registry updates are single map operations, so the map is consistent
+    // even when a panicking holder poisoned the lock.
+    fn registry(&self) -> RwLockReadGuard<'_, HashMap<String, Keyspace>> {
+        self.keyspaces
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn registry_mut(&self) -> RwLockWriteGuard<'_, HashMap<String, Keyspace>> {
+        self.keyspaces
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn changes(&self) -> MutexGuard<'_, HashMap<String, Arc<Mutex<()>>>> {
+        self.changes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Creates or deletes a keyspace once the previous changes of the same
+    /// name are done, as its files are changed outside of the registry lock.
+    fn change<T>(&self, name: &str, change: impl FnOnce() -> Result<T>) -> Result<T> {
+        let lock = self.changes().entry(name.to_string()).or_default().clone();
+        let result = {
+            let _changing = lock.lock().unwrap_or_else(PoisonError::into_inner);
+            change()
+        };
+
+        let mut changes = self.changes();
+        // Held by the map and this call only, when no other change waits.
+        if Arc::strong_count(&lock) == 2 {
+            changes.remove(name);
         }
+        result
     }
 
-    pub fn delete_keyspace(&mut self, ks: String) -> Result<()> {
-        match self.keyspaces.remove(&ks) {
-            Some(_) => {
-                std::fs::remove_dir_all(format!("{}/{}", self.path, ks))?;
-                Ok(())
+    pub fn create_keyspace(&self, ks: models::Keyspace) -> Result<()> {
+        validate_keyspace_name(&ks.name)?;
+        self.change(&ks.name, || {
+            if self.registry().contains_key(&ks.name) {
+                return Err(Error::KeyspaceAlreadyExists(ks.name.clone()));
             }
-            None => Err(Error::KeyspaceNotFound),
+            let keyspace = Keyspace::new(self.path.clone(), ks.name.clone())?;
+            self.registry_mut().insert(ks.name.clone(), keyspace);
+            Ok(())
+        })
+    }
+
+    pub fn get_keyspace(&self, ks: String) -> Result<Keyspace> {
+        match self.registry().get(&ks) {
+            Some(k) => Ok(k.clone()),
+            None => Err(Error::KeyspaceNotFound(ks)),
         }
     }
 
-    pub fn truncate_keyspace(&mut self, ks: String) -> Result<()> {
-        match self.keyspaces.get_mut(&ks) {
-            Some(ks) => {
-                ks.truncate()?;
-                Ok(())
+    pub fn delete_keyspace(&self, ks: String) -> Result<()> {
+        self.change(&ks, || {
+            let removed = self.registry_mut().remove(&ks);
+            match removed {
+                Some(_) => {
+                    std::fs::remove_dir_all(format!("{}/{}", self.path, ks))?;
+                    Ok(())
+                }
+                None => Err(Error::KeyspaceNotFound(ks.clone())),
             }
-            None => Err(Error::KeyspaceNotFound),
-        }
+        })
+    }
+
+    pub fn truncate_keyspace(&self, ks: String) -> Result<()> {
+        self.get_keyspace(ks)?.truncate()
     }
 
-    pub fn list_keyspaces(&mut self) -> Result<Vec<models::Keyspace>> {
+    pub fn list_keyspaces(&self) -> Result<Vec<models::Keyspace>> {
         Ok(self
-            .keyspaces
+            .registry()
             .values()
             .cloned()
             .map(|ks| ks.into())
             .collect())
     }
+
+    /// Writes the buffered changes of every keyspace to disk.
+    pub fn flush(&self) -> Result<()> {
+        let keyspaces: Vec<Keyspace> = self.registry().values().cloned().collect();
+        keyspaces.iter().try_for_each(|ks| ks.flush())
+    }
+
+    /// Dumps every keyspace with all of its records.
+    pub fn snapshot(&self) -> Result<Vec<KeyspaceSnapshot>> {
+        let keyspaces: Vec<Keyspace> = self.registry().values().cloned().collect();
+        keyspaces
+            .into_iter()
+            .map(|ks| {
+                Ok(KeyspaceSnapshot {
+                    records: ks.records()?,
+                    quota: Some(ks.quota()),
+                    name: ks.name,
+                })
+            })
+            .collect()
+    }
+
+    /// Replaces the content of the store with the given snapshot.
+    pub fn restore(&self, snapshot: Vec<KeyspaceSnapshot>) -> Result<()> {
+        let stale: Vec<String> = self
+            .registry()
+            .keys()
+            .filter(|name| !snapshot.iter().any(|ks| &ks.name == *name))
+            .cloned()
+            .collect();
+        for name in stale {
+            self.delete_keyspace(name)?;
+        }
+
+        for ks in snapshot {
+            let keyspace = match self.get_keyspace(ks.name.clone()) {
+                Ok(keyspace) => keyspace,
+                Err(Error::KeyspaceNotFound(_)) => {
+                    self.create_keyspace(models::Keyspace {
+                        name: ks.name.clone(),
+                    })?;
+                    self.get_keyspace(ks.name)?
+                }
+                Err(e) => return Err(e),
+            };
+            // The records are those of a store that enforced the quota.
+            keyspace.set_quota(KeyspaceQuota::default())?;
+            keyspace.truncate()?;
+            keyspace.batch_insert(ks.records)?;
+            keyspace.set_quota(ks.quota.unwrap_or_default())?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
     use uuid::Uuid;
 
     fn create_random_store() -> Store {
-        Store::new(format!(".data/{}", Uuid::new_v4()))
+        Store::new(format!(".data/{}", Uuid::new_v4())).unwrap()
     }
 
     #[test]
     fn load_keyspaces_works() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
@@ -153,18 +465,35 @@ mod tests {
         store.create_keyspace(ks1).unwrap();
         store.create_keyspace(ks2.clone()).unwrap();
         store.delete_keyspace(ks2.name).unwrap();
+        store
+            .get_keyspace(String::from("ks1"))
+            .unwrap()
+            .insert(models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            })
+            .unwrap();
 
-        let store_bis = Store::new(store.path.clone());
+        let path = store.path.clone();
+        let listed = store.list_keyspaces().unwrap();
+        // Keyspaces are locked while open, the store is reloaded once closed.
+        drop(store);
+        let store_bis = Store::new(path).unwrap();
 
+        assert_eq!(listed, store_bis.list_keyspaces().unwrap());
         assert_eq!(
-            store.keyspaces.keys().cloned().collect::<Vec<String>>(),
-            store_bis.keyspaces.keys().cloned().collect::<Vec<String>>()
-        )
+            store_bis
+                .get_keyspace(String::from("ks1"))
+                .unwrap()
+                .get(b"foo".to_vec())
+                .unwrap(),
+            b"bar".to_vec()
+        );
     }
 
     #[test]
     fn get_keyspace() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
@@ -177,20 +506,107 @@ mod tests {
 
     #[test]
     fn get_inexistant_keyspace() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
 
         match store.get_keyspace(ks1.name) {
-            Err(Error::KeyspaceNotFound) => assert!(true),
+            Err(Error::KeyspaceNotFound(_)) => (),
             _ => assert!(false, "Keyspace should not exist"),
         };
     }
 
+    #[test]
+    fn invalid_keyspace_names() {
+        let store = create_random_store();
+        for name in &["", "..", ".quarantine", "a/b", "a\\b"] {
+            match store.create_keyspace(models::Keyspace {
+                name: name.to_string(),
+            }) {
+                Err(Error::InvalidKeyspaceName { .. }) => (),
+                _ => panic!("'{}' must not be a valid keyspace name", name),
+            };
+        }
+    }
+
+    #[test]
+    fn error_statuses() {
+        let not_found = Error::KeyNotFound {
+            keyspace: String::from("ks"),
+            key: vec![0, 255],
+        };
+        let status = Status::from(not_found);
+        assert_eq!(status.code(), Code::NotFound);
+        assert_eq!(status.message(), "Key not found in keyspace 'ks'");
+        let info = rpc::error_info(&status).unwrap();
+        assert_eq!(info.reason, "KEY_NOT_FOUND");
+        assert_eq!(info.metadata["key"], "00ff");
+
+        let full = Error::IoErr(IoError::from(ErrorKind::StorageFull));
+        assert_eq!(full.code(), Code::ResourceExhausted);
+        assert!(full.is_storage_full());
+        assert!(!full.is_fatal());
+
+        let interrupted = Error::SledErr(SledError::Io(IoError::from(ErrorKind::Interrupted)));
+        assert!(interrupted.is_retryable());
+        assert!(!interrupted.is_fatal());
+        assert!(rpc::is_retryable(&Status::from(interrupted)));
+
+        let failed = Error::IoErr(IoError::other("disk"));
+        assert_eq!(failed.code(), Code::Internal);
+        assert!(!failed.is_fatal());
+
+        let corrupted = Error::SledErr(SledError::Corruption { at: None, bt: () });
+        assert_eq!(corrupted.code(), Code::DataLoss);
+        assert!(corrupted.is_fatal());
+    }
+
+    #[test]
+    fn read_only() {
+        let store = create_random_store();
+        store
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks"),
+            })
+            .unwrap();
+        store.check_writable("ks").unwrap();
+
+        store.set_read_only(true);
+        match store.check_writable("other") {
+            Err(Error::ServerReadOnly) => (),
+            _ => panic!("The server should be read-only"),
+        };
+        store.set_read_only(false);
+
+        let ks = store.get_keyspace(String::from("ks")).unwrap();
+        ks.set_read_only(true).unwrap();
+        drop(ks);
+        let path = store.path.clone();
+        drop(store);
+
+        // The keyspace flag is persisted, the server one is not.
+        let store = Store::new(path).unwrap();
+        assert!(!store.is_read_only());
+        match store.check_writable("ks") {
+            Err(Error::KeyspaceReadOnly(name)) => assert_eq!(name, "ks"),
+            _ => panic!("The keyspace should be read-only"),
+        };
+        let status = Status::from(Error::KeyspaceReadOnly(String::from("ks")));
+        assert_eq!(status.code(), Code::FailedPrecondition);
+        assert_eq!(rpc::error_info(&status).unwrap().reason, "READ_ONLY");
+
+        store
+            .get_keyspace(String::from("ks"))
+            .unwrap()
+            .set_read_only(false)
+            .unwrap();
+        store.check_writable("ks").unwrap();
+    }
+
     #[test]
     fn create_existing_keyspace() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
@@ -198,14 +614,14 @@ mod tests {
         store.create_keyspace(ks1.clone()).unwrap();
 
         match store.create_keyspace(ks1.clone()) {
-            Err(Error::KeyspaceAlreadyExists) => assert!(true),
+            Err(Error::KeyspaceAlreadyExists(_)) => (),
             _ => assert!(false, "Keyspace should already exist"),
         };
     }
 
     #[test]
     fn delete_keyspace() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
@@ -214,14 +630,14 @@ mod tests {
         store.delete_keyspace(ks1.name.clone()).unwrap();
 
         match store.get_keyspace(ks1.name.clone()) {
-            Err(Error::KeyspaceNotFound) => assert!(true),
+            Err(Error::KeyspaceNotFound(_)) => (),
             _ => assert!(false, "Keyspace should not exist after delete"),
         };
     }
 
     #[test]
     fn truncate_keyspace() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let ks1 = models::Keyspace {
             name: String::from("ks1"),
         };
@@ -235,7 +651,7 @@ mod tests {
 
     #[test]
     fn list_keyspaces() {
-        let mut store = create_random_store();
+        let store = create_random_store();
         let keyspaces = vec![
             models::Keyspace {
                 name: String::from("ks1"),
@@ -252,12 +668,114 @@ mod tests {
             .clone()
             .into_iter()
             .for_each(|ks| store.create_keyspace(ks).unwrap());
+        let mut listed = store.list_keyspaces().unwrap();
+        listed.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(keyspaces, listed)
+    }
+
+    #[test]
+    fn concurrent_access() {
+        let store = std::sync::Arc::new(create_random_store());
+        store
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks1"),
+            })
+            .unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    let ks = store.get_keyspace(String::from("ks1")).unwrap();
+                    for j in 0..100 {
+                        ks.insert(models::Record {
+                            key: format!("key{}-{}", i, j).into_bytes(),
+                            value: b"bar".to_vec(),
+                        })
+                        .unwrap();
+                    }
+                    store
+                        .create_keyspace(models::Keyspace {
+                            name: format!("ks-{}", i),
+                        })
+                        .unwrap();
+                })
+            })
+            .collect();
+        handles.into_iter().for_each(|h| h.join().unwrap());
+
+        let ks = store.get_keyspace(String::from("ks1")).unwrap();
+        assert_eq!(ks.records().unwrap().len(), 800);
+        assert_eq!(store.list_keyspaces().unwrap().len(), 9);
+    }
+
+    #[test]
+    fn concurrent_changes_of_a_keyspace() {
+        let store = std::sync::Arc::new(create_random_store());
+        let run = |change: fn(&Store) -> Result<()>| {
+            let handles: Vec<_> = (0..8)
+                .map(|_| {
+                    let store = store.clone();
+                    std::thread::spawn(move || change(&store))
+                })
+                .collect();
+            let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            results.iter().filter(|r| r.is_ok()).count()
+        };
+
+        let created = run(|store| {
+            store.create_keyspace(models::Keyspace {
+                name: String::from("ks"),
+            })
+        });
+        assert_eq!(created, 1);
+        let deleted = run(|store| store.delete_keyspace(String::from("ks")));
+        assert_eq!(deleted, 1);
+        assert!(store.list_keyspaces().unwrap().is_empty());
+        assert!(store.changes().is_empty());
+    }
+
+    #[test]
+    fn snapshot_restore() {
+        let store = create_random_store();
+        store
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks1"),
+            })
+            .unwrap();
+        store
+            .get_keyspace(String::from("ks1"))
+            .unwrap()
+            .insert(models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            })
+            .unwrap();
+        let snapshot = store.snapshot().unwrap();
+
+        let other = create_random_store();
+        other
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks2"),
+            })
+            .unwrap();
+        other.restore(snapshot).unwrap();
+
+        let mut listed = other.list_keyspaces().unwrap();
+        listed.sort_by(|a, b| a.name.cmp(&b.name));
+        assert_eq!(
+            listed,
+            vec![models::Keyspace {
+                name: String::from("ks1")
+            }]
+        );
         assert_eq!(
-            keyspaces.clone().sort_by(|a, b| a.name.cmp(&b.name)),
-            store
-                .list_keyspaces()
+            other
+                .get_keyspace(String::from("ks1"))
                 .unwrap()
-                .sort_by(|a, b| a.name.cmp(&b.name))
-        )
+                .get(b"foo".to_vec())
+                .unwrap(),
+            b"bar".to_vec()
+        );
     }
 }
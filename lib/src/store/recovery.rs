@@ -0,0 +1,185 @@
+//! Loading of the keyspaces when the store is opened. Keyspaces that can not
+//! be opened are moved to a quarantine directory, so that a damaged keyspace
+//! does not keep the others from being served.
+
+use std::fs;
+use std::io::{Error as IoError, ErrorKind};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::keyspace::Keyspace;
+use super::{Error, Result};
+
+/// Directory of the store holding quarantined keyspaces. Keyspace names can
+/// not start with a dot, so it never clashes with a keyspace.
+pub const QUARANTINE_DIR: &str = ".quarantine";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadOutcome {
+    Loaded,
+    /// The keyspace could not be opened and was moved to `path`.
+    Quarantined {
+        path: String,
+        reason: String,
+    },
+    /// The keyspace could not be opened and was left in place.
+    Failed {
+        reason: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyspaceLoad {
+    pub name: String,
+    pub outcome: LoadOutcome,
+}
+
+/// Outcome of loading each keyspace found in the store directory.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecoveryReport {
+    pub keyspaces: Vec<KeyspaceLoad>,
+}
+
+impl RecoveryReport {
+    /// Whether every keyspace was loaded.
+    pub fn is_clean(&self) -> bool {
+        self.keyspaces
+            .iter()
+            .all(|ks| ks.outcome == LoadOutcome::Loaded)
+    }
+}
+
+/// sled reports a keyspace locked by another process as an error of kind
+/// `Other`, which can only be told apart by its message.
+fn is_locked(e: &IoError) -> bool {
+    e.kind() == ErrorKind::WouldBlock || e.to_string().starts_with("could not acquire lock")
+}
+
+fn quarantine(store_path: &str, src: &Path, name: &str) -> std::io::Result<String> {
+    let dir = Path::new(store_path).join(QUARANTINE_DIR);
+    fs::create_dir_all(&dir)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    let dest = dir.join(format!("{}.{}", name, now));
+    fs::rename(src, &dest)?;
+    Ok(dest.to_string_lossy().into_owned())
+}
+
+/// Opens every keyspace of the store directory, quarantining the ones that
+/// fail to open when `quarantine_failures` is set.
+pub(super) fn load(
+    path: &str,
+    quarantine_failures: bool,
+) -> Result<(Vec<Keyspace>, RecoveryReport)> {
+    fs::create_dir_all(path)?;
+
+    let mut keyspaces = vec![];
+    let mut report = RecoveryReport::default();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        if file_name == QUARANTINE_DIR {
+            continue;
+        }
+
+        let name = file_name.to_string_lossy().into_owned();
+        let opened = match file_name.to_str() {
+            Some(name) => Keyspace::new(path.to_string(), name.to_string()),
+            None => Err(Error::InvalidKeyspaceName {
+                name: name.clone(),
+                reason: "must be valid UTF-8",
+            }),
+        };
+        let reason = match opened {
+            Ok(keyspace) => {
+                keyspaces.push(keyspace);
+                report.keyspaces.push(KeyspaceLoad {
+                    name,
+                    outcome: LoadOutcome::Loaded,
+                });
+                continue;
+            }
+            // The keyspace is locked by another process using the store,
+            // which must not be disturbed.
+            Err(Error::SledErr(sled::Error::Io(e))) if is_locked(&e) => {
+                return Err(Error::IoErr(IoError::new(
+                    ErrorKind::WouldBlock,
+                    format!("Store '{}' is in use by another process", path),
+                )));
+            }
+            Err(e) => e.to_string(),
+        };
+
+        let outcome = if !quarantine_failures {
+            LoadOutcome::Failed { reason }
+        } else {
+            match quarantine(path, &entry.path(), &name) {
+                Ok(path) => LoadOutcome::Quarantined { path, reason },
+                Err(e) => LoadOutcome::Failed {
+                    reason: format!("{}, and it could not be quarantined: {}", reason, e),
+                },
+            }
+        };
+        report.keyspaces.push(KeyspaceLoad { name, outcome });
+    }
+
+    report.keyspaces.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok((keyspaces, report))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models;
+    use crate::store::Store;
+    use uuid::Uuid;
+
+    #[test]
+    fn broken_keyspaces_are_quarantined() {
+        let path = format!(".data/{}", Uuid::new_v4());
+        let store = Store::new(path.clone()).unwrap();
+        store
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks"),
+            })
+            .unwrap();
+        drop(store);
+
+        // Keyspaces are directories, sled fails to open a file.
+        fs::write(format!("{}/corrupted", path), b"garbage").unwrap();
+
+        let store = Store::open(path.clone(), false).unwrap();
+        let report = store.recovery();
+        assert!(!report.is_clean());
+        assert_eq!(report.keyspaces[1].outcome, LoadOutcome::Loaded);
+        match &report.keyspaces[0].outcome {
+            LoadOutcome::Failed { .. } => (),
+            _ => panic!("The keyspace should be left in place"),
+        };
+        drop(store);
+
+        let store = Store::new(path.clone()).unwrap();
+        let report = store.recovery();
+        assert_eq!(report.keyspaces[0].name, "corrupted");
+        match &report.keyspaces[0].outcome {
+            LoadOutcome::Quarantined { path: dest, .. } => {
+                assert!(Path::new(dest).is_file());
+            }
+            _ => panic!("The keyspace should be quarantined"),
+        };
+        assert_eq!(store.list_keyspaces().unwrap().len(), 1);
+        assert!(!Path::new(&path).join("corrupted").exists());
+        drop(store);
+
+        let store = Store::new(path.clone()).unwrap();
+        assert!(store.recovery().is_clean());
+        // A second store on the same directory must not touch the keyspaces.
+        match Store::new(path) {
+            Err(Error::IoErr(e)) if e.kind() == ErrorKind::WouldBlock => (),
+            _ => panic!("The store should be in use"),
+        };
+        assert_eq!(store.list_keyspaces().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,70 @@
+//! Connections to dumpstors servers, over TCP or Unix domain sockets.
+
+use tonic::codegen::http::uri::InvalidUri;
+use tonic::transport::{Channel, Endpoint, Error};
+
+/// Prefix of Unix domain socket addresses, such as
+/// `unix:///run/dumpstors/dumpstors.sock`.
+pub const UNIX_PREFIX: &str = "unix://";
+
+/// Path of the Unix domain socket of `addr`, if it is one.
+pub fn unix_socket_path(addr: &str) -> Option<&str> {
+    addr.strip_prefix(UNIX_PREFIX)
+}
+
+/// Endpoint of a server address. Unix domain sockets get a `localhost` URI,
+/// the socket itself being opened by [`connect`].
+pub fn endpoint(addr: &str) -> Result<Endpoint, InvalidUri> {
+    match unix_socket_path(addr) {
+        Some(_) if cfg!(unix) => Ok(Endpoint::from_static("http://localhost")),
+        _ => Endpoint::from_shared(addr.to_string()),
+    }
+}
+
+/// Connects to the server at `addr` through `endpoint`, as returned by
+/// [`endpoint`] and configured by the caller.
+pub async fn connect(addr: &str, endpoint: Endpoint) -> Result<Channel, Error> {
+    #[cfg(unix)]
+    if let Some(path) = unix_socket_path(addr) {
+        let connector = unix::UnixConnector {
+            path: std::path::PathBuf::from(path),
+        };
+        return endpoint.connect_with_connector(connector).await;
+    }
+    #[cfg(not(unix))]
+    let _ = addr;
+    endpoint.connect().await
+}
+
+#[cfg(unix)]
+mod unix {
+    use std::future::Future;
+    use std::io;
+    use std::path::PathBuf;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::net::UnixStream;
+    use tonic::transport::Uri;
+    use tower_service::Service;
+
+    /// Opens connections to a Unix domain socket, whatever the URI.
+    #[derive(Clone)]
+    pub struct UnixConnector {
+        pub path: PathBuf,
+    }
+
+    impl Service<Uri> for UnixConnector {
+        type Response = UnixStream;
+        type Error = io::Error;
+        type Future = Pin<Box<dyn Future<Output = io::Result<UnixStream>> + Send>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _uri: Uri) -> Self::Future {
+            let path = self.path.clone();
+            Box::pin(async move { UnixStream::connect(path).await })
+        }
+    }
+}
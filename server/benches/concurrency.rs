@@ -0,0 +1,210 @@
+//! Measures how request throughput scales with the number of concurrent clients.
+//!
+//! Each client owns its connection and runs a mix of inserts and reads against a
+//! shared keyspace, or a keyspace of its own, so the numbers reflect contention
+//! inside the server. The same mix is also run on the store directly, through a
+//! single mutex as the server used to and concurrently.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::Runtime;
+use tonic::transport::Channel;
+use uuid::Uuid;
+
+use dumpstors::settings::{Settings, Store};
+use dumpstors_lib::models::{Keyspace, Record};
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{self, GetKeyQuery, InsertKeyQuery};
+
+const PORT: u16 = 55901;
+const KEYSPACE: &str = "bench";
+const OPS_PER_CLIENT: usize = 100;
+const CLIENTS: [usize; 4] = [1, 2, 4, 8];
+
+async fn start_server() -> StoreClient<Channel> {
+    let conf = Settings {
+        listen_addr: "127.0.0.1".to_string(),
+        port: PORT,
+        listeners: vec![],
+        store: Store::new(format!("./.data/{}", Uuid::new_v4())),
+        tls: None,
+        auth: None,
+        cluster: None,
+        sharding: None,
+        metrics: None,
+        rest: None,
+        grpc_web: None,
+        resp: None,
+        memcached: None,
+        limits: Default::default(),
+        rate_limits: Default::default(),
+        logging: Default::default(),
+        shutdown_timeout_secs: 5,
+    };
+    tokio::spawn(async move {
+        dumpstors::start_server(conf).await.unwrap();
+    });
+
+    for _ in 0..50 {
+        if let Ok(mut client) = StoreClient::connect(format!("http://127.0.0.1:{}", PORT)).await {
+            let names = (0..CLIENTS[CLIENTS.len() - 1]).map(keyspace_of);
+            for name in std::iter::once(KEYSPACE.to_string()).chain(names) {
+                client.create_keyspace(Keyspace { name }).await.unwrap();
+            }
+            return client;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Server on port {} did not start", PORT);
+}
+
+async fn connect(n: usize) -> Vec<StoreClient<Channel>> {
+    let mut clients = Vec::with_capacity(n);
+    for _ in 0..n {
+        let client = StoreClient::connect(format!("http://127.0.0.1:{}", PORT))
+            .await
+            .unwrap();
+        clients.push(client);
+    }
+    clients
+}
+
+fn keyspace_of(id: usize) -> String {
+    format!("{}-{}", KEYSPACE, id)
+}
+
+fn key(id: usize, i: usize) -> Vec<u8> {
+    format!("client{}-key{}", id, (i / 2) % 16).into_bytes()
+}
+
+async fn run_client(mut client: StoreClient<Channel>, id: usize, keyspace: String) {
+    for i in 0..OPS_PER_CLIENT {
+        let key = key(id, i);
+        if i % 2 == 0 {
+            client
+                .insert_key(InsertKeyQuery {
+                    keyspace: keyspace.clone(),
+                    record: Some(Record {
+                        key,
+                        value: vec![0; 128],
+                    }),
+                    ..Default::default()
+                })
+                .await
+                .unwrap();
+        } else {
+            client
+                .get_key(GetKeyQuery {
+                    keyspace: keyspace.clone(),
+                    key,
+                })
+                .await
+                .unwrap();
+        }
+    }
+}
+
+/// Runs the clients against the shared keyspace, or each against its own.
+fn bench_clients(c: &mut Criterion, rt: &Runtime, group: &str, own_keyspaces: bool) {
+    let mut group = c.benchmark_group(group);
+    for n in CLIENTS.iter().copied() {
+        // Clones share the underlying connection, so each client keeps its own.
+        let clients = rt.block_on(connect(n));
+        group.throughput(Throughput::Elements((n * OPS_PER_CLIENT) as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(n), &clients, |b, clients| {
+            b.to_async(rt).iter(|| {
+                let handles: Vec<_> = clients
+                    .iter()
+                    .cloned()
+                    .enumerate()
+                    .map(|(id, client)| {
+                        let keyspace = match own_keyspaces {
+                            true => keyspace_of(id),
+                            false => KEYSPACE.to_string(),
+                        };
+                        tokio::spawn(run_client(client, id, keyspace))
+                    })
+                    .collect();
+                async move {
+                    for handle in handles {
+                        handle.await.unwrap();
+                    }
+                }
+            })
+        });
+    }
+    group.finish();
+}
+
+fn concurrent_clients(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(start_server());
+
+    bench_clients(c, &rt, "concurrent_clients", false);
+    bench_clients(c, &rt, "concurrent_keyspaces", true);
+}
+
+/// Runs the mix of one client on the store, each operation taking `lock`.
+fn run_store_client(store: &store::Store, lock: &Mutex<()>, id: usize) {
+    let keyspace = store.get_keyspace(keyspace_of(id)).unwrap();
+    for i in 0..OPS_PER_CLIENT {
+        let _guard = lock.lock().unwrap();
+        if i % 2 == 0 {
+            let record = Record {
+                key: key(id, i),
+                value: vec![0; 128],
+            };
+            keyspace.insert(record).unwrap();
+        } else {
+            keyspace.get(key(id, i)).unwrap();
+        }
+    }
+}
+
+fn store_locking(c: &mut Criterion) {
+    let store = store::Store::new(format!("./.data/{}", Uuid::new_v4())).unwrap();
+    for id in 0..CLIENTS[CLIENTS.len() - 1] {
+        let name = keyspace_of(id);
+        store.create_keyspace(Keyspace { name }).unwrap();
+    }
+    let store = Arc::new(store);
+
+    let mut group = c.benchmark_group("store_locking");
+    for n in CLIENTS.iter().copied() {
+        group.throughput(Throughput::Elements((n * OPS_PER_CLIENT) as u64));
+        for global in [true, false] {
+            let name = match global {
+                true => "global_lock",
+                false => "concurrent",
+            };
+            group.bench_with_input(BenchmarkId::new(name, n), &n, |b, &n| {
+                b.iter(|| {
+                    // Without the global lock, each client takes a lock of its own.
+                    let global_lock = Arc::new(Mutex::new(()));
+                    let threads: Vec<_> = (0..n)
+                        .map(|id| {
+                            let store = store.clone();
+                            let lock = match global {
+                                true => global_lock.clone(),
+                                false => Arc::new(Mutex::new(())),
+                            };
+                            std::thread::spawn(move || run_store_client(&store, &lock, id))
+                        })
+                        .collect();
+                    for thread in threads {
+                        thread.join().unwrap();
+                    }
+                })
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(20);
+    targets = concurrent_clients, store_locking
+}
+criterion_main!(benches);
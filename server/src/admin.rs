@@ -0,0 +1,115 @@
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+use tracing::*;
+
+use dumpstors_lib::admin::*;
+use dumpstors_lib::store::Store;
+
+use super::ratelimit::RateLimiter;
+use super::settings;
+
+/// Node administration. Requests are authorized by the admin interceptor
+/// when authentication is enabled.
+pub struct AdminService {
+    store: Arc<Store>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl AdminService {
+    pub fn new(store: Arc<Store>, limiter: Arc<RateLimiter>) -> Self {
+        Self { store, limiter }
+    }
+}
+
+impl From<settings::RateLimit> for RateLimit {
+    fn from(limit: settings::RateLimit) -> Self {
+        RateLimit {
+            name: limit.name,
+            requests_per_sec: limit.requests_per_sec,
+            bytes_per_sec: limit.bytes_per_sec,
+        }
+    }
+}
+
+impl From<settings::RateLimits> for RateLimits {
+    fn from(limits: settings::RateLimits) -> Self {
+        RateLimits {
+            clients: limits.clients.into_iter().map(RateLimit::from).collect(),
+            keyspaces: limits.keyspaces.into_iter().map(RateLimit::from).collect(),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl admin_server::Admin for AdminService {
+    async fn list_quarantined_keyspaces(
+        &self,
+        _request: Request<()>,
+    ) -> StdResult<Response<QuarantinedKeyspaces>, Status> {
+        Ok(Response::new(QuarantinedKeyspaces::from(
+            self.store.recovery(),
+        )))
+    }
+
+    async fn set_read_only(
+        &self,
+        request: Request<SetReadOnlyQuery>,
+    ) -> StdResult<Response<()>, Status> {
+        let request = request.into_inner();
+        if request.keyspace.is_empty() {
+            self.store.set_read_only(request.read_only);
+            info!(
+                read_only = request.read_only,
+                "Server read-only mode switched"
+            );
+            return Ok(Response::new(()));
+        }
+
+        let keyspace = self.store.get_keyspace(request.keyspace)?;
+        let read_only = request.read_only;
+        let name = keyspace.name.clone();
+        tokio::task::spawn_blocking(move || keyspace.set_read_only(read_only))
+            .await
+            .map_err(|_| Status::internal("Store task failed"))??;
+        info!(keyspace = %name, read_only, "Keyspace read-only mode switched");
+        Ok(Response::new(()))
+    }
+
+    async fn get_rate_limits(
+        &self,
+        _request: Request<()>,
+    ) -> StdResult<Response<RateLimits>, Status> {
+        Ok(Response::new(self.limiter.limits().into()))
+    }
+
+    async fn set_rate_limit(
+        &self,
+        request: Request<SetRateLimitQuery>,
+    ) -> StdResult<Response<RateLimits>, Status> {
+        let request = request.into_inner();
+        let limit = request
+            .limit
+            .ok_or_else(|| Status::invalid_argument("A limit is required"))?;
+        if limit.name.is_empty() {
+            return Err(Status::invalid_argument("The limit name must not be empty"));
+        }
+        let limit = settings::RateLimit {
+            name: limit.name,
+            requests_per_sec: limit.requests_per_sec,
+            bytes_per_sec: limit.bytes_per_sec,
+        };
+        info!(
+            name = %limit.name,
+            requests_per_sec = limit.requests_per_sec,
+            bytes_per_sec = limit.bytes_per_sec,
+            "Rate limit set"
+        );
+        match RateLimitTarget::from_i32(request.target) {
+            Some(RateLimitTarget::Client) => self.limiter.set_client_limit(limit),
+            Some(RateLimitTarget::Keyspace) => self.limiter.set_keyspace_limit(limit),
+            None => return Err(Status::invalid_argument("Unknown rate limit target")),
+        }
+        Ok(Response::new(self.limiter.limits().into()))
+    }
+}
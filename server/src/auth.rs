@@ -0,0 +1,397 @@
+//! Bearer token authentication and per-keyspace authorization.
+//!
+//! Tokens are either listed in the configuration or signed with the HMAC
+//! secret. Signed tokens are `<claims>.<signature>`, both base64url encoded,
+//! the claims being a JSON object with the subject, its roles and an optional
+//! expiry in seconds since the Unix epoch.
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tonic::metadata::MetadataValue;
+use tonic::{Interceptor, Request, Status};
+
+use super::settings::{self, Grant, Permission};
+
+pub const AUTHORIZATION_METADATA_KEY: &str = "authorization";
+
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// Subject of the requests carrying the peer token.
+const PEER_SUBJECT: &str = "peer";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String,
+    pub roles: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exp: Option<u64>,
+}
+
+fn mac(secret: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(secret).expect("HMAC accepts keys of any size")
+}
+
+/// Returns a token carrying `claims`, signed with `secret`.
+pub fn sign(secret: &[u8], claims: &Claims) -> String {
+    let payload = serde_json::to_vec(claims).expect("Claims are serializable");
+    let mut mac = mac(secret);
+    mac.update(&payload);
+
+    format!(
+        "{}.{}",
+        base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+        base64::encode_config(mac.finalize().into_bytes(), base64::URL_SAFE_NO_PAD)
+    )
+}
+
+fn verify(secret: &[u8], token: &str) -> Option<Claims> {
+    let mut parts = token.splitn(2, '.');
+    let payload = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+    let signature = base64::decode_config(parts.next()?, base64::URL_SAFE_NO_PAD).ok()?;
+
+    let mut mac = mac(secret);
+    mac.update(&payload);
+    mac.verify_slice(&signature).ok()?;
+    serde_json::from_slice(&payload).ok()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Matches a keyspace name against a pattern in which `*` matches any
+/// characters.
+fn matches(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+
+    let mut rest = &name[first.len()..];
+    let parts: Vec<&str> = parts.collect();
+    match parts.split_last() {
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Principal {
+    pub subject: String,
+    grants: Vec<Grant>,
+}
+
+impl Principal {
+    /// Returns whether the principal holds `permission` on `keyspace`. Admin
+    /// implies write, which implies read.
+    pub fn can(&self, permission: Permission, keyspace: &str) -> bool {
+        self.grants
+            .iter()
+            .any(|g| g.permission >= permission && matches(&g.keyspaces, keyspace))
+    }
+
+    /// Returns whether the principal holds `permission` on every keyspace.
+    pub fn can_all(&self, permission: Permission) -> bool {
+        self.grants
+            .iter()
+            .any(|g| g.permission >= permission && g.keyspaces == "*")
+    }
+}
+
+pub struct Authenticator {
+    secret: Option<Vec<u8>>,
+    roles: BTreeMap<String, Vec<Grant>>,
+    /// Static tokens by their SHA-256, so that lookups don't leak them
+    /// through timing.
+    tokens: HashMap<Vec<u8>, (String, Vec<String>)>,
+    /// SHA-256 of the token the nodes of a cluster reach each other with.
+    peer_token: Option<Vec<u8>>,
+}
+
+fn bearer<T>(request: &Request<T>) -> Option<&str> {
+    request
+        .metadata()
+        .get(AUTHORIZATION_METADATA_KEY)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix(BEARER_PREFIX))
+}
+
+impl Authenticator {
+    pub fn new(conf: settings::Auth) -> Self {
+        Self {
+            secret: conf.secret.map(String::into_bytes),
+            roles: conf
+                .roles
+                .into_iter()
+                .map(|(name, grants)| (name.to_lowercase(), grants))
+                .collect(),
+            tokens: conf
+                .tokens
+                .into_iter()
+                .map(|t| {
+                    (
+                        Sha256::digest(t.token.as_bytes()).to_vec(),
+                        (t.subject, t.roles),
+                    )
+                })
+                .collect(),
+            peer_token: conf
+                .peer_token
+                .map(|token| Sha256::digest(token.as_bytes()).to_vec()),
+        }
+    }
+
+    fn principal(&self, subject: String, roles: &[String]) -> Principal {
+        let grants = roles
+            .iter()
+            .filter_map(|role| self.roles.get(&role.to_lowercase()))
+            .flatten()
+            .cloned()
+            .collect();
+        Principal { subject, grants }
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn authenticate<T>(&self, request: &Request<T>) -> StdResult<Principal, Status> {
+        let token =
+            bearer(request).ok_or_else(|| Status::unauthenticated("Missing bearer token"))?;
+
+        // Nodes hold the admin permission on each other, without the peer
+        // token being listed among the tokens.
+        if self.is_peer(request) {
+            return Ok(Principal {
+                subject: String::from(PEER_SUBJECT),
+                grants: vec![Grant {
+                    keyspaces: String::from("*"),
+                    permission: Permission::Admin,
+                }],
+            });
+        }
+
+        if let Some((subject, roles)) = self.tokens.get(&Sha256::digest(token.as_bytes()).to_vec())
+        {
+            return Ok(self.principal(subject.clone(), roles));
+        }
+
+        let claims = self
+            .secret
+            .as_ref()
+            .and_then(|secret| verify(secret, token))
+            .ok_or_else(|| Status::unauthenticated("Invalid token"))?;
+        if claims.exp.is_some_and(|exp| exp <= now()) {
+            return Err(Status::unauthenticated("Expired token"));
+        }
+        Ok(self.principal(claims.sub, &claims.roles))
+    }
+
+    /// Returns whether the request carries the peer token, meaning it comes
+    /// from another node of the cluster.
+    pub fn is_peer<T>(&self, request: &Request<T>) -> bool {
+        match (bearer(request), &self.peer_token) {
+            (Some(token), Some(peer)) => Sha256::digest(token.as_bytes()).as_slice() == peer,
+            _ => false,
+        }
+    }
+
+    /// Checks that the caller holds `permission` on `keyspace`.
+    #[allow(clippy::result_large_err)]
+    pub fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        keyspace: &str,
+        permission: Permission,
+    ) -> StdResult<Principal, Status> {
+        let principal = self.authenticate(request)?;
+        if !principal.can(permission, keyspace) {
+            return Err(Status::permission_denied(format!(
+                "'{}' lacks the {:?} permission on keyspace '{}'",
+                principal.subject, permission, keyspace
+            )));
+        }
+        Ok(principal)
+    }
+
+    /// Rejects requests without a valid token.
+    #[allow(clippy::result_large_err)]
+    pub fn interceptor(self: &Arc<Self>) -> Interceptor {
+        let auth = self.clone();
+        Interceptor::new(move |request: Request<()>| {
+            auth.authenticate(&request)?;
+            Ok(request)
+        })
+    }
+
+    /// Rejects requests from callers lacking the admin permission on every
+    /// keyspace.
+    #[allow(clippy::result_large_err)]
+    pub fn admin_interceptor(self: &Arc<Self>) -> Interceptor {
+        let auth = self.clone();
+        Interceptor::new(move |request: Request<()>| {
+            let principal = auth.authenticate(&request)?;
+            if !principal.can_all(Permission::Admin) {
+                return Err(Status::permission_denied(format!(
+                    "'{}' is not an administrator",
+                    principal.subject
+                )));
+            }
+            Ok(request)
+        })
+    }
+
+    /// Rejects requests without the peer token, which only the nodes of the
+    /// cluster hold.
+    #[allow(clippy::result_large_err)]
+    pub fn peer_interceptor(self: &Arc<Self>) -> Interceptor {
+        let auth = self.clone();
+        Interceptor::new(move |request: Request<()>| {
+            let principal = auth.authenticate(&request)?;
+            if !auth.is_peer(&request) {
+                return Err(Status::permission_denied(format!(
+                    "'{}' is not a cluster member",
+                    principal.subject
+                )));
+            }
+            Ok(request)
+        })
+    }
+}
+
+/// Adds `token` to outgoing requests.
+#[allow(clippy::result_large_err)]
+pub fn token_interceptor(token: Option<String>) -> Interceptor {
+    let value =
+        token.and_then(|t| MetadataValue::from_str(&format!("{}{}", BEARER_PREFIX, t)).ok());
+    Interceptor::new(move |mut request: Request<()>| {
+        if let Some(value) = &value {
+            request
+                .metadata_mut()
+                .insert(AUTHORIZATION_METADATA_KEY, value.clone());
+        }
+        Ok(request)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticator() -> Authenticator {
+        let mut roles = BTreeMap::new();
+        roles.insert(
+            String::from("Reader"),
+            vec![Grant {
+                keyspaces: String::from("logs-*"),
+                permission: Permission::Read,
+            }],
+        );
+        roles.insert(
+            String::from("admin"),
+            vec![Grant {
+                keyspaces: String::from("*"),
+                permission: Permission::Admin,
+            }],
+        );
+
+        Authenticator::new(settings::Auth {
+            secret: Some(String::from("secret")),
+            peer_token: Some(String::from("peer")),
+            roles,
+            tokens: vec![settings::Token {
+                subject: String::from("ci"),
+                token: String::from("abc"),
+                roles: vec![String::from("Reader")],
+            }],
+        })
+    }
+
+    fn request(token: &str) -> Request<()> {
+        let mut request = Request::new(());
+        request.metadata_mut().insert(
+            AUTHORIZATION_METADATA_KEY,
+            MetadataValue::from_str(&format!("Bearer {}", token)).unwrap(),
+        );
+        request
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("*", "anything"));
+        assert!(matches("logs", "logs"));
+        assert!(!matches("logs", "logs-1"));
+        assert!(matches("logs-*", "logs-1"));
+        assert!(matches("*-prod", "users-prod"));
+        assert!(matches("a*b*c", "a-b-c"));
+        assert!(!matches("a*b*c", "a-c-b"));
+        assert!(!matches("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn static_tokens() {
+        let auth = authenticator();
+        let principal = auth.authenticate(&request("abc")).unwrap();
+        assert_eq!(principal.subject, "ci");
+        assert!(principal.can(Permission::Read, "logs-1"));
+        assert!(!principal.can(Permission::Write, "logs-1"));
+        assert!(!principal.can(Permission::Read, "users"));
+
+        match auth.authorize(&request("abc"), "users", Permission::Read) {
+            Err(e) => assert_eq!(e.code(), tonic::Code::PermissionDenied),
+            _ => panic!("Readers of logs must not read users"),
+        };
+        match auth.authenticate(&request("abd")) {
+            Err(e) => assert_eq!(e.code(), tonic::Code::Unauthenticated),
+            _ => panic!("Unknown tokens must be rejected"),
+        };
+        match auth.authenticate(&Request::new(())) {
+            Err(e) => assert_eq!(e.code(), tonic::Code::Unauthenticated),
+            _ => panic!("Requests without a token must be rejected"),
+        };
+
+        let peer = auth.authenticate(&request("peer")).unwrap();
+        assert_eq!(peer.subject, "peer");
+        assert!(peer.can_all(Permission::Admin));
+        assert!(auth.is_peer(&request("peer")));
+        assert!(!auth.is_peer(&request("abc")));
+        assert!(!auth.is_peer(&Request::new(())));
+    }
+
+    #[test]
+    fn signed_tokens() {
+        let auth = authenticator();
+        let mut claims = Claims {
+            sub: String::from("ops"),
+            roles: vec![String::from("admin")],
+            exp: Some(now() + 60),
+        };
+        let principal = auth
+            .authenticate(&request(&sign(b"secret", &claims)))
+            .unwrap();
+        assert_eq!(principal.subject, "ops");
+        assert!(principal.can_all(Permission::Admin));
+
+        let forged = sign(b"other", &claims);
+        assert!(auth.authenticate(&request(&forged)).is_err());
+
+        claims.exp = Some(now() - 1);
+        let expired = sign(b"secret", &claims);
+        assert!(auth.authenticate(&request(&expired)).is_err());
+    }
+}
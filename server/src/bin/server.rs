@@ -1,9 +1,39 @@
-use dumpstors::{settings::Settings, start_server};
+use dumpstors::{auth, settings::Args, settings::Settings, start_server, telemetry};
+use std::time::{SystemTime, UNIX_EPOCH};
+use structopt::StructOpt;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    env_logger::init();
-    let conf = Settings::new().unwrap();
+    let args = Args::from_args();
+    let conf = Settings::load(&args)?;
 
-    start_server(conf).await
+    if let Some(subject) = args.issue_token {
+        let secret = conf
+            .auth
+            .and_then(|auth| auth.secret)
+            .ok_or("auth.secret must be set to issue tokens")?;
+        let claims = auth::Claims {
+            sub: subject,
+            roles: args.roles,
+            exp: args.ttl_secs.map(|ttl| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0)
+                    + ttl
+            }),
+        };
+        println!("{}", auth::sign(secret.as_bytes(), &claims));
+        return Ok(());
+    }
+
+    if args.check_config {
+        print!("{}", toml::Value::try_from(conf.redacted())?);
+        return Ok(());
+    }
+
+    telemetry::init(&conf.logging)?;
+    let result = start_server(conf).await;
+    telemetry::flush();
+    result
 }
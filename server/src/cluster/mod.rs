@@ -0,0 +1,958 @@
+//! Raft based replication of the store.
+//!
+//! Every mutating request is appended to a replicated log by the leader and
+//! applied to the local `Store` of each node once a majority of the cluster
+//! has persisted it. Reads are served from the local store, so followers may
+//! return slightly stale data.
+//!
+//! Committed entries are applied, and the log compacted, by a background task
+//! on the blocking thread pool. Snapshots are sent to lagging members in
+//! chunks of their encoded form.
+
+mod service;
+mod storage;
+
+pub use service::{ClusterService, RaftService};
+
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::result::Result as StdResult;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+use tokio::sync::{oneshot, Notify};
+use tokio::time::{interval, timeout};
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::Status;
+use tracing::*;
+
+use dumpstors_lib::raft::raft_client::RaftClient;
+use dumpstors_lib::raft::*;
+use dumpstors_lib::store::{Error, Result, Store};
+
+use super::auth::token_interceptor;
+use super::health::Health;
+use super::settings;
+use super::shutdown::Shutdown;
+use storage::RaftStorage;
+
+/// Maximum number of entries sent in a single `AppendEntries` request.
+const MAX_ENTRIES_PER_REQUEST: usize = 256;
+
+/// How long a client waits for its proposal to be committed.
+const PROPOSAL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metadata key carrying the address of the current leader on rejected writes.
+pub const LEADER_METADATA_KEY: &str = "x-dumpstors-leader";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+impl Role {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Role::Follower => "follower",
+            Role::Candidate => "candidate",
+            Role::Leader => "leader",
+        }
+    }
+}
+
+type Waiter = oneshot::Sender<StdResult<(), Status>>;
+
+struct RaftState {
+    storage: RaftStorage,
+    role: Role,
+    term: u64,
+    voted_for: Option<u64>,
+    leader_id: Option<u64>,
+    commit_index: u64,
+    last_applied: u64,
+    members: BTreeMap<u64, String>,
+    election_deadline: Instant,
+
+    // Candidate state
+    votes: HashSet<u64>,
+
+    // Leader state
+    next_index: HashMap<u64, u64>,
+    match_index: HashMap<u64, u64>,
+    in_flight: HashSet<u64>,
+    pending_membership: Option<u64>,
+    waiters: HashMap<u64, (u64, Waiter)>,
+
+    // Follower state: the snapshot being received, by its last index.
+    incoming_snapshot: Option<(u64, Vec<u8>)>,
+}
+
+impl RaftState {
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    fn reset_election_deadline(&mut self, election_timeout_ms: u64) {
+        let timeout = rand::thread_rng().gen_range(election_timeout_ms..2 * election_timeout_ms);
+        self.election_deadline = Instant::now() + Duration::from_millis(timeout);
+    }
+
+    fn set_members(&mut self, membership: Membership) {
+        self.members = membership
+            .members
+            .into_iter()
+            .map(|m| (m.id, m.addr))
+            .collect();
+    }
+
+    fn membership(&self) -> Membership {
+        Membership {
+            members: self
+                .members
+                .iter()
+                .map(|(id, addr)| Member {
+                    id: *id,
+                    addr: addr.clone(),
+                })
+                .collect(),
+        }
+    }
+
+    fn refresh_members(&mut self) -> Result<()> {
+        let last = self.storage.last_index()?;
+        if let Some(membership) = self.storage.membership_at(last)? {
+            self.set_members(membership);
+        }
+        Ok(())
+    }
+
+    fn become_follower(&mut self, term: u64, leader_id: Option<u64>) -> Result<()> {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.storage.save_hard_state(self.term, self.voted_for)?;
+        }
+        if self.role != Role::Follower {
+            info!("Stepping down to follower in term {}", self.term);
+        }
+        self.role = Role::Follower;
+        self.leader_id = leader_id;
+        self.votes.clear();
+        self.in_flight.clear();
+        self.pending_membership = None;
+        Ok(())
+    }
+}
+
+pub struct RaftNode {
+    id: u64,
+    conf: settings::Cluster,
+    store: Arc<Store>,
+    state: Mutex<RaftState>,
+    // A cache of connections, safe to use after a holder panicked.
+    clients: Mutex<HashMap<String, RaftClient<Channel>>>,
+    tls: Option<ClientTlsConfig>,
+    token: Option<String>,
+    health: Health,
+    wakeup: Notify,
+    /// Wakes the task applying committed entries.
+    committed: Notify,
+    /// Held while the store changes by applying entries or installing a
+    /// snapshot, so that both happen in log order.
+    apply_lock: Mutex<()>,
+}
+
+impl RaftNode {
+    pub fn new(conf: settings::Cluster, store: Arc<Store>) -> Result<Self> {
+        let storage = RaftStorage::new(&conf.path)?;
+        let (term, voted_for) = storage.hard_state()?;
+        let last_applied = storage.last_applied()?.max(storage.snapshot_index());
+
+        let mut state = RaftState {
+            storage,
+            role: Role::Follower,
+            term,
+            voted_for,
+            leader_id: None,
+            commit_index: last_applied,
+            last_applied,
+            members: conf
+                .members
+                .iter()
+                .map(|m| (m.id, m.addr.clone()))
+                .collect(),
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            in_flight: HashSet::new(),
+            pending_membership: None,
+            waiters: HashMap::new(),
+            incoming_snapshot: None,
+        };
+        state.refresh_members()?;
+        state.reset_election_deadline(conf.election_timeout_ms);
+
+        Ok(Self {
+            id: conf.node_id,
+            conf,
+            store,
+            state: Mutex::new(state),
+            clients: Mutex::new(HashMap::new()),
+            tls: None,
+            token: None,
+            health: Health::new(Shutdown::new()),
+            wakeup: Notify::new(),
+            committed: Notify::new(),
+            apply_lock: Mutex::new(()),
+        })
+    }
+
+    /// Connects to the other members over TLS.
+    pub fn with_tls(mut self, tls: Option<ClientTlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Authenticates to the other members with this token.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Reports unrecoverable storage errors to the server's health state.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    fn state(&self) -> MutexGuard<'_, RaftState> {
+        self.state.lock().unwrap_or_else(|e| {
+            // A panic midway through an update leaves the raft state unknown,
+            // it is only used until the server has shut down.
+            self.health.fail("Raft state lock is poisoned");
+            e.into_inner()
+        })
+    }
+
+    // The store is only changed while holding the lock, a panic doing so
+    // makes the server unhealthy through its failed task.
+    fn applying(&self) -> MutexGuard<'_, ()> {
+        self.apply_lock
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Converts a storage error into a status, failing the server when the
+    /// error is unrecoverable.
+    pub fn observe(&self, err: Error) -> Status {
+        self.health.observe(&err);
+        err.into()
+    }
+
+    /// Spawns the background tasks driving elections and replication, and
+    /// applying committed entries, until the server shuts down.
+    pub fn start(self: &Arc<Self>, shutdown: Shutdown) {
+        let node = self.clone();
+        let stopped = shutdown.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = node.committed.notified() => {},
+                    _ = stopped.requested() => break,
+                }
+                let applier = node.clone();
+                match tokio::task::spawn_blocking(move || applier.apply_committed()).await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => {
+                        error!("Failed to apply committed entries: {:?}", e);
+                        node.health.observe(&e);
+                    }
+                    Err(e) => node.health.fail(format!("Raft apply task failed: {}", e)),
+                }
+            }
+        });
+
+        let node = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_millis(node.conf.heartbeat_interval_ms));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    _ = node.wakeup.notified() => {},
+                    _ = shutdown.requested() => break,
+                }
+                if let Err(e) = node.tick() {
+                    error!("Raft tick failed: {:?}", e);
+                    node.health.observe(&e);
+                }
+            }
+        });
+    }
+
+    /// Writes the raft log and state to disk.
+    pub fn flush(&self) -> Result<()> {
+        self.state().storage.flush()
+    }
+
+    fn tick(self: &Arc<Self>) -> Result<()> {
+        let mut st = self.state();
+        match st.role {
+            Role::Leader => self.replicate(&mut st),
+            _ if Instant::now() >= st.election_deadline => self.start_election(&mut st),
+            _ => Ok(()),
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn client(&self, addr: &str) -> StdResult<RaftClient<Channel>, Status> {
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = clients.get(addr) {
+            return Ok(client.clone());
+        }
+
+        let mut endpoint = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.clone())
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+        let channel = endpoint
+            .timeout(Duration::from_millis(self.conf.election_timeout_ms))
+            .connect_lazy()
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let client = RaftClient::with_interceptor(channel, token_interceptor(self.token.clone()));
+        clients.insert(addr.to_string(), client.clone());
+        Ok(client)
+    }
+
+    fn peers(&self, st: &RaftState) -> Vec<(u64, String)> {
+        st.members
+            .iter()
+            .filter(|(id, _)| **id != self.id)
+            .map(|(id, addr)| (*id, addr.clone()))
+            .collect()
+    }
+
+    fn start_election(self: &Arc<Self>, st: &mut RaftState) -> Result<()> {
+        st.reset_election_deadline(self.conf.election_timeout_ms);
+        if !st.members.contains_key(&self.id) {
+            // Nodes waiting to join the cluster never campaign.
+            return Ok(());
+        }
+
+        st.term += 1;
+        st.role = Role::Candidate;
+        st.leader_id = None;
+        st.voted_for = Some(self.id);
+        st.votes = vec![self.id].into_iter().collect();
+        st.storage.save_hard_state(st.term, st.voted_for)?;
+        info!("Node {} starting election for term {}", self.id, st.term);
+
+        if st.votes.len() >= st.quorum() {
+            return self.become_leader(st);
+        }
+
+        let request = VoteRequest {
+            term: st.term,
+            candidate_id: self.id,
+            last_log_index: st.storage.last_index()?,
+            last_log_term: st.storage.last_term()?,
+        };
+        for (peer, addr) in self.peers(st) {
+            let node = self.clone();
+            let request = request.clone();
+            tokio::spawn(async move {
+                let mut client = match node.client(&addr) {
+                    Ok(client) => client,
+                    Err(_) => return,
+                };
+                if let Ok(resp) = client.request_vote(request.clone()).await {
+                    let resp = resp.into_inner();
+                    if let Err(e) = node.handle_vote_response(peer, request.term, resp) {
+                        error!("Failed to handle vote response: {:?}", e);
+                    }
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_vote_response(&self, peer: u64, term: u64, resp: VoteResponse) -> Result<()> {
+        let mut st = self.state();
+        if resp.term > st.term {
+            return st.become_follower(resp.term, None);
+        }
+        if st.role != Role::Candidate || st.term != term || !resp.vote_granted {
+            return Ok(());
+        }
+
+        st.votes.insert(peer);
+        let votes = st
+            .votes
+            .iter()
+            .filter(|id| st.members.contains_key(id))
+            .count();
+        if votes >= st.quorum() {
+            self.become_leader(&mut st)?;
+        }
+        Ok(())
+    }
+
+    fn become_leader(&self, st: &mut RaftState) -> Result<()> {
+        info!("Node {} elected leader for term {}", self.id, st.term);
+        st.role = Role::Leader;
+        st.leader_id = Some(self.id);
+        st.next_index.clear();
+        st.match_index.clear();
+        st.in_flight.clear();
+
+        // Committing an entry of the new term also commits every entry
+        // left over from previous terms.
+        self.append_as_leader(st, None)?;
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    fn append_as_leader(&self, st: &mut RaftState, payload: Option<entry::Payload>) -> Result<u64> {
+        let index = st.storage.last_index()? + 1;
+        let entry = Entry {
+            term: st.term,
+            index,
+            payload,
+        };
+        st.storage.append(std::slice::from_ref(&entry))?;
+        st.match_index.insert(self.id, index);
+
+        if let Some(entry::Payload::Membership(membership)) = entry.payload {
+            st.set_members(membership);
+            st.pending_membership = Some(index);
+        }
+        Ok(index)
+    }
+
+    fn replicate(self: &Arc<Self>, st: &mut RaftState) -> Result<()> {
+        let last_index = st.storage.last_index()?;
+
+        for (peer, addr) in self.peers(st) {
+            if st.in_flight.contains(&peer) {
+                continue;
+            }
+            let next = *st.next_index.entry(peer).or_insert(last_index + 1);
+            let term = st.term;
+
+            if next <= st.storage.snapshot_index() {
+                let data = match st.storage.snapshot_data()? {
+                    Some(data) => data,
+                    None => continue,
+                };
+                let last = st.storage.snapshot_index();
+                st.in_flight.insert(peer);
+
+                let node = self.clone();
+                tokio::spawn(async move {
+                    let res = match node.send_snapshot(&addr, term, last, data).await {
+                        Ok(resp) => node.handle_snapshot_response(peer, term, last, resp),
+                        Err(_) => {
+                            node.clear_in_flight(peer);
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = res {
+                        error!("Failed to handle snapshot response: {:?}", e);
+                    }
+                });
+            } else {
+                let prev_log_index = next - 1;
+                let request = AppendEntriesRequest {
+                    term,
+                    leader_id: self.id,
+                    prev_log_index,
+                    prev_log_term: st.storage.term_at(prev_log_index)?.unwrap_or(0),
+                    entries: st
+                        .storage
+                        .entries(next, last_index, MAX_ENTRIES_PER_REQUEST)?,
+                    leader_commit: st.commit_index,
+                };
+                st.in_flight.insert(peer);
+
+                let node = self.clone();
+                tokio::spawn(async move {
+                    let sent = request.entries.len() as u64;
+                    let resp = match node.client(&addr) {
+                        Ok(mut client) => client.append_entries(request).await,
+                        Err(e) => Err(e),
+                    };
+                    let res = match resp {
+                        Ok(resp) => node.handle_append_response(
+                            peer,
+                            term,
+                            prev_log_index + sent,
+                            resp.into_inner(),
+                        ),
+                        Err(_) => {
+                            node.clear_in_flight(peer);
+                            Ok(())
+                        }
+                    };
+                    if let Err(e) = res {
+                        error!("Failed to handle append response: {:?}", e);
+                    }
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a snapshot in chunks, until one is rejected or the last one is
+    /// accepted. Returns the last response.
+    async fn send_snapshot(
+        &self,
+        addr: &str,
+        term: u64,
+        last_index: u64,
+        data: sled::IVec,
+    ) -> StdResult<InstallSnapshotResponse, Status> {
+        let mut client = self.client(addr)?;
+        let chunk_size = self.conf.snapshot_chunk_size as usize;
+        let mut offset = 0;
+        loop {
+            let end = data.len().min(offset + chunk_size);
+            let request = InstallSnapshotRequest {
+                term,
+                leader_id: self.id,
+                snapshot: None,
+                last_index,
+                offset: offset as u64,
+                data: data[offset..end].to_vec(),
+                done: end == data.len(),
+            };
+            let resp = client.install_snapshot(request).await?.into_inner();
+            if !resp.success || resp.term > term || end == data.len() {
+                return Ok(resp);
+            }
+            offset = end;
+        }
+    }
+
+    fn clear_in_flight(&self, peer: u64) {
+        self.state().in_flight.remove(&peer);
+    }
+
+    fn handle_append_response(
+        &self,
+        peer: u64,
+        term: u64,
+        last_sent: u64,
+        resp: AppendEntriesResponse,
+    ) -> Result<()> {
+        let mut st = self.state();
+        st.in_flight.remove(&peer);
+        if resp.term > st.term {
+            return st.become_follower(resp.term, None);
+        }
+        if st.role != Role::Leader || st.term != term {
+            return Ok(());
+        }
+
+        if resp.success {
+            let matched = st
+                .match_index
+                .get(&peer)
+                .cloned()
+                .unwrap_or(0)
+                .max(last_sent);
+            st.match_index.insert(peer, matched);
+            st.next_index.insert(peer, matched + 1);
+            self.advance_commit(&mut st)?;
+
+            if matched < st.storage.last_index()? {
+                self.wakeup.notify_one();
+            }
+        } else {
+            let next = st.next_index.get(&peer).cloned().unwrap_or(1);
+            let next = next.saturating_sub(1).min(resp.last_log_index + 1).max(1);
+            st.next_index.insert(peer, next);
+            self.wakeup.notify_one();
+        }
+        Ok(())
+    }
+
+    fn handle_snapshot_response(
+        &self,
+        peer: u64,
+        term: u64,
+        last_index: u64,
+        resp: InstallSnapshotResponse,
+    ) -> Result<()> {
+        let mut st = self.state();
+        st.in_flight.remove(&peer);
+        if resp.term > st.term {
+            return st.become_follower(resp.term, None);
+        }
+        if st.role != Role::Leader || st.term != term || !resp.success {
+            return Ok(());
+        }
+
+        let matched = st
+            .match_index
+            .get(&peer)
+            .cloned()
+            .unwrap_or(0)
+            .max(last_index);
+        st.match_index.insert(peer, matched);
+        st.next_index.insert(peer, matched + 1);
+        self.wakeup.notify_one();
+        Ok(())
+    }
+
+    fn advance_commit(&self, st: &mut RaftState) -> Result<()> {
+        let last_index = st.storage.last_index()?;
+
+        for index in (st.commit_index + 1..=last_index).rev() {
+            if st.storage.term_at(index)? != Some(st.term) {
+                break;
+            }
+            let replicas = st
+                .members
+                .keys()
+                .filter(|id| st.match_index.get(id).cloned().unwrap_or(0) >= index)
+                .count();
+            if replicas >= st.quorum() {
+                st.commit_index = index;
+                break;
+            }
+        }
+        if st.last_applied < st.commit_index {
+            self.committed.notify_one();
+        }
+        Ok(())
+    }
+
+    /// Applies the committed entries to the store, then compacts the log if
+    /// needed. It runs on the blocking thread pool and releases the state
+    /// lock while the store applies an entry.
+    fn apply_committed(&self) -> Result<()> {
+        let _applying = self.applying();
+        loop {
+            let (index, entry) = {
+                let st = self.state();
+                if st.last_applied >= st.commit_index {
+                    break;
+                }
+                let index = st.last_applied + 1;
+                match st.storage.entry(index)? {
+                    Some(entry) => (index, entry),
+                    None => break,
+                }
+            };
+
+            let membership = matches!(entry.payload, Some(entry::Payload::Membership(_)));
+            let result = match entry.payload {
+                Some(entry::Payload::Command(command)) => {
+                    command.apply(&self.store).map_err(|e| self.observe(e))
+                }
+                _ => Ok(()),
+            };
+
+            let mut st = self.state();
+            if membership && st.pending_membership == Some(index) {
+                st.pending_membership = None;
+            }
+            st.last_applied = index;
+            st.storage.save_last_applied(index)?;
+
+            if let Some((term, waiter)) = st.waiters.remove(&index) {
+                let result = if term == entry.term {
+                    result
+                } else {
+                    Err(Status::unavailable("Entry was superseded by a new leader"))
+                };
+                let _ = waiter.send(result);
+            }
+        }
+
+        let mut st = self.state();
+        if st.role == Role::Leader && !st.members.contains_key(&self.id) {
+            // The leader was removed from the cluster and its removal is committed.
+            if st.pending_membership.is_none() {
+                let term = st.term;
+                st.become_follower(term, None)?;
+            }
+        }
+        drop(st);
+
+        self.maybe_compact()
+    }
+
+    /// Snapshots the store once enough entries were applied since the last
+    /// snapshot. The apply lock keeps the store at the last applied entry
+    /// while the state lock is released.
+    fn maybe_compact(&self) -> Result<()> {
+        let (last_index, last_term, membership) = {
+            let st = self.state();
+            if st.last_applied - st.storage.snapshot_index() < self.conf.snapshot_threshold {
+                return Ok(());
+            }
+            (
+                st.last_applied,
+                st.storage.term_at(st.last_applied)?.unwrap_or(0),
+                st.storage
+                    .membership_at(st.last_applied)?
+                    .or_else(|| Some(st.membership())),
+            )
+        };
+
+        let snapshot = Snapshot {
+            last_index,
+            last_term,
+            membership,
+            keyspaces: self.store.snapshot()?,
+        };
+        debug!("Compacting raft log up to index {}", snapshot.last_index);
+        self.state().storage.save_snapshot(&snapshot)
+    }
+
+    pub fn handle_append_entries(
+        &self,
+        req: AppendEntriesRequest,
+    ) -> Result<AppendEntriesResponse> {
+        let mut st = self.state();
+        if req.term < st.term {
+            return Ok(AppendEntriesResponse {
+                term: st.term,
+                success: false,
+                last_log_index: st.storage.last_index()?,
+            });
+        }
+        st.become_follower(req.term, Some(req.leader_id))?;
+        st.reset_election_deadline(self.conf.election_timeout_ms);
+
+        let last_index = st.storage.last_index()?;
+        let snapshot_index = st.storage.snapshot_index();
+        let consistent = req.prev_log_index <= last_index
+            && (req.prev_log_index < snapshot_index
+                || st.storage.term_at(req.prev_log_index)? == Some(req.prev_log_term));
+
+        if !consistent {
+            return Ok(AppendEntriesResponse {
+                term: st.term,
+                success: false,
+                last_log_index: last_index.min(req.prev_log_index.saturating_sub(1)),
+            });
+        }
+
+        let last_new = req.prev_log_index + req.entries.len() as u64;
+        let mut entries = req
+            .entries
+            .into_iter()
+            .filter(|e| e.index > snapshot_index)
+            .peekable();
+
+        // Skip the entries we already have, truncate on the first conflict.
+        while let Some(entry) = entries.peek() {
+            match st.storage.term_at(entry.index)? {
+                Some(term) if term == entry.term => {
+                    entries.next();
+                }
+                Some(_) => {
+                    st.storage.truncate_from(entry.index)?;
+                    break;
+                }
+                None => break,
+            }
+        }
+        let entries: Vec<Entry> = entries.collect();
+        if !entries.is_empty() {
+            st.storage.append(&entries)?;
+            st.refresh_members()?;
+        }
+
+        if req.leader_commit > st.commit_index {
+            st.commit_index = req.leader_commit.min(last_new);
+            self.committed.notify_one();
+        }
+
+        Ok(AppendEntriesResponse {
+            term: st.term,
+            success: true,
+            last_log_index: st.storage.last_index()?,
+        })
+    }
+
+    pub fn handle_request_vote(&self, req: VoteRequest) -> Result<VoteResponse> {
+        let mut st = self.state();
+        if req.term > st.term {
+            st.become_follower(req.term, None)?;
+        }
+
+        let last_term = st.storage.last_term()?;
+        let last_index = st.storage.last_index()?;
+        let up_to_date = req.last_log_term > last_term
+            || (req.last_log_term == last_term && req.last_log_index >= last_index);
+        let vote_granted = req.term == st.term
+            && up_to_date
+            && st.voted_for.is_none_or(|id| id == req.candidate_id);
+
+        if vote_granted {
+            st.voted_for = Some(req.candidate_id);
+            st.storage.save_hard_state(st.term, st.voted_for)?;
+            st.reset_election_deadline(self.conf.election_timeout_ms);
+        }
+
+        Ok(VoteResponse {
+            term: st.term,
+            vote_granted,
+        })
+    }
+
+    pub fn handle_install_snapshot(
+        &self,
+        req: InstallSnapshotRequest,
+    ) -> Result<InstallSnapshotResponse> {
+        let _applying = self.applying();
+        let mut st = self.state();
+        let response = |st: &RaftState, success| InstallSnapshotResponse {
+            term: st.term,
+            success,
+        };
+        if req.term < st.term {
+            return Ok(response(&st, false));
+        }
+        st.become_follower(req.term, Some(req.leader_id))?;
+        st.reset_election_deadline(self.conf.election_timeout_ms);
+
+        let snapshot = match req.snapshot {
+            Some(snapshot) => snapshot,
+            None => {
+                let mut data = match st.incoming_snapshot.take() {
+                    Some((index, data))
+                        if index == req.last_index && data.len() as u64 == req.offset =>
+                    {
+                        data
+                    }
+                    _ if req.offset == 0 => vec![],
+                    _ => return Ok(response(&st, false)),
+                };
+                data.extend_from_slice(&req.data);
+                if !req.done {
+                    st.incoming_snapshot = Some((req.last_index, data));
+                    return Ok(response(&st, true));
+                }
+                drop(st);
+                let snapshot = storage::decode::<Snapshot>(&data)?;
+                st = self.state();
+                snapshot
+            }
+        };
+        if snapshot.last_index <= st.commit_index {
+            return Ok(response(&st, true));
+        }
+        drop(st);
+
+        info!(
+            "Installing snapshot up to index {} from leader {}",
+            snapshot.last_index, req.leader_id
+        );
+        self.store.restore(snapshot.keyspaces.clone())?;
+
+        let mut st = self.state();
+        if st.storage.term_at(snapshot.last_index)? != Some(snapshot.last_term) {
+            st.storage.truncate_from(0)?;
+        }
+        st.storage.save_snapshot(&snapshot)?;
+        st.commit_index = st.commit_index.max(snapshot.last_index);
+        st.last_applied = snapshot.last_index;
+        st.storage.save_last_applied(snapshot.last_index)?;
+        if let Some(membership) = snapshot.membership {
+            st.set_members(membership);
+        }
+        st.refresh_members()?;
+
+        Ok(response(&st, true))
+    }
+
+    fn not_leader(&self, st: &RaftState) -> Status {
+        let mut status = Status::unavailable("Node is not the cluster leader");
+        let leader = st.leader_id.and_then(|id| st.members.get(&id));
+        if let Some(addr) = leader.and_then(|addr| MetadataValue::from_str(addr).ok()) {
+            status.metadata_mut().insert(LEADER_METADATA_KEY, addr);
+        }
+        status
+    }
+
+    async fn propose_entry(&self, payload: entry::Payload) -> StdResult<(), Status> {
+        let rx = {
+            let mut st = self.state();
+            if st.role != Role::Leader {
+                return Err(self.not_leader(&st));
+            }
+            if let entry::Payload::Membership(_) = payload {
+                if st.pending_membership.is_some() {
+                    return Err(Status::failed_precondition(
+                        "A membership change is already in progress",
+                    ));
+                }
+            }
+
+            let index = self.append_as_leader(&mut st, Some(payload))?;
+            let (tx, rx) = oneshot::channel();
+            let term = st.term;
+            st.waiters.insert(index, (term, tx));
+            self.advance_commit(&mut st)?;
+            rx
+        };
+        self.wakeup.notify_one();
+
+        match timeout(PROPOSAL_TIMEOUT, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(Status::unavailable("Proposal was dropped")),
+            Err(_) => Err(Status::deadline_exceeded(
+                "Timed out waiting for the entry to be committed",
+            )),
+        }
+    }
+
+    /// Replicates a mutating command and waits until it is applied.
+    pub async fn propose(&self, command: Command) -> StdResult<(), Status> {
+        self.propose_entry(entry::Payload::Command(command)).await
+    }
+
+    pub async fn add_member(&self, member: Member) -> StdResult<Membership, Status> {
+        let mut membership = self.state().membership();
+        membership.members.retain(|m| m.id != member.id);
+        membership.members.push(member);
+
+        self.propose_entry(entry::Payload::Membership(membership))
+            .await?;
+        Ok(self.state().membership())
+    }
+
+    pub async fn remove_member(&self, id: u64) -> StdResult<Membership, Status> {
+        let mut membership = self.state().membership();
+        if !membership.members.iter().any(|m| m.id == id) {
+            return Err(Status::not_found("Member not found"));
+        }
+        membership.members.retain(|m| m.id != id);
+
+        self.propose_entry(entry::Payload::Membership(membership))
+            .await?;
+        Ok(self.state().membership())
+    }
+
+    pub fn status(&self) -> ClusterStatus {
+        let st = self.state();
+        ClusterStatus {
+            id: self.id,
+            leader_id: st.leader_id.unwrap_or(0),
+            term: st.term,
+            role: st.role.as_str().to_string(),
+            membership: Some(st.membership()),
+            commit_index: st.commit_index,
+            last_applied: st.last_applied,
+        }
+    }
+}
@@ -0,0 +1,107 @@
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use tonic::{Request, Response, Status};
+
+use dumpstors_lib::raft::*;
+use dumpstors_lib::store::Result;
+
+use super::RaftNode;
+
+pub struct RaftService {
+    node: Arc<RaftNode>,
+}
+
+impl RaftService {
+    pub fn new(node: Arc<RaftNode>) -> Self {
+        Self { node }
+    }
+
+    /// Runs a handler on the blocking thread pool, as handlers write to the
+    /// raft log while holding the state lock.
+    async fn blocking<T, F>(&self, handler: F) -> StdResult<T, Status>
+    where
+        T: Send + 'static,
+        F: FnOnce(&RaftNode) -> Result<T> + Send + 'static,
+    {
+        let node = self.node.clone();
+        match tokio::task::spawn_blocking(move || handler(&node)).await {
+            Ok(result) => result.map_err(|e| self.node.observe(e)),
+            Err(e) => {
+                self.node.health.fail(format!("Raft handler failed: {}", e));
+                Err(Status::internal("Raft handler failed"))
+            }
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl raft_server::Raft for RaftService {
+    async fn append_entries(
+        &self,
+        request: Request<AppendEntriesRequest>,
+    ) -> StdResult<Response<AppendEntriesResponse>, Status> {
+        let request = request.into_inner();
+        let resp = self
+            .blocking(move |node| node.handle_append_entries(request))
+            .await?;
+        Ok(Response::new(resp))
+    }
+
+    async fn request_vote(
+        &self,
+        request: Request<VoteRequest>,
+    ) -> StdResult<Response<VoteResponse>, Status> {
+        let request = request.into_inner();
+        let resp = self
+            .blocking(move |node| node.handle_request_vote(request))
+            .await?;
+        Ok(Response::new(resp))
+    }
+
+    async fn install_snapshot(
+        &self,
+        request: Request<InstallSnapshotRequest>,
+    ) -> StdResult<Response<InstallSnapshotResponse>, Status> {
+        let request = request.into_inner();
+        let resp = self
+            .blocking(move |node| node.handle_install_snapshot(request))
+            .await?;
+        Ok(Response::new(resp))
+    }
+}
+
+pub struct ClusterService {
+    node: Arc<RaftNode>,
+}
+
+impl ClusterService {
+    pub fn new(node: Arc<RaftNode>) -> Self {
+        Self { node }
+    }
+}
+
+#[tonic::async_trait]
+impl cluster_server::Cluster for ClusterService {
+    async fn get_cluster_status(
+        &self,
+        _request: Request<()>,
+    ) -> StdResult<Response<ClusterStatus>, Status> {
+        Ok(Response::new(self.node.status()))
+    }
+
+    async fn add_member(
+        &self,
+        request: Request<Member>,
+    ) -> StdResult<Response<Membership>, Status> {
+        let membership = self.node.add_member(request.into_inner()).await?;
+        Ok(Response::new(membership))
+    }
+
+    async fn remove_member(
+        &self,
+        request: Request<RemoveMemberQuery>,
+    ) -> StdResult<Response<Membership>, Status> {
+        let membership = self.node.remove_member(request.into_inner().id).await?;
+        Ok(Response::new(membership))
+    }
+}
@@ -0,0 +1,322 @@
+use prost::Message;
+use std::convert::TryInto;
+use std::io::{Error as IoError, ErrorKind};
+
+use dumpstors_lib::raft::{entry, Entry, Membership, Snapshot};
+use dumpstors_lib::store::{Error, Result};
+
+const TERM: &[u8] = b"term";
+const VOTED_FOR: &[u8] = b"voted_for";
+const LAST_APPLIED: &[u8] = b"last_applied";
+const SNAPSHOT: &[u8] = b"snapshot";
+
+/// Persistent raft state: hard state, log entries and the latest snapshot.
+pub struct RaftStorage {
+    meta: sled::Db,
+    log: sled::Tree,
+    snapshot_index: u64,
+    snapshot_term: u64,
+    /// Latest membership configuration and the index it was set at, so that
+    /// looking it up does not scan the log.
+    membership: Option<(u64, Membership)>,
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    u64::from_be_bytes(bytes.try_into().unwrap_or([0; 8]))
+}
+
+pub(super) fn decode<M: Message + Default>(bytes: &[u8]) -> Result<M> {
+    M::decode(bytes).map_err(|e| Error::IoErr(IoError::new(ErrorKind::InvalidData, e)))
+}
+
+fn encode<M: Message>(msg: &M) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(msg.encoded_len());
+    // Encoding into a Vec can only fail on insufficient capacity, which Vec grows.
+    let _ = msg.encode(&mut buf);
+    buf
+}
+
+impl RaftStorage {
+    pub fn new(path: &str) -> Result<Self> {
+        let meta = sled::open(path)?;
+        let log = meta.open_tree("log")?;
+
+        let mut storage = Self {
+            meta,
+            log,
+            snapshot_index: 0,
+            snapshot_term: 0,
+            membership: None,
+        };
+        if let Some(snapshot) = storage.snapshot()? {
+            storage.snapshot_index = snapshot.last_index;
+            storage.snapshot_term = snapshot.last_term;
+        }
+        storage.membership = storage.find_membership(storage.last_index()?)?;
+        Ok(storage)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    fn get_u64(&self, key: &[u8]) -> Result<u64> {
+        Ok(self.meta.get(key)?.map(|v| decode_u64(&v)).unwrap_or(0))
+    }
+
+    pub fn hard_state(&self) -> Result<(u64, Option<u64>)> {
+        let term = self.get_u64(TERM)?;
+        let voted_for = self.meta.get(VOTED_FOR)?.map(|v| decode_u64(&v));
+        Ok((term, voted_for))
+    }
+
+    pub fn save_hard_state(&self, term: u64, voted_for: Option<u64>) -> Result<()> {
+        self.meta.insert(TERM, &term.to_be_bytes())?;
+        match voted_for {
+            Some(id) => self.meta.insert(VOTED_FOR, &id.to_be_bytes())?,
+            None => self.meta.remove(VOTED_FOR)?,
+        };
+        self.meta.flush()?;
+        Ok(())
+    }
+
+    pub fn last_applied(&self) -> Result<u64> {
+        self.get_u64(LAST_APPLIED)
+    }
+
+    pub fn save_last_applied(&self, index: u64) -> Result<()> {
+        self.meta.insert(LAST_APPLIED, &index.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn snapshot_index(&self) -> u64 {
+        self.snapshot_index
+    }
+
+    pub fn snapshot(&self) -> Result<Option<Snapshot>> {
+        match self.meta.get(SNAPSHOT)? {
+            Some(bytes) => Ok(Some(decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the encoded snapshot, which is sent to members lagging behind
+    /// without decoding it.
+    pub fn snapshot_data(&self) -> Result<Option<sled::IVec>> {
+        Ok(self.meta.get(SNAPSHOT)?)
+    }
+
+    /// Stores a snapshot and discards every log entry it covers.
+    pub fn save_snapshot(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.meta.insert(SNAPSHOT, encode(snapshot))?;
+        self.meta.flush()?;
+
+        self.snapshot_index = snapshot.last_index;
+        self.snapshot_term = snapshot.last_term;
+        let compacted = match &self.membership {
+            Some((index, _)) => *index <= snapshot.last_index,
+            None => true,
+        };
+        if compacted {
+            if let Some(membership) = &snapshot.membership {
+                self.membership = Some((snapshot.last_index, membership.clone()));
+            }
+        }
+
+        for key in self.log.range(..snapshot.last_index.to_be_bytes()).keys() {
+            self.log.remove(key?)?;
+        }
+        self.log.remove(snapshot.last_index.to_be_bytes())?;
+        Ok(())
+    }
+
+    pub fn entry(&self, index: u64) -> Result<Option<Entry>> {
+        match self.log.get(index.to_be_bytes())? {
+            Some(bytes) => Ok(Some(decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the entries in `[from, to]`, at most `max` of them.
+    pub fn entries(&self, from: u64, to: u64, max: usize) -> Result<Vec<Entry>> {
+        self.log
+            .range(from.to_be_bytes()..=to.to_be_bytes())
+            .values()
+            .take(max)
+            .map(|v| decode(&v?))
+            .collect()
+    }
+
+    pub fn term_at(&self, index: u64) -> Result<Option<u64>> {
+        if index == 0 {
+            return Ok(Some(0));
+        }
+        if index == self.snapshot_index {
+            return Ok(Some(self.snapshot_term));
+        }
+        Ok(self.entry(index)?.map(|e| e.term))
+    }
+
+    pub fn last_index(&self) -> Result<u64> {
+        match self.log.last()? {
+            Some((key, _)) => Ok(decode_u64(&key)),
+            None => Ok(self.snapshot_index),
+        }
+    }
+
+    pub fn last_term(&self) -> Result<u64> {
+        match self.log.last()? {
+            Some((_, value)) => Ok(decode::<Entry>(&value)?.term),
+            None => Ok(self.snapshot_term),
+        }
+    }
+
+    pub fn append(&mut self, entries: &[Entry]) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        entries
+            .iter()
+            .for_each(|e| batch.insert(&e.index.to_be_bytes(), encode(e)));
+        self.log.apply_batch(batch)?;
+        self.log.flush()?;
+
+        for e in entries {
+            if let Some(entry::Payload::Membership(m)) = &e.payload {
+                self.membership = Some((e.index, m.clone()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes every entry starting at `index`.
+    pub fn truncate_from(&mut self, index: u64) -> Result<()> {
+        for key in self.log.range(index.to_be_bytes()..).keys() {
+            self.log.remove(key?)?;
+        }
+        if matches!(&self.membership, Some((at, _)) if *at >= index) {
+            self.membership = match index.checked_sub(1) {
+                Some(last) => self.find_membership(last)?,
+                None => None,
+            };
+        }
+        Ok(())
+    }
+
+    /// Returns the latest membership configuration at or before `index`.
+    pub fn membership_at(&self, index: u64) -> Result<Option<Membership>> {
+        match &self.membership {
+            Some((at, membership)) if *at <= index => Ok(Some(membership.clone())),
+            _ => Ok(self.find_membership(index)?.map(|(_, m)| m)),
+        }
+    }
+
+    /// Scans the log back from `index` for the latest membership configuration.
+    fn find_membership(&self, index: u64) -> Result<Option<(u64, Membership)>> {
+        for value in self.log.range(..=index.to_be_bytes()).values().rev() {
+            let entry = decode::<Entry>(&value?)?;
+            if let Some(entry::Payload::Membership(m)) = entry.payload {
+                return Ok(Some((entry.index, m)));
+            }
+        }
+        Ok(self
+            .snapshot()?
+            .and_then(|s| Some(s.last_index).zip(s.membership)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dumpstors_lib::raft::Member;
+    use uuid::Uuid;
+
+    fn create_random_storage() -> RaftStorage {
+        RaftStorage::new(&format!(".data/{}", Uuid::new_v4())).unwrap()
+    }
+
+    fn entry(term: u64, index: u64) -> Entry {
+        Entry {
+            term,
+            index,
+            payload: None,
+        }
+    }
+
+    #[test]
+    fn hard_state_test() {
+        let storage = create_random_storage();
+        assert_eq!(storage.hard_state().unwrap(), (0, None));
+
+        storage.save_hard_state(3, Some(2)).unwrap();
+        assert_eq!(storage.hard_state().unwrap(), (3, Some(2)));
+    }
+
+    #[test]
+    fn append_truncate_test() {
+        let mut storage = create_random_storage();
+        storage
+            .append(&[entry(1, 1), entry(1, 2), entry(2, 3)])
+            .unwrap();
+
+        assert_eq!(storage.last_index().unwrap(), 3);
+        assert_eq!(storage.last_term().unwrap(), 2);
+        assert_eq!(storage.entries(2, 3, 10).unwrap().len(), 2);
+
+        storage.truncate_from(2).unwrap();
+        assert_eq!(storage.last_index().unwrap(), 1);
+        assert_eq!(storage.term_at(2).unwrap(), None);
+    }
+
+    #[test]
+    fn snapshot_compacts_log_test() {
+        let mut storage = create_random_storage();
+        storage
+            .append(&[entry(1, 1), entry(1, 2), entry(2, 3)])
+            .unwrap();
+
+        let membership = Membership {
+            members: vec![Member {
+                id: 1,
+                addr: String::from("http://127.0.0.1:4242"),
+            }],
+        };
+        storage
+            .save_snapshot(&Snapshot {
+                last_index: 2,
+                last_term: 1,
+                membership: Some(membership.clone()),
+                keyspaces: vec![],
+            })
+            .unwrap();
+
+        assert_eq!(storage.entry(1).unwrap(), None);
+        assert_eq!(storage.entry(2).unwrap(), None);
+        assert_eq!(storage.term_at(2).unwrap(), Some(1));
+        assert_eq!(storage.last_index().unwrap(), 3);
+        assert_eq!(storage.membership_at(3).unwrap(), Some(membership));
+    }
+
+    #[test]
+    fn membership_test() {
+        let mut storage = create_random_storage();
+        let membership = |id: u64| Membership {
+            members: vec![Member {
+                id,
+                addr: format!("http://127.0.0.1:{}", 4242 + id),
+            }],
+        };
+        let change = |index: u64, id: u64| Entry {
+            term: 1,
+            index,
+            payload: Some(entry::Payload::Membership(membership(id))),
+        };
+        storage
+            .append(&[change(1, 1), entry(1, 2), change(3, 2), entry(1, 4)])
+            .unwrap();
+        assert_eq!(storage.membership_at(4).unwrap(), Some(membership(2)));
+        assert_eq!(storage.membership_at(2).unwrap(), Some(membership(1)));
+
+        storage.truncate_from(3).unwrap();
+        assert_eq!(storage.membership_at(4).unwrap(), Some(membership(1)));
+    }
+}
@@ -0,0 +1,87 @@
+//! Completion of gRPC responses, observed by the metrics and tracing layers.
+
+use http::{HeaderMap, Response};
+use http_body::Body as HttpBody;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::{Code, Status};
+
+type Done = Box<dyn FnOnce(Code) + Send + Sync>;
+
+/// Calls `done` once with the status of the response. Responses dropped before
+/// their status was sent are reported as cancelled.
+struct Completion(Option<Done>);
+
+impl Completion {
+    fn complete(&mut self, code: Code) {
+        if let Some(done) = self.0.take() {
+            done(code);
+        }
+    }
+}
+
+impl Drop for Completion {
+    fn drop(&mut self) {
+        self.complete(Code::Cancelled)
+    }
+}
+
+/// Response body completing with the status sent in the trailers.
+struct CompletionBody {
+    inner: BoxBody,
+    completion: Completion,
+}
+
+impl HttpBody for CompletionBody {
+    type Data = bytes::Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.inner).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let trailers = futures::ready!(Pin::new(&mut self.inner).poll_trailers(cx));
+        self.completion.complete(match &trailers {
+            Ok(Some(trailers)) => Status::from_header_map(trailers).map_or(Code::Ok, |s| s.code()),
+            Ok(None) => Code::Ok,
+            Err(status) => status.code(),
+        });
+        Poll::Ready(trailers)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Calls `done` with the status of a response once it is sent.
+pub fn on_completion<F>(response: Response<BoxBody>, done: F) -> Response<BoxBody>
+where
+    F: FnOnce(Code) + Send + Sync + 'static,
+{
+    let mut completion = Completion(Some(Box::new(done)));
+    // Failures before the first message are sent in the headers alone.
+    if let Some(status) = Status::from_header_map(response.headers()) {
+        completion.complete(status.code());
+        return response;
+    }
+
+    let (parts, body) = response.into_parts();
+    let body = BoxBody::new(CompletionBody {
+        inner: body,
+        completion,
+    });
+    Response::from_parts(parts, body)
+}
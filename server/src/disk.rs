@@ -0,0 +1,199 @@
+//! Disk usage watermarks of the volume holding the store. Usage is checked
+//! periodically: above the low watermark warnings are logged, above the high
+//! one writes are rejected before sled runs out of space, while reads and
+//! deletes are still served. Running out of space anyway rejects writes until
+//! usage drops below the low watermark.
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::interval;
+use tonic::{Code, Status};
+use tracing::*;
+
+use dumpstors_lib::rpc::{self, Detail};
+
+use super::settings::Watermarks;
+use super::shutdown::Shutdown;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Normal,
+    /// Above the low watermark.
+    Low,
+    /// Above the high watermark, writes are rejected.
+    High,
+}
+
+/// Fraction of the volume holding `path` that is used.
+fn usage(path: &str) -> io::Result<f64> {
+    let total = fs2::total_space(path)?;
+    let available = fs2::available_space(path)?;
+    if total == 0 {
+        return Ok(0.0);
+    }
+    Ok(1.0 - available as f64 / total as f64)
+}
+
+pub struct DiskMonitor {
+    path: String,
+    conf: Watermarks,
+    /// Bits of the last measured usage.
+    usage: AtomicU64,
+    /// Whether the volume ran out of space since usage was last below the low
+    /// watermark.
+    full: AtomicBool,
+}
+
+impl DiskMonitor {
+    pub fn new(path: String, conf: Watermarks) -> Self {
+        Self {
+            path,
+            conf,
+            usage: AtomicU64::new(0f64.to_bits()),
+            full: AtomicBool::new(false),
+        }
+    }
+
+    /// Last measured usage of the volume.
+    pub fn usage(&self) -> f64 {
+        f64::from_bits(self.usage.load(Ordering::Relaxed))
+    }
+
+    pub fn level(&self) -> Level {
+        let usage = self.usage();
+        if usage >= self.conf.high || self.full.load(Ordering::Relaxed) {
+            Level::High
+        } else if usage >= self.conf.low {
+            Level::Low
+        } else {
+            Level::Normal
+        }
+    }
+
+    /// Measures the usage of the volume, logging when a watermark is exceeded.
+    pub fn check(&self) -> io::Result<Level> {
+        let usage = usage(&self.path)?;
+        self.usage.store(usage.to_bits(), Ordering::Relaxed);
+        if usage < self.conf.low && self.full.swap(false, Ordering::Relaxed) {
+            info!(usage, "Disk space was reclaimed, accepting writes");
+        }
+
+        let level = self.level();
+        match level {
+            Level::High => warn!(
+                usage,
+                watermark = self.conf.high,
+                "Disk usage above the high watermark, rejecting writes"
+            ),
+            Level::Low => warn!(
+                usage,
+                watermark = self.conf.low,
+                "Disk usage above the low watermark"
+            ),
+            Level::Normal => {}
+        }
+        Ok(level)
+    }
+
+    /// Rejects writes after the volume ran out of space, whatever its measured
+    /// usage, until it drops below the low watermark.
+    pub fn mark_full(&self) {
+        if !self.full.swap(true, Ordering::Relaxed) {
+            warn!("Disk ran out of space, rejecting writes");
+        }
+    }
+
+    /// Fails with `ResourceExhausted` above the high watermark.
+    #[allow(clippy::result_large_err)]
+    pub fn check_writes(&self) -> Result<(), Status> {
+        if self.level() != Level::High {
+            return Ok(());
+        }
+        let mut metadata = HashMap::new();
+        metadata.insert(String::from("usage"), format!("{:.3}", self.usage()));
+        metadata.insert(String::from("watermark"), self.conf.high.to_string());
+        Err(rpc::status(
+            Code::ResourceExhausted,
+            "Disk usage is above the high watermark",
+            vec![
+                Detail::error_info("STORAGE_FULL", metadata),
+                Detail::quota_failure("disk", "Writes are rejected until disk space is reclaimed"),
+            ],
+        ))
+    }
+
+    /// Checks the usage periodically until a shutdown is requested.
+    pub fn watch(self: &Arc<Self>, shutdown: Shutdown) {
+        let monitor = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(monitor.conf.check_interval_secs));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    _ = shutdown.requested() => break,
+                }
+                let checked = monitor.clone();
+                if let Ok(Err(e)) = tokio::task::spawn_blocking(move || checked.check()).await {
+                    error!("Failed to measure disk usage: {}", e);
+                }
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn monitor(low: f64, high: f64) -> DiskMonitor {
+        DiskMonitor::new(
+            String::from("."),
+            Watermarks {
+                low,
+                high,
+                check_interval_secs: 1,
+            },
+        )
+    }
+
+    #[test]
+    fn levels() {
+        let monitor = monitor(0.5, 0.9);
+        for (usage, level) in [(0.1, Level::Normal), (0.6, Level::Low), (0.95, Level::High)] {
+            monitor.usage.store(f64::to_bits(usage), Ordering::Relaxed);
+            assert_eq!(monitor.level(), level);
+        }
+        match monitor.check_writes() {
+            Err(status) => {
+                assert_eq!(status.code(), Code::ResourceExhausted);
+                assert_eq!(rpc::error_info(&status).unwrap().reason, "STORAGE_FULL");
+            }
+            Ok(_) => panic!("Writes should be rejected"),
+        };
+    }
+
+    #[test]
+    fn full_until_below_low_watermark() {
+        let full = monitor(0.0, 1.0);
+        full.mark_full();
+        assert_eq!(full.level(), Level::High);
+        assert!(full.check_writes().is_err());
+        full.check().unwrap();
+        assert_eq!(full.level(), Level::High);
+
+        let reclaimed = monitor(1.0, 1.0);
+        reclaimed.mark_full();
+        reclaimed.check().unwrap();
+        assert!(reclaimed.check_writes().is_ok());
+    }
+
+    #[test]
+    fn measures_usage() {
+        let monitor = monitor(0.0, 1.0);
+        monitor.check().unwrap();
+        assert!((0.0..=1.0).contains(&monitor.usage()));
+    }
+}
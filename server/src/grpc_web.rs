@@ -0,0 +1,443 @@
+//! gRPC-Web gateway, letting browsers call the gRPC services over HTTP/1.1.
+//!
+//! Requests are translated to gRPC and passed to the same services as the
+//! gRPC server, with their interceptors and tower layers, so that
+//! authentication, limits, metrics and tracing apply alike. Trailers are sent
+//! at the end of the body as gRPC-Web expects, base64 encoded along with the
+//! messages for `application/grpc-web-text`. Cross-origin requests are only
+//! served for the configured origins.
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::future::{poll_fn, BoxFuture};
+use futures::StreamExt;
+use http::header::{self, HeaderValue};
+use http::{HeaderMap, Method, Request, Response, StatusCode, Version};
+use http_body::Body as HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::Body;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::error::Error as StdError;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic::Status;
+use tower_service::Service;
+use tracing::*;
+
+use super::ratelimit::RemoteAddr;
+use super::settings;
+use super::shutdown::Shutdown;
+use super::tls::TlsCerts;
+
+const GRPC: &str = "application/grpc";
+const GRPC_WEB: &str = "application/grpc-web";
+const GRPC_WEB_TEXT: &str = "application/grpc-web-text";
+/// Flag of the message holding the trailers.
+const TRAILERS_FLAG: u8 = 0x80;
+/// Headers browsers may send, unless the preflight request lists others.
+const ALLOWED_HEADERS: &str =
+    "content-type, x-grpc-web, x-user-agent, grpc-timeout, authorization, client-id, x-request-id";
+/// Response headers readable by browser code.
+const EXPOSED_HEADERS: &str =
+    "grpc-status, grpc-message, grpc-status-details-bin, x-request-id, retry-after";
+const PREFLIGHT_MAX_AGE_SECS: u64 = 24 * 60 * 60;
+
+/// Encoding of the messages of a call.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Encoding {
+    Binary,
+    Text,
+}
+
+impl Encoding {
+    fn from_content_type(headers: &HeaderMap) -> Option<Self> {
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        if content_type.starts_with(GRPC_WEB_TEXT) {
+            Some(Encoding::Text)
+        } else if content_type.starts_with(GRPC_WEB) {
+            Some(Encoding::Binary)
+        } else {
+            None
+        }
+    }
+
+    fn content_type(self) -> HeaderValue {
+        match self {
+            Encoding::Binary => HeaderValue::from_static("application/grpc-web+proto"),
+            Encoding::Text => HeaderValue::from_static("application/grpc-web-text+proto"),
+        }
+    }
+
+    fn encode(self, data: Bytes) -> Bytes {
+        match self {
+            Encoding::Binary => data,
+            Encoding::Text => Bytes::from(base64::encode(&data)),
+        }
+    }
+}
+
+/// Trailers as a gRPC-Web message.
+fn trailers_frame(trailers: &HeaderMap) -> Bytes {
+    let mut block = BytesMut::new();
+    for (name, value) in trailers.iter() {
+        block.put_slice(name.as_str().as_bytes());
+        block.put_slice(b": ");
+        block.put_slice(value.as_bytes());
+        block.put_slice(b"\r\n");
+    }
+    let mut frame = BytesMut::with_capacity(5 + block.len());
+    frame.put_u8(TRAILERS_FLAG);
+    frame.put_u32(block.len() as u32);
+    frame.put_slice(&block);
+    frame.freeze()
+}
+
+/// Decodes the base64 body of a text request as it arrives, a group of four
+/// characters at a time.
+fn decode_text(body: Body) -> Body {
+    let mut pending = BytesMut::new();
+    Body::wrap_stream(body.map(move |chunk| {
+        let chunk = chunk.map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)?;
+        pending.extend(chunk.iter().filter(|b| !b.is_ascii_whitespace()));
+        let complete = pending.len() - pending.len() % 4;
+        let groups = pending.split_to(complete);
+        base64::decode(&groups).map(Bytes::from).map_err(|e| {
+            let status = Status::invalid_argument(format!("Invalid base64 body: {}", e));
+            Box::new(status) as Box<dyn StdError + Send + Sync>
+        })
+    }))
+}
+
+/// Body of a gRPC-Web response: the messages of the gRPC response followed
+/// by its trailers.
+pub struct WebBody {
+    inner: BoxBody,
+    encoding: Encoding,
+    trailers_sent: bool,
+}
+
+impl HttpBody for WebBody {
+    type Data = Bytes;
+    type Error = Status;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        if self.trailers_sent {
+            return Poll::Ready(None);
+        }
+        let encoding = self.encoding;
+        match Pin::new(&mut self.inner).poll_data(cx) {
+            Poll::Ready(Some(data)) => return Poll::Ready(Some(data.map(|d| encoding.encode(d)))),
+            Poll::Ready(None) => (),
+            Poll::Pending => return Poll::Pending,
+        }
+        let trailers = match Pin::new(&mut self.inner).poll_trailers(cx) {
+            Poll::Ready(trailers) => trailers,
+            Poll::Pending => return Poll::Pending,
+        };
+        self.trailers_sent = true;
+        match trailers {
+            Ok(Some(trailers)) => Poll::Ready(Some(Ok(encoding.encode(trailers_frame(&trailers))))),
+            // Trailers-only responses hold the status in their headers.
+            Ok(None) => Poll::Ready(None),
+            Err(status) => {
+                let trailers = status.to_http().into_parts().0.headers;
+                Poll::Ready(Some(Ok(encoding.encode(trailers_frame(&trailers)))))
+            }
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+}
+
+type Handler = Arc<dyn Fn(Request<Body>) -> BoxFuture<'static, Response<BoxBody>> + Send + Sync>;
+
+/// Gateway translating gRPC-Web calls to the services it was given.
+#[derive(Clone)]
+pub struct Gateway {
+    services: HashMap<&'static str, Handler>,
+    allowed_origins: Arc<Vec<String>>,
+}
+
+impl Gateway {
+    pub fn new(conf: &settings::GrpcWeb) -> Self {
+        Self {
+            services: HashMap::new(),
+            allowed_origins: Arc::new(conf.allowed_origins.clone()),
+        }
+    }
+
+    /// Serves the calls to a gRPC service, as layered on the gRPC server.
+    pub fn add_service<S>(mut self, service: S) -> Self
+    where
+        S: Service<Request<Body>, Response = Response<BoxBody>>
+            + NamedService
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+        S::Error: Into<Box<dyn StdError + Send + Sync>> + Send,
+        S::Future: Send + 'static,
+    {
+        let handler = move |req| {
+            let mut service = service.clone();
+            Box::pin(async move {
+                let served = match poll_fn(|cx| service.poll_ready(cx)).await {
+                    Ok(()) => service.call(req).await,
+                    Err(e) => Err(e),
+                };
+                served.unwrap_or_else(|e| Status::internal(e.into().to_string()).to_http())
+            }) as BoxFuture<'static, _>
+        };
+        self.services.insert(S::NAME, Arc::new(handler));
+        self
+    }
+
+    fn is_allowed(&self, origin: &HeaderValue) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed == "*" || origin == allowed.as_str())
+    }
+
+    async fn handle(&self, remote: SocketAddr, mut req: Request<Body>) -> Response<WebBody> {
+        let origin = req.headers().get(header::ORIGIN).cloned();
+        if let Some(origin) = &origin {
+            if !self.is_allowed(origin) {
+                debug!(remote = %remote, origin = ?origin, "Rejected gRPC-Web origin");
+                return plain_response(StatusCode::FORBIDDEN);
+            }
+        }
+        let mut response = match (req.method(), Encoding::from_content_type(req.headers())) {
+            (&Method::OPTIONS, _) => preflight_response(req.headers()),
+            (&Method::POST, Some(encoding)) => {
+                // Rate limits need the client address, which tonic only
+                // sets for its own connections.
+                req.extensions_mut().insert(RemoteAddr(remote.ip()));
+                self.call(req, encoding).await
+            }
+            (&Method::POST, None) => plain_response(StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            _ => plain_response(StatusCode::METHOD_NOT_ALLOWED),
+        };
+
+        if let Some(origin) = origin {
+            let headers = response.headers_mut();
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.insert(
+                header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                HeaderValue::from_static(EXPOSED_HEADERS),
+            );
+            headers.insert(header::VARY, HeaderValue::from_static("origin"));
+        }
+        response
+    }
+
+    async fn call(&self, req: Request<Body>, encoding: Encoding) -> Response<WebBody> {
+        let (mut parts, body) = req.into_parts();
+        let body = match encoding {
+            Encoding::Binary => body,
+            Encoding::Text => {
+                parts.headers.remove(header::CONTENT_LENGTH);
+                decode_text(body)
+            }
+        };
+        parts.version = Version::HTTP_2;
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, HeaderValue::from_static(GRPC));
+        parts
+            .headers
+            .insert(header::TE, HeaderValue::from_static("trailers"));
+
+        let service = parts.uri.path().split('/').nth(1).unwrap_or_default();
+        let response = match self.services.get(service) {
+            Some(handler) => handler(Request::from_parts(parts, body)).await,
+            None => Status::unimplemented(format!("Unknown service '{}'", service)).to_http(),
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts.version = Version::HTTP_11;
+        parts
+            .headers
+            .insert(header::CONTENT_TYPE, encoding.content_type());
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let body = WebBody {
+            inner: body,
+            encoding,
+            trailers_sent: false,
+        };
+        Response::from_parts(parts, body)
+    }
+}
+
+fn plain_response(status: StatusCode) -> Response<WebBody> {
+    let mut response = Response::new(WebBody {
+        inner: BoxBody::empty(),
+        encoding: Encoding::Binary,
+        trailers_sent: true,
+    });
+    *response.status_mut() = status;
+    response
+}
+
+fn preflight_response(headers: &HeaderMap) -> Response<WebBody> {
+    let allowed_headers = headers
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .cloned()
+        .unwrap_or_else(|| HeaderValue::from_static(ALLOWED_HEADERS));
+    let mut response = plain_response(StatusCode::NO_CONTENT);
+    let headers = response.headers_mut();
+    headers.insert(
+        header::ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("POST, OPTIONS"),
+    );
+    headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed_headers);
+    headers.insert(
+        header::ACCESS_CONTROL_MAX_AGE,
+        HeaderValue::from(PREFLIGHT_MAX_AGE_SECS),
+    );
+    response
+}
+
+async fn serve_connection<I>(gateway: Gateway, io: I, remote: SocketAddr, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let gateway = gateway.clone();
+        async move { Ok::<_, Infallible>(gateway.handle(remote, req).await) }
+    });
+    let conn = Http::new().http1_only(true).serve_connection(io, service);
+    tokio::pin!(conn);
+
+    let served = tokio::select! {
+        served = &mut conn => served,
+        _ = shutdown.requested() => {
+            // In-flight requests are completed before closing.
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = served {
+        debug!(remote = %remote, "gRPC-Web connection failed: {}", e);
+    }
+}
+
+/// Serves the gateway on `addr` until a shutdown is requested, over TLS when
+/// certificates are given. Fails right away if the address can not be bound.
+pub fn serve(
+    gateway: Gateway,
+    addr: SocketAddr,
+    tls: Option<Arc<TlsCerts>>,
+    shutdown: Shutdown,
+) -> io::Result<JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let acceptor =
+        tls.map(|certs| TlsAcceptor::from(Arc::new(certs.rustls_config(&[b"http/1.1"]))));
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, remote) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept gRPC-Web connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.requested() => break,
+            };
+
+            let gateway = gateway.clone();
+            let shutdown = shutdown.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(gateway, stream, remote, shutdown).await,
+                        Err(e) => debug!(remote = %remote, "TLS handshake failed: {}", e),
+                    },
+                    None => serve_connection(gateway, stream, remote, shutdown).await,
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use http::header::HeaderName;
+
+    #[test]
+    fn trailers_are_framed() {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        trailers.insert(
+            HeaderName::from_static("grpc-message"),
+            HeaderValue::from_static("ok"),
+        );
+        let frame = trailers_frame(&trailers);
+        let block = b"grpc-status: 0\r\ngrpc-message: ok\r\n";
+        assert_eq!(frame[0], TRAILERS_FLAG);
+        assert_eq!(&frame[1..5], &(block.len() as u32).to_be_bytes());
+        assert_eq!(&frame[5..], &block[..]);
+    }
+
+    #[tokio::test]
+    async fn text_bodies_are_decoded_across_chunks() {
+        let encoded = base64::encode(b"\x00\x00\x00\x00\x03abc");
+        let chunks: Vec<Result<String, io::Error>> = encoded
+            .as_bytes()
+            .chunks(3)
+            .map(|c| Ok(String::from_utf8(c.to_vec()).unwrap()))
+            .collect();
+        let body = decode_text(Body::wrap_stream(futures::stream::iter(chunks)));
+        let decoded = hyper::body::to_bytes(body).await.unwrap();
+        assert_eq!(&decoded[..], b"\x00\x00\x00\x00\x03abc");
+    }
+
+    #[test]
+    fn origins_are_checked() {
+        let gateway = Gateway::new(&settings::GrpcWeb {
+            port: 8081,
+            allowed_origins: vec![String::from("https://admin.example.com")],
+        });
+        assert!(gateway.is_allowed(&HeaderValue::from_static("https://admin.example.com")));
+        assert!(!gateway.is_allowed(&HeaderValue::from_static("https://evil.example.com")));
+
+        assert_eq!(
+            Encoding::from_content_type(&header_map("application/grpc-web-text+proto")),
+            Some(Encoding::Text)
+        );
+        assert_eq!(
+            Encoding::from_content_type(&header_map("application/grpc-web")),
+            Some(Encoding::Binary)
+        );
+        assert_eq!(Encoding::from_content_type(&header_map(GRPC)), None);
+    }
+
+    fn header_map(content_type: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+        headers
+    }
+}
@@ -0,0 +1,279 @@
+//! Serving state of the server, exposed through the standard
+//! `grpc.health.v1.Health` service. It turns unhealthy on errors leaving the
+//! store in an unknown state, which also starts an orderly shutdown rather
+//! than crashing the process. Running out of disk space only rejects writes.
+
+use futures::Stream;
+use std::pin::Pin;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use tokio::sync::{mpsc, watch};
+use tonic::{Request, Response, Status};
+use tracing::*;
+
+use dumpstors_lib::health::health_check_response::ServingStatus;
+use dumpstors_lib::health::{health_server, HealthCheckRequest, HealthCheckResponse};
+use dumpstors_lib::store::Error;
+
+use super::disk::DiskMonitor;
+use super::shutdown::Shutdown;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HealthState {
+    /// The store is loading its keyspaces.
+    Loading,
+    Serving,
+    /// A shutdown was requested and in-flight requests are draining.
+    Stopping,
+    Unhealthy(String),
+}
+
+/// Shared health state. Clones observe and update the same state.
+#[derive(Clone)]
+pub struct Health {
+    tx: Arc<watch::Sender<HealthState>>,
+    rx: watch::Receiver<HealthState>,
+    shutdown: Shutdown,
+    disk: Option<Arc<DiskMonitor>>,
+}
+
+impl Health {
+    pub fn new(shutdown: Shutdown) -> Self {
+        let (tx, rx) = watch::channel(HealthState::Loading);
+        Self {
+            tx: Arc::new(tx),
+            rx,
+            shutdown,
+            disk: None,
+        }
+    }
+
+    /// Rejects writes through `disk` once the store runs out of space.
+    pub fn with_disk(mut self, disk: Arc<DiskMonitor>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    pub fn state(&self) -> HealthState {
+        self.rx.borrow().clone()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !matches!(*self.rx.borrow(), HealthState::Unhealthy(_))
+    }
+
+    /// Returns a receiver notified of every state change.
+    pub fn subscribe(&self) -> watch::Receiver<HealthState> {
+        self.rx.clone()
+    }
+
+    /// Moves to a new state, unless the server already failed.
+    fn set(&self, state: HealthState) {
+        if self.is_healthy() {
+            let _ = self.tx.send(state);
+        }
+    }
+
+    pub fn set_serving(&self) {
+        self.set(HealthState::Serving)
+    }
+
+    pub fn set_stopping(&self) {
+        self.set(HealthState::Stopping)
+    }
+
+    /// Marks the server unhealthy and starts shutting it down. Only the
+    /// first failure is kept.
+    pub fn fail(&self, reason: impl Into<String>) {
+        if !self.is_healthy() {
+            return;
+        }
+        let reason = reason.into();
+        error!("Server is unhealthy, shutting down: {}", reason);
+        let _ = self.tx.send(HealthState::Unhealthy(reason));
+        self.shutdown.trigger();
+    }
+
+    /// Logs store errors hidden from clients and fails the server when they
+    /// are unrecoverable.
+    pub fn observe(&self, err: &Error) {
+        if err.is_storage_failure() {
+            error!(error = ?err, "Store error");
+        }
+        if let (true, Some(disk)) = (err.is_storage_full(), &self.disk) {
+            disk.mark_full();
+        }
+        if err.is_fatal() {
+            self.fail(format!("Unrecoverable store error: {:?}", err));
+        }
+    }
+
+    /// Rejects requests once the server is unhealthy.
+    #[allow(clippy::result_large_err)]
+    pub fn check(&self) -> StdResult<(), Status> {
+        match self.state() {
+            HealthState::Unhealthy(reason) => Err(Status::unavailable(format!(
+                "Server is unhealthy: {}",
+                reason
+            ))),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `grpc.health.v1.Health` service. The empty service name stands for the
+/// whole server.
+pub struct HealthService {
+    health: Health,
+    services: Vec<String>,
+}
+
+impl HealthService {
+    pub fn new(health: Health, services: Vec<String>) -> Self {
+        Self { health, services }
+    }
+
+    fn status(&self, service: &str, state: &HealthState) -> Option<ServingStatus> {
+        if !service.is_empty() && !self.services.iter().any(|s| s == service) {
+            return None;
+        }
+        Some(match state {
+            HealthState::Serving => ServingStatus::Serving,
+            _ => ServingStatus::NotServing,
+        })
+    }
+}
+
+fn response(status: ServingStatus) -> HealthCheckResponse {
+    HealthCheckResponse {
+        status: status as i32,
+    }
+}
+
+#[tonic::async_trait]
+impl health_server::Health for HealthService {
+    async fn check(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> StdResult<Response<HealthCheckResponse>, Status> {
+        let service = request.into_inner().service;
+        match self.status(&service, &self.health.state()) {
+            Some(status) => Ok(Response::new(response(status))),
+            None => Err(Status::not_found(format!("Unknown service '{}'", service))),
+        }
+    }
+
+    type WatchStream =
+        Pin<Box<dyn Stream<Item = StdResult<HealthCheckResponse, Status>> + Send + Sync + 'static>>;
+
+    async fn watch(
+        &self,
+        request: Request<HealthCheckRequest>,
+    ) -> StdResult<Response<Self::WatchStream>, Status> {
+        let service = request.into_inner().service;
+        let mut rx = self.health.subscribe();
+        let this = Self::new(self.health.clone(), self.services.clone());
+
+        let (tx, out) = mpsc::channel(4);
+        tokio::spawn(async move {
+            let mut last = None;
+            loop {
+                let state = rx.borrow().clone();
+                let status = this
+                    .status(&service, &state)
+                    .unwrap_or(ServingStatus::ServiceUnknown);
+                if last != Some(status) {
+                    if tx.send(Ok(response(status))).await.is_err() {
+                        break;
+                    }
+                    last = Some(status);
+                }
+                // The stream ends with the server so that it does not hold up
+                // the shutdown.
+                let ending = matches!(state, HealthState::Stopping | HealthState::Unhealthy(_));
+                if ending || rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(out),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::disk::Level;
+    use super::*;
+    use std::io::{Error as IoError, ErrorKind};
+    use tonic::Code;
+
+    #[test]
+    fn fatal_errors_fail_the_server() {
+        let shutdown = Shutdown::new();
+        let health = Health::new(shutdown.clone());
+
+        health.observe(&Error::KeyspaceNotFound(String::from("ks")));
+        assert!(health.is_healthy());
+        assert!(health.check().is_ok());
+        assert!(!shutdown.is_triggered());
+
+        health.observe(&Error::IoErr(IoError::other("disk")));
+        assert!(health.is_healthy());
+
+        health.observe(&Error::SledErr(sled::Error::ReportableBug(String::from(
+            "bug",
+        ))));
+        health.fail("ignored");
+        match health.state() {
+            HealthState::Unhealthy(reason) => assert!(reason.contains("bug")),
+            _ => panic!("A fatal error must make the server unhealthy"),
+        };
+        match health.check() {
+            Err(status) if status.code() == Code::Unavailable => (),
+            _ => panic!("An unhealthy server must reject requests"),
+        };
+        assert!(shutdown.is_triggered());
+    }
+
+    #[test]
+    fn full_disk_rejects_writes() {
+        let shutdown = Shutdown::new();
+        let disk = Arc::new(DiskMonitor::new(String::from("."), Default::default()));
+        let health = Health::new(shutdown.clone()).with_disk(disk.clone());
+
+        health.observe(&Error::IoErr(IoError::from(ErrorKind::StorageFull)));
+        assert!(health.is_healthy());
+        assert!(!shutdown.is_triggered());
+        assert_eq!(disk.level(), Level::High);
+    }
+
+    #[tokio::test]
+    async fn health_service_status() {
+        use dumpstors_lib::health::health_server::Health as _;
+        use tonic::IntoRequest;
+
+        let health = Health::new(Shutdown::new());
+        let srv = HealthService::new(health.clone(), vec![String::from("dumpstors.store.Store")]);
+        let check = |service: &str| {
+            HealthCheckRequest {
+                service: service.to_string(),
+            }
+            .into_request()
+        };
+
+        let resp = srv.check(check("")).await.unwrap().into_inner();
+        assert_eq!(resp.status, ServingStatus::NotServing as i32);
+
+        health.set_serving();
+        let resp = srv.check(check("dumpstors.store.Store")).await.unwrap();
+        assert_eq!(resp.into_inner().status, ServingStatus::Serving as i32);
+
+        match srv.check(check("unknown")).await {
+            Err(status) if status.code() == Code::NotFound => (),
+            _ => panic!("Unknown services must not be found"),
+        };
+    }
+}
@@ -2,29 +2,399 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
-use log::*;
-use std::sync::{Arc, Mutex};
-use tonic::transport::Server;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::sleep;
+use tonic::transport::{NamedService, Server};
+use tower_layer::Layer;
+use tracing::*;
 
+use dumpstors_lib::admin::admin_server::AdminServer;
+use dumpstors_lib::health::health_server::HealthServer;
+use dumpstors_lib::raft::cluster_server::ClusterServer;
+use dumpstors_lib::raft::raft_server::RaftServer;
+use dumpstors_lib::reflection::server_reflection_server::ServerReflectionServer;
+use dumpstors_lib::store::recovery::LoadOutcome;
 use dumpstors_lib::store::store_server::StoreServer;
 use dumpstors_lib::store::Store;
 
+pub mod admin;
+pub mod auth;
+pub mod cluster;
+mod completion;
+pub mod disk;
+pub mod grpc_web;
+pub mod health;
+pub mod limits;
+mod listen;
+pub mod memcached;
+pub mod metrics;
+pub mod ratelimit;
+pub mod reflection;
+pub mod resp;
+pub mod rest;
 pub mod settings;
+pub mod shard;
+pub mod shutdown;
 mod store;
+pub mod telemetry;
+pub mod tls;
 
+use shutdown::Shutdown;
+
+/// Runs the server until it receives SIGINT or SIGTERM.
 pub async fn start_server(conf: settings::Settings) -> Result<(), Box<dyn std::error::Error>> {
+    start_server_with_shutdown(conf, Shutdown::new().on_signals()).await
+}
+
+/// Runs the server until a shutdown is requested through the handle.
+pub async fn start_server_with_shutdown(
+    conf: settings::Settings,
+    shutdown: Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let result = serve(conf, &shutdown).await;
+    shutdown.complete();
+    result
+}
+
+/// Loads the store while answering health checks with NOT_SERVING, so that
+/// probes can tell a starting server from a dead one.
+async fn load_store(
+    server: &Server,
+    sockaddr: SocketAddr,
+    conf: settings::Store,
+    health: &health::Health,
+) -> Result<Store, Box<dyn std::error::Error>> {
+    let probe = server
+        .clone()
+        .add_service(HealthServer::new(health::HealthService::new(
+            health.clone(),
+            vec![],
+        )))
+        .serve(sockaddr);
+    let probe = tokio::spawn(probe);
+
+    info!("Loading store at '{}'", conf.path);
+    let strict = conf.strict_recovery;
+    let store = tokio::task::spawn_blocking(move || Store::open(conf.path, !strict)).await?;
+
+    // Dropping the probe server releases the address for the full server.
+    probe.abort();
+    if let Ok(Err(e)) = probe.await {
+        return Err(e.into());
+    }
+
+    let store = store?;
+    let report = store.recovery();
+    for ks in report.keyspaces.iter() {
+        match &ks.outcome {
+            LoadOutcome::Loaded => info!(keyspace = %ks.name, "Keyspace loaded"),
+            LoadOutcome::Quarantined { path, reason } => warn!(
+                keyspace = %ks.name,
+                path = %path,
+                reason = %reason,
+                "Keyspace failed to load and was quarantined"
+            ),
+            LoadOutcome::Failed { reason } => {
+                error!(keyspace = %ks.name, reason = %reason, "Keyspace failed to load")
+            }
+        }
+    }
+    if strict && !report.is_clean() {
+        return Err(
+            "Keyspaces failed to load, refusing to start with store.strict_recovery".into(),
+        );
+    }
+    Ok(store)
+}
+
+async fn serve(
+    conf: settings::Settings,
+    shutdown: &Shutdown,
+) -> Result<(), Box<dyn std::error::Error>> {
     let sockaddr = format!("{}:{}", conf.listen_addr, conf.port).parse()?;
+    conf.validate()?;
+
+    let disk = Arc::new(disk::DiskMonitor::new(
+        conf.store.path.clone(),
+        conf.store.watermarks.clone(),
+    ));
+    let health = health::Health::new(shutdown.clone()).with_disk(disk.clone());
+
+    let mut server = Server::builder();
+    let mut peer_tls = None;
+    let mut certs = None;
+    if let Some(tls_conf) = conf.tls {
+        info!("Enabling TLS with certificate '{}'", tls_conf.cert_path);
+        let loaded = tls::TlsCerts::load(tls_conf)?;
+        server = server.tls_config(loaded.server_config())?;
+        peer_tls = Some(loaded.client_config()?);
+        loaded.watch(shutdown.clone());
+        certs = Some(loaded);
+    }
+
+    let read_only = conf.store.read_only;
+    let store = Arc::new(load_store(&server, sockaddr, conf.store, &health).await?);
+    if read_only {
+        info!("Starting in read-only mode");
+        store.set_read_only(true);
+    }
+
+    let mut metrics_srv = None;
+    if let Err(e) = disk.check() {
+        error!("Failed to measure disk usage: {}", e);
+    }
+    disk.watch(shutdown.clone());
+
+    let metrics = metrics::Metrics::new()
+        .with_store(store.clone())
+        .with_disk(disk.clone());
+    let metrics = Arc::new(metrics);
+    if let Some(metrics_conf) = conf.metrics {
+        let addr = SocketAddr::new(sockaddr.ip(), metrics_conf.port);
+        info!("Serving metrics on '{}'", addr);
+        metrics_srv = Some(metrics::serve(metrics.clone(), addr, shutdown.clone())?);
+    }
 
-    info!("Loading store at '{}'", conf.store.path);
-    let store = Arc::new(Mutex::new(Store::new(conf.store.path)));
-    let store_srv = store::DumpstorsStoreServer::new(store);
+    let auth = conf.auth.map(|auth_conf| {
+        info!("Enabling authentication");
+        let peer_token = auth_conf.peer_token.clone();
+        (Arc::new(auth::Authenticator::new(auth_conf)), peer_token)
+    });
+
+    let limits = dumpstors_lib::store::Limits::from(&conf.limits);
+    let mut store_srv = store::DumpstorsStoreServer::new(store.clone())
+        .with_health(health.clone())
+        .with_disk(disk.clone())
+        .with_limits(limits.clone());
+
+    let mut raft_node = None;
+    let (raft_srv, cluster_srv) = match conf.cluster {
+        Some(cluster_conf) => {
+            info!(
+                "Starting raft node {} with log at '{}'",
+                cluster_conf.node_id, cluster_conf.path
+            );
+            let peer_token = auth.as_ref().and_then(|(_, token)| token.clone());
+            let node = cluster::RaftNode::new(cluster_conf, store.clone())
+                .map_err(|e| format!("Failed to start raft node: {:?}", e))?
+                .with_tls(peer_tls.clone())
+                .with_token(peer_token)
+                .with_health(health.clone());
+            let node = Arc::new(node);
+            node.start(shutdown.clone());
+            raft_node = Some(node.clone());
+
+            store_srv = store_srv.with_raft(node.clone());
+            let cluster_svc = cluster::ClusterService::new(node.clone());
+            let raft_svc = cluster::RaftService::new(node);
+            // Members replace each other's store, so only the peer token is
+            // accepted by the Raft service.
+            match &auth {
+                Some((auth, _)) => (
+                    Some(RaftServer::with_interceptor(
+                        raft_svc,
+                        auth.peer_interceptor(),
+                    )),
+                    Some(ClusterServer::with_interceptor(
+                        cluster_svc,
+                        auth.admin_interceptor(),
+                    )),
+                ),
+                None => (
+                    Some(RaftServer::new(raft_svc)),
+                    Some(ClusterServer::new(cluster_svc)),
+                ),
+            }
+        }
+        None => (None, None),
+    };
+
+    if let Some(sharding_conf) = conf.sharding {
+        info!("Starting shard node {}", sharding_conf.node_id);
+        let peer_token = auth.as_ref().and_then(|(_, token)| token.clone());
+        let router = shard::ShardRouter::new(sharding_conf, store.clone())
+            .with_tls(peer_tls)
+            .with_token(peer_token)
+            .with_health(health.clone());
+        let router = Arc::new(router);
+
+        let discovery = router.clone();
+        tokio::spawn(async move { discovery.discover().await });
+        store_srv = store_srv.with_shards(router);
+    }
 
     info!("Starting server on '{}'", sockaddr);
 
-    Server::builder()
-        .add_service(StoreServer::new(store_srv))
-        .serve(sockaddr)
-        .await?;
+    let limiter = Arc::new(ratelimit::RateLimiter::new(conf.rate_limits));
+    let admin_svc = admin::AdminService::new(store.clone(), limiter.clone());
+    let admin_srv = match &auth {
+        Some((auth, _)) => AdminServer::with_interceptor(admin_svc, auth.admin_interceptor()),
+        None => AdminServer::new(admin_svc),
+    };
+
+    let interceptor = match &auth {
+        Some((auth, _)) => {
+            store_srv = store_srv.with_auth(auth.clone());
+            Some(auth.interceptor())
+        }
+        None => None,
+    };
+
+    let mut rest_srv = None;
+    if let Some(rest_conf) = conf.rest {
+        let addr = SocketAddr::new(sockaddr.ip(), rest_conf.port);
+        info!("Serving REST gateway on '{}'", addr);
+        let mut gateway = rest::Gateway::new(store_srv.clone(), limits.clone(), limiter.clone());
+        if let Some((auth, _)) = &auth {
+            gateway = gateway.with_auth(auth.clone());
+        }
+        rest_srv = Some(rest::serve(gateway, addr, certs.clone(), shutdown.clone())?);
+    }
+
+    let mut resp_srv = None;
+    if let Some(resp_conf) = conf.resp {
+        let addr = SocketAddr::new(sockaddr.ip(), resp_conf.port);
+        info!("Serving Redis protocol on '{}'", addr);
+        let mut listener = resp::Listener::new(
+            store_srv.clone(),
+            &resp_conf,
+            limits.clone(),
+            limiter.clone(),
+        );
+        if let Some((auth, _)) = &auth {
+            listener = listener.with_auth(auth.clone());
+        }
+        resp_srv = Some(resp::serve(
+            listener,
+            addr,
+            certs.clone(),
+            shutdown.clone(),
+        )?);
+    }
+
+    let mut memcached_srv = None;
+    if let Some(memcached_conf) = conf.memcached {
+        let name = memcached_conf.keyspace.clone();
+        match store.create_keyspace(dumpstors_lib::models::Keyspace { name: name.clone() }) {
+            Ok(()) => info!(keyspace = %name, "Created keyspace for memcached"),
+            Err(dumpstors_lib::store::Error::KeyspaceAlreadyExists(_)) => (),
+            Err(e) => return Err(e.into()),
+        }
+        let addr = SocketAddr::new(sockaddr.ip(), memcached_conf.port);
+        info!("Serving memcached protocol on '{}'", addr);
+        let listener = memcached::Listener::new(
+            store.clone(),
+            &memcached_conf,
+            limits.clone(),
+            limiter.clone(),
+        )
+        .with_health(health.clone())
+        .with_disk(disk.clone());
+        memcached_srv = Some(memcached::serve(
+            listener,
+            addr,
+            certs.clone(),
+            shutdown.clone(),
+        )?);
+    }
+
+    let store_srv = match interceptor {
+        Some(interceptor) => StoreServer::with_interceptor(store_srv, interceptor),
+        None => StoreServer::new(store_srv),
+    };
+    // Rejected requests are still counted and traced.
+    let store_srv = ratelimit::RateLimitLayer::new(limiter).layer(store_srv);
+    // Oversized messages are rejected before being buffered for rate limits.
+    let store_srv = limits::MessageSizeLayer::new(limits).layer(store_srv);
+    let store_srv = metrics::MetricsLayer::new(metrics).layer(store_srv);
+    let store_srv = telemetry::TraceLayer.layer(store_srv);
+
+    let mut services = vec![
+        StoreServer::<store::DumpstorsStoreServer>::NAME,
+        AdminServer::<admin::AdminService>::NAME,
+        HealthServer::<health::HealthService>::NAME,
+        ServerReflectionServer::<reflection::ReflectionService>::NAME,
+    ];
+    if raft_srv.is_some() {
+        services.push(RaftServer::<cluster::RaftService>::NAME);
+        services.push(ClusterServer::<cluster::ClusterService>::NAME);
+    }
+    let services: Vec<String> = services.into_iter().map(String::from).collect();
+    // Health checks and reflection are left unauthenticated for probes and tools.
+    let health_srv =
+        HealthServer::new(health::HealthService::new(health.clone(), services.clone()));
+    let reflection_srv = ServerReflectionServer::new(reflection::ReflectionService::new(services)?);
+
+    let mut grpc_web_srv = None;
+    if let Some(grpc_web_conf) = conf.grpc_web {
+        let addr = SocketAddr::new(sockaddr.ip(), grpc_web_conf.port);
+        info!("Serving gRPC-Web on '{}'", addr);
+        let gateway = grpc_web::Gateway::new(&grpc_web_conf)
+            .add_service(store_srv.clone())
+            .add_service(admin_srv.clone())
+            .add_service(health_srv.clone());
+        grpc_web_srv = Some(grpc_web::serve(gateway, addr, certs, shutdown.clone())?);
+    }
+
+    let (incoming, sockets) = listen::bind(sockaddr, &conf.listeners)?;
+    let signal = shutdown.clone();
+    let stopping = health.clone();
+    let serving = server
+        .add_service(store_srv)
+        .add_service(admin_srv)
+        .add_service(health_srv)
+        .add_service(reflection_srv)
+        .add_optional_service(raft_srv)
+        .add_optional_service(cluster_srv)
+        .serve_with_incoming_shutdown(incoming, async move {
+            signal.requested().await;
+            stopping.set_stopping();
+        });
+    health.set_serving();
+    let deadline = Duration::from_secs(conf.shutdown_timeout_secs);
+
+    tokio::select! {
+        served = serving => served?,
+        _ = async {
+            shutdown.requested().await;
+            info!("Draining in-flight requests for up to {:?}", deadline);
+            sleep(deadline).await
+        } => warn!("In-flight requests did not complete in time, shutting down anyway"),
+    }
 
-    Ok(())
+    drop(sockets);
+    if let Some(metrics_srv) = metrics_srv {
+        let _ = metrics_srv.await;
+    }
+    if let Some(rest_srv) = rest_srv {
+        let _ = rest_srv.await;
+    }
+    if let Some(grpc_web_srv) = grpc_web_srv {
+        let _ = grpc_web_srv.await;
+    }
+    if let Some(resp_srv) = resp_srv {
+        let _ = resp_srv.await;
+    }
+    if let Some(memcached_srv) = memcached_srv {
+        let _ = memcached_srv.await;
+    }
+    if let Some(node) = raft_node {
+        node.flush()
+            .map_err(|e| format!("Failed to flush raft log: {:?}", e))?;
+    }
+    info!("Flushing store");
+    store
+        .flush()
+        .map_err(|e| format!("Failed to flush store: {:?}", e))?;
+    match health.state() {
+        health::HealthState::Unhealthy(reason) => {
+            Err(format!("Server stopped after an unrecoverable error: {}", reason).into())
+        }
+        _ => {
+            info!("Server stopped");
+            Ok(())
+        }
+    }
 }
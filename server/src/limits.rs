@@ -0,0 +1,169 @@
+//! Limits of store requests. Message sizes are checked against the length
+//! prefixing each gRPC message, before the message is read, while the other
+//! limits are checked by the store service on decoded requests.
+
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use http::{Request, Response};
+use hyper::Body;
+use std::error::Error as StdError;
+use std::task::{Context, Poll};
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic::Status;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use dumpstors_lib::store::Limits;
+
+use super::settings;
+
+/// Compression flag and length prefixing each gRPC message.
+const HEADER_SIZE: usize = 5;
+
+impl From<&settings::Limits> for Limits {
+    fn from(limits: &settings::Limits) -> Self {
+        Limits {
+            max_message_size: limits.max_message_size,
+            max_batch_records: limits.max_batch_records,
+            max_key_size: limits.max_key_size,
+            max_value_size: limits.max_value_size,
+            max_staged_bytes: limits.max_staged_bytes,
+        }
+    }
+}
+
+/// Follows the messages of a request body as its chunks go by.
+#[derive(Default)]
+struct Frames {
+    header: Vec<u8>,
+    /// Bytes of the current message not seen yet.
+    remaining: usize,
+}
+
+impl Frames {
+    /// Returns the length of the first message of the chunk found over `max`.
+    fn observe(&mut self, mut chunk: &[u8], max: u64) -> Result<(), usize> {
+        while !chunk.is_empty() {
+            if self.remaining > 0 {
+                let skipped = self.remaining.min(chunk.len());
+                self.remaining -= skipped;
+                chunk = &chunk[skipped..];
+                continue;
+            }
+
+            let taken = (HEADER_SIZE - self.header.len()).min(chunk.len());
+            self.header.extend_from_slice(&chunk[..taken]);
+            chunk = &chunk[taken..];
+            if self.header.len() == HEADER_SIZE {
+                let mut len = [0; 4];
+                len.copy_from_slice(&self.header[1..]);
+                let len = u32::from_be_bytes(len) as usize;
+                if max > 0 && len as u64 > max {
+                    return Err(len);
+                }
+                self.remaining = len;
+                self.header.clear();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tower layer failing requests holding a message over `max_message_size`,
+/// before it is buffered.
+#[derive(Clone)]
+pub struct MessageSizeLayer {
+    limits: Limits,
+}
+
+impl MessageSizeLayer {
+    pub fn new(limits: Limits) -> Self {
+        Self { limits }
+    }
+}
+
+impl<S> Layer<S> for MessageSizeLayer {
+    type Service = MessageSizeService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MessageSizeService {
+            inner,
+            limits: self.limits.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MessageSizeService<S> {
+    inner: S,
+    limits: Limits,
+}
+
+impl<S: NamedService> NamedService for MessageSizeService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<Body>> for MessageSizeService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        if self.limits.max_message_size == 0 {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let limits = self.limits.clone();
+        let mut frames = Frames::default();
+        // tonic reports the status failing the body as the status of the call.
+        let req = req.map(|body| {
+            Body::wrap_stream(body.map(move |chunk| {
+                let chunk = chunk.map_err(|e| Box::new(e) as Box<dyn StdError + Send + Sync>)?;
+                if let Err(size) = frames.observe(&chunk, limits.max_message_size) {
+                    let status = Status::from(limits.check_message_size(size).unwrap_err());
+                    return Err(Box::new(status) as Box<dyn StdError + Send + Sync>);
+                }
+                Ok(chunk)
+            }))
+        });
+        Box::pin(self.inner.call(req))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(len: u32) -> Vec<u8> {
+        let mut message = vec![0];
+        message.extend_from_slice(&len.to_be_bytes());
+        message.resize(HEADER_SIZE + len as usize, 1);
+        message
+    }
+
+    #[test]
+    fn messages_are_followed_across_chunks() {
+        let body = [message(3), message(10), message(4)].concat();
+
+        let mut frames = Frames::default();
+        for chunk in body.chunks(2) {
+            frames.observe(chunk, 10).unwrap();
+        }
+        assert_eq!(frames.remaining, 0);
+        assert!(frames.header.is_empty());
+
+        let mut frames = Frames::default();
+        let chunks: Vec<&[u8]> = body.chunks(7).collect();
+        frames.observe(chunks[0], 5).unwrap();
+        assert_eq!(frames.observe(chunks[1], 5), Err(10));
+    }
+}
@@ -0,0 +1,224 @@
+//! Endpoints of the gRPC server: `listen_addr` and `port`, along with the TCP
+//! addresses and Unix domain sockets of `listeners`, all accepted by the same
+//! server.
+
+use futures::stream::{self, BoxStream, StreamExt};
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::transport::server::Connected;
+use tracing::*;
+
+use super::settings;
+
+/// Connection accepted on any endpoint.
+pub enum Conn {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixStream),
+}
+
+/// Unix domain socket clients have no address, so rate limits only tell them
+/// apart by their `client-id` header.
+impl Connected for Conn {
+    fn remote_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Conn::Tcp(stream) => stream.peer_addr().ok(),
+            #[cfg(unix)]
+            Conn::Unix(_) => None,
+        }
+    }
+}
+
+impl AsyncRead for Conn {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Conn {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Pin::new(stream).poll_write_vectored(cx, bufs),
+        }
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        match self {
+            Conn::Tcp(stream) => stream.is_write_vectored(),
+            #[cfg(unix)]
+            Conn::Unix(stream) => stream.is_write_vectored(),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Conn::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            Conn::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Socket files of the server, removed when dropped.
+#[derive(Default)]
+pub struct Sockets(Vec<std::path::PathBuf>);
+
+impl Drop for Sockets {
+    fn drop(&mut self) {
+        for path in self.0.iter() {
+            if let Err(e) = std::fs::remove_file(path) {
+                warn!("Failed to remove socket '{}': {}", path.display(), e);
+            }
+        }
+    }
+}
+
+fn bind_tcp(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    TcpListener::from_std(listener)
+}
+
+/// Binds a Unix domain socket with the given permissions, replacing a
+/// socket left by a server that is no longer running.
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: u32) -> io::Result<tokio::net::UnixListener> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        let in_use = || std::os::unix::net::UnixStream::connect(path).is_ok();
+        if !metadata.file_type().is_socket() {
+            let msg = format!("'{}' exists and is not a socket", path);
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists, msg));
+        } else if in_use() {
+            let msg = format!("'{}' is used by a running server", path);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+        }
+        std::fs::remove_file(path)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    Ok(listener)
+}
+
+/// Binds every endpoint, failing right away if one can not be bound, and
+/// returns their connections along with the socket files to remove once the
+/// server stopped. Failures to accept a connection are logged and skipped.
+pub fn bind(
+    addr: SocketAddr,
+    listeners: &[settings::Listener],
+) -> io::Result<(BoxStream<'static, io::Result<Conn>>, Sockets)> {
+    let mut streams = vec![TcpListenerStream::new(bind_tcp(addr)?)
+        .map(|accepted| accepted.map(Conn::Tcp))
+        .boxed()];
+    let mut sockets = Sockets::default();
+
+    for listener in listeners {
+        match (&listener.addr, &listener.path) {
+            (Some(addr), _) => {
+                let addr = addr
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                info!("Listening on '{}'", addr);
+                let stream = TcpListenerStream::new(bind_tcp(addr)?);
+                streams.push(stream.map(|accepted| accepted.map(Conn::Tcp)).boxed());
+            }
+            #[cfg(unix)]
+            (None, Some(path)) => {
+                let mode = listener.socket_mode().unwrap_or(0o660);
+                let socket = bind_unix(path, mode)?;
+                sockets.0.push(std::path::PathBuf::from(path));
+                info!("Listening on Unix domain socket '{}'", path);
+                let stream = tokio_stream::wrappers::UnixListenerStream::new(socket);
+                streams.push(stream.map(|accepted| accepted.map(Conn::Unix)).boxed());
+            }
+            _ => {
+                let msg = "Listeners must set either addr or path";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        }
+    }
+
+    let incoming = stream::select_all(streams).filter_map(|accepted| async move {
+        match accepted {
+            Ok(conn) => Some(Ok(conn)),
+            Err(e) => {
+                warn!("Failed to accept connection: {}", e);
+                None
+            }
+        }
+    });
+    Ok((incoming.boxed(), sockets))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[tokio::test]
+    async fn stale_sockets_are_replaced() {
+        let dir = std::env::temp_dir().join(format!("dumpstors-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.sock");
+        let path = path.to_str().unwrap();
+
+        let listener = bind_unix(path, 0o600).unwrap();
+        let mode = std::fs::metadata(path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert_eq!(
+            bind_unix(path, 0o600).unwrap_err().kind(),
+            io::ErrorKind::AddrInUse
+        );
+
+        drop(listener);
+        bind_unix(path, 0o660).unwrap();
+
+        let file = dir.join("file");
+        std::fs::write(&file, b"").unwrap();
+        assert_eq!(
+            bind_unix(file.to_str().unwrap(), 0o660).unwrap_err().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
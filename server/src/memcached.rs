@@ -0,0 +1,900 @@
+//! Memcached text protocol listener, giving memcached clients durable storage
+//! in one keyspace.
+//!
+//! Items are stored with a header holding their flags, expiry and CAS unique
+//! before their data, so the keyspace is best left to memcached clients.
+//! Expired items are removed as they are read. Writes go to the local
+//! keyspace through the same read-only, disk, limit and quota checks as the
+//! store service, and failures are answered with the `ErrorInfo` reason of
+//! their status, such as `SERVER_ERROR READ_ONLY Server is read-only`.
+
+use bytes::{Buf, BufMut, BytesMut};
+use std::convert::TryInto;
+use std::io;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tonic::{Code, Status};
+use tracing::*;
+
+use dumpstors_lib::models;
+use dumpstors_lib::rpc;
+use dumpstors_lib::store::keyspace::Keyspace;
+use dumpstors_lib::store::{Error, Limits, Result, Store};
+
+use super::disk::DiskMonitor;
+use super::health::Health;
+use super::ratelimit::{Client, RateLimiter};
+use super::settings;
+use super::shutdown::Shutdown;
+use super::store::blocking;
+use super::tls::TlsCerts;
+
+/// Marks the values written by the listener, followed by the version of their
+/// header.
+const ITEM_MAGIC: &[u8] = b"\xffmc\x01";
+/// Magic, flags, expiry and CAS unique of an item.
+const ITEM_HEADER: usize = ITEM_MAGIC.len() + 20;
+const MAX_KEY: usize = 250;
+const MAX_LINE: usize = 2048;
+/// Largest item when values are unlimited.
+const MAX_DATA: u64 = 512 * 1024 * 1024;
+/// Longest relative expiry, larger ones are Unix times.
+const MAX_RELATIVE_EXPTIME: i64 = 60 * 60 * 24 * 30;
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Expiry of an item as a Unix time, 0 for none. Negative times expire items
+/// right away.
+fn expiry(exptime: i64, now: u64) -> u64 {
+    match exptime {
+        0 => 0,
+        t if t < 0 => 1,
+        t if t <= MAX_RELATIVE_EXPTIME => now + t as u64,
+        t => t as u64,
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Item {
+    flags: u32,
+    /// Unix time, 0 for none.
+    expires: u64,
+    cas: u64,
+    data: Vec<u8>,
+}
+
+impl Item {
+    fn encode(&self) -> Vec<u8> {
+        let mut value = Vec::with_capacity(ITEM_HEADER + self.data.len());
+        value.extend_from_slice(ITEM_MAGIC);
+        value.extend_from_slice(&self.flags.to_be_bytes());
+        value.extend_from_slice(&self.expires.to_be_bytes());
+        value.extend_from_slice(&self.cas.to_be_bytes());
+        value.extend_from_slice(&self.data);
+        value
+    }
+
+    /// Values without the header, written through other APIs, are read as
+    /// items without flags nor expiry.
+    fn decode(value: &[u8]) -> Self {
+        if value.len() < ITEM_HEADER || !value.starts_with(ITEM_MAGIC) {
+            return Item {
+                flags: 0,
+                expires: 0,
+                cas: 0,
+                data: value.to_vec(),
+            };
+        }
+        let value = &value[ITEM_MAGIC.len()..];
+        let u64_at = |i: usize| u64::from_be_bytes(value[i..i + 8].try_into().expect("8 bytes"));
+        Item {
+            flags: u32::from_be_bytes(value[..4].try_into().expect("4 bytes")),
+            expires: u64_at(4),
+            cas: u64_at(12),
+            data: value[20..].to_vec(),
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        self.expires != 0 && self.expires <= now
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Mode {
+    Set,
+    Add,
+    Replace,
+    Cas(u64),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Get {
+        keys: Vec<Vec<u8>>,
+        cas: bool,
+    },
+    Store {
+        mode: Mode,
+        key: Vec<u8>,
+        flags: u32,
+        exptime: i64,
+        data: Vec<u8>,
+    },
+    Delete {
+        key: Vec<u8>,
+    },
+    Arith {
+        key: Vec<u8>,
+        delta: u64,
+        incr: bool,
+    },
+    Touch {
+        key: Vec<u8>,
+        exptime: i64,
+    },
+    Stats,
+    Version,
+    Quit,
+}
+
+impl Command {
+    /// Bytes counted against rate limits.
+    fn size(&self) -> u64 {
+        let size = match self {
+            Command::Get { keys, .. } => keys.iter().map(Vec::len).sum(),
+            Command::Store { key, data, .. } => key.len() + data.len(),
+            Command::Delete { key } | Command::Arith { key, .. } | Command::Touch { key, .. } => {
+                key.len()
+            }
+            Command::Stats | Command::Version | Command::Quit => 0,
+        };
+        size as u64
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct Request {
+    command: Command,
+    noreply: bool,
+}
+
+/// Failure to parse a command. The connection is closed after fatal ones,
+/// after which the stream can not be followed.
+#[derive(Debug, PartialEq)]
+struct ParseError {
+    reply: String,
+    fatal: bool,
+}
+
+fn unknown_command() -> ParseError {
+    ParseError {
+        reply: String::from("ERROR"),
+        fatal: false,
+    }
+}
+
+fn bad_format() -> ParseError {
+    ParseError {
+        reply: String::from("CLIENT_ERROR bad command line format"),
+        fatal: false,
+    }
+}
+
+fn number<T: FromStr>(token: &[u8]) -> std::result::Result<T, ParseError> {
+    std::str::from_utf8(token)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(bad_format)
+}
+
+fn key(token: &[u8]) -> std::result::Result<Vec<u8>, ParseError> {
+    if token.len() > MAX_KEY || token.iter().any(|b| b.is_ascii_control()) {
+        return Err(bad_format());
+    }
+    Ok(token.to_vec())
+}
+
+/// Splits the `noreply` flag from the arguments, which must then number
+/// `expected`.
+fn noreply<'a>(
+    args: &'a [&'a [u8]],
+    expected: usize,
+) -> std::result::Result<(&'a [&'a [u8]], bool), ParseError> {
+    match args.len() {
+        len if len == expected => Ok((args, false)),
+        len if len == expected + 1 && args[expected] == b"noreply" => Ok((&args[..expected], true)),
+        _ => Err(bad_format()),
+    }
+}
+
+/// Parses a command line, with the length of the data block following it.
+fn parse_line(line: &[u8]) -> std::result::Result<(Request, Option<usize>), ParseError> {
+    let tokens: Vec<&[u8]> = line
+        .split(|&b| b == b' ')
+        .filter(|t| !t.is_empty())
+        .collect();
+    let (name, args) = tokens.split_first().ok_or_else(unknown_command)?;
+    let request = |command, noreply| Request { command, noreply };
+
+    match *name {
+        b"get" | b"gets" => {
+            if args.is_empty() {
+                return Err(unknown_command());
+            }
+            let keys = args
+                .iter()
+                .map(|t| key(t))
+                .collect::<std::result::Result<_, _>>()?;
+            let cas = *name == b"gets";
+            Ok((request(Command::Get { keys, cas }, false), None))
+        }
+        b"set" | b"add" | b"replace" | b"cas" => {
+            let (args, noreply) = noreply(args, if *name == b"cas" { 5 } else { 4 })?;
+            let mode = match *name {
+                b"set" => Mode::Set,
+                b"add" => Mode::Add,
+                b"replace" => Mode::Replace,
+                _ => Mode::Cas(number(args[4])?),
+            };
+            let command = Command::Store {
+                mode,
+                key: key(args[0])?,
+                flags: number(args[1])?,
+                exptime: number(args[2])?,
+                data: vec![],
+            };
+            Ok((request(command, noreply), Some(number(args[3])?)))
+        }
+        b"delete" => {
+            let (args, noreply) = noreply(args, 1)?;
+            let command = Command::Delete { key: key(args[0])? };
+            Ok((request(command, noreply), None))
+        }
+        b"incr" | b"decr" => {
+            let (args, noreply) = noreply(args, 2)?;
+            let delta = number(args[1]).map_err(|_| ParseError {
+                reply: String::from("CLIENT_ERROR invalid numeric delta argument"),
+                fatal: false,
+            })?;
+            let command = Command::Arith {
+                key: key(args[0])?,
+                delta,
+                incr: *name == b"incr",
+            };
+            Ok((request(command, noreply), None))
+        }
+        b"touch" => {
+            let (args, noreply) = noreply(args, 2)?;
+            let command = Command::Touch {
+                key: key(args[0])?,
+                exptime: number(args[1])?,
+            };
+            Ok((request(command, noreply), None))
+        }
+        b"stats" if args.is_empty() => Ok((request(Command::Stats, false), None)),
+        b"version" if args.is_empty() => Ok((request(Command::Version, false), None)),
+        b"quit" if args.is_empty() => Ok((request(Command::Quit, false), None)),
+        _ => Err(unknown_command()),
+    }
+}
+
+/// Parses the next command of the buffer, `None` until it is complete along
+/// with its data block.
+fn parse_command(
+    buf: &mut BytesMut,
+    limits: &Limits,
+) -> std::result::Result<Option<Request>, ParseError> {
+    let end = match buf.iter().position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() > MAX_LINE => {
+            return Err(ParseError {
+                reply: String::from("CLIENT_ERROR line too long"),
+                fatal: true,
+            })
+        }
+        None => return Ok(None),
+    };
+    let line = buf[..end].strip_suffix(b"\r").unwrap_or(&buf[..end]);
+    let parsed = parse_line(line);
+
+    let (mut request, data_len) = match parsed {
+        Ok((request, Some(len))) => (request, len),
+        Ok((request, None)) => {
+            buf.advance(end + 1);
+            return Ok(Some(request));
+        }
+        Err(e) => {
+            buf.advance(end + 1);
+            return Err(e);
+        }
+    };
+    let max = match limits.max_value_size {
+        0 => MAX_DATA,
+        max => max,
+    };
+    if data_len as u64 > max {
+        return Err(ParseError {
+            reply: String::from("SERVER_ERROR object too large for cache"),
+            fatal: true,
+        });
+    }
+    let start = end + 1;
+    if buf.len() < start + data_len + 2 {
+        return Ok(None);
+    }
+    let chunk_end = &buf[start + data_len..start + data_len + 2];
+    if chunk_end != b"\r\n" {
+        buf.advance(start + data_len + 2);
+        return Err(ParseError {
+            reply: String::from("CLIENT_ERROR bad data chunk"),
+            fatal: false,
+        });
+    }
+    if let Command::Store { data, .. } = &mut request.command {
+        *data = buf[start..start + data_len].to_vec();
+    }
+    buf.advance(start + data_len + 2);
+    Ok(Some(request))
+}
+
+fn error_line(status: &Status) -> String {
+    let kind = match status.code() {
+        Code::InvalidArgument => "CLIENT_ERROR",
+        _ => "SERVER_ERROR",
+    };
+    match rpc::error_info(status) {
+        Some(info) if !info.reason.is_empty() => {
+            format!("{} {} {}", kind, info.reason, status.message())
+        }
+        _ => format!("{} {}", kind, status.message()),
+    }
+}
+
+/// Reads the item of a key along with the value holding it, removing it
+/// once expired.
+fn load(keyspace: &Keyspace, key: &[u8], now: u64) -> Result<Option<(Vec<u8>, Item)>> {
+    let value = match keyspace.get(key.to_vec()) {
+        Ok(value) => value,
+        Err(Error::KeyNotFound { .. }) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let item = Item::decode(&value);
+    if item.is_expired(now) {
+        keyspace.compare_and_swap(key.to_vec(), Some(value), None)?;
+        return Ok(None);
+    }
+    Ok(Some((value, item)))
+}
+
+enum Update {
+    /// Replaces the item, or removes it when `None`, and answers the reply.
+    Write(Option<Item>, String),
+    Keep(String),
+}
+
+/// Updates the item of a key, retrying when it was written concurrently.
+fn update<F>(keyspace: &Keyspace, key: &[u8], mut f: F) -> Result<String>
+where
+    F: FnMut(Option<&Item>) -> Update,
+{
+    let now = now();
+    loop {
+        let (value, item) = match load(keyspace, key, now)? {
+            Some((value, item)) => (Some(value), Some(item)),
+            None => (None, None),
+        };
+        match f(item.as_ref()) {
+            Update::Keep(reply) => return Ok(reply),
+            Update::Write(new, reply) => {
+                let new = new.map(|item| item.encode());
+                if keyspace.compare_and_swap(key.to_vec(), value, new)? {
+                    return Ok(reply);
+                }
+            }
+        }
+    }
+}
+
+struct Stats {
+    started: Instant,
+    current_connections: AtomicU64,
+    total_connections: AtomicU64,
+    cmd_get: AtomicU64,
+    cmd_set: AtomicU64,
+    cmd_touch: AtomicU64,
+    get_hits: AtomicU64,
+    get_misses: AtomicU64,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Stats {
+            started: Instant::now(),
+            current_connections: AtomicU64::new(0),
+            total_connections: AtomicU64::new(0),
+            cmd_get: AtomicU64::new(0),
+            cmd_set: AtomicU64::new(0),
+            cmd_touch: AtomicU64::new(0),
+            get_hits: AtomicU64::new(0),
+            get_misses: AtomicU64::new(0),
+        }
+    }
+}
+
+fn count(counter: &AtomicU64, n: u64) {
+    counter.fetch_add(n, Ordering::Relaxed);
+}
+
+/// Listener serving memcached clients from a keyspace.
+#[derive(Clone)]
+pub struct Listener {
+    store: Arc<Store>,
+    keyspace: String,
+    health: Health,
+    disk: Option<Arc<DiskMonitor>>,
+    limiter: Arc<RateLimiter>,
+    limits: Limits,
+    stats: Arc<Stats>,
+    /// Next CAS unique, starting from the time so that they are not reused
+    /// after a restart.
+    cas: Arc<AtomicU64>,
+}
+
+impl Listener {
+    pub fn new(
+        store: Arc<Store>,
+        conf: &settings::Memcached,
+        limits: Limits,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        let start = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(1);
+        Self {
+            store,
+            keyspace: conf.keyspace.clone(),
+            health: Health::new(Shutdown::new()),
+            disk: None,
+            limiter,
+            limits,
+            stats: Arc::new(Stats::new()),
+            cas: Arc::new(AtomicU64::new(start)),
+        }
+    }
+
+    /// Shares the server's health state.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Rejects writes while the disk usage is above the high watermark.
+    pub fn with_disk(mut self, disk: Arc<DiskMonitor>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Rejects writes while the server or the keyspace is read-only, and
+    /// writes taking up disk space above the high watermark unless `deletes`.
+    #[allow(clippy::result_large_err)]
+    fn check_writes(&self, deletes: bool) -> std::result::Result<(), Status> {
+        self.store.check_writable(&self.keyspace)?;
+        match &self.disk {
+            Some(disk) if !deletes => disk.check_writes(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs `f` on the keyspace on the blocking thread pool.
+    async fn on_keyspace<T, F>(&self, f: F) -> std::result::Result<T, Status>
+    where
+        F: FnOnce(&Keyspace) -> Result<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let keyspace = self.keyspace.clone();
+        blocking(&self.store, &self.health, move |store| {
+            f(&store.get_keyspace(keyspace)?)
+        })
+        .await
+    }
+
+    async fn execute(&self, remote: SocketAddr, command: Command) -> Vec<u8> {
+        let span = info_span!("memcached", keyspace = %self.keyspace);
+        let client = Client::new(None, Some(remote.ip()));
+        let served = match self
+            .limiter
+            .check(&client, Some(&self.keyspace), command.size())
+        {
+            Ok(()) => self.run(command).instrument(span.clone()).await,
+            Err(rejection) => Err(rejection.into()),
+        };
+        match served {
+            Ok(reply) => reply,
+            Err(status) => {
+                span.in_scope(|| debug!("Command failed: {}", status.message()));
+                format!("{}\r\n", error_line(&status)).into_bytes()
+            }
+        }
+    }
+
+    #[allow(clippy::result_large_err)]
+    async fn run(&self, command: Command) -> std::result::Result<Vec<u8>, Status> {
+        let line = |reply: String| Ok(format!("{}\r\n", reply).into_bytes());
+        match command {
+            Command::Get { keys, cas } => {
+                for key in keys.iter() {
+                    self.limits.check_key(key)?;
+                }
+                count(&self.stats.cmd_get, keys.len() as u64);
+                let requested = keys.len() as u64;
+                let items = self
+                    .on_keyspace(move |keyspace| {
+                        let now = now();
+                        let mut items = vec![];
+                        for key in keys {
+                            if let Some((_, item)) = load(keyspace, &key, now)? {
+                                items.push((key, item));
+                            }
+                        }
+                        Ok(items)
+                    })
+                    .await?;
+                count(&self.stats.get_hits, items.len() as u64);
+                count(&self.stats.get_misses, requested - items.len() as u64);
+
+                let mut reply = vec![];
+                for (key, item) in items {
+                    reply.extend_from_slice(b"VALUE ");
+                    reply.extend_from_slice(&key);
+                    let header = match cas {
+                        true => format!(" {} {} {}\r\n", item.flags, item.data.len(), item.cas),
+                        false => format!(" {} {}\r\n", item.flags, item.data.len()),
+                    };
+                    reply.extend_from_slice(header.as_bytes());
+                    reply.extend_from_slice(&item.data);
+                    reply.extend_from_slice(b"\r\n");
+                }
+                reply.extend_from_slice(b"END\r\n");
+                Ok(reply)
+            }
+            Command::Store {
+                mode,
+                key,
+                flags,
+                exptime,
+                data,
+            } => {
+                self.limits.check_record(&models::Record {
+                    key: key.clone(),
+                    value: data.clone(),
+                })?;
+                self.check_writes(false)?;
+                count(&self.stats.cmd_set, 1);
+                let cas = self.cas.clone();
+                let expires = expiry(exptime, now());
+                line(
+                    self.on_keyspace(move |keyspace| {
+                        update(keyspace, &key, |current| match (&mode, current) {
+                            (Mode::Add, Some(_)) | (Mode::Replace, None) => {
+                                Update::Keep(String::from("NOT_STORED"))
+                            }
+                            (Mode::Cas(_), None) => Update::Keep(String::from("NOT_FOUND")),
+                            (Mode::Cas(unique), Some(item)) if item.cas != *unique => {
+                                Update::Keep(String::from("EXISTS"))
+                            }
+                            _ => {
+                                let item = Item {
+                                    flags,
+                                    expires,
+                                    cas: cas.fetch_add(1, Ordering::Relaxed),
+                                    data: data.clone(),
+                                };
+                                Update::Write(Some(item), String::from("STORED"))
+                            }
+                        })
+                    })
+                    .await?,
+                )
+            }
+            Command::Delete { key } => {
+                self.limits.check_key(&key)?;
+                self.check_writes(true)?;
+                line(
+                    self.on_keyspace(move |keyspace| {
+                        update(keyspace, &key, |current| match current {
+                            Some(_) => Update::Write(None, String::from("DELETED")),
+                            None => Update::Keep(String::from("NOT_FOUND")),
+                        })
+                    })
+                    .await?,
+                )
+            }
+            Command::Arith { key, delta, incr } => {
+                self.limits.check_key(&key)?;
+                self.check_writes(false)?;
+                let cas = self.cas.clone();
+                line(
+                    self.on_keyspace(move |keyspace| {
+                        update(keyspace, &key, |current| {
+                            let item = match current {
+                                Some(item) => item,
+                                None => return Update::Keep(String::from("NOT_FOUND")),
+                            };
+                            let value = std::str::from_utf8(&item.data)
+                                .ok()
+                                .and_then(|s| s.parse::<u64>().ok());
+                            let value = match (value, incr) {
+                                (Some(value), true) => value.wrapping_add(delta),
+                                (Some(value), false) => value.saturating_sub(delta),
+                                (None, _) => return Update::Keep(String::from(
+                                    "CLIENT_ERROR cannot increment or decrement non-numeric value",
+                                )),
+                            };
+                            let item = Item {
+                                cas: cas.fetch_add(1, Ordering::Relaxed),
+                                data: value.to_string().into_bytes(),
+                                ..item.clone()
+                            };
+                            Update::Write(Some(item), value.to_string())
+                        })
+                    })
+                    .await?,
+                )
+            }
+            Command::Touch { key, exptime } => {
+                self.limits.check_key(&key)?;
+                self.check_writes(false)?;
+                count(&self.stats.cmd_touch, 1);
+                let expires = expiry(exptime, now());
+                line(
+                    self.on_keyspace(move |keyspace| {
+                        update(keyspace, &key, |current| match current {
+                            Some(item) => {
+                                let item = Item {
+                                    expires,
+                                    ..item.clone()
+                                };
+                                Update::Write(Some(item), String::from("TOUCHED"))
+                            }
+                            None => Update::Keep(String::from("NOT_FOUND")),
+                        })
+                    })
+                    .await?,
+                )
+            }
+            Command::Stats => {
+                // Expired items not read since are still counted.
+                let usage = self.on_keyspace(|keyspace| keyspace.usage()).await?;
+                let stats = &self.stats;
+                let stat = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+                let lines = [
+                    ("pid", std::process::id().to_string()),
+                    ("uptime", stats.started.elapsed().as_secs().to_string()),
+                    ("time", now().to_string()),
+                    ("version", env!("CARGO_PKG_VERSION").to_string()),
+                    (
+                        "curr_connections",
+                        stat(&stats.current_connections).to_string(),
+                    ),
+                    (
+                        "total_connections",
+                        stat(&stats.total_connections).to_string(),
+                    ),
+                    ("cmd_get", stat(&stats.cmd_get).to_string()),
+                    ("cmd_set", stat(&stats.cmd_set).to_string()),
+                    ("cmd_touch", stat(&stats.cmd_touch).to_string()),
+                    ("get_hits", stat(&stats.get_hits).to_string()),
+                    ("get_misses", stat(&stats.get_misses).to_string()),
+                    ("curr_items", usage.keys.to_string()),
+                    ("bytes", usage.bytes.to_string()),
+                ];
+                let mut reply = String::new();
+                for (name, value) in lines.iter() {
+                    reply.push_str(&format!("STAT {} {}\r\n", name, value));
+                }
+                reply.push_str("END\r\n");
+                Ok(reply.into_bytes())
+            }
+            Command::Version => line(format!("VERSION {}", env!("CARGO_PKG_VERSION"))),
+            Command::Quit => Ok(vec![]),
+        }
+    }
+}
+
+async fn serve_connection<I>(listener: Listener, mut io: I, remote: SocketAddr, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let stats = listener.stats.clone();
+    count(&stats.current_connections, 1);
+    count(&stats.total_connections, 1);
+
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut out = BytesMut::new();
+    'connection: loop {
+        // Pipelined commands are answered together.
+        let mut closing = false;
+        loop {
+            match parse_command(&mut buf, &listener.limits) {
+                Ok(Some(Request {
+                    command: Command::Quit,
+                    ..
+                })) => {
+                    closing = true;
+                    break;
+                }
+                Ok(Some(request)) => {
+                    let reply = listener.execute(remote, request.command).await;
+                    if !request.noreply {
+                        out.put_slice(&reply);
+                    }
+                }
+                Ok(None) => break,
+                Err(e) => {
+                    out.put_slice(format!("{}\r\n", e.reply).as_bytes());
+                    if e.fatal {
+                        debug!(remote = %remote, "Closing memcached connection: {}", e.reply);
+                        closing = true;
+                        break;
+                    }
+                }
+            }
+        }
+        if !out.is_empty() {
+            if let Err(e) = io.write_all(&out).await {
+                debug!(remote = %remote, "memcached connection failed: {}", e);
+                break;
+            }
+            out.clear();
+        }
+        if closing {
+            let _ = io.shutdown().await;
+            break;
+        }
+
+        let read = tokio::select! {
+            read = io.read_buf(&mut buf) => read,
+            _ = shutdown.requested() => break,
+        };
+        match read {
+            Ok(0) => break,
+            Ok(_) => (),
+            Err(e) => {
+                debug!(remote = %remote, "memcached connection failed: {}", e);
+                break 'connection;
+            }
+        }
+    }
+    stats.current_connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Serves memcached clients on `addr` until a shutdown is requested, over TLS
+/// when certificates are given. Fails right away if the address can not be
+/// bound.
+pub fn serve(
+    listener: Listener,
+    addr: SocketAddr,
+    tls: Option<Arc<TlsCerts>>,
+    shutdown: Shutdown,
+) -> io::Result<JoinHandle<()>> {
+    let tcp = std::net::TcpListener::bind(addr)?;
+    tcp.set_nonblocking(true)?;
+    let tcp = TcpListener::from_std(tcp)?;
+    let acceptor = tls.map(|certs| TlsAcceptor::from(Arc::new(certs.rustls_config(&[]))));
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, remote) = tokio::select! {
+                accepted = tcp.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept memcached connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.requested() => break,
+            };
+
+            let listener = listener.clone();
+            let shutdown = shutdown.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(listener, stream, remote, shutdown).await,
+                        Err(e) => debug!(remote = %remote, "TLS handshake failed: {}", e),
+                    },
+                    None => serve_connection(listener, stream, remote, shutdown).await,
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> std::result::Result<Option<Request>, ParseError> {
+        parse_command(&mut BytesMut::from(input), &Limits::default())
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let request = parse(b"cas k 5 60 3 42 noreply\r\nabc\r\n")
+            .unwrap()
+            .unwrap();
+        assert!(request.noreply);
+        assert_eq!(
+            request.command,
+            Command::Store {
+                mode: Mode::Cas(42),
+                key: b"k".to_vec(),
+                flags: 5,
+                exptime: 60,
+                data: b"abc".to_vec(),
+            }
+        );
+        assert_eq!(
+            parse(b"gets a b\r\n").unwrap().unwrap().command,
+            Command::Get {
+                keys: vec![b"a".to_vec(), b"b".to_vec()],
+                cas: true
+            }
+        );
+        // Until the data block is complete.
+        assert_eq!(parse(b"set k 0 0 3\r\nab").unwrap(), None);
+
+        assert_eq!(parse(b"flush_all\r\n").unwrap_err(), unknown_command());
+        assert_eq!(parse(b"set k 0 0\r\n").unwrap_err(), bad_format());
+        assert_eq!(
+            parse(b"set k 0 0 1\r\nab\r\n").unwrap_err().reply,
+            "CLIENT_ERROR bad data chunk"
+        );
+        let limits = Limits {
+            max_value_size: 2,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::from(&b"set k 0 0 3\r\nabc\r\n"[..]);
+        assert!(parse_command(&mut buf, &limits).unwrap_err().fatal);
+    }
+
+    #[test]
+    fn items_keep_their_metadata() {
+        let item = Item {
+            flags: 7,
+            expires: 100,
+            cas: 3,
+            data: b"value".to_vec(),
+        };
+        assert_eq!(Item::decode(&item.encode()), item);
+        assert!(item.is_expired(100));
+        assert!(!item.is_expired(99));
+        assert_eq!(Item::decode(b"raw").data, b"raw".to_vec());
+
+        // Values written through other APIs are never expired.
+        let foreign = [1u8; 40];
+        let read = Item::decode(&foreign);
+        assert_eq!(read.data, foreign.to_vec());
+        assert!(!read.is_expired(u64::MAX));
+
+        assert_eq!(expiry(0, 1000), 0);
+        assert_eq!(expiry(10, 1000), 1010);
+        assert_eq!(expiry(2_000_000_000, 1000), 2_000_000_000);
+        assert!(expiry(-1, 1000) < 1000);
+    }
+}
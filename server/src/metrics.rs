@@ -0,0 +1,442 @@
+//! Prometheus metrics served over HTTP at `/metrics`. Requests are counted by
+//! `MetricsLayer`, which wraps gRPC services, and the keyspace and sled gauges
+//! are refreshed on each scrape.
+
+use futures::future::BoxFuture;
+use http::{Request, Response, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::Body;
+use prometheus::{
+    Encoder, Gauge, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tokio::task::JoinHandle;
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tonic::Code;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::*;
+
+use dumpstors_lib::store::keyspace::CACHE_CAPACITY;
+use dumpstors_lib::store::Store;
+
+use super::completion::on_completion;
+use super::disk::{DiskMonitor, Level};
+use super::shutdown::Shutdown;
+
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    errors: IntCounterVec,
+    latency: HistogramVec,
+    in_flight: IntGauge,
+    keys: IntGaugeVec,
+    bytes: IntGaugeVec,
+    disk_bytes: IntGaugeVec,
+    cache_capacity: IntGaugeVec,
+    disk_usage: Gauge,
+    disk_watermark: IntGaugeVec,
+    store: Option<Arc<Store>>,
+    disk: Option<Arc<DiskMonitor>>,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some(String::from("dumpstors")), None)
+            .expect("The metrics prefix is valid");
+
+        let requests = IntCounterVec::new(
+            Opts::new("grpc_requests_total", "gRPC requests received"),
+            &["method"],
+        )
+        .expect("Metric definitions are valid");
+        let errors = IntCounterVec::new(
+            Opts::new("grpc_errors_total", "gRPC requests failed, by status code"),
+            &["method", "code"],
+        )
+        .expect("Metric definitions are valid");
+        let latency = HistogramVec::new(
+            HistogramOpts::new(
+                "grpc_request_duration_seconds",
+                "Time until the last message of a gRPC response was sent",
+            ),
+            &["method"],
+        )
+        .expect("Metric definitions are valid");
+        let in_flight = IntGauge::new("grpc_requests_in_flight", "gRPC requests being handled")
+            .expect("Metric definitions are valid");
+        let keys = IntGaugeVec::new(
+            Opts::new("keyspace_keys", "Keys stored in a keyspace"),
+            &["keyspace"],
+        )
+        .expect("Metric definitions are valid");
+        let bytes = IntGaugeVec::new(
+            Opts::new(
+                "keyspace_bytes",
+                "Size of the keys and values of a keyspace",
+            ),
+            &["keyspace"],
+        )
+        .expect("Metric definitions are valid");
+        let disk_bytes = IntGaugeVec::new(
+            Opts::new("sled_disk_bytes", "Size of the sled files of a keyspace"),
+            &["keyspace"],
+        )
+        .expect("Metric definitions are valid");
+        let cache_capacity = IntGaugeVec::new(
+            Opts::new("sled_cache_capacity_bytes", "Page cache size of a keyspace"),
+            &["keyspace"],
+        )
+        .expect("Metric definitions are valid");
+
+        let disk_usage = Gauge::new(
+            "disk_usage_ratio",
+            "Used fraction of the volume holding the store",
+        )
+        .expect("Metric definitions are valid");
+        let disk_watermark = IntGaugeVec::new(
+            Opts::new(
+                "disk_watermark_exceeded",
+                "Whether disk usage is above a watermark",
+            ),
+            &["watermark"],
+        )
+        .expect("Metric definitions are valid");
+
+        let collectors: Vec<Box<dyn prometheus::core::Collector>> = vec![
+            Box::new(requests.clone()),
+            Box::new(errors.clone()),
+            Box::new(latency.clone()),
+            Box::new(in_flight.clone()),
+            Box::new(keys.clone()),
+            Box::new(bytes.clone()),
+            Box::new(disk_bytes.clone()),
+            Box::new(cache_capacity.clone()),
+            Box::new(disk_usage.clone()),
+            Box::new(disk_watermark.clone()),
+        ];
+        for collector in collectors {
+            registry
+                .register(collector)
+                .expect("Metrics are registered once");
+        }
+
+        Self {
+            registry,
+            requests,
+            errors,
+            latency,
+            in_flight,
+            keys,
+            bytes,
+            disk_bytes,
+            cache_capacity,
+            disk_usage,
+            disk_watermark,
+            store: None,
+            disk: None,
+        }
+    }
+
+    /// Exports the keyspace gauges of this store.
+    pub fn with_store(mut self, store: Arc<Store>) -> Self {
+        self.store = Some(store);
+        self
+    }
+
+    /// Exports the disk usage measured by this monitor.
+    pub fn with_disk(mut self, disk: Arc<DiskMonitor>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    fn start(self: &Arc<Self>, method: String) -> Call {
+        self.requests.with_label_values(&[&method]).inc();
+        self.in_flight.inc();
+        Call {
+            metrics: self.clone(),
+            method,
+            start: Instant::now(),
+            code: None,
+        }
+    }
+
+    /// Scans the keyspaces, which blocks for as long as they take to read.
+    fn refresh_keyspaces(&self) {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return,
+        };
+        let keyspaces = match store.list_keyspaces() {
+            Ok(keyspaces) => keyspaces,
+            Err(e) => return warn!("Failed to list keyspaces for metrics: {:?}", e),
+        };
+
+        // Deleted keyspaces must not linger in the export.
+        for gauge in [
+            &self.keys,
+            &self.bytes,
+            &self.disk_bytes,
+            &self.cache_capacity,
+        ] {
+            gauge.reset();
+        }
+        for ks in keyspaces {
+            let stats = match store
+                .get_keyspace(ks.name.clone())
+                .and_then(|ks| ks.stats())
+            {
+                Ok(stats) => stats,
+                Err(e) => {
+                    warn!("Failed to read the size of keyspace '{}': {:?}", ks.name, e);
+                    continue;
+                }
+            };
+            let labels = [ks.name.as_str()];
+            self.keys.with_label_values(&labels).set(stats.keys as i64);
+            self.bytes
+                .with_label_values(&labels)
+                .set(stats.bytes as i64);
+            self.disk_bytes
+                .with_label_values(&labels)
+                .set(stats.disk_bytes as i64);
+            self.cache_capacity
+                .with_label_values(&labels)
+                .set(CACHE_CAPACITY as i64);
+        }
+    }
+
+    fn refresh_disk(&self) {
+        if let Some(disk) = &self.disk {
+            let level = disk.level();
+            self.disk_usage.set(disk.usage());
+            self.disk_watermark
+                .with_label_values(&["low"])
+                .set((level != Level::Normal) as i64);
+            self.disk_watermark
+                .with_label_values(&["high"])
+                .set((level == Level::High) as i64);
+        }
+    }
+
+    /// Renders every metric in the Prometheus text format.
+    pub fn render(&self) -> Vec<u8> {
+        self.refresh_keyspaces();
+        self.refresh_disk();
+
+        let mut buf = vec![];
+        let encoder = TextEncoder::new();
+        if let Err(e) = encoder.encode(&self.registry.gather(), &mut buf) {
+            error!("Failed to encode metrics: {}", e);
+        }
+        buf
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Request being handled. Its outcome is recorded when it is dropped, as
+/// cancelled if no status was set.
+struct Call {
+    metrics: Arc<Metrics>,
+    method: String,
+    start: Instant,
+    code: Option<Code>,
+}
+
+impl Call {
+    fn finish(mut self, code: Code) {
+        self.code = Some(code);
+    }
+}
+
+impl Drop for Call {
+    fn drop(&mut self) {
+        let metrics = &self.metrics;
+        let code = self.code.unwrap_or(Code::Cancelled);
+        if code != Code::Ok {
+            metrics
+                .errors
+                .with_label_values(&[&self.method, &format!("{:?}", code)])
+                .inc();
+        }
+        metrics
+            .latency
+            .with_label_values(&[&self.method])
+            .observe(self.start.elapsed().as_secs_f64());
+        metrics.in_flight.dec();
+    }
+}
+
+/// Tower layer counting the requests of a gRPC service.
+#[derive(Clone)]
+pub struct MetricsLayer {
+    metrics: Arc<Metrics>,
+}
+
+impl MetricsLayer {
+    pub fn new(metrics: Arc<Metrics>) -> Self {
+        Self { metrics }
+    }
+}
+
+impl<S> Layer<S> for MetricsLayer {
+    type Service = MetricsService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        MetricsService {
+            inner,
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct MetricsService<S> {
+    inner: S,
+    metrics: Arc<Metrics>,
+}
+
+impl<S: NamedService> NamedService for MetricsService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<Body>> for MetricsService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let call = self.metrics.start(req.uri().path().to_string());
+        let response = self.inner.call(req);
+
+        Box::pin(async move {
+            let response = response.await?;
+            Ok(on_completion(response, move |code| call.finish(code)))
+        })
+    }
+}
+
+async fn handle(metrics: Arc<Metrics>, req: Request<Body>) -> Result<Response<Body>, Infallible> {
+    if req.uri().path() != "/metrics" {
+        let mut response = Response::new(Body::empty());
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        return Ok(response);
+    }
+
+    Ok(
+        match tokio::task::spawn_blocking(move || metrics.render()).await {
+            Ok(body) => Response::builder()
+                .header(http::header::CONTENT_TYPE, TextEncoder::new().format_type())
+                .body(Body::from(body))
+                .expect("The response is valid"),
+            Err(e) => {
+                error!("Failed to render metrics: {}", e);
+                let mut response = Response::new(Body::empty());
+                *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+                response
+            }
+        },
+    )
+}
+
+/// Serves `/metrics` on `addr` until a shutdown is requested. Fails right
+/// away if the address can not be bound.
+pub fn serve(
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    shutdown: Shutdown,
+) -> Result<JoinHandle<()>, hyper::Error> {
+    let server = hyper::Server::try_bind(&addr)?.serve(make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move { Ok::<_, Infallible>(service_fn(move |req| handle(metrics.clone(), req))) }
+    }));
+
+    Ok(tokio::spawn(async move {
+        tokio::select! {
+            served = server => {
+                if let Err(e) = served {
+                    error!("Metrics server failed: {}", e);
+                }
+            }
+            _ = shutdown.requested() => (),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use dumpstors_lib::models;
+    use uuid::Uuid;
+
+    #[test]
+    fn keyspace_gauges() {
+        let store = Arc::new(Store::new(format!(".data/{}", Uuid::new_v4())).unwrap());
+        store
+            .create_keyspace(models::Keyspace {
+                name: String::from("ks"),
+            })
+            .unwrap();
+        store
+            .get_keyspace(String::from("ks"))
+            .unwrap()
+            .insert(models::Record {
+                key: b"foo".to_vec(),
+                value: b"bar".to_vec(),
+            })
+            .unwrap();
+
+        let metrics = Metrics::new().with_store(store.clone());
+        let text = String::from_utf8(metrics.render()).unwrap();
+        assert!(text.contains("dumpstors_keyspace_keys{keyspace=\"ks\"} 1"));
+        assert!(text.contains("dumpstors_keyspace_bytes{keyspace=\"ks\"} 6"));
+        assert!(text.contains("dumpstors_sled_disk_bytes{keyspace=\"ks\"}"));
+
+        store.delete_keyspace(String::from("ks")).unwrap();
+        let text = String::from_utf8(metrics.render()).unwrap();
+        assert!(!text.contains("keyspace=\"ks\""));
+    }
+
+    #[tokio::test]
+    async fn calls_are_recorded_when_done() {
+        let metrics = Arc::new(Metrics::new());
+        let method = "/dumpstors.store.Store/GetKey";
+
+        let call = metrics.start(method.to_string());
+        assert_eq!(metrics.in_flight.get(), 1);
+        call.finish(Code::NotFound);
+        drop(metrics.start(method.to_string()));
+
+        assert_eq!(metrics.in_flight.get(), 0);
+        assert_eq!(metrics.requests.with_label_values(&[method]).get(), 2);
+        for code in ["NotFound", "Cancelled"] {
+            assert_eq!(metrics.errors.with_label_values(&[method, code]).get(), 1);
+        }
+        assert_eq!(
+            metrics
+                .latency
+                .with_label_values(&[method])
+                .get_sample_count(),
+            2
+        );
+    }
+}
@@ -0,0 +1,703 @@
+//! Token-bucket rate limits on the requests and bytes received by the store
+//! service, per client and per keyspace. Clients are told apart by their
+//! `client-id` header, or by their peer IP address without one. The buckets of
+//! a named client are kept per address, so that naming itself after another
+//! client does not use up the buckets of that client.
+
+use futures::future::BoxFuture;
+use futures::stream::{self, StreamExt};
+use http::{Request, Response};
+use hyper::Body;
+use std::collections::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tonic::body::BoxBody;
+use tonic::metadata::MetadataValue;
+use tonic::transport::NamedService;
+use tonic::{Code, Status};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::*;
+
+use dumpstors_lib::rpc::{self, Detail};
+
+use super::settings::{RateLimit, RateLimits};
+
+pub const CLIENT_ID_HEADER: &str = "client-id";
+
+/// Name of the limit applying to every client or keyspace without a limit
+/// of its own.
+pub const DEFAULT_LIMIT: &str = "*";
+
+/// Client-streaming methods, whose body is throttled as it is received
+/// rather than read at once.
+const STREAMING_METHODS: &[&str] = &["/dumpstors.store.Store/BulkInsert"];
+
+/// Longest keyspace name, beyond which the first field of a streamed request
+/// is not waited for.
+const MAX_KEYSPACE_LEN: u64 = 255;
+
+/// Buckets are dropped once full when there are more than this many, so that
+/// short-lived clients do not accumulate.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Holds up to one second worth of tokens. A request larger than that only
+/// waits for a full bucket, so that it is not rejected forever, and leaves
+/// the bucket in debt.
+struct Bucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Self {
+        Self {
+            rate: rate as f64,
+            tokens: rate as f64,
+            last: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+    }
+
+    fn is_full(&self) -> bool {
+        self.tokens >= self.rate
+    }
+
+    /// Time until `cost` tokens are available, if they are not.
+    fn wait(&self, cost: f64) -> Option<Duration> {
+        let needed = cost.min(self.rate);
+        if self.tokens >= needed {
+            return None;
+        }
+        let wait = Duration::from_secs_f64((needed - self.tokens) / self.rate);
+        Some(wait.max(Duration::from_millis(1)))
+    }
+}
+
+/// Buckets of a client or a keyspace, `None` where unlimited.
+struct Buckets {
+    requests: Option<Bucket>,
+    bytes: Option<Bucket>,
+}
+
+impl Buckets {
+    fn new(limit: &RateLimit, now: Instant) -> Self {
+        let bucket = |rate| match rate {
+            0 => None,
+            rate => Some(Bucket::new(rate, now)),
+        };
+        Self {
+            requests: bucket(limit.requests_per_sec),
+            bytes: bucket(limit.bytes_per_sec),
+        }
+    }
+
+    /// Each bucket with its limit and the tokens taken by `requests` of
+    /// `bytes` in total. The request bucket is left out without requests.
+    fn each(
+        &mut self,
+        requests: u64,
+        bytes: u64,
+    ) -> impl Iterator<Item = (&'static str, &mut Bucket, f64)> {
+        let requests = self
+            .requests
+            .as_mut()
+            .filter(|_| requests > 0)
+            .map(|b| ("requests_per_sec", b, requests as f64));
+        let bytes = self
+            .bytes
+            .as_mut()
+            .map(|b| ("bytes_per_sec", b, bytes as f64));
+        requests.into_iter().chain(bytes)
+    }
+}
+
+/// Request rejected by a limit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rejection {
+    /// `client` or `keyspace`.
+    pub kind: &'static str,
+    pub subject: String,
+    pub limit: &'static str,
+    pub retry_after: Duration,
+}
+
+impl From<Rejection> for Status {
+    fn from(rejection: Rejection) -> Self {
+        let mut metadata = HashMap::new();
+        metadata.insert(String::from(rejection.kind), rejection.subject.clone());
+        metadata.insert(String::from("limit"), String::from(rejection.limit));
+        let mut status = rpc::status(
+            Code::ResourceExhausted,
+            format!(
+                "Rate limit {} of {} '{}' exceeded",
+                rejection.limit, rejection.kind, rejection.subject
+            ),
+            vec![
+                Detail::error_info("RATE_LIMITED", metadata),
+                Detail::retry_info(rejection.retry_after),
+            ],
+        );
+        // Whole seconds, as in the HTTP header.
+        let secs = rejection.retry_after.as_secs_f64().ceil() as u64;
+        status
+            .metadata_mut()
+            .insert("retry-after", MetadataValue::from(secs));
+        status
+    }
+}
+
+/// Address of the client of a request received outside of tonic's own
+/// connections, set as a request extension by the server that received it.
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteAddr(pub IpAddr);
+
+/// Client a request is counted against: it gets the limit of its name, and
+/// buckets of its own per address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    name: String,
+    addr: Option<IpAddr>,
+}
+
+impl Client {
+    /// Names a client by its `client-id`, or else by its address.
+    pub fn new(id: Option<&str>, addr: Option<IpAddr>) -> Self {
+        let name = match (id, addr) {
+            (Some(id), _) => id.to_string(),
+            (None, Some(addr)) => addr.to_string(),
+            (None, None) => String::from("unknown"),
+        };
+        Self { name, addr }
+    }
+
+    fn key(&self) -> String {
+        match self.addr {
+            Some(addr) if self.name != addr.to_string() => format!("{}@{}", self.name, addr),
+            _ => self.name.clone(),
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+fn find<'a>(limits: &'a [RateLimit], name: &str) -> Option<&'a RateLimit> {
+    limits
+        .iter()
+        .find(|l| l.name == name)
+        .or_else(|| limits.iter().find(|l| l.name == DEFAULT_LIMIT))
+}
+
+/// Replaces the limit of the same name, or removes it when unlimited.
+fn replace(limits: &mut Vec<RateLimit>, limit: RateLimit) {
+    limits.retain(|l| l.name != limit.name);
+    if limit.requests_per_sec > 0 || limit.bytes_per_sec > 0 {
+        limits.push(limit);
+        limits.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+}
+
+pub struct RateLimiter {
+    limits: RwLock<RateLimits>,
+    clients: Mutex<HashMap<String, Buckets>>,
+    keyspaces: Mutex<HashMap<String, Buckets>>,
+}
+
+impl RateLimiter {
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            clients: Mutex::new(HashMap::new()),
+            keyspaces: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn limits(&self) -> RateLimits {
+        self.limits
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Whether requests need to be inspected at all.
+    pub fn is_enabled(&self) -> bool {
+        let limits = self.limits.read().unwrap_or_else(PoisonError::into_inner);
+        !limits.clients.is_empty() || !limits.keyspaces.is_empty()
+    }
+
+    /// Whether the keyspace or the size of requests is needed to enforce
+    /// the limits, which requires reading their body.
+    fn needs_body(&self) -> bool {
+        let limits = self.limits.read().unwrap_or_else(PoisonError::into_inner);
+        !limits.keyspaces.is_empty() || limits.clients.iter().any(|l| l.bytes_per_sec > 0)
+    }
+
+    pub fn set_client_limit(&self, limit: RateLimit) {
+        let mut limits = self.limits.write().unwrap_or_else(PoisonError::into_inner);
+        replace(&mut limits.clients, limit);
+        // Buckets are recreated with the new rates.
+        self.clients
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    pub fn set_keyspace_limit(&self, limit: RateLimit) {
+        let mut limits = self.limits.write().unwrap_or_else(PoisonError::into_inner);
+        replace(&mut limits.keyspaces, limit);
+        self.keyspaces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Takes a request of `bytes` from the buckets of the client and of the
+    /// keyspace. Nothing is taken when any of them is empty.
+    pub fn check(
+        &self,
+        client: &Client,
+        keyspace: Option<&str>,
+        bytes: u64,
+    ) -> Result<(), Rejection> {
+        self.take(client, keyspace, 1, bytes)
+    }
+
+    /// Waits until `bytes` more of a streamed request can be taken from the
+    /// byte buckets, slowing down the stream rather than failing it.
+    pub async fn throttle(&self, client: &Client, keyspace: Option<&str>, bytes: u64) {
+        while let Err(rejection) = self.take(client, keyspace, 0, bytes) {
+            debug!(
+                client = %client,
+                subject = %rejection.subject,
+                limit = rejection.limit,
+                "Stream throttled"
+            );
+            tokio::time::sleep(rejection.retry_after).await;
+        }
+    }
+
+    fn take(
+        &self,
+        client: &Client,
+        keyspace: Option<&str>,
+        requests: u64,
+        bytes: u64,
+    ) -> Result<(), Rejection> {
+        let limits = self.limits.read().unwrap_or_else(PoisonError::into_inner);
+        let now = Instant::now();
+
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut keyspaces = self
+            .keyspaces
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut subjects = vec![];
+        if let Some(limit) = find(&limits.clients, &client.name) {
+            prune(&mut clients, now);
+            let buckets = clients
+                .entry(client.key())
+                .or_insert_with(|| Buckets::new(limit, now));
+            subjects.push(("client", client.name.as_str(), buckets));
+        }
+        if let Some(keyspace) = keyspace {
+            if let Some(limit) = find(&limits.keyspaces, keyspace) {
+                prune(&mut keyspaces, now);
+                let buckets = keyspaces
+                    .entry(keyspace.to_string())
+                    .or_insert_with(|| Buckets::new(limit, now));
+                subjects.push(("keyspace", keyspace, buckets));
+            }
+        }
+
+        for (kind, subject, buckets) in subjects.iter_mut() {
+            for (limit, bucket, cost) in buckets.each(requests, bytes) {
+                bucket.refill(now);
+                if let Some(retry_after) = bucket.wait(cost) {
+                    return Err(Rejection {
+                        kind,
+                        subject: subject.to_string(),
+                        limit,
+                        retry_after,
+                    });
+                }
+            }
+        }
+        for (_, _, buckets) in subjects.iter_mut() {
+            for (_, bucket, cost) in buckets.each(requests, bytes) {
+                bucket.tokens -= cost;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn prune(buckets: &mut HashMap<String, Buckets>, now: Instant) {
+    if buckets.len() < MAX_BUCKETS {
+        return;
+    }
+    buckets.retain(|_, b| {
+        b.each(1, 0).any(|(_, bucket, _)| {
+            bucket.refill(now);
+            !bucket.is_full()
+        })
+    });
+}
+
+/// Reads a varint at the start of `buf`, returning it with its length.
+fn varint(buf: &[u8]) -> Option<(u64, usize)> {
+    let mut value = 0u64;
+    for (i, byte) in buf.iter().enumerate().take(10) {
+        value |= u64::from(byte & 0x7f) << (7 * i);
+        if byte & 0x80 == 0 {
+            return Some((value, i + 1));
+        }
+    }
+    None
+}
+
+/// Keyspace of a gRPC request body. Every store request naming a keyspace
+/// holds it in its first field, encoded first.
+fn keyspace(body: &[u8]) -> Option<&str> {
+    // Compression flag and length of the message.
+    let message = body.get(5..)?;
+    // Field 1 with the length-delimited wire type.
+    if *message.first()? != 0x0a {
+        return None;
+    }
+    let (len, read) = varint(&message[1..])?;
+    let start = 1 + read;
+    let name = message.get(start..start.checked_add(len as usize)?)?;
+    std::str::from_utf8(name).ok()
+}
+
+/// Whether a request body holds its first field, or enough of it to tell
+/// that the field is not a keyspace name.
+fn has_first_field(body: &[u8]) -> bool {
+    let (header, message) = match (body.get(..5), body.get(5..)) {
+        (Some(header), Some(message)) => (header, message),
+        _ => return false,
+    };
+    if header[1..] == [0, 0, 0, 0] {
+        return true;
+    }
+    match message.first() {
+        None => false,
+        Some(0x0a) => match varint(&message[1..]) {
+            Some((len, read)) => len > MAX_KEYSPACE_LEN || (message.len() - 1 - read) as u64 >= len,
+            None => message.len() > 10,
+        },
+        Some(_) => true,
+    }
+}
+
+/// Client of a request: its `client-id` header, or its peer IP address.
+fn client(req: &mut Request<Body>) -> Client {
+    let id = req
+        .headers()
+        .get(CLIENT_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .map(String::from);
+    let received = req.extensions().get::<RemoteAddr>().map(|addr| addr.0);
+    // tonic only exposes the connection of a request through its own request
+    // type, which takes the extensions along. The store service does not
+    // read them.
+    let mut probe = Request::new(());
+    *probe.extensions_mut() = std::mem::take(req.extensions_mut());
+    let addr = tonic::Request::from_http(probe)
+        .remote_addr()
+        .map(|addr| addr.ip())
+        .or(received);
+    Client::new(id.as_deref(), addr)
+}
+
+/// Checks a streamed request from its first chunks, up to the first field
+/// naming the keyspace, and throttles the rest of its body.
+async fn throttled(
+    limiter: Arc<RateLimiter>,
+    client: Client,
+    mut body: Body,
+) -> (Body, Result<(), Rejection>) {
+    let mut first = vec![];
+    while !has_first_field(&first) {
+        match body.next().await {
+            Some(Ok(bytes)) => first.extend_from_slice(&bytes),
+            Some(Err(e)) => {
+                let failed = Body::wrap_stream(stream::once(async { Err::<bytes::Bytes, _>(e) }));
+                return (failed, Ok(()));
+            }
+            None => break,
+        }
+    }
+    let keyspace = keyspace(&first).map(String::from);
+    let checked = limiter.check(&client, keyspace.as_deref(), first.len() as u64);
+    let first = bytes::Bytes::from(first);
+
+    let rest = body.then(move |chunk| {
+        let (limiter, client, keyspace) = (limiter.clone(), client.clone(), keyspace.clone());
+        async move {
+            if let Ok(bytes) = &chunk {
+                let len = bytes.len() as u64;
+                limiter.throttle(&client, keyspace.as_deref(), len).await;
+            }
+            chunk
+        }
+    });
+    let body = Body::wrap_stream(stream::once(async { Ok(first) }).chain(rest));
+    (body, checked)
+}
+
+/// Tower layer enforcing rate limits on a gRPC service.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(limiter: Arc<RateLimiter>) -> Self {
+        Self { limiter }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S: NamedService> NamedService for RateLimitService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<Body>> for RateLimitService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        if !self.limiter.is_enabled() {
+            return Box::pin(self.inner.call(req));
+        }
+
+        let client = client(&mut req);
+        let streaming = STREAMING_METHODS.contains(&req.uri().path());
+        let limiter = self.limiter.clone();
+        // The ready service is taken, leaving its clone for the next call.
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            let (parts, body) = req.into_parts();
+            let (body, checked) = if limiter.needs_body() && streaming {
+                throttled(limiter.clone(), client.clone(), body).await
+            } else if limiter.needs_body() {
+                match hyper::body::to_bytes(body).await {
+                    Ok(bytes) => {
+                        let checked = limiter.check(&client, keyspace(&bytes), bytes.len() as u64);
+                        (Body::from(bytes), checked)
+                    }
+                    // The service reports the failure of the body as usual.
+                    Err(e) => (
+                        Body::wrap_stream(stream::once(async { Err::<bytes::Bytes, _>(e) })),
+                        Ok(()),
+                    ),
+                }
+            } else {
+                (body, limiter.check(&client, None, 0))
+            };
+
+            if let Err(rejection) = checked {
+                debug!(
+                    client = %client,
+                    subject = %rejection.subject,
+                    limit = rejection.limit,
+                    "Request rate limited"
+                );
+                return Ok(Status::from(rejection).to_http());
+            }
+            inner.call(Request::from_parts(parts, body)).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn client(name: &str) -> Client {
+        Client::new(Some(name), None)
+    }
+
+    fn limit(name: &str, requests_per_sec: u64, bytes_per_sec: u64) -> RateLimit {
+        RateLimit {
+            name: String::from(name),
+            requests_per_sec,
+            bytes_per_sec,
+        }
+    }
+
+    #[test]
+    fn buckets_refill_over_time() {
+        let now = Instant::now();
+        let mut bucket = Bucket::new(10, now);
+        assert_eq!(bucket.wait(100.0), None);
+        bucket.tokens -= 15.0;
+        assert_eq!(bucket.wait(5.0), Some(Duration::from_secs(1)));
+
+        bucket.refill(now + Duration::from_secs(1));
+        assert_eq!(bucket.wait(5.0), None);
+        assert!(bucket.wait(6.0).is_some());
+        bucket.refill(now + Duration::from_secs(10));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.tokens, 10.0);
+    }
+
+    #[test]
+    fn limits_apply_per_client_and_keyspace() {
+        let limiter = RateLimiter::new(RateLimits {
+            clients: vec![limit("*", 2, 0), limit("batch", 1, 0)],
+            keyspaces: vec![limit("logs", 0, 10)],
+        });
+
+        limiter.check(&client("batch"), None, 0).unwrap();
+        match limiter.check(&client("batch"), None, 0) {
+            Err(rejection) => {
+                assert_eq!(rejection.kind, "client");
+                assert_eq!(rejection.limit, "requests_per_sec");
+            }
+            Ok(_) => panic!("The client should be limited"),
+        };
+        // Other clients have buckets of their own.
+        limiter.check(&client("a"), None, 0).unwrap();
+        limiter.check(&client("b"), Some("logs"), 20).unwrap();
+        match limiter.check(&client("c"), Some("logs"), 1) {
+            Err(rejection) => {
+                assert_eq!(rejection.subject, "logs");
+                assert_eq!(rejection.limit, "bytes_per_sec");
+            }
+            Ok(_) => panic!("The keyspace should be limited"),
+        };
+        // The rejected request did not take from the client bucket.
+        limiter.check(&client("c"), Some("other"), 1).unwrap();
+        limiter.check(&client("c"), None, 0).unwrap();
+
+        // Clients taking the name of another one get buckets of their own.
+        let addr = |ip: &str| Some(ip.parse().unwrap());
+        let other = Client::new(Some("b"), addr("10.0.0.2"));
+        limiter.check(&other, None, 0).unwrap();
+        limiter.check(&other, None, 0).unwrap();
+        assert!(limiter.check(&other, None, 0).is_err());
+        limiter
+            .check(&Client::new(Some("b"), addr("10.0.0.1")), None, 0)
+            .unwrap();
+        assert_eq!(Client::new(None, addr("10.0.0.1")).to_string(), "10.0.0.1");
+
+        limiter.set_client_limit(limit("batch", 0, 0));
+        limiter.check(&client("batch"), None, 0).unwrap();
+        limiter.set_keyspace_limit(limit("logs", 0, 0));
+        limiter.check(&client("d"), Some("logs"), 1000).unwrap();
+        assert_eq!(limiter.limits().clients.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn streams_are_throttled() {
+        let limiter = RateLimiter::new(RateLimits {
+            clients: vec![limit("*", 0, 100)],
+            keyspaces: vec![],
+        });
+        limiter.check(&client("a"), None, 100).unwrap();
+        assert!(limiter.check(&client("a"), None, 50).is_err());
+
+        // Streamed bytes wait for the bucket instead of being rejected.
+        let start = Instant::now();
+        limiter.throttle(&client("a"), None, 50).await;
+        assert!(start.elapsed() >= Duration::from_millis(400));
+    }
+
+    #[test]
+    fn rejections_carry_retry_after() {
+        let status = Status::from(Rejection {
+            kind: "client",
+            subject: String::from("batch"),
+            limit: "requests_per_sec",
+            retry_after: Duration::from_millis(1200),
+        });
+        assert_eq!(status.code(), Code::ResourceExhausted);
+        assert_eq!(status.metadata().get("retry-after").unwrap(), "2");
+        assert_eq!(rpc::error_info(&status).unwrap().reason, "RATE_LIMITED");
+        assert!(rpc::is_retryable(&status));
+    }
+
+    #[test]
+    fn keyspaces_are_read_from_requests() {
+        let mut body = vec![0, 0, 0, 0, 9, 0x0a, 4];
+        body.extend_from_slice(b"logs");
+        body.extend_from_slice(&[0x12, 1, b'k']);
+        assert_eq!(keyspace(&body), Some("logs"));
+
+        // A cluster topology starts with its version.
+        assert_eq!(keyspace(&[0, 0, 0, 0, 2, 0x08, 1]), None);
+        assert_eq!(keyspace(&[0, 0, 0, 0, 2, 0x0a, 9]), None);
+        assert_eq!(keyspace(&[]), None);
+    }
+
+    #[tokio::test]
+    async fn keyspaces_split_across_chunks_are_read() {
+        let limiter = Arc::new(RateLimiter::new(RateLimits {
+            clients: vec![],
+            keyspaces: vec![limit("logs", 1, 0)],
+        }));
+        let mut message = vec![0, 0, 0, 0, 9, 0x0a, 4];
+        message.extend_from_slice(b"logs");
+        message.extend_from_slice(&[0x12, 1, b'k']);
+        assert!(!has_first_field(&message[..9]));
+        assert!(has_first_field(&message[..11]));
+        assert!(has_first_field(&[0, 0, 0, 0, 0]));
+
+        let request = || {
+            let chunks: Vec<Result<bytes::Bytes, std::io::Error>> = message
+                .chunks(3)
+                .map(|chunk| Ok(bytes::Bytes::copy_from_slice(chunk)))
+                .collect();
+            Body::wrap_stream(stream::iter(chunks))
+        };
+        let (body, checked) = throttled(limiter.clone(), client("a"), request()).await;
+        checked.unwrap();
+        assert_eq!(hyper::body::to_bytes(body).await.unwrap(), message);
+        match throttled(limiter, client("a"), request()).await.1 {
+            Err(rejection) => assert_eq!(rejection.subject, "logs"),
+            Ok(_) => panic!("The keyspace should be limited"),
+        };
+    }
+}
@@ -0,0 +1,275 @@
+//! `grpc.reflection.v1alpha.ServerReflection` service, describing the served
+//! APIs to tools such as grpcurl from the descriptors compiled by `lib`.
+
+use futures::{Stream, StreamExt};
+use prost::Message;
+use prost_types::{DescriptorProto, FileDescriptorProto, FileDescriptorSet};
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::result::Result as StdResult;
+use std::sync::Arc;
+use tokio::sync::mpsc;
+use tonic::{Request, Response, Status, Streaming};
+
+use dumpstors_lib::reflection::server_reflection_request::MessageRequest;
+use dumpstors_lib::reflection::server_reflection_response::MessageResponse;
+use dumpstors_lib::reflection::*;
+
+struct Descriptors {
+    files: HashMap<String, FileDescriptorProto>,
+    /// File defining each fully qualified symbol.
+    symbols: HashMap<String, String>,
+    services: Vec<String>,
+}
+
+fn index_message(
+    symbols: &mut HashMap<String, String>,
+    file: &str,
+    prefix: &str,
+    msg: &DescriptorProto,
+) {
+    let name = format!("{}.{}", prefix, msg.name());
+    for nested in msg.nested_type.iter() {
+        index_message(symbols, file, &name, nested);
+    }
+    for e in msg.enum_type.iter() {
+        symbols.insert(format!("{}.{}", name, e.name()), file.to_string());
+    }
+    symbols.insert(name, file.to_string());
+}
+
+impl Descriptors {
+    fn new(set: FileDescriptorSet, services: Vec<String>) -> Self {
+        let mut files = HashMap::new();
+        let mut symbols = HashMap::new();
+
+        for file in set.file {
+            let name = file.name().to_string();
+            let package = file.package().to_string();
+            let qualify = |symbol: &str| match package.as_str() {
+                "" => symbol.to_string(),
+                package => format!("{}.{}", package, symbol),
+            };
+
+            for msg in file.message_type.iter() {
+                index_message(&mut symbols, &name, &package, msg);
+            }
+            for e in file.enum_type.iter() {
+                symbols.insert(qualify(e.name()), name.clone());
+            }
+            for service in file.service.iter() {
+                let service_name = qualify(service.name());
+                for method in service.method.iter() {
+                    symbols.insert(format!("{}.{}", service_name, method.name()), name.clone());
+                }
+                symbols.insert(service_name, name.clone());
+            }
+            files.insert(name, file);
+        }
+        // Messages of packages without a name are indexed with a leading dot.
+        let symbols = symbols
+            .into_iter()
+            .map(|(symbol, file)| (symbol.trim_start_matches('.').to_string(), file))
+            .collect();
+
+        Self {
+            files,
+            symbols,
+            services,
+        }
+    }
+
+    /// Encodes a file followed by its transitive dependencies.
+    #[allow(clippy::result_large_err)]
+    fn file_with_dependencies(&self, name: &str) -> StdResult<Vec<Vec<u8>>, Status> {
+        let mut encoded = vec![];
+        let mut seen = vec![];
+        let mut pending = vec![name.to_string()];
+
+        while let Some(name) = pending.pop() {
+            if seen.contains(&name) {
+                continue;
+            }
+            let file = self
+                .files
+                .get(&name)
+                .ok_or_else(|| Status::not_found(format!("Unknown file '{}'", name)))?;
+            let mut buf = Vec::with_capacity(file.encoded_len());
+            // Encoding into a Vec can only fail on insufficient capacity, which Vec grows.
+            let _ = file.encode(&mut buf);
+            encoded.push(buf);
+            pending.extend(file.dependency.iter().cloned());
+            seen.push(name);
+        }
+        Ok(encoded)
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn respond(&self, request: &MessageRequest) -> StdResult<MessageResponse, Status> {
+        match request {
+            MessageRequest::FileByFilename(name) => Ok(MessageResponse::FileDescriptorResponse(
+                FileDescriptorResponse {
+                    file_descriptor_proto: self.file_with_dependencies(name)?,
+                },
+            )),
+            MessageRequest::FileContainingSymbol(symbol) => {
+                let file = self
+                    .symbols
+                    .get(symbol.trim_start_matches('.'))
+                    .ok_or_else(|| Status::not_found(format!("Unknown symbol '{}'", symbol)))?;
+                Ok(MessageResponse::FileDescriptorResponse(
+                    FileDescriptorResponse {
+                        file_descriptor_proto: self.file_with_dependencies(file)?,
+                    },
+                ))
+            }
+            MessageRequest::ListServices(_) => {
+                Ok(MessageResponse::ListServicesResponse(ListServiceResponse {
+                    service: self
+                        .services
+                        .iter()
+                        .map(|name| ServiceResponse { name: name.clone() })
+                        .collect(),
+                }))
+            }
+            // The served protos define no extensions.
+            MessageRequest::FileContainingExtension(_) => {
+                Err(Status::not_found("Extensions are not supported"))
+            }
+            MessageRequest::AllExtensionNumbersOfType(name) => Ok(
+                MessageResponse::AllExtensionNumbersResponse(ExtensionNumberResponse {
+                    base_type_name: name.clone(),
+                    extension_number: vec![],
+                }),
+            ),
+        }
+    }
+}
+
+pub struct ReflectionService {
+    descriptors: Arc<Descriptors>,
+}
+
+impl ReflectionService {
+    /// Describes the given services, which must be defined by the protos
+    /// compiled in `lib`.
+    pub fn new(services: Vec<String>) -> StdResult<Self, prost::DecodeError> {
+        let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET)?;
+        Ok(Self {
+            descriptors: Arc::new(Descriptors::new(set, services)),
+        })
+    }
+}
+
+#[tonic::async_trait]
+impl server_reflection_server::ServerReflection for ReflectionService {
+    type ServerReflectionInfoStream = Pin<
+        Box<dyn Stream<Item = StdResult<ServerReflectionResponse, Status>> + Send + Sync + 'static>,
+    >;
+
+    async fn server_reflection_info(
+        &self,
+        request: Request<Streaming<ServerReflectionRequest>>,
+    ) -> StdResult<Response<Self::ServerReflectionInfoStream>, Status> {
+        let mut requests = request.into_inner();
+        let descriptors = self.descriptors.clone();
+
+        let (tx, rx) = mpsc::channel(4);
+        tokio::spawn(async move {
+            while let Some(request) = requests.next().await {
+                let request = match request {
+                    Ok(request) => request,
+                    Err(_) => break,
+                };
+                let response = match &request.message_request {
+                    Some(message) => descriptors.respond(message),
+                    None => Err(Status::invalid_argument("Missing message request")),
+                };
+                let message_response = match response {
+                    Ok(response) => response,
+                    Err(status) => MessageResponse::ErrorResponse(ErrorResponse {
+                        error_code: status.code() as i32,
+                        error_message: status.message().to_string(),
+                    }),
+                };
+                let response = ServerReflectionResponse {
+                    valid_host: request.host.clone(),
+                    original_request: Some(request),
+                    message_response: Some(message_response),
+                };
+                if tx.send(Ok(response)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tonic::Code;
+
+    fn descriptors() -> Descriptors {
+        let set = FileDescriptorSet::decode(FILE_DESCRIPTOR_SET).unwrap();
+        Descriptors::new(set, vec![String::from("dumpstors.store.Store")])
+    }
+
+    fn files(response: MessageResponse) -> Vec<FileDescriptorProto> {
+        match response {
+            MessageResponse::FileDescriptorResponse(r) => r
+                .file_descriptor_proto
+                .iter()
+                .map(|bytes| FileDescriptorProto::decode(bytes.as_slice()).unwrap())
+                .collect(),
+            _ => panic!("Expected file descriptors"),
+        }
+    }
+
+    #[test]
+    fn symbols_resolve_to_their_file_and_dependencies() {
+        let descriptors = descriptors();
+
+        let resolved = files(
+            descriptors
+                .respond(&MessageRequest::FileContainingSymbol(String::from(
+                    "dumpstors.store.Store",
+                )))
+                .unwrap(),
+        );
+        let names: Vec<&str> = resolved.iter().map(|f| f.name()).collect();
+        assert_eq!(names[0], "proto/store.proto");
+        assert!(names.contains(&"proto/models.proto"));
+        assert!(names.contains(&"google/protobuf/empty.proto"));
+
+        let resolved = files(
+            descriptors
+                .respond(&MessageRequest::FileContainingSymbol(String::from(
+                    "dumpstors.models.Record",
+                )))
+                .unwrap(),
+        );
+        assert_eq!(resolved[0].name(), "proto/models.proto");
+
+        match descriptors.respond(&MessageRequest::FileContainingSymbol(String::from(
+            "dumpstors.Unknown",
+        ))) {
+            Err(status) if status.code() == Code::NotFound => (),
+            _ => panic!("Unknown symbols must not resolve"),
+        };
+    }
+
+    #[test]
+    fn list_services() {
+        match descriptors().respond(&MessageRequest::ListServices(String::new())) {
+            Ok(MessageResponse::ListServicesResponse(r)) => {
+                assert_eq!(r.service[0].name, "dumpstors.store.Store")
+            }
+            _ => panic!("Expected the list of services"),
+        };
+    }
+}
@@ -0,0 +1,974 @@
+//! Redis protocol (RESP2 and RESP3) listener, for tools that already speak
+//! Redis.
+//!
+//! Commands are served by the handlers of the gRPC store service, as on the
+//! REST gateway, so that authorization, validation and errors are the same.
+//! Redis databases are keyspaces: `SELECT` takes a keyspace name, and new
+//! connections start on `resp.keyspace`. Failures are answered with the
+//! `ErrorInfo` reason of the status as error code, such as
+//! `-KEYSPACE_NOT_FOUND Keyspace 'logs' not found`.
+
+use bytes::{Buf, BufMut, BytesMut};
+use futures::StreamExt;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Status};
+use tracing::*;
+
+use dumpstors_lib::models;
+use dumpstors_lib::rpc;
+use dumpstors_lib::store::store_server::Store as _;
+use dumpstors_lib::store::{
+    DeleteKeyQuery, GetKeyQuery, GetKeyRangesQuery, GetKeysQuery, GetKeyspaceQuery,
+    GetKeyspaceUsageQuery, InsertCondition, InsertKeyQuery, InsertKeysQuery, Limits,
+};
+
+use super::auth::{Authenticator, AUTHORIZATION_METADATA_KEY};
+use super::ratelimit::{Client, RateLimiter};
+use super::settings;
+use super::shutdown::Shutdown;
+use super::store::{is_condition_not_met, is_key_not_found, DumpstorsStoreServer};
+use super::tls::TlsCerts;
+
+/// Version reported to clients, the first one speaking RESP3.
+const REDIS_VERSION: &str = "6.0.0";
+/// Longest inline command or array header.
+const MAX_LINE: usize = 64 * 1024;
+/// Longest argument, as in Redis.
+const MAX_BULK: u64 = 512 * 1024 * 1024;
+const MAX_ARGS: i64 = 1024 * 1024;
+/// `SCAN` cursors are buckets of the keyspace Merkle trees at this depth.
+const SCAN_DEPTH: u32 = 10;
+const SCAN_BUCKETS: u64 = 1 << SCAN_DEPTH;
+const DEFAULT_SCAN_COUNT: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Reply {
+    Status(&'static str),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Nil,
+    Array(Vec<Reply>),
+    /// Sent as a flat array of keys and values over RESP2.
+    Map(Vec<(&'static str, Reply)>),
+}
+
+impl Reply {
+    fn error(message: &str) -> Self {
+        Reply::Error(message.to_string())
+    }
+
+    fn encode(&self, protocol: u8, out: &mut BytesMut) {
+        match self {
+            Reply::Status(status) => {
+                out.put_u8(b'+');
+                out.put_slice(status.as_bytes());
+            }
+            Reply::Error(message) => {
+                out.put_u8(b'-');
+                out.put_slice(message.replace(&['\r', '\n'][..], " ").as_bytes());
+            }
+            Reply::Integer(i) => out.put_slice(format!(":{}", i).as_bytes()),
+            Reply::Bulk(bytes) => {
+                out.put_slice(format!("${}\r\n", bytes.len()).as_bytes());
+                out.put_slice(bytes);
+            }
+            Reply::Nil if protocol >= 3 => out.put_u8(b'_'),
+            Reply::Nil => out.put_slice(b"$-1"),
+            Reply::Array(items) => {
+                out.put_slice(format!("*{}\r\n", items.len()).as_bytes());
+                for item in items {
+                    item.encode(protocol, out);
+                }
+                return;
+            }
+            Reply::Map(entries) => {
+                let header = match protocol {
+                    3 => format!("%{}\r\n", entries.len()),
+                    _ => format!("*{}\r\n", entries.len() * 2),
+                };
+                out.put_slice(header.as_bytes());
+                for (key, value) in entries {
+                    Reply::Bulk(key.as_bytes().to_vec()).encode(protocol, out);
+                    value.encode(protocol, out);
+                }
+                return;
+            }
+        }
+        out.put_slice(b"\r\n");
+    }
+}
+
+impl From<Status> for Reply {
+    fn from(status: Status) -> Self {
+        let code = match rpc::error_info(&status) {
+            Some(info) if !info.reason.is_empty() => info.reason,
+            _ => String::from(match status.code() {
+                Code::Unauthenticated => "NOAUTH",
+                Code::PermissionDenied => "NOPERM",
+                _ => "ERR",
+            }),
+        };
+        Reply::Error(format!("{} {}", code, status.message()))
+    }
+}
+
+/// Returns the line starting at `pos` without its line ending, moving `pos`
+/// past it, or `None` until it is complete.
+fn line<'a>(buf: &'a [u8], pos: &mut usize) -> Result<Option<&'a [u8]>, Reply> {
+    match buf[*pos..].iter().position(|&b| b == b'\n') {
+        Some(end) => {
+            let line = &buf[*pos..*pos + end];
+            *pos += end + 1;
+            Ok(Some(line.strip_suffix(b"\r").unwrap_or(line)))
+        }
+        None if buf.len() - *pos > MAX_LINE => Err(protocol_error("too big inline request")),
+        None => Ok(None),
+    }
+}
+
+fn protocol_error(message: &str) -> Reply {
+    Reply::Error(format!("ERR Protocol error: {}", message))
+}
+
+fn length(line: &[u8], what: &str) -> Result<i64, Reply> {
+    std::str::from_utf8(line)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| protocol_error(&format!("invalid {}", what)))
+}
+
+/// Parses the next command of the buffer, `None` until it is complete. Errors
+/// leave the stream out of sync, the connection is closed after them.
+fn parse_command(buf: &mut BytesMut, limits: &Limits) -> Result<Option<Vec<Vec<u8>>>, Reply> {
+    let mut pos = 0;
+    let header = match line(buf, &mut pos)? {
+        Some(header) => header,
+        None => return Ok(None),
+    };
+    if header.first() != Some(&b'*') {
+        // Inline command, as typed in telnet.
+        let args = header
+            .split(|b| b.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(<[u8]>::to_vec)
+            .collect();
+        buf.advance(pos);
+        return Ok(Some(args));
+    }
+
+    let count = length(&header[1..], "multibulk length")?;
+    if count > MAX_ARGS {
+        return Err(protocol_error("invalid multibulk length"));
+    }
+    let mut args = Vec::with_capacity(count.max(0) as usize);
+    let mut size = 0;
+    for _ in 0..count {
+        let header = match line(buf, &mut pos)? {
+            Some(header) => header,
+            None => return Ok(None),
+        };
+        if header.first() != Some(&b'$') {
+            return Err(protocol_error("expected '$'"));
+        }
+        let len = length(&header[1..], "bulk length")?;
+        if len < 0 || len as u64 > MAX_BULK {
+            return Err(protocol_error("invalid bulk length"));
+        }
+        let len = len as usize;
+        size += len;
+        limits.check_message_size(size).map_err(Status::from)?;
+        if buf.len() < pos + len + 2 {
+            return Ok(None);
+        }
+        if &buf[pos + len..pos + len + 2] != b"\r\n" {
+            return Err(protocol_error("expected CRLF after bulk"));
+        }
+        args.push(buf[pos..pos + len].to_vec());
+        pos += len + 2;
+    }
+    buf.advance(pos);
+    Ok(Some(args))
+}
+
+/// Returns the length of the match of a `[...]` class at the start of the
+/// pattern and whether it matches `c`, `None` when it is not closed.
+fn class(pattern: &[u8], c: u8) -> Option<(usize, bool)> {
+    let mut i = 1;
+    let negated = matches!(pattern.get(i), Some(b'^'));
+    if negated {
+        i += 1;
+    }
+    let mut matched = false;
+    loop {
+        let mut start = *pattern.get(i)?;
+        if start == b']' {
+            return Some((i + 1, matched != negated));
+        }
+        if start == b'\\' {
+            i += 1;
+            start = *pattern.get(i)?;
+        }
+        match (pattern.get(i + 1), pattern.get(i + 2)) {
+            (Some(b'-'), Some(&end)) if end != b']' => {
+                let (low, high) = (start.min(end), start.max(end));
+                matched |= (low..=high).contains(&c);
+                i += 3;
+            }
+            _ => {
+                matched |= start == c;
+                i += 1;
+            }
+        }
+    }
+}
+
+/// Matches keys against the glob-style patterns of `SCAN ... MATCH`.
+fn glob_matches(pattern: &[u8], key: &[u8]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    // Position after the last `*` and the key position it was tried at.
+    let mut star = None;
+    while k < key.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match class(&pattern[p..], key[k]) {
+                Some((len, true)) => Some(len),
+                Some((_, false)) => None,
+                None => Some(1).filter(|_| key[k] == b'['),
+            },
+            Some(b'\\') if p + 1 < pattern.len() => Some(2).filter(|_| pattern[p + 1] == key[k]),
+            Some(&c) => Some(1).filter(|_| c == key[k]),
+            None => None,
+        };
+        match (step, star) {
+            (Some(len), _) => {
+                p += len;
+                k += 1;
+            }
+            (None, Some((after, tried))) => {
+                p = after;
+                k = tried + 1;
+                star = Some((after, tried + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+fn wrong_arity(name: &str) -> Reply {
+    Reply::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        name.to_lowercase()
+    ))
+}
+
+fn check_arity(name: &str, args: &[Vec<u8>], min: usize, max: Option<usize>) -> Result<(), Reply> {
+    if args.len() < min || max.is_some_and(|max| args.len() > max) {
+        return Err(wrong_arity(name));
+    }
+    Ok(())
+}
+
+fn syntax_error() -> Reply {
+    Reply::error("ERR syntax error")
+}
+
+fn unsupported(command: &str, option: &[u8]) -> Reply {
+    Reply::Error(format!(
+        "ERR {} option '{}' is not supported",
+        command,
+        String::from_utf8_lossy(option)
+    ))
+}
+
+fn text(arg: &[u8]) -> Result<String, Reply> {
+    String::from_utf8(arg.to_vec()).map_err(|_| Reply::error("ERR invalid UTF-8 argument"))
+}
+
+fn integer(arg: &[u8]) -> Result<i64, Reply> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(|| Reply::error("ERR value is not an integer or out of range"))
+}
+
+/// gRPC request carrying the bearer token given to `AUTH`.
+fn request<T>(token: Option<&str>, message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    if let Some(value) = token.and_then(|t| MetadataValue::from_str(&format!("Bearer {}", t)).ok())
+    {
+        request
+            .metadata_mut()
+            .insert(AUTHORIZATION_METADATA_KEY, value);
+    }
+    request
+}
+
+/// State of a client connection.
+struct Session {
+    id: u64,
+    remote: SocketAddr,
+    keyspace: String,
+    token: Option<String>,
+    /// Set by `CLIENT SETNAME`, and used instead of the IP address for rate
+    /// limits as the `client-id` header is.
+    name: Option<String>,
+    protocol: u8,
+}
+
+impl Session {
+    fn request<T>(&self, message: T) -> tonic::Request<T> {
+        request(self.token.as_deref(), message)
+    }
+
+    fn client(&self) -> Client {
+        Client::new(self.name.as_deref(), Some(self.remote.ip()))
+    }
+}
+
+/// Listener serving Redis commands with the gRPC store handlers.
+#[derive(Clone)]
+pub struct Listener {
+    store: Arc<DumpstorsStoreServer>,
+    auth: Option<Arc<Authenticator>>,
+    limiter: Arc<RateLimiter>,
+    limits: Limits,
+    keyspace: String,
+    connections: Arc<AtomicU64>,
+    shutdown: Shutdown,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        store: DumpstorsStoreServer,
+        conf: &settings::Resp,
+        limits: Limits,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            auth: None,
+            limiter,
+            limits,
+            keyspace: conf.keyspace.clone(),
+            connections: Arc::new(AtomicU64::new(0)),
+            shutdown: Shutdown::new(),
+        }
+    }
+
+    /// Requires clients to `AUTH` with a valid token before other commands.
+    pub fn with_auth(mut self, auth: Arc<Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    async fn execute(&self, session: &mut Session, mut args: Vec<Vec<u8>>) -> Reply {
+        let name = String::from_utf8_lossy(&args.remove(0)).to_uppercase();
+        let span = info_span!("resp", command = %name.to_lowercase());
+        let reply = match self
+            .command(session, &name, &args)
+            .instrument(span.clone())
+            .await
+        {
+            Ok(reply) => reply,
+            Err(reply) => reply,
+        };
+        if let Reply::Error(message) = &reply {
+            span.in_scope(|| debug!("Command failed: {}", message));
+        }
+        reply
+    }
+
+    async fn command(
+        &self,
+        session: &mut Session,
+        name: &str,
+        args: &[Vec<u8>],
+    ) -> Result<Reply, Reply> {
+        match name {
+            "AUTH" => {
+                check_arity(name, args, 1, Some(2))?;
+                self.authenticate(session, &args[args.len() - 1])?;
+                return Ok(Reply::Status("OK"));
+            }
+            "HELLO" => return self.hello(session, args),
+            _ => (),
+        }
+        if self.auth.is_some() && session.token.is_none() {
+            return Err(Reply::error("NOAUTH Authentication required."));
+        }
+
+        let keyspace = match name {
+            "GET" | "SET" | "DEL" | "EXISTS" | "MGET" | "MSET" | "SCAN" => {
+                Some(session.keyspace.as_str())
+            }
+            _ => None,
+        };
+        let bytes = args.iter().map(|arg| arg.len() as u64).sum();
+        self.limiter
+            .check(&session.client(), keyspace, bytes)
+            .map_err(Status::from)?;
+
+        let store = &self.store;
+        match name {
+            "PING" => {
+                check_arity(name, args, 0, Some(1))?;
+                store.ping(session.request(())).await?;
+                Ok(match args.first() {
+                    Some(message) => Reply::Bulk(message.clone()),
+                    None => Reply::Status("PONG"),
+                })
+            }
+            "CLIENT" => self.client(session, args),
+            "SELECT" => {
+                check_arity(name, args, 1, Some(1))?;
+                let keyspace = text(&args[0])?;
+                let query = GetKeyspaceQuery {
+                    keyspace: keyspace.clone(),
+                };
+                store.get_keyspace(session.request(query)).await?;
+                session.keyspace = keyspace;
+                Ok(Reply::Status("OK"))
+            }
+            "GET" => {
+                check_arity(name, args, 1, Some(1))?;
+                let query = GetKeyQuery {
+                    keyspace: session.keyspace.clone(),
+                    key: args[0].clone(),
+                };
+                match store.get_key(session.request(query)).await {
+                    Ok(record) => Ok(Reply::Bulk(record.into_inner().value)),
+                    Err(e) if is_key_not_found(&e) => Ok(Reply::Nil),
+                    Err(e) => Err(e.into()),
+                }
+            }
+            "SET" => self.set(session, args).await,
+            "DEL" => {
+                check_arity(name, args, 1, None)?;
+                let mut deleted = 0;
+                for key in args {
+                    let query = DeleteKeyQuery {
+                        keyspace: session.keyspace.clone(),
+                        key: key.clone(),
+                    };
+                    match store.delete_key(session.request(query)).await {
+                        Ok(_) => deleted += 1,
+                        Err(e) if is_key_not_found(&e) => (),
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Ok(Reply::Integer(deleted))
+            }
+            "EXISTS" => {
+                check_arity(name, args, 1, None)?;
+                let values = self.get_keys(session, args.to_vec()).await?;
+                Ok(Reply::Integer(values.iter().flatten().count() as i64))
+            }
+            "MGET" => {
+                check_arity(name, args, 1, None)?;
+                let values = self.get_keys(session, args.to_vec()).await?;
+                Ok(Reply::Array(
+                    values
+                        .into_iter()
+                        .map(|value| value.map_or(Reply::Nil, Reply::Bulk))
+                        .collect(),
+                ))
+            }
+            "MSET" => {
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(wrong_arity(name));
+                }
+                let records = args
+                    .chunks(2)
+                    .map(|pair| models::Record {
+                        key: pair[0].clone(),
+                        value: pair[1].clone(),
+                    })
+                    .collect();
+                let query = InsertKeysQuery {
+                    keyspace: session.keyspace.clone(),
+                    records,
+                };
+                store.insert_keys(session.request(query)).await?;
+                Ok(Reply::Status("OK"))
+            }
+            "SCAN" => self.scan(session, args).await,
+            "INFO" => self.info(session, args).await,
+            _ => Err(Reply::Error(format!(
+                "ERR unknown command '{}'",
+                name.to_lowercase()
+            ))),
+        }
+    }
+
+    fn authenticate(&self, session: &mut Session, token: &[u8]) -> Result<(), Reply> {
+        let auth = match &self.auth {
+            Some(auth) => auth,
+            None => {
+                return Err(Reply::error(
+                    "ERR AUTH called without authentication enabled",
+                ))
+            }
+        };
+        let token = text(token)?;
+        auth.authenticate(&request(Some(&token), ()))
+            .map_err(|e| Reply::Error(format!("WRONGPASS {}", e.message())))?;
+        session.token = Some(token);
+        Ok(())
+    }
+
+    /// `HELLO [protover [AUTH username token] [SETNAME name]]`
+    fn hello(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let mut protocol = session.protocol;
+        let mut options = args.iter();
+        if let Some(version) = options.next() {
+            protocol = match integer(version) {
+                Ok(version @ 2..=3) => version as u8,
+                _ => return Err(Reply::error("NOPROTO unsupported protocol version")),
+            };
+        }
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"AUTH" => {
+                    let token = options.nth(1).ok_or_else(syntax_error)?;
+                    self.authenticate(session, token)?;
+                }
+                b"SETNAME" => {
+                    session.name = Some(text(options.next().ok_or_else(syntax_error)?)?);
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+        if self.auth.is_some() && session.token.is_none() {
+            return Err(Reply::error("NOAUTH HELLO must be called with AUTH"));
+        }
+
+        session.protocol = protocol;
+        let text = |s: &str| Reply::Bulk(s.as_bytes().to_vec());
+        Ok(Reply::Map(vec![
+            ("server", text("dumpstors")),
+            ("version", text(env!("CARGO_PKG_VERSION"))),
+            ("proto", Reply::Integer(protocol as i64)),
+            ("id", Reply::Integer(session.id as i64)),
+            ("mode", text("standalone")),
+            ("role", text("master")),
+            ("modules", Reply::Array(vec![])),
+        ]))
+    }
+
+    fn client(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        check_arity("CLIENT", args, 1, None)?;
+        let subcommand = String::from_utf8_lossy(&args[0]).to_uppercase();
+        match (subcommand.as_str(), &args[1..]) {
+            ("ID", []) => Ok(Reply::Integer(session.id as i64)),
+            ("GETNAME", []) => Ok(session
+                .name
+                .as_ref()
+                .map_or(Reply::Nil, |name| Reply::Bulk(name.as_bytes().to_vec()))),
+            ("SETNAME", [name]) => {
+                let name = text(name)?;
+                if name.contains(' ') {
+                    return Err(Reply::error("ERR Client names cannot contain spaces"));
+                }
+                session.name = Some(name).filter(|name| !name.is_empty());
+                Ok(Reply::Status("OK"))
+            }
+            // Sent by client libraries on connection, and ignored.
+            ("SETINFO", [_, _]) => Ok(Reply::Status("OK")),
+            ("ID", _) | ("GETNAME", _) | ("SETNAME", _) | ("SETINFO", _) => {
+                Err(wrong_arity(&format!("client|{}", subcommand)))
+            }
+            _ => Err(Reply::Error(format!(
+                "ERR unknown subcommand '{}'",
+                subcommand.to_lowercase()
+            ))),
+        }
+    }
+
+    /// `SET key value [NX|XX]`. The `NX` and `XX` conditions are checked by
+    /// the store as it writes the key. Expiries are not supported, the store
+    /// having no way to keep them along with the value.
+    async fn set(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        check_arity("SET", args, 2, None)?;
+        let mut condition = None;
+        for option in &args[2..] {
+            match option.to_ascii_uppercase().as_slice() {
+                b"NX" if condition.is_none() => condition = Some(InsertCondition::IfMissing),
+                b"XX" if condition.is_none() => condition = Some(InsertCondition::IfExists),
+                b"EX" | b"PX" | b"EXAT" | b"PXAT" | b"KEEPTTL" | b"GET" => {
+                    return Err(unsupported("SET", option))
+                }
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let key = args[0].clone();
+        let query = InsertKeyQuery {
+            keyspace: session.keyspace.clone(),
+            record: Some(models::Record {
+                key: key.clone(),
+                value: args[1].clone(),
+            }),
+            condition: condition.unwrap_or(InsertCondition::Always) as i32,
+        };
+        match self.store.insert_key(session.request(query)).await {
+            Ok(_) => (),
+            Err(e) if is_condition_not_met(&e) => return Ok(Reply::Nil),
+            Err(e) => return Err(e.into()),
+        }
+        Ok(Reply::Status("OK"))
+    }
+
+    /// Values of the keys of the current keyspace, `None` for missing ones.
+    async fn get_keys(
+        &self,
+        session: &Session,
+        keys: Vec<Vec<u8>>,
+    ) -> Result<Vec<Option<Vec<u8>>>, Status> {
+        let query = GetKeysQuery {
+            keyspace: session.keyspace.clone(),
+            keys,
+        };
+        let mut results = self
+            .store
+            .get_keys(session.request(query))
+            .await?
+            .into_inner();
+        let mut values = vec![];
+        while let Some(result) = results.next().await {
+            let result = result?;
+            let found = result.found;
+            values.push(Some(result.value).filter(|_| found));
+        }
+        Ok(values)
+    }
+
+    /// `SCAN cursor [MATCH pattern] [COUNT count]`, going through the buckets
+    /// of the keyspace Merkle tree, about `count` keys at a time. Only the
+    /// keys stored on this node are listed.
+    async fn scan(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        check_arity("SCAN", args, 1, None)?;
+        let cursor = integer(&args[0])?;
+        if cursor < 0 || cursor as u64 >= SCAN_BUCKETS {
+            return Err(Reply::error("ERR invalid cursor"));
+        }
+        let cursor = cursor as u64;
+        let mut pattern = None;
+        let mut count = DEFAULT_SCAN_COUNT;
+        let mut options = args[1..].iter();
+        while let Some(option) = options.next() {
+            match option.to_ascii_uppercase().as_slice() {
+                b"MATCH" => pattern = Some(options.next().ok_or_else(syntax_error)?.clone()),
+                b"COUNT" => {
+                    count = match integer(options.next().ok_or_else(syntax_error)?)? {
+                        count if count > 0 => count as u64,
+                        _ => return Err(syntax_error()),
+                    }
+                }
+                b"TYPE" => return Err(unsupported("SCAN", option)),
+                _ => return Err(syntax_error()),
+            }
+        }
+
+        let query = GetKeyspaceUsageQuery {
+            keyspace: session.keyspace.clone(),
+        };
+        let usage = self
+            .store
+            .get_keyspace_usage(session.request(query))
+            .await?
+            .into_inner();
+        // Buckets expected to hold `count` keys.
+        let span = (count.saturating_mul(SCAN_BUCKETS) / usage.keys.max(1))
+            .clamp(1, SCAN_BUCKETS - cursor);
+        let query = GetKeyRangesQuery {
+            keyspace: session.keyspace.clone(),
+            depth: SCAN_DEPTH,
+            buckets: (cursor..cursor + span).map(|b| b as u32).collect(),
+        };
+        let mut records = self
+            .store
+            .get_key_ranges(session.request(query))
+            .await?
+            .into_inner();
+        let mut keys = vec![];
+        while let Some(record) = records.next().await {
+            let key = record?.key;
+            if pattern.as_ref().is_none_or(|p| glob_matches(p, &key)) {
+                keys.push(Reply::Bulk(key));
+            }
+        }
+
+        let next = match cursor + span {
+            SCAN_BUCKETS => 0,
+            next => next,
+        };
+        Ok(Reply::Array(vec![
+            Reply::Bulk(next.to_string().into_bytes()),
+            Reply::Array(keys),
+        ]))
+    }
+
+    /// `INFO [section ...]`, with the `server` and `keyspace` sections.
+    async fn info(&self, session: &mut Session, args: &[Vec<u8>]) -> Result<Reply, Reply> {
+        let sections: Vec<String> = args
+            .iter()
+            .map(|arg| String::from_utf8_lossy(arg).to_lowercase())
+            .collect();
+        let shows = |section: &str| {
+            sections.is_empty()
+                || sections
+                    .iter()
+                    .any(|s| s == section || s == "all" || s == "everything" || s == "default")
+        };
+
+        let mut info = String::new();
+        if shows("server") {
+            info.push_str(&format!(
+                "# Server\r\nredis_version:{}\r\ndumpstors_version:{}\r\nredis_mode:standalone\r\n",
+                REDIS_VERSION,
+                env!("CARGO_PKG_VERSION")
+            ));
+        }
+        if shows("keyspace") {
+            if !info.is_empty() {
+                info.push_str("\r\n");
+            }
+            info.push_str("# Keyspace\r\n");
+            let keyspaces = self
+                .store
+                .list_keyspaces(session.request(()))
+                .await?
+                .into_inner()
+                .keyspaces;
+            for ks in keyspaces {
+                let query = GetKeyspaceUsageQuery {
+                    keyspace: ks.name.clone(),
+                };
+                match self.store.get_keyspace_usage(session.request(query)).await {
+                    Ok(usage) => {
+                        info.push_str(&format!("{}:keys={}\r\n", ks.name, usage.get_ref().keys))
+                    }
+                    // Keyspaces the client can not read are left out.
+                    Err(e) if e.code() == Code::PermissionDenied => (),
+                    Err(e) => return Err(e.into()),
+                }
+            }
+        }
+        Ok(Reply::Bulk(info.into_bytes()))
+    }
+}
+
+async fn serve_connection<I>(listener: Listener, mut io: I, remote: SocketAddr, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut session = Session {
+        id: listener.connections.fetch_add(1, Ordering::Relaxed) + 1,
+        remote,
+        keyspace: listener.keyspace.clone(),
+        token: None,
+        name: None,
+        protocol: 2,
+    };
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut out = BytesMut::new();
+    loop {
+        // Pipelined commands are answered together.
+        let mut closing = false;
+        loop {
+            match parse_command(&mut buf, &listener.limits) {
+                Ok(Some(args)) if args.is_empty() => continue,
+                Ok(Some(args)) if args[0].eq_ignore_ascii_case(b"QUIT") => {
+                    Reply::Status("OK").encode(session.protocol, &mut out);
+                    closing = true;
+                    break;
+                }
+                Ok(Some(args)) => {
+                    let reply = listener.execute(&mut session, args).await;
+                    reply.encode(session.protocol, &mut out);
+                }
+                Ok(None) => break,
+                Err(reply) => {
+                    debug!(remote = %remote, "Closing RESP connection: {:?}", reply);
+                    reply.encode(session.protocol, &mut out);
+                    closing = true;
+                    break;
+                }
+            }
+        }
+        if !out.is_empty() {
+            if let Err(e) = io.write_all(&out).await {
+                debug!(remote = %remote, "RESP connection failed: {}", e);
+                return;
+            }
+            out.clear();
+        }
+        if closing {
+            let _ = io.shutdown().await;
+            return;
+        }
+
+        let read = tokio::select! {
+            read = io.read_buf(&mut buf) => read,
+            _ = shutdown.requested() => return,
+        };
+        match read {
+            Ok(0) => return,
+            Ok(_) => (),
+            Err(e) => {
+                debug!(remote = %remote, "RESP connection failed: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Serves Redis clients on `addr` until a shutdown is requested, over TLS
+/// when certificates are given. Fails right away if the address can not be
+/// bound.
+pub fn serve(
+    mut listener: Listener,
+    addr: SocketAddr,
+    tls: Option<Arc<TlsCerts>>,
+    shutdown: Shutdown,
+) -> io::Result<JoinHandle<()>> {
+    let tcp = std::net::TcpListener::bind(addr)?;
+    tcp.set_nonblocking(true)?;
+    let tcp = TcpListener::from_std(tcp)?;
+    let acceptor = tls.map(|certs| TlsAcceptor::from(Arc::new(certs.rustls_config(&[]))));
+    listener.shutdown = shutdown.clone();
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, remote) = tokio::select! {
+                accepted = tcp.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept RESP connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.requested() => break,
+            };
+
+            let listener = listener.clone();
+            let shutdown = shutdown.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(listener, stream, remote, shutdown).await,
+                        Err(e) => debug!(remote = %remote, "TLS handshake failed: {}", e),
+                    },
+                    None => serve_connection(listener, stream, remote, shutdown).await,
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &[u8]) -> Result<Option<Vec<Vec<u8>>>, Reply> {
+        parse_command(&mut BytesMut::from(input), &Limits::default())
+    }
+
+    fn encode(reply: Reply, protocol: u8) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        reply.encode(protocol, &mut out);
+        out.to_vec()
+    }
+
+    #[test]
+    fn commands_are_parsed() {
+        let args = |args: &[&[u8]]| Some(args.iter().map(|a| a.to_vec()).collect());
+        assert_eq!(
+            parse(b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$4\r\nv\r\nv\r\n").unwrap(),
+            args(&[b"SET", b"k", b"v\r\nv"])
+        );
+        assert_eq!(
+            parse(b"PING  hello\r\n").unwrap(),
+            args(&[b"PING", b"hello"])
+        );
+        assert_eq!(parse(b"*2\r\n$3\r\nGET\r\n$1\r\n").unwrap(), None);
+        assert_eq!(parse(b"*2\r\n$3\r\nGET").unwrap(), None);
+        assert!(parse(b"*1\r\n:1\r\n").is_err());
+
+        let limits = Limits {
+            max_message_size: 4,
+            ..Default::default()
+        };
+        let mut buf = BytesMut::from(&b"*2\r\n$3\r\nGET\r\n$2\r\nkk\r\n"[..]);
+        match parse_command(&mut buf, &limits) {
+            Err(Reply::Error(e)) => assert!(e.starts_with("LIMIT_EXCEEDED")),
+            other => panic!("The command should be too large, got {:?}", other),
+        }
+
+        // Pipelined commands are parsed one at a time.
+        let mut buf = BytesMut::from(&b"PING\r\n*1\r\n$4\r\nPING\r\n"[..]);
+        let limits = Limits::default();
+        assert!(parse_command(&mut buf, &limits).unwrap().is_some());
+        assert!(parse_command(&mut buf, &limits).unwrap().is_some());
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn replies_follow_the_protocol_version() {
+        assert_eq!(encode(Reply::Nil, 2), b"$-1\r\n");
+        assert_eq!(encode(Reply::Nil, 3), b"_\r\n");
+        let map = Reply::Map(vec![("proto", Reply::Integer(3))]);
+        assert_eq!(encode(map.clone(), 2), b"*2\r\n$5\r\nproto\r\n:3\r\n");
+        assert_eq!(encode(map, 3), b"%1\r\n$5\r\nproto\r\n:3\r\n");
+
+        let status = Status::from(dumpstors_lib::store::Error::KeyspaceNotFound(String::from(
+            "logs",
+        )));
+        match Reply::from(status) {
+            Reply::Error(e) => assert!(e.starts_with("KEYSPACE_NOT_FOUND ")),
+            other => panic!("Expected an error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn patterns_match_keys() {
+        let cases: &[(&[u8], &[u8], bool)] = &[
+            (b"*", b"anything", true),
+            (b"user:*", b"user:42", true),
+            (b"user:*", b"session:42", false),
+            (b"h?llo", b"hallo", true),
+            (b"h[ae]llo", b"hello", true),
+            (b"h[^e]llo", b"hello", false),
+            (b"h[a-c]llo", b"hbllo", true),
+            (b"*:*:end", b"a:b:c:end", true),
+            (b"a\\*", b"a*", true),
+            (b"a\\*", b"ab", false),
+            (b"[unclosed", b"[unclosed", true),
+        ];
+        for (pattern, key, expected) in cases {
+            assert_eq!(
+                glob_matches(pattern, key),
+                *expected,
+                "{:?} on {:?}",
+                String::from_utf8_lossy(pattern),
+                String::from_utf8_lossy(key)
+            );
+        }
+    }
+}
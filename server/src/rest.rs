@@ -0,0 +1,464 @@
+//! HTTP/JSON gateway to the store, for clients that can not speak gRPC.
+//!
+//! Requests are served by the handlers of the gRPC store service, so that
+//! authorization, validation and errors are the same on both. Failures are
+//! answered with the HTTP status matching their gRPC code and a JSON body
+//! holding the code, message and `ErrorInfo` of the status.
+
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderValue, ACCEPT, CONTENT_TYPE, RETRY_AFTER};
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use http_body::Body as HttpBody;
+use hyper::server::conn::Http;
+use hyper::service::service_fn;
+use hyper::Body;
+use percent_encoding::percent_decode_str;
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_rustls::TlsAcceptor;
+use tonic::metadata::MetadataMap;
+use tonic::{Code, Status};
+use tracing::*;
+
+use dumpstors_lib::models;
+use dumpstors_lib::rpc::{self, Detail};
+use dumpstors_lib::store::store_server::Store as _;
+use dumpstors_lib::store::{
+    DeleteKeyQuery, DeleteKeyspaceQuery, GetKeyQuery, GetKeyspaceQuery, InsertKeyQuery, Limits,
+    TruncateKeyspaceQuery,
+};
+
+use super::auth::Authenticator;
+use super::ratelimit::{Client, RateLimiter, CLIENT_ID_HEADER};
+use super::shutdown::Shutdown;
+use super::store::DumpstorsStoreServer;
+use super::tls::TlsCerts;
+
+const JSON: &str = "application/json";
+const OCTET_STREAM: &str = "application/octet-stream";
+
+/// HTTP status answering a gRPC code, as mapped by grpc-gateway.
+fn http_status(code: Code) -> StatusCode {
+    match code {
+        Code::Ok => StatusCode::OK,
+        Code::Cancelled => StatusCode::from_u16(499).expect("499 is a valid status"),
+        Code::InvalidArgument | Code::FailedPrecondition | Code::OutOfRange => {
+            StatusCode::BAD_REQUEST
+        }
+        Code::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+        Code::NotFound => StatusCode::NOT_FOUND,
+        Code::AlreadyExists | Code::Aborted => StatusCode::CONFLICT,
+        Code::PermissionDenied => StatusCode::FORBIDDEN,
+        Code::Unauthenticated => StatusCode::UNAUTHORIZED,
+        Code::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+        Code::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+        Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    }
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, JSON)
+        .body(Body::from(body.to_string()))
+        .expect("The response is valid")
+}
+
+fn error_response(http: StatusCode, status: &Status) -> Response<Body> {
+    let mut body = json!({
+        "code": status.code() as i32,
+        "message": status.message(),
+    });
+    if let Some(info) = rpc::error_info(status) {
+        body["reason"] = json!(info.reason);
+        body["metadata"] = json!(info.metadata);
+    }
+    let mut response = json_response(http, body);
+
+    let retry_after = status
+        .metadata()
+        .get("retry-after")
+        .and_then(|v| HeaderValue::from_bytes(v.as_bytes()).ok())
+        .or_else(|| {
+            rpc::details(status).into_iter().find_map(|d| match d {
+                // Whole seconds, rounded up.
+                Detail::RetryInfo(info) => info
+                    .retry_delay
+                    .map(|d| HeaderValue::from(d.seconds.max(0) + (d.nanos > 0) as i64)),
+                _ => None,
+            })
+        });
+    if let Some(retry_after) = retry_after {
+        response.headers_mut().insert(RETRY_AFTER, retry_after);
+    }
+    response
+}
+
+#[derive(Debug, PartialEq)]
+enum Route {
+    Keyspaces,
+    Keyspace(String),
+    Truncate(String),
+    Key(String, Vec<u8>),
+}
+
+impl Route {
+    #[allow(clippy::result_large_err)]
+    fn parse(path: &str) -> Result<Self, Status> {
+        let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+        let keyspace = |segment: &str| {
+            percent_decode_str(segment)
+                .decode_utf8()
+                .map(|name| name.into_owned())
+                .map_err(|_| Status::invalid_argument("Keyspace names must be valid UTF-8"))
+        };
+        match segments[..] {
+            ["v1", "keyspaces"] => Ok(Route::Keyspaces),
+            ["v1", "keyspaces", ks] => Ok(Route::Keyspace(keyspace(ks)?)),
+            ["v1", "keyspaces", ks, "truncate"] => Ok(Route::Truncate(keyspace(ks)?)),
+            ["v1", "keyspaces", ks, "keys", key] => {
+                Ok(Route::Key(keyspace(ks)?, percent_decode_str(key).collect()))
+            }
+            _ => Err(Status::not_found(format!("No route for '{}'", path))),
+        }
+    }
+
+    fn keyspace(&self) -> Option<&str> {
+        match self {
+            Route::Keyspaces => None,
+            Route::Keyspace(ks) | Route::Truncate(ks) | Route::Key(ks, _) => Some(ks),
+        }
+    }
+}
+
+/// Gateway serving REST requests with the gRPC store handlers.
+#[derive(Clone)]
+pub struct Gateway {
+    store: Arc<DumpstorsStoreServer>,
+    auth: Option<Arc<Authenticator>>,
+    limiter: Arc<RateLimiter>,
+    limits: Limits,
+}
+
+impl Gateway {
+    pub(crate) fn new(
+        store: DumpstorsStoreServer,
+        limits: Limits,
+        limiter: Arc<RateLimiter>,
+    ) -> Self {
+        Self {
+            store: Arc::new(store),
+            auth: None,
+            limiter,
+            limits,
+        }
+    }
+
+    /// Rejects requests without a valid token, as the store interceptor does.
+    pub fn with_auth(mut self, auth: Arc<Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    async fn handle(&self, remote: SocketAddr, req: Request<Body>) -> Response<Body> {
+        let span = info_span!("rest", method = %req.method(), path = %req.uri().path());
+        let response = self.serve(remote, req).instrument(span.clone()).await;
+        match response {
+            Ok(response) => response,
+            Err((http, status)) => {
+                span.in_scope(
+                    || debug!(code = ?status.code(), "Request failed: {}", status.message()),
+                );
+                error_response(http, &status)
+            }
+        }
+    }
+
+    async fn serve(
+        &self,
+        remote: SocketAddr,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, (StatusCode, Status)> {
+        let failed = |status: Status| (http_status(status.code()), status);
+        let (parts, body) = req.into_parts();
+        let route = Route::parse(parts.uri.path()).map_err(failed)?;
+        let body = self.read_body(body).await.map_err(failed)?;
+
+        let id = parts
+            .headers
+            .get(CLIENT_ID_HEADER)
+            .and_then(|id| id.to_str().ok());
+        let client = Client::new(id, Some(remote.ip()));
+        self.limiter
+            .check(&client, route.keyspace(), body.len() as u64)
+            .map_err(|rejection| failed(rejection.into()))?;
+
+        let headers = &parts.headers;
+        if let Some(auth) = &self.auth {
+            auth.authenticate(&request(headers, ())).map_err(failed)?;
+        }
+        self.dispatch(&parts.method, route, headers, body)
+            .await
+            .map_err(failed)?
+            .ok_or_else(|| {
+                let status = Status::unimplemented(format!(
+                    "{} is not supported on '{}'",
+                    parts.method,
+                    parts.uri.path()
+                ));
+                (StatusCode::METHOD_NOT_ALLOWED, status)
+            })
+    }
+
+    /// Reads the body, failing as soon as it exceeds the message size limit.
+    async fn read_body(&self, mut body: Body) -> Result<Bytes, Status> {
+        let mut buf = BytesMut::new();
+        while let Some(chunk) = body.data().await {
+            let chunk = chunk.map_err(|e| Status::cancelled(e.to_string()))?;
+            self.limits.check_message_size(buf.len() + chunk.len())?;
+            buf.extend_from_slice(&chunk);
+        }
+        Ok(buf.freeze())
+    }
+
+    /// Serves the route with the store handlers, `None` when the method does
+    /// not apply to it.
+    async fn dispatch(
+        &self,
+        method: &Method,
+        route: Route,
+        headers: &HeaderMap,
+        body: Bytes,
+    ) -> Result<Option<Response<Body>>, Status> {
+        let store = &self.store;
+        let empty = || json_response(StatusCode::OK, json!({}));
+        let response = match (method, route) {
+            (&Method::GET, Route::Keyspaces) => {
+                let keyspaces = store.list_keyspaces(request(headers, ())).await?;
+                let names: Vec<Value> = keyspaces
+                    .into_inner()
+                    .keyspaces
+                    .into_iter()
+                    .map(|ks| json!({ "name": ks.name }))
+                    .collect();
+                json_response(StatusCode::OK, json!({ "keyspaces": names }))
+            }
+            (&Method::GET, Route::Keyspace(keyspace)) => {
+                let ks = store
+                    .get_keyspace(request(headers, GetKeyspaceQuery { keyspace }))
+                    .await?;
+                json_response(StatusCode::OK, json!({ "name": ks.into_inner().name }))
+            }
+            (&Method::PUT, Route::Keyspace(name)) => {
+                store
+                    .create_keyspace(request(headers, models::Keyspace { name }))
+                    .await?;
+                empty()
+            }
+            (&Method::DELETE, Route::Keyspace(keyspace)) => {
+                store
+                    .delete_keyspace(request(headers, DeleteKeyspaceQuery { keyspace }))
+                    .await?;
+                empty()
+            }
+            (&Method::POST, Route::Truncate(keyspace)) => {
+                store
+                    .truncate_keyspace(request(headers, TruncateKeyspaceQuery { keyspace }))
+                    .await?;
+                empty()
+            }
+            (&Method::GET, Route::Key(keyspace, key)) => {
+                let record = store
+                    .get_key(request(headers, GetKeyQuery { keyspace, key }))
+                    .await?
+                    .into_inner();
+                if accepts_json(headers) {
+                    json_response(
+                        StatusCode::OK,
+                        json!({
+                            "key": base64::encode(&record.key),
+                            "value": base64::encode(&record.value),
+                        }),
+                    )
+                } else {
+                    Response::builder()
+                        .header(CONTENT_TYPE, OCTET_STREAM)
+                        .body(Body::from(record.value))
+                        .expect("The response is valid")
+                }
+            }
+            (&Method::PUT, Route::Key(keyspace, key)) => {
+                let value = match is_json(headers) {
+                    true => json_value(&body)?,
+                    false => body.to_vec(),
+                };
+                let query = InsertKeyQuery {
+                    keyspace,
+                    record: Some(models::Record { key, value }),
+                    ..Default::default()
+                };
+                store.insert_key(request(headers, query)).await?;
+                empty()
+            }
+            (&Method::DELETE, Route::Key(keyspace, key)) => {
+                store
+                    .delete_key(request(headers, DeleteKeyQuery { keyspace, key }))
+                    .await?;
+                empty()
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(response))
+    }
+}
+
+/// gRPC request carrying the headers of the HTTP request as metadata, such
+/// as the bearer token.
+fn request<T>(headers: &HeaderMap, message: T) -> tonic::Request<T> {
+    let mut request = tonic::Request::new(message);
+    *request.metadata_mut() = MetadataMap::from_headers(headers.clone());
+    request
+}
+
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains(JSON))
+}
+
+fn is_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with(JSON))
+}
+
+/// Value of a `{"value": "<base64>"}` body.
+#[allow(clippy::result_large_err)]
+fn json_value(body: &[u8]) -> Result<Vec<u8>, Status> {
+    let body: Value = serde_json::from_slice(body)
+        .map_err(|e| Status::invalid_argument(format!("Invalid JSON body: {}", e)))?;
+    let value = body["value"]
+        .as_str()
+        .ok_or_else(|| Status::invalid_argument("The body must hold a base64 'value'"))?;
+    base64::decode(value)
+        .map_err(|e| Status::invalid_argument(format!("Invalid base64 value: {}", e)))
+}
+
+async fn serve_connection<I>(gateway: Gateway, io: I, remote: SocketAddr, shutdown: Shutdown)
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let service = service_fn(move |req| {
+        let gateway = gateway.clone();
+        async move { Ok::<_, Infallible>(gateway.handle(remote, req).await) }
+    });
+    let conn = Http::new().serve_connection(io, service);
+    tokio::pin!(conn);
+
+    let served = tokio::select! {
+        served = &mut conn => served,
+        _ = shutdown.requested() => {
+            // In-flight requests are completed before closing.
+            conn.as_mut().graceful_shutdown();
+            conn.await
+        }
+    };
+    if let Err(e) = served {
+        debug!(remote = %remote, "REST connection failed: {}", e);
+    }
+}
+
+/// Serves the gateway on `addr` until a shutdown is requested, over TLS when
+/// certificates are given. Fails right away if the address can not be bound.
+pub fn serve(
+    gateway: Gateway,
+    addr: SocketAddr,
+    tls: Option<Arc<TlsCerts>>,
+    shutdown: Shutdown,
+) -> io::Result<JoinHandle<()>> {
+    let listener = std::net::TcpListener::bind(addr)?;
+    listener.set_nonblocking(true)?;
+    let listener = TcpListener::from_std(listener)?;
+    let acceptor =
+        tls.map(|certs| TlsAcceptor::from(Arc::new(certs.rustls_config(&[b"http/1.1"]))));
+
+    Ok(tokio::spawn(async move {
+        loop {
+            let (stream, remote) = tokio::select! {
+                accepted = listener.accept() => match accepted {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Failed to accept REST connection: {}", e);
+                        continue;
+                    }
+                },
+                _ = shutdown.requested() => break,
+            };
+
+            let gateway = gateway.clone();
+            let shutdown = shutdown.clone();
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                match acceptor {
+                    Some(acceptor) => match acceptor.accept(stream).await {
+                        Ok(stream) => serve_connection(gateway, stream, remote, shutdown).await,
+                        Err(e) => debug!(remote = %remote, "TLS handshake failed: {}", e),
+                    },
+                    None => serve_connection(gateway, stream, remote, shutdown).await,
+                }
+            });
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        assert_eq!(Route::parse("/v1/keyspaces").unwrap(), Route::Keyspaces);
+        assert_eq!(
+            Route::parse("/v1/keyspaces/logs%2Dold/truncate").unwrap(),
+            Route::Truncate(String::from("logs-old"))
+        );
+        assert_eq!(
+            Route::parse("/v1/keyspaces/logs/keys/a%2Fb%00").unwrap(),
+            Route::Key(String::from("logs"), b"a/b\0".to_vec())
+        );
+        for path in ["/", "/v1/keyspaces/logs/keys", "/v2/keyspaces"] {
+            assert_eq!(Route::parse(path).unwrap_err().code(), Code::NotFound);
+        }
+        assert_eq!(
+            Route::parse("/v1/keyspaces/%FF").unwrap_err().code(),
+            Code::InvalidArgument
+        );
+    }
+
+    #[test]
+    fn errors_keep_their_details() {
+        let status = Status::from(dumpstors_lib::store::Error::KeyspaceNotFound(String::from(
+            "logs",
+        )));
+        let response = error_response(http_status(status.code()), &status);
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers()[CONTENT_TYPE], JSON);
+
+        let status = Status::from(dumpstors_lib::store::Error::IoErr(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "timed out",
+        )));
+        let response = error_response(http_status(status.code()), &status);
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(response.headers()[RETRY_AFTER], "1");
+    }
+}
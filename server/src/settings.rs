@@ -1,25 +1,917 @@
-use config::{Config, ConfigError};
+use config::{Config, ConfigError, File};
+use std::collections::BTreeMap;
+use std::net::{IpAddr, SocketAddr};
+use structopt::StructOpt;
 
-#[derive(Debug, Deserialize)]
+/// Command-line flags of the `dumpstors` binary. They take precedence over
+/// `DUMPSTORS_*` environment variables, which take precedence over the
+/// configuration file.
+#[derive(Debug, Default, StructOpt)]
+#[structopt(name = "dumpstors")]
+pub struct Args {
+    /// Path to a TOML, YAML or JSON configuration file
+    #[structopt(short, long)]
+    pub config: Option<String>,
+
+    #[structopt(long)]
+    pub listen_addr: Option<String>,
+
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+
+    #[structopt(long)]
+    pub store_path: Option<String>,
+
+    /// Refuse to start if a keyspace fails to load, instead of quarantining it
+    #[structopt(long)]
+    pub strict_recovery: bool,
+
+    /// Validate and print the effective configuration, then exit
+    #[structopt(long)]
+    pub check_config: bool,
+
+    /// Print a token for this subject signed with `auth.secret`, then exit
+    #[structopt(long)]
+    pub issue_token: Option<String>,
+
+    /// Roles of the issued token
+    #[structopt(long, use_delimiter = true)]
+    pub roles: Vec<String>,
+
+    /// Lifetime of the issued token, which never expires if unset
+    #[structopt(long)]
+    pub ttl_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Store {
     pub path: String,
+    /// Refuse to start if a keyspace fails to load. Such keyspaces are left
+    /// in place, rather than moved to the `.quarantine` directory.
+    #[serde(default)]
+    pub strict_recovery: bool,
+    /// Reject writes until switched off through the admin API.
+    #[serde(default)]
+    pub read_only: bool,
+    #[serde(default)]
+    pub watermarks: Watermarks,
+}
+
+/// Disk usage thresholds of the volume holding the store, as fractions of
+/// its size.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Watermarks {
+    /// Usage above which warnings are logged.
+    #[serde(default = "Watermarks::default_low")]
+    pub low: f64,
+    /// Usage above which writes are rejected. Reads and deletes are still
+    /// served so that space can be reclaimed.
+    #[serde(default = "Watermarks::default_high")]
+    pub high: f64,
+    #[serde(default = "Watermarks::default_check_interval_secs")]
+    pub check_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Member {
+    pub id: u64,
+    pub addr: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Cluster {
+    pub node_id: u64,
+    pub path: String,
+    #[serde(default = "Cluster::default_heartbeat_interval_ms")]
+    pub heartbeat_interval_ms: u64,
+    #[serde(default = "Cluster::default_election_timeout_ms")]
+    pub election_timeout_ms: u64,
+    #[serde(default = "Cluster::default_snapshot_threshold")]
+    pub snapshot_threshold: u64,
+    /// Bytes of a snapshot sent to a lagging member in each request.
+    #[serde(default = "Cluster::default_snapshot_chunk_size")]
+    pub snapshot_chunk_size: u64,
+    /// Initial members of the cluster, used when the raft log is empty.
+    /// Leave it empty on a node that will join through `AddMember`.
+    #[serde(default)]
+    pub members: Vec<Member>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Sharding {
+    pub node_id: u64,
+    #[serde(default = "Sharding::default_version")]
+    pub version: u64,
+    #[serde(default = "Sharding::default_vnodes")]
+    pub vnodes: u32,
+    /// Nodes sharing the keys, this node included. Leave it empty on a node
+    /// that will be added through `UpdateClusterTopology`.
+    #[serde(default)]
+    pub nodes: Vec<Member>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tls {
+    pub cert_path: String,
+    pub key_path: String,
+    /// CA verifying client certificates. When set, clients must present a
+    /// certificate signed by it.
+    pub client_ca_path: Option<String>,
+    /// CA verifying the other nodes of a cluster, `client_ca_path` if unset.
+    pub peer_ca_path: Option<String>,
+    /// Interval at which certificate files are checked for changes.
+    #[serde(default = "Tls::default_reload_interval_secs")]
+    pub reload_interval_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Admin,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Grant {
+    /// Keyspace name, or pattern in which `*` matches any characters.
+    pub keyspaces: String,
+    pub permission: Permission,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Token {
+    pub subject: String,
+    pub token: String,
+    pub roles: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Auth {
+    /// Secret verifying HMAC-signed tokens, which are rejected when unset.
+    pub secret: Option<String>,
+    /// Token this node uses to reach the other nodes of a cluster or of a
+    /// sharded cluster. It holds the admin permission on every keyspace.
+    pub peer_token: Option<String>,
+    /// Grants of each role. Role names are case insensitive.
+    #[serde(default)]
+    pub roles: BTreeMap<String, Vec<Grant>>,
+    #[serde(default)]
+    pub tokens: Vec<Token>,
+}
+
+/// Limits of store requests, unlimited when 0. Clients read them through
+/// `GetLimits` to split their batches.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Limits {
+    /// Size of an encoded request message, in bytes.
+    #[serde(default = "Limits::default_max_message_size")]
+    pub max_message_size: u64,
+    /// Records or keys of a batch request.
+    #[serde(default = "Limits::default_max_batch_records")]
+    pub max_batch_records: u64,
+    #[serde(default = "Limits::default_max_key_size")]
+    pub max_key_size: u64,
+    #[serde(default = "Limits::default_max_value_size")]
+    pub max_value_size: u64,
+    /// Keys and values of a staged bulk insert, published in a single write
+    /// built in memory, in bytes.
+    #[serde(default = "Limits::default_max_staged_bytes")]
+    pub max_staged_bytes: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimit {
+    /// Client ID, client IP address or keyspace the limit applies to. `*`
+    /// applies to each of those without a limit of their own.
+    pub name: String,
+    /// Requests per second, unlimited when 0.
+    #[serde(default)]
+    pub requests_per_sec: u64,
+    /// Bytes of request messages per second, unlimited when 0.
+    #[serde(default)]
+    pub bytes_per_sec: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RateLimits {
+    /// Limits of clients, identified by their `client-id` header or else by
+    /// their IP address.
+    #[serde(default)]
+    pub clients: Vec<RateLimit>,
+    #[serde(default)]
+    pub keyspaces: Vec<RateLimit>,
+}
+
+/// Endpoint serving the gRPC services besides `listen_addr` and `port`,
+/// either a TCP address or a Unix domain socket.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Listener {
+    /// TCP address, such as `10.0.0.5:4242`.
+    pub addr: Option<String>,
+    /// Unix domain socket, such as `/run/dumpstors/dumpstors.sock`. A stale
+    /// socket left at the path is replaced.
+    pub path: Option<String>,
+    /// Permissions of the socket file, in octal.
+    #[serde(default = "Listener::default_mode")]
+    pub mode: String,
+}
+
+impl Listener {
+    fn default_mode() -> String {
+        String::from("660")
+    }
+
+    /// Permissions of the socket file, if `mode` is valid.
+    pub fn socket_mode(&self) -> Option<u32> {
+        u32::from_str_radix(&self.mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o777)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Metrics {
+    /// Port serving `/metrics` on `listen_addr`.
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Rest {
+    /// Port serving the HTTP/JSON gateway on `listen_addr`, over TLS when
+    /// `tls` is set.
+    pub port: u16,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrpcWeb {
+    /// Port serving gRPC-Web over HTTP/1.1 on `listen_addr`, over TLS when
+    /// `tls` is set.
+    pub port: u16,
+    /// Origins allowed to call the server from a browser, such as
+    /// `https://admin.example.com`, or `*` for any.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Resp {
+    /// Port serving the Redis protocol on `listen_addr`, over TLS when `tls`
+    /// is set.
+    pub port: u16,
+    /// Keyspace of new connections, until they `SELECT` another one.
+    #[serde(default = "Resp::default_keyspace")]
+    pub keyspace: String,
 }
 
-#[derive(Debug, Deserialize)]
+impl Resp {
+    fn default_keyspace() -> String {
+        String::from("0")
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Memcached {
+    /// Port serving the memcached text protocol on `listen_addr`, over TLS
+    /// when `tls` is set.
+    pub port: u16,
+    /// Keyspace holding the items, created when missing.
+    pub keyspace: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Json,
+    Text,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Logging {
+    #[serde(default = "Logging::default_format")]
+    pub format: LogFormat,
+    /// Filter directives such as `info,dumpstors=debug`. `RUST_LOG` takes
+    /// precedence when set.
+    #[serde(default = "Logging::default_filter")]
+    pub filter: String,
+    /// OpenTelemetry collector receiving request spans over OTLP/gRPC, such
+    /// as `http://localhost:4317`.
+    pub otlp_endpoint: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct Settings {
     pub listen_addr: String,
     pub port: u16,
+    /// Additional endpoints, served with the same TLS settings.
+    #[serde(default)]
+    pub listeners: Vec<Listener>,
     pub store: Store,
+    pub tls: Option<Tls>,
+    pub auth: Option<Auth>,
+    pub cluster: Option<Cluster>,
+    pub sharding: Option<Sharding>,
+    pub metrics: Option<Metrics>,
+    pub rest: Option<Rest>,
+    pub grpc_web: Option<GrpcWeb>,
+    pub resp: Option<Resp>,
+    pub memcached: Option<Memcached>,
+    #[serde(default)]
+    pub limits: Limits,
+    /// Initial rate limits, adjustable through the admin API.
+    #[serde(default)]
+    pub rate_limits: RateLimits,
+    #[serde(default)]
+    pub logging: Logging,
+    /// Time given to in-flight requests to complete when shutting down.
+    #[serde(default = "Settings::default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
 }
 
 impl Settings {
+    fn default_shutdown_timeout_secs() -> u64 {
+        30
+    }
+
     pub fn new() -> Result<Self, ConfigError> {
+        Self::load(&Args::default())
+    }
+
+    /// Layers the defaults, the configuration file, the environment and the
+    /// command-line flags, in that order.
+    pub fn load(args: &Args) -> Result<Self, ConfigError> {
+        Self::load_from(args, std::env::vars())
+    }
+
+    /// Loads the settings as `load` does, reading the environment from
+    /// `vars`.
+    fn load_from<I>(args: &Args, vars: I) -> Result<Self, ConfigError>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
         let mut s = Config::new();
 
-        s.set("listen_addr", "0.0.0.0")?;
-        s.set("port", "4242")?;
-        s.set("store.path", "/var/lib/dumpstors/data")?;
+        s.set_default("listen_addr", "0.0.0.0")?;
+        s.set_default("port", "4242")?;
+        s.set_default("store.path", "/var/lib/dumpstors/data")?;
+
+        if let Some(path) = &args.config {
+            s.merge(File::with_name(path))?;
+        }
+
+        // Nested keys are separated by two underscores: DUMPSTORS_STORE__PATH.
+        for (name, value) in vars {
+            let name = name.to_lowercase();
+            if let Some(key) = name.strip_prefix("dumpstors_") {
+                s.set(&key.replace("__", "."), value)?;
+            }
+        }
+
+        if let Some(listen_addr) = &args.listen_addr {
+            s.set("listen_addr", listen_addr.as_str())?;
+        }
+        if let Some(port) = args.port {
+            s.set("port", port.to_string())?;
+        }
+        if let Some(path) = &args.store_path {
+            s.set("store.path", path.as_str())?;
+        }
+        if args.strict_recovery {
+            s.set("store.strict_recovery", true)?;
+        }
+
+        let settings: Self = s.try_into()?;
+        settings.validate()?;
+        Ok(settings)
+    }
+
+    /// Hides the secrets and tokens, to print the settings.
+    pub fn redacted(mut self) -> Self {
+        let redact = |_| String::from("<redacted>");
+        if let Some(auth) = self.auth.as_mut() {
+            auth.secret = auth.secret.take().map(redact);
+            auth.peer_token = auth.peer_token.take().map(redact);
+            for token in auth.tokens.iter_mut() {
+                token.token = redact(std::mem::take(&mut token.token));
+            }
+        }
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |msg: &str| Err(ConfigError::Message(msg.to_string()));
+
+        if self.listen_addr.parse::<IpAddr>().is_err() {
+            return invalid("listen_addr must be an IP address");
+        }
+        for (i, listener) in self.listeners.iter().enumerate() {
+            match (&listener.addr, &listener.path) {
+                (Some(addr), None) if addr.parse::<SocketAddr>().is_err() => {
+                    return invalid("listeners addr must be an IP address and port");
+                }
+                (Some(_), None) => (),
+                (None, Some(path)) => {
+                    if !cfg!(unix) {
+                        return invalid("Unix domain sockets are not supported on this platform");
+                    }
+                    if path.is_empty() {
+                        return invalid("listeners path must not be empty");
+                    }
+                    if self.listeners[..i]
+                        .iter()
+                        .any(|l| l.path.as_ref() == Some(path))
+                    {
+                        return invalid("listeners paths must differ");
+                    }
+                    if listener.socket_mode().is_none() {
+                        return invalid("listeners mode must be octal permissions such as 660");
+                    }
+                }
+                _ => return invalid("listeners must set either addr or path"),
+            }
+        }
+        if self.store.path.is_empty() {
+            return invalid("store.path must not be empty");
+        }
+        let watermarks = &self.store.watermarks;
+        if !(0.0..=1.0).contains(&watermarks.low) || !(0.0..=1.0).contains(&watermarks.high) {
+            return invalid("store.watermarks must be between 0 and 1");
+        }
+        if watermarks.low > watermarks.high {
+            return invalid("store.watermarks.low must not exceed the high watermark");
+        }
+        if watermarks.check_interval_secs == 0 {
+            return invalid("store.watermarks.check_interval_secs must be positive");
+        }
+        let limits = &self.rate_limits;
+        if limits
+            .clients
+            .iter()
+            .chain(limits.keyspaces.iter())
+            .any(|l| l.name.is_empty())
+        {
+            return invalid("rate_limits names must not be empty");
+        }
+        if self.cluster.is_some() && self.sharding.is_some() {
+            return invalid("Sharding can not be combined with cluster mode");
+        }
+        if self.memcached.is_some() && (self.cluster.is_some() || self.sharding.is_some()) {
+            // Items are written to the local keyspace only.
+            return invalid("memcached can not be combined with cluster or sharding mode");
+        }
+        if self.memcached.is_some() && self.auth.is_some() {
+            return invalid("memcached can not be combined with auth, its protocol has none");
+        }
+        if let Some(cluster) = &self.cluster {
+            if cluster.election_timeout_ms <= cluster.heartbeat_interval_ms {
+                return invalid("cluster.election_timeout_ms must exceed the heartbeat interval");
+            }
+            if cluster.snapshot_chunk_size == 0 {
+                return invalid("cluster.snapshot_chunk_size must be positive");
+            }
+        }
+        let ports = [
+            ("port", Some(self.port)),
+            ("metrics.port", self.metrics.as_ref().map(|m| m.port)),
+            ("rest.port", self.rest.as_ref().map(|r| r.port)),
+            ("grpc_web.port", self.grpc_web.as_ref().map(|g| g.port)),
+            ("resp.port", self.resp.as_ref().map(|r| r.port)),
+            ("memcached.port", self.memcached.as_ref().map(|m| m.port)),
+        ];
+        for (i, (name, port)) in ports.iter().enumerate() {
+            if let Some((other, _)) = ports[..i]
+                .iter()
+                .find(|(_, other)| port.is_some() && other == port)
+            {
+                return invalid(&format!("{} must differ from {}", name, other));
+            }
+        }
+        if let Some(grpc_web) = &self.grpc_web {
+            if grpc_web.allowed_origins.iter().any(|o| o.is_empty()) {
+                return invalid("grpc_web.allowed_origins must not be empty");
+            }
+        }
+        if let Some(tls) = &self.tls {
+            if tls.reload_interval_secs == 0 {
+                return invalid("tls.reload_interval_secs must be positive");
+            }
+        }
+        if let Some(auth) = &self.auth {
+            if (self.cluster.is_some() || self.sharding.is_some()) && auth.peer_token.is_none() {
+                // Nodes authenticate to each other with it.
+                return invalid("auth.peer_token is required in cluster and sharding modes");
+            }
+            if auth.secret.as_deref() == Some("") {
+                return invalid("auth.secret must not be empty");
+            }
+            for token in auth.tokens.iter() {
+                if token.token.is_empty() {
+                    return invalid("auth.tokens must not be empty");
+                }
+                let unknown = token
+                    .roles
+                    .iter()
+                    .find(|role| !auth.roles.keys().any(|r| r.eq_ignore_ascii_case(role)));
+                if let Some(role) = unknown {
+                    return Err(ConfigError::Message(format!(
+                        "Token of '{}' has unknown role '{}'",
+                        token.subject, role
+                    )));
+                }
+            }
+        }
+        if let Some(sharding) = &self.sharding {
+            // A node joining the cluster starts without nodes and receives
+            // the topology through `UpdateClusterTopology`.
+            let joining = sharding.nodes.is_empty();
+            if !joining && !sharding.nodes.iter().any(|n| n.id == sharding.node_id) {
+                return invalid("sharding.nodes must contain this node");
+            }
+            if sharding.vnodes == 0 {
+                return invalid("sharding.vnodes must be positive");
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Store {
+    pub fn new(path: String) -> Self {
+        Self {
+            path,
+            strict_recovery: false,
+            read_only: false,
+            watermarks: Default::default(),
+        }
+    }
+}
+
+impl Watermarks {
+    fn default_low() -> f64 {
+        0.85
+    }
+
+    fn default_high() -> f64 {
+        0.95
+    }
+
+    fn default_check_interval_secs() -> u64 {
+        10
+    }
+}
+
+impl Default for Watermarks {
+    fn default() -> Self {
+        Self {
+            low: Self::default_low(),
+            high: Self::default_high(),
+            check_interval_secs: Self::default_check_interval_secs(),
+        }
+    }
+}
+
+impl Limits {
+    fn default_max_message_size() -> u64 {
+        64 * 1024 * 1024
+    }
+
+    fn default_max_batch_records() -> u64 {
+        10_000
+    }
+
+    fn default_max_key_size() -> u64 {
+        16 * 1024
+    }
+
+    fn default_max_value_size() -> u64 {
+        16 * 1024 * 1024
+    }
+
+    fn default_max_staged_bytes() -> u64 {
+        256 * 1024 * 1024
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_message_size: Self::default_max_message_size(),
+            max_batch_records: Self::default_max_batch_records(),
+            max_key_size: Self::default_max_key_size(),
+            max_value_size: Self::default_max_value_size(),
+            max_staged_bytes: Self::default_max_staged_bytes(),
+        }
+    }
+}
+
+impl Cluster {
+    pub fn new(node_id: u64, members: Vec<Member>, path: String) -> Self {
+        Self {
+            node_id,
+            members,
+            path,
+            heartbeat_interval_ms: Self::default_heartbeat_interval_ms(),
+            election_timeout_ms: Self::default_election_timeout_ms(),
+            snapshot_threshold: Self::default_snapshot_threshold(),
+            snapshot_chunk_size: Self::default_snapshot_chunk_size(),
+        }
+    }
+
+    fn default_heartbeat_interval_ms() -> u64 {
+        50
+    }
+
+    fn default_election_timeout_ms() -> u64 {
+        300
+    }
+
+    fn default_snapshot_threshold() -> u64 {
+        1000
+    }
+
+    fn default_snapshot_chunk_size() -> u64 {
+        1024 * 1024
+    }
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Self {
+            format: Self::default_format(),
+            filter: Self::default_filter(),
+            otlp_endpoint: None,
+        }
+    }
+}
+
+impl Logging {
+    fn default_format() -> LogFormat {
+        LogFormat::Json
+    }
+
+    fn default_filter() -> String {
+        String::from("info")
+    }
+}
+
+impl Tls {
+    pub fn new(cert_path: String, key_path: String) -> Self {
+        Self {
+            cert_path,
+            key_path,
+            client_ca_path: None,
+            peer_ca_path: None,
+            reload_interval_secs: Self::default_reload_interval_secs(),
+        }
+    }
+
+    fn default_reload_interval_secs() -> u64 {
+        60
+    }
+}
+
+impl Sharding {
+    pub fn new(node_id: u64, nodes: Vec<Member>) -> Self {
+        Self {
+            node_id,
+            nodes,
+            version: Self::default_version(),
+            vnodes: Self::default_vnodes(),
+        }
+    }
+
+    fn default_version() -> u64 {
+        1
+    }
+
+    fn default_vnodes() -> u32 {
+        64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use uuid::Uuid;
+
+    fn config_file(content: &str) -> String {
+        let dir = format!(".data/{}", Uuid::new_v4());
+        fs::create_dir_all(&dir).unwrap();
+        let path = format!("{}/dumpstors.toml", dir);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[test]
+    fn defaults() {
+        let settings = Settings::load(&Args::default()).unwrap();
+        assert_eq!(settings.listen_addr, "0.0.0.0");
+        assert_eq!(settings.store.path, "/var/lib/dumpstors/data");
+        assert_eq!(settings.shutdown_timeout_secs, 30);
+        assert_eq!(settings.logging.format, LogFormat::Json);
+        assert_eq!(settings.store.watermarks.high, 0.95);
+        assert_eq!(settings.limits.max_batch_records, 10_000);
+        assert!(settings.cluster.is_none());
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let path = config_file(
+            r#"
+port = 5000
+
+[store]
+path = "/tmp/file"
+
+[cluster]
+node_id = 1
+path = "/tmp/raft"
+members = [{ id = 1, addr = "http://127.0.0.1:5000" }]
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            store_path: Some(String::from("/tmp/flag")),
+            ..Default::default()
+        };
+
+        let settings = Settings::load(&args).unwrap();
+        assert_eq!(settings.port, 5000);
+        assert_eq!(settings.store.path, "/tmp/flag");
+
+        let cluster = settings.cluster.unwrap();
+        assert_eq!(cluster.members.len(), 1);
+        assert_eq!(cluster.heartbeat_interval_ms, 50);
+    }
+
+    #[test]
+    fn environment_overrides_file() {
+        let path = config_file(
+            r#"
+[store]
+path = "/tmp/file"
+strict_recovery = true
+
+[limits]
+max_key_size = 100
+"#,
+        );
+        let vars = || {
+            vec![
+                ("DUMPSTORS_STORE__STRICT_RECOVERY", "false"),
+                ("DUMPSTORS_LIMITS__MAX_KEY_SIZE", "200"),
+                ("OTHER_PORT", "1"),
+            ]
+            .into_iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+        };
+        let mut args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        let settings = Settings::load_from(&args, vars()).unwrap();
+        assert!(!settings.store.strict_recovery);
+        assert_eq!(settings.store.path, "/tmp/file");
+        assert_eq!(settings.limits.max_key_size, 200);
+        assert_eq!(settings.port, 4242);
+
+        args.strict_recovery = true;
+        let settings = Settings::load_from(&args, vars()).unwrap();
+        assert!(settings.store.strict_recovery);
+        assert_eq!(settings.limits.max_key_size, 200);
+    }
+
+    #[test]
+    fn auth_settings() {
+        let path = config_file(
+            r#"
+[auth]
+secret = "secret"
+
+[auth.roles]
+Reader = [{ keyspaces = "logs-*", permission = "read" }]
+
+[[auth.tokens]]
+subject = "ci"
+token = "abc"
+roles = ["Reader"]
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        let settings = Settings::load(&args).unwrap();
+        let auth = settings.auth.clone().unwrap();
+        let grants = auth.roles.values().next().unwrap();
+        assert_eq!(grants[0].permission, Permission::Read);
+        assert_eq!(auth.tokens[0].roles, vec![String::from("Reader")]);
+
+        let printed = toml::Value::try_from(settings.redacted())
+            .unwrap()
+            .to_string();
+        assert!(!printed.contains("\"secret\""), "{}", printed);
+        assert!(!printed.contains("\"abc\""), "{}", printed);
+        assert!(printed.contains("subject = \"ci\""), "{}", printed);
+    }
+
+    #[test]
+    fn invalid_settings() {
+        let path = config_file(
+            r#"
+[sharding]
+node_id = 3
+nodes = [{ id = 1, addr = "http://127.0.0.1:5000" }]
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        match Settings::load(&args) {
+            Err(ConfigError::Message(_)) => (),
+            _ => panic!("A shard node must be part of the topology"),
+        };
+
+        let path = config_file(
+            r#"
+[store.watermarks]
+low = 0.9
+high = 0.8
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        match Settings::load(&args) {
+            Err(ConfigError::Message(_)) => (),
+            _ => panic!("The low watermark must not exceed the high one"),
+        };
+
+        let path = config_file(
+            r#"
+[rest]
+port = 8080
+
+[memcached]
+port = 8080
+keyspace = "cache"
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        match Settings::load(&args) {
+            Err(ConfigError::Message(msg)) => {
+                assert_eq!(msg, "memcached.port must differ from rest.port")
+            }
+            _ => panic!("Listeners must not share a port"),
+        };
+
+        let path = config_file(
+            r#"
+[[listeners]]
+path = "/run/dumpstors.sock"
+mode = "rw"
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
+
+        match Settings::load(&args) {
+            Err(ConfigError::Message(msg)) => assert!(msg.contains("octal"), "{}", msg),
+            _ => panic!("Socket permissions must be octal"),
+        };
+
+        let path = config_file(
+            r#"
+[auth]
+secret = "secret"
+
+[cluster]
+node_id = 1
+path = "/tmp/raft"
+members = [{ id = 1, addr = "http://127.0.0.1:5000" }]
+"#,
+        );
+        let args = Args {
+            config: Some(path),
+            ..Default::default()
+        };
 
-        s.try_into()
+        match Settings::load(&args) {
+            Err(ConfigError::Message(msg)) => assert!(msg.contains("peer_token"), "{}", msg),
+            _ => panic!("Members must authenticate to each other"),
+        };
     }
 }
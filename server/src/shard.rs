@@ -0,0 +1,637 @@
+//! Hash partitioning of keys across the nodes of a sharded cluster.
+//!
+//! Every node knows the cluster topology and forwards requests for keys it
+//! does not own to their owner, so clients may talk to any node. Keyspaces
+//! exist on every node: keyspace operations are broadcast to all of them.
+//!
+//! When the topology changes, each node moves the keys it no longer owns to
+//! their new owner in the background, retrying failed batches with a backoff.
+//! Until every node reports having moved its keys, reads missing on the new
+//! owner fall back to the owners in the previous topologies and deletes are
+//! sent to all of them, so the cluster keeps serving requests during the move.
+
+use futures::Future;
+use std::collections::HashMap;
+use std::result::Result as StdResult;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::time::sleep;
+use tonic::metadata::MetadataValue;
+use tonic::transport::{Channel, ClientTlsConfig, Endpoint};
+use tonic::{Code, Request, Response, Status};
+use tracing::*;
+
+use dumpstors_lib::models::{ClusterTopology, Keyspace, Record, ShardNode};
+use dumpstors_lib::ring::HashRing;
+use dumpstors_lib::store::store_client::StoreClient;
+use dumpstors_lib::store::{GetKeyQuery, InsertKeysQuery, Limits, Store};
+
+use super::auth::token_interceptor;
+use super::health::Health;
+use super::settings;
+use super::shutdown::Shutdown;
+use super::store::blocking;
+
+/// Marks requests sent by another node, which must be served locally.
+pub const FORWARDED_METADATA_KEY: &str = "x-dumpstors-forwarded";
+
+/// Marks inserts moving keys to their new owner, which never overwrite data.
+pub const MIGRATION_METADATA_KEY: &str = "x-dumpstors-migration";
+
+const MIGRATION_BATCH_SIZE: usize = 500;
+
+/// Delays between attempts to reach another node while moving keys, doubled
+/// after each failure.
+const MIGRATION_MIN_BACKOFF: Duration = Duration::from_millis(100);
+const MIGRATION_MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+pub fn is_forwarded<T>(request: &Request<T>) -> bool {
+    request.metadata().get(FORWARDED_METADATA_KEY).is_some()
+}
+
+pub fn is_migration<T>(request: &Request<T>) -> bool {
+    request.metadata().get(MIGRATION_METADATA_KEY).is_some()
+}
+
+/// Wraps a message into a request that the receiving node serves locally.
+pub fn forwarded<T>(message: T) -> Request<T> {
+    let mut request = Request::new(message);
+    request
+        .metadata_mut()
+        .insert(FORWARDED_METADATA_KEY, MetadataValue::from_static("1"));
+    request
+}
+
+/// Wraps a message into a request that moves keys between owners.
+pub fn migration<T>(message: T) -> Request<T> {
+    let mut request = forwarded(message);
+    request
+        .metadata_mut()
+        .insert(MIGRATION_METADATA_KEY, MetadataValue::from_static("1"));
+    request
+}
+
+struct Topologies {
+    current: ClusterTopology,
+    ring: HashRing,
+    // Rings keys may still be moved out of, oldest first, with the version
+    // of the topology that replaced them.
+    previous: Vec<(u64, HashRing)>,
+}
+
+pub struct ShardRouter {
+    node_id: u64,
+    store: Arc<Store>,
+    // Both are only ever replaced or extended as a whole, so they are still
+    // consistent when a holder panicked and poisoned the lock.
+    topologies: RwLock<Topologies>,
+    clients: Mutex<HashMap<String, StoreClient<Channel>>>,
+    tls: Option<ClientTlsConfig>,
+    token: Option<String>,
+    health: Health,
+    // Newest topology version whose keys this node finished moving.
+    migrated: AtomicU64,
+    // Last failure to move the keys of the current topology.
+    migration_error: Mutex<Option<String>>,
+    // Moves the keys of one topology at a time.
+    migration: tokio::sync::Mutex<()>,
+}
+
+impl ShardRouter {
+    pub fn new(conf: settings::Sharding, store: Arc<Store>) -> Self {
+        let current = ClusterTopology {
+            version: conf.version,
+            vnodes: conf.vnodes,
+            nodes: conf
+                .nodes
+                .into_iter()
+                .map(|n| ShardNode {
+                    id: n.id,
+                    addr: n.addr,
+                })
+                .collect(),
+        };
+
+        Self {
+            node_id: conf.node_id,
+            store,
+            migrated: AtomicU64::new(current.version),
+            migration_error: Mutex::new(None),
+            topologies: RwLock::new(Topologies {
+                ring: HashRing::new(&current),
+                current,
+                previous: vec![],
+            }),
+            clients: Mutex::new(HashMap::new()),
+            tls: None,
+            token: None,
+            health: Health::new(Shutdown::new()),
+            migration: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Connects to the other nodes over TLS.
+    pub fn with_tls(mut self, tls: Option<ClientTlsConfig>) -> Self {
+        self.tls = tls;
+        self
+    }
+
+    /// Authenticates to the other nodes with this token.
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Reports unrecoverable storage errors to the server's health state.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    pub fn topology(&self) -> ClusterTopology {
+        self.topologies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .current
+            .clone()
+    }
+
+    fn remote(&self, ring: &HashRing, keyspace: &str, key: &[u8]) -> Option<ShardNode> {
+        ring.node_for(keyspace, key)
+            .filter(|node| node.id != self.node_id)
+            .cloned()
+    }
+
+    /// Returns the node owning a key, or `None` when this node owns it.
+    pub fn owner(&self, keyspace: &str, key: &[u8]) -> Option<ShardNode> {
+        let topologies = self
+            .topologies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        self.remote(&topologies.ring, keyspace, key)
+    }
+
+    /// Returns the nodes that owned a key in the topologies it may still be
+    /// moved out of, oldest first, when they differ from this node.
+    pub fn previous_owners(&self, keyspace: &str, key: &[u8]) -> Vec<ShardNode> {
+        let topologies = self
+            .topologies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let mut owners: Vec<ShardNode> = vec![];
+        for (_, ring) in &topologies.previous {
+            if let Some(node) = self.remote(ring, keyspace, key) {
+                if owners.iter().all(|n| n.id != node.id) {
+                    owners.push(node);
+                }
+            }
+        }
+        owners
+    }
+
+    /// Newest topology version whose keys this node finished moving.
+    pub fn migrated(&self) -> u64 {
+        self.migrated.load(Ordering::SeqCst)
+    }
+
+    /// Last failure to move the keys of the current topology, if they were
+    /// not moved yet.
+    pub fn migration_error(&self) -> Option<String> {
+        self.migration_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    fn set_migration_error(&self, error: Option<String>) {
+        *self
+            .migration_error
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = error;
+    }
+
+    /// Splits records between this node and the remote nodes owning them.
+    pub fn partition<T, F>(
+        &self,
+        keyspace: &str,
+        items: Vec<T>,
+        key: F,
+    ) -> (Vec<T>, Vec<(ShardNode, Vec<T>)>)
+    where
+        F: Fn(&T) -> &[u8],
+    {
+        let mut local = vec![];
+        let mut remote: HashMap<u64, (ShardNode, Vec<T>)> = HashMap::new();
+
+        for item in items {
+            match self.owner(keyspace, key(&item)) {
+                Some(node) => remote
+                    .entry(node.id)
+                    .or_insert_with(|| (node, vec![]))
+                    .1
+                    .push(item),
+                None => local.push(item),
+            }
+        }
+        (local, remote.into_values().collect())
+    }
+
+    fn other_nodes(&self) -> Vec<ShardNode> {
+        let topologies = self
+            .topologies
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        topologies
+            .current
+            .nodes
+            .iter()
+            .filter(|n| n.id != self.node_id)
+            .cloned()
+            .collect()
+    }
+
+    #[allow(clippy::result_large_err)]
+    pub fn client(&self, addr: &str) -> StdResult<StoreClient<Channel>, Status> {
+        let mut clients = self.clients.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(client) = clients.get(addr) {
+            return Ok(client.clone());
+        }
+
+        let mut endpoint = Endpoint::from_shared(addr.to_string())
+            .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        if let Some(tls) = &self.tls {
+            endpoint = endpoint
+                .tls_config(tls.clone())
+                .map_err(|e| Status::invalid_argument(e.to_string()))?;
+        }
+        let channel = endpoint
+            .connect_lazy()
+            .map_err(|e| Status::unavailable(e.to_string()))?;
+        let client = StoreClient::with_interceptor(channel, token_interceptor(self.token.clone()));
+        clients.insert(addr.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// Sends a request to every other node, ignoring errors with the given codes.
+    pub async fn broadcast<T, F, Fut>(
+        &self,
+        message: T,
+        ignored: &[Code],
+        call: F,
+    ) -> StdResult<(), Status>
+    where
+        T: Clone,
+        F: Fn(StoreClient<Channel>, Request<T>) -> Fut,
+        Fut: Future<Output = StdResult<Response<()>, Status>>,
+    {
+        let calls = self
+            .other_nodes()
+            .into_iter()
+            .map(|node| {
+                let client = self.client(&node.addr);
+                let message = message.clone();
+                let call = &call;
+                async move { call(client?, forwarded(message)).await }
+            })
+            .collect::<Vec<_>>();
+
+        for result in futures::future::join_all(calls).await {
+            match result {
+                Err(e) if !ignored.contains(&e.code()) => return Err(e),
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Looks a key up on its previous owners while it is being moved here.
+    /// They are asked in the order the key moves in, oldest first, so that a
+    /// key moving between them is not missed.
+    pub async fn get_from_previous_owner(&self, query: GetKeyQuery) -> Option<Record> {
+        for node in self.previous_owners(&query.keyspace, &query.key) {
+            let mut client = match self.client(&node.addr) {
+                Ok(client) => client,
+                Err(_) => continue,
+            };
+            if let Ok(record) = client.get_key(migration(query.clone())).await {
+                return Some(record.into_inner());
+            }
+        }
+        None
+    }
+
+    /// Replaces the topology if it is newer than the current one and starts
+    /// moving the keys this node no longer owns. Returns whether it changed.
+    #[allow(clippy::result_large_err)]
+    pub fn update_topology(self: &Arc<Self>, topology: ClusterTopology) -> StdResult<bool, Status> {
+        let version = topology.version;
+        {
+            let mut topologies = self
+                .topologies
+                .write()
+                .unwrap_or_else(PoisonError::into_inner);
+            if topology.version == topologies.current.version && topology == topologies.current {
+                return Ok(false);
+            }
+            if topology.version <= topologies.current.version {
+                return Err(Status::failed_precondition(format!(
+                    "Topology version must be greater than {}",
+                    topologies.current.version
+                )));
+            }
+
+            info!("Switching to topology version {}", topology.version);
+            let ring = HashRing::new(&topology);
+            let previous = std::mem::replace(&mut topologies.ring, ring);
+            let previous = match topologies.current.nodes.is_empty() {
+                // A node joining the cluster did not know its previous
+                // topology, made of the other nodes.
+                true => HashRing::new(&ClusterTopology {
+                    nodes: topology
+                        .nodes
+                        .iter()
+                        .filter(|n| n.id != self.node_id)
+                        .cloned()
+                        .collect(),
+                    ..topology.clone()
+                }),
+                false => previous,
+            };
+            topologies.previous.push((topology.version, previous));
+            topologies.current = topology;
+        }
+
+        self.set_migration_error(None);
+        let router = self.clone();
+        tokio::spawn(async move { router.run_migration(version).await });
+        Ok(true)
+    }
+
+    /// Sends a new topology to every node of the old and new topologies.
+    pub async fn propagate_topology(
+        &self,
+        previous: &ClusterTopology,
+        topology: ClusterTopology,
+    ) -> StdResult<(), Status> {
+        let mut nodes: Vec<ShardNode> = previous.nodes.clone();
+        nodes.extend(topology.nodes.iter().cloned());
+        nodes.sort_by_key(|n| n.id);
+        nodes.dedup_by_key(|n| n.id);
+
+        let mut result = Ok(());
+        for node in nodes.into_iter().filter(|n| n.id != self.node_id) {
+            let resp = match self.client(&node.addr) {
+                Ok(mut client) => client
+                    .update_cluster_topology(forwarded(topology.clone()))
+                    .await
+                    .map(|_| ()),
+                Err(e) => Err(e),
+            };
+            if let Err(e) = resp {
+                warn!("Failed to send topology to node {}: {}", node.id, e);
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Adopts the newest topology known by the other nodes.
+    pub async fn discover(self: &Arc<Self>) {
+        for node in self.other_nodes() {
+            let topology = match self.client(&node.addr) {
+                Ok(mut client) => client.get_cluster_topology(forwarded(())).await,
+                Err(e) => Err(e),
+            };
+            if let Ok(topology) = topology {
+                let topology = topology.into_inner();
+                if topology.version > self.topology().version {
+                    let _ = self.update_topology(topology);
+                }
+            }
+        }
+    }
+
+    /// Moves the keys this node no longer owns in topology `version`, after
+    /// the previous topologies, retrying until it succeeds or a newer one
+    /// takes over. Then forgets the previous topologies once every node
+    /// moved its keys.
+    async fn run_migration(self: Arc<Self>, version: u64) {
+        {
+            let _migrating = self.migration.lock().await;
+            let mut backoff = MIGRATION_MIN_BACKOFF;
+            loop {
+                if self.topology().version != version {
+                    return;
+                }
+                match self.migrate(version).await {
+                    Ok(()) => break,
+                    Err(e) if self.topology().version == version => {
+                        error!(
+                            "Failed to move keys to their new owner, retrying in {:?}: {}",
+                            backoff, e
+                        );
+                        self.set_migration_error(Some(e.message().to_string()));
+                        sleep(backoff).await;
+                        backoff = (backoff * 2).min(MIGRATION_MAX_BACKOFF);
+                    }
+                    Err(_) => return,
+                }
+            }
+            self.migrated.fetch_max(version, Ordering::SeqCst);
+            self.set_migration_error(None);
+        }
+        self.forget_previous(version).await;
+    }
+
+    /// Waits for the nodes of the current and previous topologies to move the
+    /// keys they no longer own in topology `version`, then forgets the
+    /// topologies it replaced. Nodes that can't be reached keep them around.
+    async fn forget_previous(&self, version: u64) {
+        let mut nodes = {
+            let topologies = self
+                .topologies
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            let mut nodes = topologies.current.nodes.clone();
+            for (_, ring) in &topologies.previous {
+                nodes.extend(ring.nodes().iter().cloned());
+            }
+            nodes
+        };
+        nodes.sort_by_key(|n| n.id);
+        nodes.dedup_by_key(|n| n.id);
+        nodes.retain(|n| n.id != self.node_id);
+
+        let mut backoff = MIGRATION_MIN_BACKOFF;
+        while !nodes.is_empty() {
+            if self.topology().version != version {
+                return;
+            }
+            let mut pending = vec![];
+            for node in nodes {
+                let status = match self.client(&node.addr) {
+                    Ok(mut client) => client.get_migration_status(forwarded(())).await,
+                    Err(e) => Err(e),
+                };
+                match status {
+                    Ok(status) if status.get_ref().version >= version => {}
+                    // Nodes predating `GetMigrationStatus` can't tell.
+                    Err(e) if e.code() == Code::Unimplemented => {}
+                    _ => pending.push(node),
+                }
+            }
+            nodes = pending;
+            if !nodes.is_empty() {
+                sleep(backoff).await;
+                backoff = (backoff * 2).min(MIGRATION_MAX_BACKOFF);
+            }
+        }
+
+        let mut topologies = self
+            .topologies
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        topologies
+            .previous
+            .retain(|(replaced_by, _)| *replaced_by > version);
+        debug!("Forgot the topologies replaced up to version {}", version);
+    }
+
+    /// Retries a call to another node with a backoff while it fails with a
+    /// transient error, until a newer topology than `version` takes over.
+    async fn retry<T, F, Fut>(&self, version: u64, call: F) -> StdResult<T, Status>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = StdResult<T, Status>>,
+    {
+        let mut backoff = MIGRATION_MIN_BACKOFF;
+        loop {
+            match call().await {
+                Err(e) if is_transient(&e) && self.topology().version == version => {
+                    warn!("Failed to move keys, retrying in {:?}: {}", backoff, e);
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MIGRATION_MAX_BACKOFF);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Creates a keyspace on another node and returns the limits that the
+    /// batches sent to it must fit.
+    async fn prepare(
+        &self,
+        version: u64,
+        client: &StoreClient<Channel>,
+        keyspace: &str,
+    ) -> StdResult<Limits, Status> {
+        let mut limits = self
+            .retry(version, || {
+                let mut client = client.clone();
+                async move {
+                    let created = client
+                        .create_keyspace(forwarded(Keyspace {
+                            name: keyspace.to_string(),
+                        }))
+                        .await;
+                    match created {
+                        Err(e) if e.code() != Code::AlreadyExists => return Err(e),
+                        _ => {}
+                    }
+                    match client.get_limits(forwarded(())).await {
+                        Ok(limits) => Ok(limits.into_inner()),
+                        // Nodes predating `GetLimits` accept batches of any size.
+                        Err(e) if e.code() == Code::Unimplemented => Ok(Limits::default()),
+                        Err(e) => Err(e),
+                    }
+                }
+            })
+            .await?;
+        if limits.max_batch_records == 0 || limits.max_batch_records > MIGRATION_BATCH_SIZE as u64 {
+            limits.max_batch_records = MIGRATION_BATCH_SIZE as u64;
+        }
+        Ok(limits)
+    }
+
+    /// Moves the keys this node no longer owns to their owner, reading them
+    /// a page at a time on the blocking thread pool.
+    async fn migrate(&self, version: u64) -> StdResult<(), Status> {
+        let keyspaces = blocking(&self.store, &self.health, |store| store.list_keyspaces()).await?;
+        for ks in keyspaces {
+            let mut limits: HashMap<u64, Limits> = HashMap::new();
+            let mut moved: HashMap<u64, usize> = HashMap::new();
+            let mut after: Option<Vec<u8>> = None;
+            loop {
+                let (name, from) = (ks.name.clone(), after.clone());
+                let page = blocking(&self.store, &self.health, move |store| {
+                    store
+                        .get_keyspace(name)?
+                        .records_after(from.as_deref(), MIGRATION_BATCH_SIZE)
+                })
+                .await?;
+                after = match page.last() {
+                    Some(record) => Some(record.key.clone()),
+                    None => break,
+                };
+
+                let (_, remote) = self.partition(&ks.name, page, |r| &r.key);
+                for (node, records) in remote {
+                    let client = self.client(&node.addr)?;
+                    let node_limits = match limits.get(&node.id) {
+                        Some(limits) => limits.clone(),
+                        None => {
+                            let node_limits = self.prepare(version, &client, &ks.name).await?;
+                            limits.insert(node.id, node_limits.clone());
+                            node_limits
+                        }
+                    };
+                    *moved.entry(node.id).or_default() += records.len();
+
+                    for chunk in node_limits.batches(&ks.name, records) {
+                        let query = InsertKeysQuery {
+                            keyspace: ks.name.clone(),
+                            records: chunk.clone(),
+                        };
+                        self.retry(version, || {
+                            let mut client = client.clone();
+                            let query = query.clone();
+                            async move { client.insert_keys(migration(query)).await }
+                        })
+                        .await?;
+
+                        let name = ks.name.clone();
+                        blocking(&self.store, &self.health, move |store| {
+                            let ks = store.get_keyspace(name)?;
+                            for r in chunk {
+                                ks.delete_if_unchanged(r.key, r.value)?;
+                            }
+                            Ok(())
+                        })
+                        .await?;
+                    }
+                }
+            }
+
+            for (node, count) in moved {
+                info!(
+                    "Moved {} keys of keyspace '{}' to node {}",
+                    count, ks.name, node
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a call that failed may succeed later.
+fn is_transient(status: &Status) -> bool {
+    matches!(
+        status.code(),
+        Code::Unavailable
+            | Code::DeadlineExceeded
+            | Code::ResourceExhausted
+            | Code::Aborted
+            | Code::Unknown
+    )
+}
@@ -0,0 +1,126 @@
+//! Graceful shutdown. The server stops accepting connections once a shutdown
+//! is requested, drains in-flight requests and flushes the store to disk.
+
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::*;
+
+/// Handle stopping a running server. Clones share the same state.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<watch::Sender<bool>>,
+    requested_rx: watch::Receiver<bool>,
+    completed: Arc<watch::Sender<bool>>,
+    completed_rx: watch::Receiver<bool>,
+}
+
+async fn wait_for(mut rx: watch::Receiver<bool>) {
+    while !*rx.borrow() {
+        // The sender lives as long as any handle, so this only errs once
+        // every handle is dropped, when there is nothing left to wait for.
+        if rx.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (requested, requested_rx) = watch::channel(false);
+        let (completed, completed_rx) = watch::channel(false);
+        Self {
+            requested: Arc::new(requested),
+            requested_rx,
+            completed: Arc::new(completed),
+            completed_rx,
+        }
+    }
+
+    /// Also requests a shutdown when the process receives SIGINT or SIGTERM.
+    pub fn on_signals(self) -> Self {
+        let shutdown = self.clone();
+        tokio::spawn(async move {
+            match wait_for_signal().await {
+                Ok(name) => {
+                    info!("Received {}, shutting down", name);
+                    shutdown.trigger();
+                }
+                Err(e) => error!("Failed to listen for shutdown signals: {}", e),
+            }
+        });
+        self
+    }
+
+    /// Requests a shutdown without waiting for it.
+    pub fn trigger(&self) {
+        let _ = self.requested.send(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.requested_rx.borrow()
+    }
+
+    /// Resolves once a shutdown was requested.
+    pub async fn requested(&self) {
+        wait_for(self.requested_rx.clone()).await
+    }
+
+    /// Requests a shutdown and waits until the server has stopped and flushed
+    /// the store.
+    pub async fn stop(&self) {
+        self.trigger();
+        wait_for(self.completed_rx.clone()).await
+    }
+
+    pub(crate) fn complete(&self) {
+        let _ = self.completed.send(true);
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = sigterm.recv() => Ok("SIGTERM"),
+        _ = sigint.recv() => Ok("SIGINT"),
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_signal() -> std::io::Result<&'static str> {
+    tokio::signal::ctrl_c().await?;
+    Ok("Ctrl-C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    #[tokio::test]
+    async fn stop_waits_for_completion() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.is_triggered());
+
+        let server = shutdown.clone();
+        tokio::spawn(async move {
+            server.requested().await;
+            server.complete();
+        });
+
+        timeout(Duration::from_secs(1), shutdown.stop())
+            .await
+            .unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
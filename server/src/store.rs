@@ -1,36 +1,355 @@
 use futures::Stream;
 use std::pin::Pin;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::Arc;
+use std::time::Instant;
 use tokio::sync::mpsc;
-use tonic::{Request, Response, Status};
+use tonic::transport::Channel;
+use tonic::{Code, Request, Response, Status, Streaming};
+use tracing::Instrument;
 
+use dumpstors_lib::merkle;
 use dumpstors_lib::models;
+use dumpstors_lib::raft::{command, Command};
+use dumpstors_lib::rpc;
+use dumpstors_lib::store::keyspace::Staging;
+use dumpstors_lib::store::store_client::StoreClient;
 use dumpstors_lib::store::store_server;
 use dumpstors_lib::store::*;
 
+use super::auth::Authenticator;
+use super::cluster::RaftNode;
+use super::disk::DiskMonitor;
+use super::health::Health;
+use super::settings::Permission;
+use super::shard::{self, ShardRouter};
+use super::shutdown::Shutdown;
+use super::telemetry;
 use std::result::Result as StdResult;
 
+/// Records a bulk insert applies at once, unless batches are limited to fewer.
+const BULK_BATCH_RECORDS: usize = 10_000;
+/// Key and value bytes a bulk insert applies at once.
+const BULK_BATCH_BYTES: usize = 8 * 1024 * 1024;
+
+/// Runs blocking store work on the blocking thread pool, away from the executor.
+/// Unrecoverable errors and panics make the server unhealthy.
+pub(crate) async fn blocking<T, F>(
+    store: &Arc<Store>,
+    health: &Health,
+    f: F,
+) -> StdResult<T, Status>
+where
+    F: FnOnce(&Store) -> Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    health.check()?;
+    let store = store.clone();
+    match tokio::task::spawn_blocking(move || f(&store)).await {
+        Ok(Ok(value)) => Ok(value),
+        Ok(Err(e)) => {
+            health.observe(&e);
+            Err(e.into())
+        }
+        Err(e) => {
+            health.fail(format!("Store task failed: {}", e));
+            Err(Status::internal("Store task failed"))
+        }
+    }
+}
+
+/// Tells a missing key from a missing keyspace, both reported as not found.
+pub(crate) fn is_key_not_found(status: &Status) -> bool {
+    rpc::error_info(status).is_some_and(|info| info.reason == "KEY_NOT_FOUND")
+}
+
+/// Tells a conditional insert that was not written from other failed
+/// preconditions.
+pub(crate) fn is_condition_not_met(status: &Status) -> bool {
+    rpc::error_info(status).is_some_and(|info| info.reason == "CONDITION_NOT_MET")
+}
+
+#[derive(Clone)]
 pub struct DumpstorsStoreServer {
-    store: Arc<Mutex<Store>>,
+    store: Arc<Store>,
+    raft: Option<Arc<RaftNode>>,
+    shards: Option<Arc<ShardRouter>>,
+    auth: Option<Arc<Authenticator>>,
+    disk: Option<Arc<DiskMonitor>>,
+    limits: Limits,
+    health: Health,
 }
 
 impl DumpstorsStoreServer {
-    fn get_store_guard(&self) -> StdResult<MutexGuard<Store>, Status> {
-        match self.store.lock() {
-            Ok(store) => Ok(store),
-            // TODO: Find proper way to shutdown Tokio
-            Err(e) => panic!("{:?}\nPoisonError on store Mutex. Shutting down.", e),
+    pub fn new(store: Arc<Store>) -> Self {
+        Self {
+            store,
+            raft: None,
+            shards: None,
+            auth: None,
+            disk: None,
+            limits: Limits::default(),
+            health: Health::new(Shutdown::new()),
+        }
+    }
+
+    /// Sends writes through the raft log.
+    pub fn with_raft(mut self, raft: Arc<RaftNode>) -> Self {
+        self.raft = Some(raft);
+        self
+    }
+
+    /// Routes keys to the node of the sharded cluster owning them.
+    pub fn with_shards(mut self, shards: Arc<ShardRouter>) -> Self {
+        self.shards = Some(shards);
+        self
+    }
+
+    /// Shares the server's health state.
+    pub fn with_health(mut self, health: Health) -> Self {
+        self.health = health;
+        self
+    }
+
+    /// Checks the caller's permissions on keyspaces.
+    pub fn with_auth(mut self, auth: Arc<Authenticator>) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    /// Rejects writes while the disk usage is above the high watermark.
+    pub fn with_disk(mut self, disk: Arc<DiskMonitor>) -> Self {
+        self.disk = Some(disk);
+        self
+    }
+
+    /// Rejects requests over the limits, which are advertised to clients.
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    #[allow(clippy::result_large_err)]
+    fn authorize<T>(
+        &self,
+        request: &Request<T>,
+        keyspace: &str,
+        permission: Permission,
+    ) -> StdResult<(), Status> {
+        match &self.auth {
+            Some(auth) => auth.authorize(request, keyspace, permission).map(|_| ()),
+            None => Ok(()),
         }
     }
 
-    pub fn new(store: Arc<Mutex<Store>>) -> Self {
-        Self { store }
+    /// Rejects writes while the server or the keyspace is read-only.
+    #[allow(clippy::result_large_err)]
+    fn check_writable(&self, keyspace: &str) -> StdResult<(), Status> {
+        Ok(self.store.check_writable(keyspace)?)
+    }
+
+    /// Rejects writes taking up disk space above the high watermark. Deletes
+    /// are let through to reclaim space.
+    #[allow(clippy::result_large_err)]
+    fn check_disk(&self) -> StdResult<(), Status> {
+        match &self.disk {
+            Some(disk) => disk.check_writes(),
+            None => Ok(()),
+        }
+    }
+
+    async fn execute(&self, op: command::Op) -> StdResult<(), Status> {
+        let command = Command::from(op);
+        match &self.raft {
+            Some(raft) => raft.propose(command).await,
+            None => blocking(&self.store, &self.health, move |store| command.apply(store)).await,
+        }
+    }
+
+    /// Returns whether the request comes from another node of the sharded
+    /// cluster, whose forwarding and migration markers are then honoured.
+    /// Without auth, nodes can't be told from clients and every caller is
+    /// trusted, as for any other request.
+    fn is_peer_request<T>(&self, request: &Request<T>) -> bool {
+        match (&self.shards, &self.auth) {
+            (None, _) => false,
+            (Some(_), Some(auth)) => auth.is_peer(request),
+            (Some(_), None) => true,
+        }
+    }
+
+    fn is_forwarded<T>(&self, request: &Request<T>) -> bool {
+        self.is_peer_request(request) && shard::is_forwarded(request)
+    }
+
+    fn is_migration<T>(&self, request: &Request<T>) -> bool {
+        self.is_peer_request(request) && shard::is_migration(request)
+    }
+
+    /// Returns the shard router unless the request was forwarded by another node.
+    fn router<T>(&self, request: &Request<T>) -> Option<Arc<ShardRouter>> {
+        match &self.shards {
+            Some(router) if !self.is_forwarded(request) => Some(router.clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns a client to the node owning a key, when it is not this node.
+    #[allow(clippy::result_large_err)]
+    fn owner_client(
+        router: &Option<Arc<ShardRouter>>,
+        keyspace: &str,
+        key: &[u8],
+    ) -> StdResult<Option<StoreClient<Channel>>, Status> {
+        match router.as_ref().map(|r| (r, r.owner(keyspace, key))) {
+            Some((router, Some(node))) => Ok(Some(router.client(&node.addr)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the shard router when a key may still live on its previous owner.
+    fn fallback_router<T>(&self, request: &Request<T>) -> Option<Arc<ShardRouter>> {
+        match &self.shards {
+            Some(router) if !self.is_migration(request) => Some(router.clone()),
+            _ => None,
+        }
+    }
+
+    async fn get_local_key(
+        store: &Arc<Store>,
+        health: &Health,
+        fallback: &Option<Arc<ShardRouter>>,
+        query: GetKeyQuery,
+    ) -> StdResult<models::Record, Status> {
+        let value = Self::get_local_value(store, health, &query).await?;
+
+        let not_found = |query: GetKeyQuery| Error::KeyNotFound {
+            keyspace: query.keyspace,
+            key: query.key,
+        };
+        match (value, fallback) {
+            (Some(value), _) => Ok(models::Record {
+                key: query.key,
+                value,
+            }),
+            (None, Some(router)) => match router.get_from_previous_owner(query.clone()).await {
+                Some(record) => Ok(record),
+                // Keys are only deleted from their previous owner once moved
+                // here, so a key moved in the meantime is now found locally.
+                None => match Self::get_local_value(store, health, &query).await? {
+                    Some(value) => Ok(models::Record {
+                        key: query.key,
+                        value,
+                    }),
+                    None => Err(not_found(query).into()),
+                },
+            },
+            (None, None) => Err(not_found(query).into()),
+        }
+    }
+
+    async fn get_local_value(
+        store: &Arc<Store>,
+        health: &Health,
+        query: &GetKeyQuery,
+    ) -> StdResult<Option<Vec<u8>>, Status> {
+        let (keyspace, key) = (query.keyspace.clone(), query.key.clone());
+        blocking(store, health, move |store| {
+            match store.get_keyspace(keyspace)?.get(key) {
+                Ok(value) => Ok(Some(value)),
+                Err(Error::KeyNotFound { .. }) => Ok(None),
+                Err(e) => Err(e),
+            }
+        })
+        .await
+    }
+
+    /// Writes records on the nodes owning them.
+    async fn write_records(
+        &self,
+        router: &Option<Arc<ShardRouter>>,
+        mut query: InsertKeysQuery,
+    ) -> StdResult<(), Status> {
+        if let Some(router) = router {
+            let (local, remote) = router.partition(&query.keyspace, query.records, |r| &r.key);
+            for (node, records) in remote {
+                let query = InsertKeysQuery {
+                    keyspace: query.keyspace.clone(),
+                    records,
+                };
+                router
+                    .client(&node.addr)?
+                    .insert_keys(shard::forwarded(query))
+                    .await?;
+            }
+            if local.is_empty() {
+                return Ok(());
+            }
+            query.records = local;
+        }
+        self.execute(command::Op::InsertKeys(query)).await
+    }
+
+    /// Applies a batch of a bulk insert, either to its staging tree or to the
+    /// keyspace. The staging tree is handed back to stage the next batch.
+    async fn apply_bulk(
+        &self,
+        router: &Option<Arc<ShardRouter>>,
+        keyspace: &str,
+        staging: Option<Staging>,
+        records: Vec<models::Record>,
+    ) -> StdResult<Option<Staging>, Status> {
+        self.check_writable(keyspace)?;
+        self.check_disk()?;
+        match staging {
+            Some(staging) => {
+                blocking(&self.store, &self.health, move |_| {
+                    staging.insert(records)?;
+                    Ok(Some(staging))
+                })
+                .await
+            }
+            None => {
+                let query = InsertKeysQuery {
+                    keyspace: keyspace.to_string(),
+                    records,
+                };
+                self.write_records(router, query).await?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Deletes keys from their previous owners while they are being moved here.
+    async fn delete_from_previous_owners(
+        fallback: &Option<Arc<ShardRouter>>,
+        keyspace: &str,
+        keys: &[Vec<u8>],
+    ) -> bool {
+        let router = match fallback {
+            Some(router) => router,
+            None => return false,
+        };
+
+        let mut deleted = false;
+        for key in keys {
+            for node in router.previous_owners(keyspace, key) {
+                if let Ok(mut client) = router.client(&node.addr) {
+                    let request = shard::migration(DeleteKeyQuery {
+                        keyspace: keyspace.to_string(),
+                        key: key.clone(),
+                    });
+                    deleted |= client.delete_key(request).await.is_ok();
+                }
+            }
+        }
+        deleted
     }
 }
 
 #[tonic::async_trait]
 impl store_server::Store for DumpstorsStoreServer {
     async fn ping(&self, _request: Request<()>) -> StdResult<Response<()>, Status> {
+        self.health.check()?;
         Ok(Response::new(()))
     }
 
@@ -38,31 +357,97 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<GetKeyspaceQuery>,
     ) -> StdResult<Response<models::Keyspace>, Status> {
-        let mut store = self.get_store_guard()?;
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
         let request = request.into_inner();
 
-        let ks = store.get_keyspace(request.keyspace)?;
-        Ok(Response::new(models::Keyspace::from(ks.clone())))
+        let ks = self.store.get_keyspace(request.keyspace)?;
+        Ok(Response::new(models::Keyspace::from(ks)))
     }
 
     async fn list_keyspaces(
         &self,
-        _request: Request<()>,
+        request: Request<()>,
     ) -> StdResult<Response<ListKeyspacesResponse>, Status> {
-        let mut store = self.get_store_guard()?;
-        let mut keyspaces = store.list_keyspaces()?;
+        let principal = match &self.auth {
+            Some(auth) => Some(auth.authenticate(&request)?),
+            None => None,
+        };
+        let mut keyspaces = self.store.list_keyspaces()?;
+        if let Some(principal) = principal {
+            keyspaces.retain(|ks| principal.can(Permission::Read, &ks.name));
+        }
         keyspaces.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(Response::new(ListKeyspacesResponse { keyspaces }))
     }
 
+    async fn get_limits(&self, _request: Request<()>) -> StdResult<Response<Limits>, Status> {
+        Ok(Response::new(self.limits.clone()))
+    }
+
+    async fn set_keyspace_quota(
+        &self,
+        request: Request<SetKeyspaceQuotaQuery>,
+    ) -> StdResult<Response<()>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Admin)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        let router = self.router(&request);
+        let request = request.into_inner();
+
+        self.execute(command::Op::SetKeyspaceQuota(request.clone()))
+            .await?;
+        if let Some(router) = router {
+            router
+                .broadcast(request, &[], |mut c, r| async move {
+                    c.set_keyspace_quota(r).await
+                })
+                .await?;
+        }
+        Ok(Response::new(()))
+    }
+
+    async fn get_keyspace_usage(
+        &self,
+        request: Request<GetKeyspaceUsageQuery>,
+    ) -> StdResult<Response<KeyspaceUsage>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        let request = request.into_inner();
+
+        let usage = blocking(&self.store, &self.health, move |store| {
+            let keyspace = store.get_keyspace(request.keyspace)?;
+            let usage = keyspace.usage()?;
+            Ok(KeyspaceUsage {
+                keyspace: keyspace.name.clone(),
+                keys: usage.keys,
+                bytes: usage.bytes,
+                quota: Some(keyspace.quota()),
+            })
+        })
+        .await?;
+        Ok(Response::new(usage))
+    }
+
     async fn create_keyspace(
         &self,
         request: Request<models::Keyspace>,
     ) -> StdResult<Response<()>, Status> {
-        let mut store = self.get_store_guard()?;
+        self.authorize(&request, &request.get_ref().name, Permission::Admin)?;
+        telemetry::record_key(&request.get_ref().name, None);
+        self.check_writable(&request.get_ref().name)?;
+        self.check_disk()?;
+        let router = self.router(&request);
         let request = request.into_inner();
 
-        store.create_keyspace(request)?;
+        self.execute(command::Op::CreateKeyspace(request.clone()))
+            .await?;
+        if let Some(router) = router {
+            router
+                .broadcast(request, &[Code::AlreadyExists], |mut c, r| async move {
+                    c.create_keyspace(r).await
+                })
+                .await?;
+        }
         Ok(Response::new(()))
     }
 
@@ -70,10 +455,21 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<DeleteKeyspaceQuery>,
     ) -> StdResult<Response<()>, Status> {
-        let mut store = self.get_store_guard()?;
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Admin)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        self.check_writable(&request.get_ref().keyspace)?;
+        let router = self.router(&request);
         let request = request.into_inner();
 
-        store.delete_keyspace(request.keyspace)?;
+        self.execute(command::Op::DeleteKeyspace(request.clone()))
+            .await?;
+        if let Some(router) = router {
+            router
+                .broadcast(request, &[Code::NotFound], |mut c, r| async move {
+                    c.delete_keyspace(r).await
+                })
+                .await?;
+        }
         Ok(Response::new(()))
     }
 
@@ -81,10 +477,21 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<TruncateKeyspaceQuery>,
     ) -> StdResult<Response<()>, Status> {
-        let mut store = self.get_store_guard()?;
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Admin)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        self.check_writable(&request.get_ref().keyspace)?;
+        let router = self.router(&request);
         let request = request.into_inner();
 
-        store.truncate_keyspace(request.keyspace)?;
+        self.execute(command::Op::TruncateKeyspace(request.clone()))
+            .await?;
+        if let Some(router) = router {
+            router
+                .broadcast(request, &[Code::NotFound], |mut c, r| async move {
+                    c.truncate_keyspace(r).await
+                })
+                .await?;
+        }
         Ok(Response::new(()))
     }
 
@@ -92,29 +499,44 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<GetKeyQuery>,
     ) -> StdResult<Response<models::Record>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, Some(&request.get_ref().key));
+        self.limits.check_key(&request.get_ref().key)?;
+        let router = self.router(&request);
+        let fallback = self.fallback_router(&request);
         let request = request.into_inner();
 
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace.clone())?;
+        if let Some(mut client) = Self::owner_client(&router, &request.keyspace, &request.key)? {
+            return client.get_key(shard::forwarded(request)).await;
+        }
 
-        let value = ks.get(request.key.clone())?;
-        Ok(Response::new(models::Record {
-            key: request.key,
-            value,
-        }))
+        let record = Self::get_local_key(&self.store, &self.health, &fallback, request).await?;
+        Ok(Response::new(record))
     }
 
     async fn insert_key(
         &self,
         request: Request<InsertKeyQuery>,
     ) -> StdResult<Response<()>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Write)?;
+        let router = self.router(&request);
         let request = request.into_inner();
-        let record = request.record.unwrap(); // Remove this prost is building Option<T> instead of T
+        let key = match &request.record {
+            Some(record) => {
+                self.limits.check_record(record)?;
+                record.key.clone()
+            }
+            None => return Err(Status::invalid_argument("Missing record")),
+        };
+        telemetry::record_key(&request.keyspace, Some(&key));
+        self.check_writable(&request.keyspace)?;
+        self.check_disk()?;
 
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace)?;
+        if let Some(mut client) = Self::owner_client(&router, &request.keyspace, &key)? {
+            return client.insert_key(shard::forwarded(request)).await;
+        }
 
-        ks.insert(record)?;
+        self.execute(command::Op::InsertKey(request)).await?;
         Ok(Response::new(()))
     }
 
@@ -122,43 +544,92 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<DeleteKeyQuery>,
     ) -> StdResult<Response<()>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Write)?;
+        telemetry::record_key(&request.get_ref().keyspace, Some(&request.get_ref().key));
+        self.limits.check_key(&request.get_ref().key)?;
+        self.check_writable(&request.get_ref().keyspace)?;
+        let router = self.router(&request);
+        let fallback = self.fallback_router(&request);
         let request = request.into_inner();
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace.clone())?;
 
-        ks.delete(request.key)?;
-        Ok(Response::new(()))
+        if let Some(mut client) = Self::owner_client(&router, &request.keyspace, &request.key)? {
+            return client.delete_key(shard::forwarded(request)).await;
+        }
+
+        let keyspace = request.keyspace.clone();
+        let key = request.key.clone();
+        let result = self.execute(command::Op::DeleteKey(request)).await;
+        let deleted_elsewhere =
+            Self::delete_from_previous_owners(&fallback, &keyspace, &[key]).await;
+
+        match result {
+            Err(e) if e.code() == Code::NotFound && deleted_elsewhere => Ok(Response::new(())),
+            Err(e) => Err(e),
+            Ok(_) => Ok(Response::new(())),
+        }
     }
 
     type GetKeysStream =
-        Pin<Box<dyn Stream<Item = StdResult<models::Record, Status>> + Send + Sync + 'static>>;
+        Pin<Box<dyn Stream<Item = StdResult<KeyResult, Status>> + Send + Sync + 'static>>;
 
     async fn get_keys(
         &self,
         request: Request<GetKeysQuery>,
     ) -> StdResult<Response<Self::GetKeysStream>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        let router = self.router(&request);
+        let fallback = self.fallback_router(&request);
         let request = request.into_inner();
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace.clone())?.clone();
+        self.limits.check_batch("keys", request.keys.len())?;
+        for key in request.keys.iter() {
+            self.limits.check_key(key)?;
+        }
+        // Fail early when the keyspace does not exist, before streaming anything.
+        self.store.get_keyspace(request.keyspace.clone())?;
+        let store = self.store.clone();
+        let health = self.health.clone();
 
         let (tx, rx) = mpsc::channel(4);
 
-        tokio::spawn(async move {
-            let ks = ks.clone();
-
-            for key in request.keys {
-                match ks.clone().get(key.clone()) {
-                    Ok(value) => tx
-                        .send(Ok(models::Record {
-                            key: key.clone(),
-                            value,
-                        }))
-                        .await
-                        .unwrap(),
-                    Err(e) => tx.send(Err(Status::from(e))).await.unwrap(),
-                };
+        tokio::spawn(
+            async move {
+                for key in request.keys {
+                    let query = GetKeyQuery {
+                        keyspace: request.keyspace.clone(),
+                        key,
+                    };
+                    let key = query.key.clone();
+                    let result = match Self::owner_client(&router, &query.keyspace, &query.key) {
+                        Ok(Some(mut client)) => client
+                            .get_key(shard::forwarded(query))
+                            .await
+                            .map(|r| r.into_inner()),
+                        Ok(None) => Self::get_local_key(&store, &health, &fallback, query).await,
+                        Err(e) => Err(e),
+                    };
+                    let result = match result {
+                        Ok(record) => Ok(KeyResult {
+                            key: record.key,
+                            found: true,
+                            value: record.value,
+                        }),
+                        Err(e) if is_key_not_found(&e) => Ok(KeyResult {
+                            key,
+                            found: false,
+                            value: vec![],
+                        }),
+                        Err(e) => Err(e),
+                    };
+                    let failed = result.is_err();
+                    // The client went away, there is no one left to send keys to.
+                    if tx.send(result).await.is_err() || failed {
+                        break;
+                    }
+                }
             }
-        });
+            .in_current_span(),
+        );
 
         Ok(Response::new(Box::pin(
             tokio_stream::wrappers::ReceiverStream::new(rx),
@@ -169,29 +640,272 @@ impl store_server::Store for DumpstorsStoreServer {
         &self,
         request: Request<InsertKeysQuery>,
     ) -> StdResult<Response<()>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Write)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        self.check_writable(&request.get_ref().keyspace)?;
+        self.check_disk()?;
+        let router = self.router(&request);
+        let migration = self.is_migration(&request);
         let request = request.into_inner();
+        self.limits.check_batch("records", request.records.len())?;
+        for record in request.records.iter() {
+            self.limits.check_record(record)?;
+        }
 
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace)?;
+        if migration {
+            blocking(&self.store, &self.health, move |store| {
+                store
+                    .get_keyspace(request.keyspace)?
+                    .insert_missing(request.records)
+            })
+            .await?;
+            return Ok(Response::new(()));
+        }
 
-        ks.batch_insert(request.records)?;
+        self.write_records(&router, request).await?;
         Ok(Response::new(()))
     }
 
+    async fn bulk_insert(
+        &self,
+        mut request: Request<Streaming<BulkInsertChunk>>,
+    ) -> StdResult<Response<BulkInsertSummary>, Status> {
+        let start = Instant::now();
+        let first = match request.get_mut().message().await? {
+            Some(chunk) => chunk,
+            None => return Err(Status::invalid_argument("Missing first chunk")),
+        };
+        let keyspace = first.keyspace.clone();
+        self.authorize(&request, &keyspace, Permission::Write)?;
+        telemetry::record_key(&keyspace, None);
+        self.check_writable(&keyspace)?;
+        self.check_disk()?;
+        let router = self.router(&request);
+
+        let mut staging = match BulkInsertMode::from_i32(first.mode) {
+            Some(BulkInsertMode::CommitAsYouGo) => None,
+            Some(BulkInsertMode::StageThenPublish) => {
+                if self.raft.is_some() || self.shards.is_some() {
+                    return Err(Status::failed_precondition(
+                        "Staged bulk inserts are not supported in cluster or sharding mode",
+                    ));
+                }
+                let keyspace = keyspace.clone();
+                let staging = blocking(&self.store, &self.health, move |store| {
+                    store.get_keyspace(keyspace)?.stage()
+                })
+                .await?;
+                Some(staging)
+            }
+            None => return Err(Status::invalid_argument("Unknown bulk insert mode")),
+        };
+
+        let max_records = match self.limits.max_batch_records as usize {
+            0 => BULK_BATCH_RECORDS,
+            max => max.min(BULK_BATCH_RECORDS),
+        };
+        let mut summary = BulkInsertSummary::default();
+        let mut batch = vec![];
+        let mut batch_bytes = 0;
+        let mut chunk = Some(first);
+        while let Some(next) = chunk {
+            if !next.keyspace.is_empty() && next.keyspace != keyspace {
+                return Err(Status::invalid_argument(format!(
+                    "Every chunk must target keyspace '{}'",
+                    keyspace
+                )));
+            }
+            self.limits.check_batch("records", next.records.len())?;
+            for record in next.records {
+                self.limits.check_record(&record)?;
+                batch_bytes += record.key.len() + record.value.len();
+                if staging.is_some() {
+                    // Checked as records arrive, before staging them.
+                    self.limits
+                        .check_staged(summary.bytes + batch_bytes as u64)?;
+                }
+                batch.push(record);
+                if batch.len() >= max_records || batch_bytes >= BULK_BATCH_BYTES {
+                    summary.records += batch.len() as u64;
+                    summary.bytes += batch_bytes as u64;
+                    let records = std::mem::take(&mut batch);
+                    staging = self
+                        .apply_bulk(&router, &keyspace, staging, records)
+                        .await?;
+                    batch_bytes = 0;
+                }
+            }
+            // Chunks are only read once the previous ones are applied, so that
+            // flow control slows down clients sending faster than the store
+            // writes.
+            chunk = request.get_mut().message().await?;
+        }
+        if !batch.is_empty() {
+            summary.records += batch.len() as u64;
+            summary.bytes += batch_bytes as u64;
+            staging = self.apply_bulk(&router, &keyspace, staging, batch).await?;
+        }
+
+        if let Some(staging) = staging {
+            self.check_writable(&keyspace)?;
+            self.check_disk()?;
+            blocking(&self.store, &self.health, move |_| staging.publish()).await?;
+        }
+        summary.duration_ms = start.elapsed().as_millis() as u64;
+        Ok(Response::new(summary))
+    }
+
     async fn delete_keys(
         &self,
         request: Request<DeleteKeysQuery>,
     ) -> StdResult<Response<()>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Write)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        self.check_writable(&request.get_ref().keyspace)?;
+        let router = self.router(&request);
+        let fallback = self.fallback_router(&request);
+        let mut request = request.into_inner();
+        self.limits.check_batch("keys", request.keys.len())?;
+        for key in request.keys.iter() {
+            self.limits.check_key(key)?;
+        }
+
+        if let Some(router) = router {
+            let (local, remote) = router.partition(&request.keyspace, request.keys, |k| k);
+            for (node, keys) in remote {
+                let query = DeleteKeysQuery {
+                    keyspace: request.keyspace.clone(),
+                    keys,
+                };
+                router
+                    .client(&node.addr)?
+                    .delete_keys(shard::forwarded(query))
+                    .await?;
+            }
+            if local.is_empty() {
+                return Ok(Response::new(()));
+            }
+            request.keys = local;
+        }
+
+        let keyspace = request.keyspace.clone();
+        let keys = request.keys.clone();
+        self.execute(command::Op::DeleteKeys(request)).await?;
+        Self::delete_from_previous_owners(&fallback, &keyspace, &keys).await;
+        Ok(Response::new(()))
+    }
+
+    async fn get_keyspace_digest(
+        &self,
+        request: Request<GetKeyspaceDigestQuery>,
+    ) -> StdResult<Response<KeyspaceDigest>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
         let request = request.into_inner();
-        let mut store = self.get_store_guard()?;
-        let ks = store.get_keyspace(request.keyspace.clone())?;
+        if request.depth > merkle::MAX_DEPTH {
+            return Err(Status::invalid_argument(format!(
+                "Digest depth must be at most {}",
+                merkle::MAX_DEPTH
+            )));
+        }
+
+        let tree = blocking(&self.store, &self.health, move |store| {
+            store
+                .get_keyspace(request.keyspace)?
+                .merkle_tree(request.depth)
+        })
+        .await?;
+        Ok(Response::new(tree.into()))
+    }
+
+    type GetKeyRangesStream =
+        Pin<Box<dyn Stream<Item = StdResult<models::Record, Status>> + Send + Sync + 'static>>;
+
+    async fn get_key_ranges(
+        &self,
+        request: Request<GetKeyRangesQuery>,
+    ) -> StdResult<Response<Self::GetKeyRangesStream>, Status> {
+        self.authorize(&request, &request.get_ref().keyspace, Permission::Read)?;
+        telemetry::record_key(&request.get_ref().keyspace, None);
+        let request = request.into_inner();
+        let records = blocking(&self.store, &self.health, move |store| {
+            store
+                .get_keyspace(request.keyspace)?
+                .records_in_buckets(request.depth, &request.buckets)
+        })
+        .await?;
+
+        let (tx, rx) = mpsc::channel(4);
+
+        tokio::spawn(async move {
+            for record in records {
+                if tx.send(Ok(record)).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Response::new(Box::pin(
+            tokio_stream::wrappers::ReceiverStream::new(rx),
+        )))
+    }
+
+    async fn get_cluster_topology(
+        &self,
+        _request: Request<()>,
+    ) -> StdResult<Response<models::ClusterTopology>, Status> {
+        let topology = match &self.shards {
+            Some(router) => router.topology(),
+            None => models::ClusterTopology::default(),
+        };
+        Ok(Response::new(topology))
+    }
+
+    async fn get_migration_status(
+        &self,
+        _request: Request<()>,
+    ) -> StdResult<Response<MigrationStatus>, Status> {
+        let (version, error) = match &self.shards {
+            Some(router) => (router.migrated(), router.migration_error()),
+            None => (0, None),
+        };
+        Ok(Response::new(MigrationStatus {
+            version,
+            error: error.unwrap_or_default(),
+        }))
+    }
 
-        ks.batch_delete(request.keys)?;
+    async fn update_cluster_topology(
+        &self,
+        request: Request<models::ClusterTopology>,
+    ) -> StdResult<Response<()>, Status> {
+        if let Some(auth) = &self.auth {
+            let principal = auth.authenticate(&request)?;
+            if !principal.can_all(Permission::Admin) {
+                return Err(Status::permission_denied(format!(
+                    "'{}' is not an administrator",
+                    principal.subject
+                )));
+            }
+        }
+        let forwarded = self.is_forwarded(&request);
+        let topology = request.into_inner();
+        let router = match &self.shards {
+            Some(router) => router,
+            None => return Err(Status::failed_precondition("Sharding is not enabled")),
+        };
+
+        let previous = router.topology();
+        router.update_topology(topology.clone())?;
+        if !forwarded {
+            router.propagate_topology(&previous, topology).await?;
+        }
         Ok(Response::new(()))
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::assertions_on_constants)]
 mod tests {
     use super::*;
     use futures::StreamExt;
@@ -199,12 +913,12 @@ mod tests {
     use uuid::Uuid;
 
     use dumpstors_lib::store::store_server::Store as StoreServer;
-    use std::sync::{Arc, Mutex};
+    use std::sync::Arc;
 
     use tonic::IntoRequest;
 
     async fn create_random_store_server() -> DumpstorsStoreServer {
-        let store = Arc::new(Mutex::new(Store::new(format!(".data/{}", Uuid::new_v4()))));
+        let store = Arc::new(Store::new(format!(".data/{}", Uuid::new_v4())).unwrap());
         super::DumpstorsStoreServer::new(store)
     }
 
@@ -214,6 +928,43 @@ mod tests {
         assert_eq!(srv.ping(().into_request()).await.unwrap().into_inner(), ());
     }
 
+    #[tokio::test]
+    async fn store_server_unhealthy_test() {
+        let shutdown = Shutdown::new();
+        let health = Health::new(shutdown.clone());
+        let srv = create_random_store_server()
+            .await
+            .with_health(health.clone());
+        srv.create_keyspace(
+            models::Keyspace {
+                name: String::from("ks1"),
+            }
+            .into_request(),
+        )
+        .await
+        .unwrap();
+
+        health.fail("test failure");
+        assert!(shutdown.is_triggered());
+        match srv.ping(().into_request()).await {
+            Err(e) if e.code() == Code::Unavailable => (),
+            _ => panic!("Ping must fail once the server is unhealthy"),
+        };
+        match srv
+            .get_key(
+                GetKeyQuery {
+                    keyspace: String::from("ks1"),
+                    key: b"foo".to_vec(),
+                }
+                .into_request(),
+            )
+            .await
+        {
+            Err(e) if e.code() == Code::Unavailable => (),
+            _ => panic!("Requests must be rejected once the server is unhealthy"),
+        };
+    }
+
     #[tokio::test]
     async fn store_server_keyspace_test() {
         let srv = create_random_store_server().await;
@@ -388,10 +1139,7 @@ mod tests {
 
         match resp {
             Err(e) => assert_eq!(e.code(), Code::NotFound),
-            _ => assert!(
-                false,
-                "Deleting a key on a unknown keyspace must return an NotFound"
-            ),
+            _ => panic!("Deleting a key on a unknown keyspace must return an NotFound"),
         };
 
         let resp = srv
@@ -402,6 +1150,7 @@ mod tests {
                         key: b"foo".to_vec(),
                         value: b"foo".to_vec(),
                     }),
+                    ..Default::default()
                 }
                 .into_request(),
             )
@@ -409,10 +1158,7 @@ mod tests {
 
         match resp {
             Err(e) => assert_eq!(e.code(), Code::NotFound),
-            _ => assert!(
-                false,
-                "Inserting a key on a unknown keyspace must return an NotFound"
-            ),
+            _ => panic!("Inserting a key on a unknown keyspace must return an NotFound"),
         };
 
         let resp = srv
@@ -427,10 +1173,7 @@ mod tests {
 
         match resp {
             Err(e) => assert_eq!(e.code(), Code::NotFound),
-            _ => assert!(
-                false,
-                "Getting a key on a unknown keyspace must return an NotFound"
-            ),
+            _ => panic!("Getting a key on a unknown keyspace must return an NotFound"),
         };
     }
 
@@ -468,6 +1211,7 @@ mod tests {
                 InsertKeyQuery {
                     keyspace: ks.name.clone(),
                     record: Some(r.clone()), // Fix this Some...
+                    ..Default::default()
                 }
                 .into_request(),
             )
@@ -647,6 +1391,7 @@ mod tests {
                 InsertKeyQuery {
                     keyspace: ks.name.clone(),
                     record: Some(r.clone()), // Fix this Some...
+                    ..Default::default()
                 }
                 .into_request(),
             )
@@ -654,11 +1399,13 @@ mod tests {
             .unwrap();
         }
 
+        let mut keys: Vec<Vec<u8>> = records.clone().into_iter().map(|r| r.key).collect();
+        keys.insert(1, b"missing".to_vec());
         let mut resp = srv
             .get_keys(
                 GetKeysQuery {
                     keyspace: ks.name.clone(),
-                    keys: records.clone().into_iter().map(|r| r.key).collect(),
+                    keys,
                 }
                 .into_request(),
             )
@@ -666,11 +1413,78 @@ mod tests {
             .unwrap()
             .into_inner();
 
-        let mut inserted_records = vec![];
+        let mut results = vec![];
         while let Some(r) = resp.next().await {
-            inserted_records.push(r.unwrap());
+            results.push(r.unwrap());
         }
 
-        assert_eq!(records, inserted_records)
+        let missing = results.remove(1);
+        assert_eq!(missing.key, b"missing".to_vec());
+        assert!(!missing.found);
+        assert!(results.iter().all(|r| r.found));
+        let found: Vec<models::Record> = results
+            .into_iter()
+            .map(|r| models::Record {
+                key: r.key,
+                value: r.value,
+            })
+            .collect();
+        assert_eq!(records, found)
+    }
+
+    #[tokio::test]
+    async fn error_details_test() {
+        let srv = create_random_store_server().await;
+
+        let status = srv
+            .get_key(
+                GetKeyQuery {
+                    keyspace: String::from("ks"),
+                    key: b"foo".to_vec(),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        let info = rpc::error_info(&status).unwrap();
+        assert_eq!(info.reason, "KEYSPACE_NOT_FOUND");
+        assert_eq!(info.metadata["keyspace"], "ks");
+
+        srv.create_keyspace(
+            models::Keyspace {
+                name: String::from("ks"),
+            }
+            .into_request(),
+        )
+        .await
+        .unwrap();
+        let status = srv
+            .get_key(
+                GetKeyQuery {
+                    keyspace: String::from("ks"),
+                    key: b"foo".to_vec(),
+                }
+                .into_request(),
+            )
+            .await
+            .unwrap_err();
+        let info = rpc::error_info(&status).unwrap();
+        assert_eq!(info.reason, "KEY_NOT_FOUND");
+        assert_eq!(info.metadata["key"], "666f6f");
+        assert!(!rpc::is_retryable(&status));
+
+        match srv
+            .create_keyspace(
+                models::Keyspace {
+                    name: String::from("../ks"),
+                }
+                .into_request(),
+            )
+            .await
+        {
+            Err(e) if e.code() == Code::InvalidArgument => (),
+            _ => panic!("Keyspace names must not escape the store directory"),
+        };
     }
 }
@@ -0,0 +1,173 @@
+//! Structured logs and request tracing. Each gRPC request runs in a span
+//! carrying its method and request ID, which handlers complete with the
+//! keyspace and key size. Spans can also be exported to an OpenTelemetry
+//! collector.
+
+use futures::future::BoxFuture;
+use http::header::HeaderValue;
+use http::{Request, Response};
+use hyper::Body;
+use opentelemetry::sdk::{trace, Resource};
+use opentelemetry::KeyValue;
+use std::task::{Context, Poll};
+use std::time::Instant;
+use tonic::body::BoxBody;
+use tonic::transport::NamedService;
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::field::{display, Empty};
+use tracing::*;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::EnvFilter;
+use uuid::Uuid;
+
+use super::completion::on_completion;
+use super::settings::{LogFormat, Logging};
+
+/// Header carrying the ID of a request, generated when the client sends none.
+/// It is echoed back in the response.
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+
+/// Installs the global subscriber. Events of the `log` crate are forwarded to it.
+pub fn init(conf: &Logging) -> Result<(), Box<dyn std::error::Error>> {
+    let filter = match EnvFilter::try_from_default_env() {
+        Ok(filter) => filter,
+        Err(_) => EnvFilter::try_new(&conf.filter)?,
+    };
+
+    let otlp = match &conf.otlp_endpoint {
+        Some(endpoint) => {
+            let tracer = opentelemetry_otlp::new_pipeline()
+                .with_endpoint(endpoint.as_str())
+                .with_trace_config(trace::config().with_resource(Resource::new(vec![
+                    KeyValue::new("service.name", "dumpstors"),
+                ])))
+                .with_tonic()
+                .install_batch(opentelemetry::runtime::Tokio)?;
+            Some(tracing_opentelemetry::layer().with_tracer(tracer))
+        }
+        None => None,
+    };
+
+    let registry = tracing_subscriber::registry().with(filter).with(otlp);
+    match conf.format {
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .with_span_list(false),
+            )
+            .try_init()?,
+        LogFormat::Text => registry.with(tracing_subscriber::fmt::layer()).try_init()?,
+    };
+    Ok(())
+}
+
+/// Exports the spans that are still buffered.
+pub fn flush() {
+    opentelemetry::global::shutdown_tracer_provider();
+}
+
+/// Records the keyspace and key size of the request being handled.
+pub fn record_key(keyspace: &str, key: Option<&[u8]>) {
+    let span = Span::current();
+    span.record("keyspace", keyspace);
+    if let Some(key) = key {
+        span.record("key_size", key.len());
+    }
+}
+
+fn request_id<T>(request: &mut Request<T>) -> HeaderValue {
+    match request.headers().get(REQUEST_ID_HEADER) {
+        Some(id) if !id.is_empty() => id.clone(),
+        _ => {
+            let id = HeaderValue::from_str(&Uuid::new_v4().to_string())
+                .expect("UUIDs are valid header values");
+            request.headers_mut().insert(REQUEST_ID_HEADER, id.clone());
+            id
+        }
+    }
+}
+
+/// Tower layer running each request of a gRPC service in its own span.
+#[derive(Clone, Default)]
+pub struct TraceLayer;
+
+impl<S> Layer<S> for TraceLayer {
+    type Service = TraceService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        TraceService { inner }
+    }
+}
+
+#[derive(Clone)]
+pub struct TraceService<S> {
+    inner: S,
+}
+
+impl<S: NamedService> NamedService for TraceService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+impl<S> Service<Request<Body>> for TraceService<S>
+where
+    S: Service<Request<Body>, Response = Response<BoxBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = Response<BoxBody>;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let id = request_id(&mut req);
+        let span = info_span!(
+            "rpc",
+            method = %req.uri().path(),
+            request_id = %id.to_str().unwrap_or_default(),
+            keyspace = Empty,
+            key_size = Empty,
+            status = Empty,
+            duration_ms = Empty,
+        );
+        let start = Instant::now();
+        let response = span.in_scope(|| self.inner.call(req));
+
+        let fut = async move {
+            let mut response = response.await?;
+            response.headers_mut().insert(REQUEST_ID_HEADER, id);
+
+            let span = Span::current();
+            Ok(on_completion(response, move |code| {
+                let duration = start.elapsed().as_secs_f64() * 1000.0;
+                span.record("status", display(format!("{:?}", code)));
+                span.record("duration_ms", duration);
+                span.in_scope(|| info!("Request completed"));
+            }))
+        };
+        Box::pin(fut.instrument(span))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_ids_are_kept_or_generated() {
+        let mut request = Request::new(());
+        request
+            .headers_mut()
+            .insert(REQUEST_ID_HEADER, HeaderValue::from_static("abc"));
+        assert_eq!(request_id(&mut request), "abc");
+
+        let mut request = Request::new(());
+        let id = request_id(&mut request);
+        assert_eq!(id.len(), 36);
+        assert_eq!(request.headers().get(REQUEST_ID_HEADER), Some(&id));
+    }
+}
@@ -0,0 +1,226 @@
+//! TLS for the gRPC server. Certificates are read again from disk whenever
+//! the files change, so that they can be rotated without a restart.
+
+use rustls::internal::pemfile;
+use rustls::sign::{self, CertifiedKey};
+use rustls::{
+    AllowAnyAuthenticatedClient, Certificate, ClientCertVerified, ClientCertVerifier, ClientHello,
+    DistinguishedNames, NoClientAuth, ResolvesServerCert, RootCertStore, ServerConfig, TLSError,
+};
+use std::fs;
+use std::io::{self, BufReader, Error, ErrorKind};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::Duration;
+use tokio::time::interval;
+use tracing::*;
+
+use super::shutdown::Shutdown;
+use tokio_rustls::webpki::DNSName;
+use tonic::transport::{self, ClientTlsConfig, ServerTlsConfig};
+
+use super::settings;
+
+const ALPN_H2: &[u8] = b"h2";
+
+fn invalid_data(msg: String) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+fn read_certs(pem: &[u8], path: &str) -> io::Result<Vec<Certificate>> {
+    match pemfile::certs(&mut BufReader::new(pem)) {
+        Ok(certs) if !certs.is_empty() => Ok(certs),
+        _ => Err(invalid_data(format!("No certificate found in '{}'", path))),
+    }
+}
+
+fn read_key(pem: &[u8], path: &str) -> io::Result<CertifiedKey> {
+    let mut keys = pemfile::pkcs8_private_keys(&mut BufReader::new(pem)).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut BufReader::new(pem)).unwrap_or_default();
+    }
+    let key = keys
+        .first()
+        .ok_or_else(|| invalid_data(format!("No private key found in '{}'", path)))?;
+    let key = sign::any_supported_type(key)
+        .map_err(|_| invalid_data(format!("Unsupported private key in '{}'", path)))?;
+
+    Ok(CertifiedKey::new(vec![], Arc::new(key)))
+}
+
+/// Raw content of the certificate files, compared to detect changes.
+#[derive(PartialEq)]
+struct Files {
+    cert: Vec<u8>,
+    key: Vec<u8>,
+    client_ca: Option<Vec<u8>>,
+}
+
+impl Files {
+    fn read(conf: &settings::Tls) -> io::Result<Self> {
+        Ok(Self {
+            cert: fs::read(&conf.cert_path)?,
+            key: fs::read(&conf.key_path)?,
+            client_ca: match &conf.client_ca_path {
+                Some(path) => Some(fs::read(path)?),
+                None => None,
+            },
+        })
+    }
+}
+
+struct Loaded {
+    key: CertifiedKey,
+    client_verifier: Option<Arc<dyn ClientCertVerifier>>,
+}
+
+impl Loaded {
+    fn parse(conf: &settings::Tls, files: &Files) -> io::Result<Self> {
+        let mut key = read_key(&files.key, &conf.key_path)?;
+        key.cert = read_certs(&files.cert, &conf.cert_path)?;
+        key.cross_check_end_entity_cert(None).map_err(|e| {
+            invalid_data(format!("Invalid certificate '{}': {}", conf.cert_path, e))
+        })?;
+
+        let client_verifier = match (&files.client_ca, &conf.client_ca_path) {
+            (Some(pem), Some(path)) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(pem, path)? {
+                    roots
+                        .add(&cert)
+                        .map_err(|e| invalid_data(format!("Invalid CA in '{}': {}", path, e)))?;
+                }
+                Some(AllowAnyAuthenticatedClient::new(roots))
+            }
+            _ => None,
+        };
+
+        Ok(Self {
+            key,
+            client_verifier,
+        })
+    }
+}
+
+pub struct TlsCerts {
+    conf: settings::Tls,
+    // Replaced as a whole on reload, so a poisoned lock still holds valid
+    // certificates.
+    files: Mutex<Files>,
+    loaded: RwLock<Loaded>,
+}
+
+impl TlsCerts {
+    pub fn load(conf: settings::Tls) -> io::Result<Arc<Self>> {
+        let files = Files::read(&conf)?;
+        let loaded = Loaded::parse(&conf, &files)?;
+
+        Ok(Arc::new(Self {
+            conf,
+            files: Mutex::new(files),
+            loaded: RwLock::new(loaded),
+        }))
+    }
+
+    fn loaded(&self) -> std::sync::RwLockReadGuard<'_, Loaded> {
+        self.loaded.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerTlsConfig {
+        let mut tls = ServerTlsConfig::new();
+        tls.rustls_server_config(self.rustls_config(&[ALPN_H2]));
+        tls
+    }
+
+    /// Configuration of listeners other than the gRPC one, negotiating
+    /// `protocols` through ALPN.
+    pub fn rustls_config(self: &Arc<Self>, protocols: &[&[u8]]) -> ServerConfig {
+        let mut config = match self.conf.client_ca_path {
+            Some(_) => ServerConfig::new(self.clone()),
+            None => ServerConfig::new(NoClientAuth::new()),
+        };
+        config.cert_resolver = self.clone();
+        config.set_protocols(&protocols.iter().map(|p| p.to_vec()).collect::<Vec<_>>());
+        config
+    }
+
+    /// Returns the configuration used to connect to the other nodes of a
+    /// cluster, presenting this node's certificate.
+    pub fn client_config(&self) -> io::Result<ClientTlsConfig> {
+        let files = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut tls = ClientTlsConfig::new().identity(transport::Identity::from_pem(
+            files.cert.clone(),
+            files.key.clone(),
+        ));
+
+        let ca = match &self.conf.peer_ca_path {
+            Some(path) => Some(fs::read(path)?),
+            None => files.client_ca.clone(),
+        };
+        if let Some(ca) = ca {
+            tls = tls.ca_certificate(transport::Certificate::from_pem(ca));
+        }
+        Ok(tls)
+    }
+
+    /// Reads the certificate files again, returning whether they changed.
+    /// Invalid files are rejected and the previous certificates kept.
+    pub fn reload(&self) -> io::Result<bool> {
+        let files = Files::read(&self.conf)?;
+        let mut current = self.files.lock().unwrap_or_else(PoisonError::into_inner);
+        if files == *current {
+            return Ok(false);
+        }
+
+        let loaded = Loaded::parse(&self.conf, &files)?;
+        *self.loaded.write().unwrap_or_else(PoisonError::into_inner) = loaded;
+        *current = files;
+        Ok(true)
+    }
+
+    /// Spawns a task checking the certificate files for changes.
+    pub fn watch(self: &Arc<Self>, shutdown: Shutdown) {
+        let certs = self.clone();
+        tokio::spawn(async move {
+            let mut ticker = interval(Duration::from_secs(certs.conf.reload_interval_secs));
+            loop {
+                tokio::select! {
+                    _ = ticker.tick() => {},
+                    _ = shutdown.requested() => break,
+                }
+                match certs.reload() {
+                    Ok(true) => info!("Reloaded TLS certificates"),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to reload TLS certificates: {}", e),
+                }
+            }
+        });
+    }
+}
+
+impl ResolvesServerCert for TlsCerts {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<CertifiedKey> {
+        Some(self.loaded().key.clone())
+    }
+}
+
+impl ClientCertVerifier for TlsCerts {
+    fn client_auth_root_subjects(&self, sni: Option<&DNSName>) -> Option<DistinguishedNames> {
+        self.loaded()
+            .client_verifier
+            .as_ref()
+            .and_then(|v| v.client_auth_root_subjects(sni))
+    }
+
+    fn verify_client_cert(
+        &self,
+        presented_certs: &[Certificate],
+        sni: Option<&DNSName>,
+    ) -> Result<ClientCertVerified, TLSError> {
+        match &self.loaded().client_verifier {
+            Some(verifier) => verifier.verify_client_cert(presented_certs, sni),
+            None => Err(TLSError::General(String::from(
+                "No client CA is configured",
+            ))),
+        }
+    }
+}